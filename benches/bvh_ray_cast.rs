@@ -0,0 +1,127 @@
+//! Compares the ray cast performance of the BVH built with
+//! [`BVHBuildMode::Median`] against [`BVHBuildMode::SAH`].
+//!
+//! Run with `cargo bench --bench bvh_ray_cast`.
+//!
+//! The scene is non uniform, a dense cluster of small triangles in
+//! the middle of a sparse floor of large triangles (the "teapot in a
+//! stadium" problem) where the median split does poorly.
+
+use std::time::{Duration, Instant};
+
+use quick_renderer::bvh::{BVHBuildMode, BVHTree, BVHTriangle};
+use quick_renderer::glm;
+use rand::{Rng, SeedableRng};
+
+const NUM_CLUSTER_TRIS: usize = 100_000;
+const NUM_FLOOR_TRIS_PER_SIDE: usize = 40;
+const NUM_RAYS: usize = 100_000;
+const TREE_TYPE: u8 = 4;
+const AXIS: u8 = 6;
+
+fn generate_tris(rng: &mut impl Rng) -> Vec<BVHTriangle<f64>> {
+    let mut tris = Vec::new();
+
+    // floor, 100x100 units
+    let cell_size = 100.0 / NUM_FLOOR_TRIS_PER_SIDE as f64;
+    for i in 0..NUM_FLOOR_TRIS_PER_SIDE {
+        for j in 0..NUM_FLOOR_TRIS_PER_SIDE {
+            let x = -50.0 + i as f64 * cell_size;
+            let z = -50.0 + j as f64 * cell_size;
+            let p1 = glm::vec3(x, 0.0, z);
+            let p2 = glm::vec3(x + cell_size, 0.0, z);
+            let p3 = glm::vec3(x + cell_size, 0.0, z + cell_size);
+            let p4 = glm::vec3(x, 0.0, z + cell_size);
+            tris.push(BVHTriangle::new(p1, p2, p3));
+            tris.push(BVHTriangle::new(p1, p3, p4));
+        }
+    }
+
+    // cluster, 1x1x1 units
+    for _ in 0..NUM_CLUSTER_TRIS {
+        let center = glm::vec3(
+            rng.gen_range(-0.5..0.5),
+            rng.gen_range(0.5..1.5),
+            rng.gen_range(-0.5..0.5),
+        );
+        let mut vert = || {
+            center
+                + glm::vec3(
+                    rng.gen_range(-0.01..0.01),
+                    rng.gen_range(-0.01..0.01),
+                    rng.gen_range(-0.01..0.01),
+                )
+        };
+        tris.push(BVHTriangle::new(vert(), vert(), vert()));
+    }
+
+    tris
+}
+
+/// Casts all the rays, returns the time taken and the number of hits.
+fn cast_rays(
+    bvh: &BVHTree<f64, usize>,
+    tris: &[BVHTriangle<f64>],
+    rays: &[(glm::DVec3, glm::DVec3)],
+) -> (Duration, usize) {
+    let start = Instant::now();
+    let num_hits = rays
+        .iter()
+        .filter(|(co, dir)| bvh.ray_cast_primitives(tris, *co, *dir, f64::MAX).is_some())
+        .count();
+    (start.elapsed(), num_hits)
+}
+
+fn main() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let tris = generate_tris(&mut rng);
+
+    // rays from above the floor aimed at the cluster
+    let rays: Vec<_> = (0..NUM_RAYS)
+        .map(|_| {
+            let co = glm::vec3(
+                rng.gen_range(-50.0..50.0),
+                rng.gen_range(1.0..20.0),
+                rng.gen_range(-50.0..50.0),
+            );
+            let target = glm::vec3(
+                rng.gen_range(-0.5..0.5),
+                rng.gen_range(0.5..1.5),
+                rng.gen_range(-0.5..0.5),
+            );
+            (co, glm::normalize(&(target - co)))
+        })
+        .collect();
+
+    println!(
+        "{} triangles, {} rays, tree_type: {}, axis: {}",
+        tris.len(),
+        rays.len(),
+        TREE_TYPE,
+        AXIS
+    );
+
+    let mut num_hits_all = Vec::new();
+    for build_mode in [BVHBuildMode::Median, BVHBuildMode::SAH] {
+        let start = Instant::now();
+        let bvh = BVHTree::from_primitives(&tris, 0.0, TREE_TYPE, AXIS, build_mode);
+        let build_time = start.elapsed();
+
+        let (cast_time, num_hits) = cast_rays(&bvh, &tris, &rays);
+        num_hits_all.push(num_hits);
+
+        println!(
+            "{:?}: build: {:.3?}, ray cast: {:.3?} ({:.3?} per ray), hits: {}",
+            build_mode,
+            build_time,
+            cast_time,
+            cast_time / rays.len() as u32,
+            num_hits
+        );
+    }
+
+    assert!(
+        num_hits_all.windows(2).all(|hits| hits[0] == hits[1]),
+        "build modes must give the same hits"
+    );
+}
@@ -7,6 +7,7 @@ use glfw::{Action, Context, Key};
 
 use quick_renderer::bvh::nearest_point_to_tri;
 use quick_renderer::bvh::BVHDrawData;
+use quick_renderer::bvh::BVHDrawMode;
 use quick_renderer::bvh::BVHTree;
 use quick_renderer::bvh::NearestData;
 use quick_renderer::bvh::RayHitData;
@@ -35,6 +36,7 @@ struct Config {
     bvh: Option<BVHTree<f64, FaceIndex>>,
     draw_bvh: bool,
     bvh_draw_level: usize,
+    bvh_draw_mode: BVHDrawMode,
     should_cast_ray: bool,
     bvh_tree_type: u8,
     _bvh_axis: u8,
@@ -52,6 +54,7 @@ impl Default for Config {
             bvh: None,
             draw_bvh: true,
             bvh_draw_level: 0,
+            bvh_draw_mode: BVHDrawMode::Level,
             should_cast_ray: false,
             bvh_tree_type: 4,
             _bvh_axis: 8,
@@ -67,25 +70,9 @@ impl Default for Config {
 
 impl Config {
     fn build_bvh<END, EVD, EED, EFD>(&mut self, mesh: &Mesh<END, EVD, EED, EFD>, epsilon: f64) {
-        let mut bvh = BVHTree::new(mesh.get_faces().len(), epsilon, self.bvh_tree_type, 8);
-
-        mesh.get_faces().iter().for_each(|(_, face)| {
-            let co: Vec<_> = face
-                .get_verts()
-                .iter()
-                .map(|v_index| {
-                    mesh.get_node(mesh.get_vert(*v_index).unwrap().get_node().unwrap())
-                        .unwrap()
-                        .pos
-                })
-                .collect();
-
-            bvh.insert(face.get_self_index(), &co);
-        });
-
-        bvh.balance();
-
-        self.bvh = Some(bvh)
+        let mut bvh = BVHTree::from_mesh(mesh, epsilon, self.bvh_tree_type, 8);
+        bvh.enable_query_record();
+        self.bvh = Some(bvh);
     }
 }
 
@@ -267,10 +254,11 @@ fn main() {
 
         let bvh = config.bvh.as_ref().unwrap();
 
-        bvh.draw(&BVHDrawData::new(
+        bvh.draw(&BVHDrawData::new_with_mode(
             imm.clone(),
             config.bvh_draw_level,
             config.bvh_color,
+            config.bvh_draw_mode,
         ))
         .unwrap();
 
@@ -463,6 +451,17 @@ fn main() {
                 ui.add(
                     egui::Slider::new(&mut config.bvh_draw_level, 0..=15).text("BVH Draw Level"),
                 );
+                ui.horizontal(|ui| {
+                    ui.label("BVH Draw Mode");
+                    for mode in [
+                        BVHDrawMode::Level,
+                        BVHDrawMode::Leafs,
+                        BVHDrawMode::DepthHeatMap,
+                        BVHDrawMode::QueryHighlight,
+                    ] {
+                        ui.radio_value(&mut config.bvh_draw_mode, mode, format!("{:?}", mode));
+                    }
+                });
                 ui.checkbox(
                     &mut config.bvh_nearest_point_use_callback,
                     "Use Nearest Point Callback",
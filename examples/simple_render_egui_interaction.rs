@@ -48,7 +48,10 @@ impl App for Application {
         shader::builtins::display_uniform_and_attribute_info();
 
         // setup the egui backend
-        let egui = EguiBackend::new(&mut environment.window, &mut environment.glfw);
+        let (glfw, window) = environment
+            .get_glfw_and_window_mut()
+            .ok_or("needs a window")?;
+        let egui = EguiBackend::new(window, glfw);
 
         // larger text
         let mut style = (*egui.get_egui_ctx().style()).clone();
@@ -102,14 +105,18 @@ impl App for Application {
     ) -> Result<MaybeContinue<Self::ExitData>, Box<dyn std::error::Error>> {
         if self.camera.get_fps_mode() {
             environment
-                .window
+                .get_window_mut()
+                .unwrap()
                 .set_cursor_mode(glfw::CursorMode::Disabled);
         } else {
-            environment.window.set_cursor_mode(glfw::CursorMode::Normal);
+            environment
+                .get_window_mut()
+                .unwrap()
+                .set_cursor_mode(glfw::CursorMode::Normal);
         }
 
-        self.egui
-            .begin_frame(&environment.window, &mut environment.glfw);
+        let (glfw, window) = environment.get_glfw_and_window_mut().unwrap();
+        self.egui.begin_frame(window, glfw);
 
         egui::CentralPanel::default().show(&self.egui.get_egui_ctx().clone(), |ui| {
             let render_width = ui.available_width().floor() as usize;
@@ -141,7 +148,7 @@ impl App for Application {
             gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
         }
 
-        let (window_width, window_height) = environment.window.get_size();
+        let (window_width, window_height) = environment.get_window().unwrap().get_size();
         let _output = self.egui.end_frame((window_width as _, window_height as _));
 
         Ok(MaybeContinue::Continue)
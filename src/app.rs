@@ -8,13 +8,23 @@ use std::{fmt::Display, sync::mpsc::Receiver};
 use glfw::{self, Context};
 
 use crate::fps::FPS;
+use crate::framebuffer::{capture_framebuffer, FrameBuffer};
+use crate::headless::{self, HeadlessContext};
+use crate::recording::{Recording, RecordingSettings};
+use crate::texture::TextureSaveError;
 
 #[derive(Debug)]
 pub enum Error {
     GlfwInit(glfw::InitError),
     Glfw(glfw::Error),
     GlfwWindowCreation,
+    /// Creating the [`HeadlessContext`] failed, see
+    /// [`EnvironmentSettings::surfaceless`].
+    Headless(headless::Error),
     App(Box<dyn std::error::Error>),
+    /// Writing a frame in recording mode failed, see
+    /// [`EnvironmentSettings::recording`].
+    Recording(TextureSaveError),
 }
 
 impl Display for Error {
@@ -23,7 +33,9 @@ impl Display for Error {
             Error::GlfwInit(err) => write!(f, "{}", err),
             Error::Glfw(err) => write!(f, "Glfw: {}", err),
             Error::GlfwWindowCreation => write!(f, "Glfw window creation"),
+            Error::Headless(err) => write!(f, "Headless: {}", err),
             Error::App(err) => write!(f, "App: {}", err),
+            Error::Recording(err) => write!(f, "Recording: {}", err),
         }
     }
 }
@@ -42,6 +54,12 @@ impl From<glfw::Error> for Error {
     }
 }
 
+impl From<headless::Error> for Error {
+    fn from(err: headless::Error) -> Self {
+        Self::Headless(err)
+    }
+}
+
 /// Implementing this trait is the way to create a simple application.
 pub trait App {
     /// Type of data to pass to [`Self::init()`].
@@ -78,6 +96,9 @@ pub trait App {
 
     /// Handle events of the window (application). There may be more
     /// than 1 event per frame.
+    ///
+    /// Never called when surfaceless, there is no window, see
+    /// [`EnvironmentSettings::surfaceless`].
     fn handle_window_event(
         &mut self,
         event: &glfw::WindowEvent,
@@ -94,22 +115,33 @@ pub enum MaybeContinue<T> {
     Exit(T),
 }
 
-/// Environment of the application that handles the boiler plate code
-/// to create a GUI application.
-pub struct Environment {
-    pub glfw: glfw::Glfw,
-    pub window: glfw::Window,
-    events_receiver: Receiver<(f64, glfw::WindowEvent)>,
-    pub fps: FPS,
+/// What the [`Environment`] renders to.
+enum Backend {
+    /// GLFW window, invisible if headless.
+    Window {
+        glfw: glfw::Glfw,
+        window: glfw::Window,
+        events_receiver: Receiver<(f64, glfw::WindowEvent)>,
+    },
+    /// Render target of a [`HeadlessContext`], see
+    /// [`EnvironmentSettings::surfaceless`].
+    Surfaceless(HeadlessContext),
 }
 
-impl Environment {
-    /// Create a new environment.
-    ///
-    /// Spawns a new window with an OpenGL context and window title as
-    /// `application_name`.
-    pub fn new(application_name: &str, settings: &EnvironmentSettings) -> Result<Self, Error> {
-        let mut glfw = glfw::init(glfw::fail_on_errors)?;
+impl Backend {
+    /// Create the window, see [`Environment::new()`].
+    fn new_window(
+        application_name: &str,
+        settings: &EnvironmentSettings,
+        window_dimensions: (u32, u32),
+    ) -> Result<Self, Error> {
+        // window creation failures are expected while falling back
+        // in headless mode
+        let mut glfw = if settings.headless {
+            glfw::init(glfw::log_errors)?
+        } else {
+            glfw::init(glfw::fail_on_errors)?
+        };
 
         glfw.window_hint(glfw::WindowHint::ContextVersion(
             settings.context_version.0,
@@ -118,16 +150,36 @@ impl Environment {
         glfw.window_hint(glfw::WindowHint::OpenGlProfile(
             settings.opengl_profile_hint,
         ));
+        if settings.recording.is_some() {
+            glfw.window_hint(glfw::WindowHint::Resizable(false));
+        }
 
         // creating window
-        let (mut window, events_receiver) = glfw
-            .create_window(
-                settings.window_dimensions.0,
-                settings.window_dimensions.1,
+        let (mut window, events_receiver) = if settings.headless {
+            glfw.window_hint(glfw::WindowHint::Visible(false));
+            IntoIterator::into_iter([
+                glfw::ContextCreationApi::Native,
+                glfw::ContextCreationApi::Egl,
+                glfw::ContextCreationApi::OsMesa,
+            ])
+            .find_map(|context_creation_api| {
+                glfw.window_hint(glfw::WindowHint::ContextCreationApi(context_creation_api));
+                glfw.create_window(
+                    window_dimensions.0,
+                    window_dimensions.1,
+                    application_name,
+                    glfw::WindowMode::Windowed,
+                )
+            })
+        } else {
+            glfw.create_window(
+                window_dimensions.0,
+                window_dimensions.1,
                 application_name,
                 glfw::WindowMode::Windowed,
             )
-            .ok_or(Error::GlfwWindowCreation)?;
+        }
+        .ok_or(Error::GlfwWindowCreation)?;
 
         // setup bunch of polling data
         window.set_pos_polling(settings.pos_polling);
@@ -160,16 +212,226 @@ impl Environment {
             }
         }
 
-        let fps = FPS::default();
-
-        Ok(Self {
+        Ok(Self::Window {
             glfw,
             window,
             events_receiver,
+        })
+    }
+
+    /// Width and height of the framebuffer of the window or of the
+    /// render target.
+    fn get_render_target_dimensions(&self) -> (usize, usize) {
+        match self {
+            Self::Window { window, .. } => {
+                let (width, height) = window.get_framebuffer_size();
+                (width as _, height as _)
+            }
+            Self::Surfaceless(context) => context.get_dimensions(),
+        }
+    }
+}
+
+/// Environment of the application that handles the boiler plate code
+/// to create a GUI application.
+pub struct Environment {
+    backend: Backend,
+    pub fps: FPS,
+    headless: bool,
+    recording: Option<Recording>,
+}
+
+impl Environment {
+    /// Create a new environment.
+    ///
+    /// Spawns a new window with an OpenGL context and window title as
+    /// `application_name`.
+    ///
+    /// With [`EnvironmentSettings::headless`], the window is
+    /// invisible and the context creation falls back from the native
+    /// API to EGL and then OSMesa (eg: Mesa's llvmpipe on a virtual
+    /// display). GLFW cannot be initialized without a display server
+    /// (fails with [`Error::GlfwInit`]) so the fallbacks only help
+    /// when there is one (eg: Xvfb).
+    ///
+    /// With [`EnvironmentSettings::surfaceless`], there is no window
+    /// and no GLFW, the [`App`] renders to the render target of a
+    /// [`HeadlessContext`] of the window dimensions instead, which
+    /// doesn't need a display server.
+    ///
+    /// With [`EnvironmentSettings::recording`], the window (or render
+    /// target) is created with the output dimensions of the recording instead of
+    /// [`EnvironmentSettings::window_dimensions`] and cannot be
+    /// resized, and [`Self::fps`] is fixed to the frame rate of the
+    /// recording.
+    pub fn new(application_name: &str, settings: &EnvironmentSettings) -> Result<Self, Error> {
+        let dimensions = match &settings.recording {
+            Some(recording) => recording.output_dimensions,
+            None => settings.window_dimensions,
+        };
+
+        let mut backend = if settings.surfaceless {
+            Backend::Surfaceless(HeadlessContext::new(
+                (dimensions.0 as _, dimensions.1 as _),
+                settings.context_version,
+            )?)
+        } else {
+            Backend::new_window(application_name, settings, dimensions)?
+        };
+
+        let (fps, recording) = match &settings.recording {
+            Some(recording) => {
+                std::fs::create_dir_all(&recording.output_directory)
+                    .map_err(|err| Error::Recording(err.into()))?;
+                // frames are not shown in real time, no need to wait
+                // for the display
+                if let Backend::Window { glfw, .. } = &mut backend {
+                    glfw.set_swap_interval(glfw::SwapInterval::None);
+                }
+                (
+                    FPS::new_fixed(recording.frame_rate),
+                    Some(Recording::new(recording.clone())),
+                )
+            }
+            None => (FPS::default(), None),
+        };
+
+        Ok(Self {
+            backend,
             fps,
+            headless: settings.headless || settings.surfaceless,
+            recording,
         })
     }
 
+    /// Is the environment headless (invisible window or
+    /// surfaceless)? See [`EnvironmentSettings::headless`] and
+    /// [`EnvironmentSettings::surfaceless`].
+    pub fn is_headless(&self) -> bool {
+        self.headless
+    }
+
+    /// Is the environment running on a [`HeadlessContext`]? See
+    /// [`EnvironmentSettings::surfaceless`].
+    pub fn is_surfaceless(&self) -> bool {
+        matches!(self.backend, Backend::Surfaceless(_))
+    }
+
+    /// Get the GLFW instance, [`None`] if surfaceless.
+    pub fn get_glfw(&self) -> Option<&glfw::Glfw> {
+        match &self.backend {
+            Backend::Window { glfw, .. } => Some(glfw),
+            Backend::Surfaceless(_) => None,
+        }
+    }
+
+    /// Get the GLFW instance mutably, [`None`] if surfaceless.
+    pub fn get_glfw_mut(&mut self) -> Option<&mut glfw::Glfw> {
+        self.get_glfw_and_window_mut().map(|(glfw, _)| glfw)
+    }
+
+    /// Get the window, [`None`] if surfaceless.
+    pub fn get_window(&self) -> Option<&glfw::Window> {
+        match &self.backend {
+            Backend::Window { window, .. } => Some(window),
+            Backend::Surfaceless(_) => None,
+        }
+    }
+
+    /// Get the window mutably, [`None`] if surfaceless.
+    pub fn get_window_mut(&mut self) -> Option<&mut glfw::Window> {
+        self.get_glfw_and_window_mut().map(|(_, window)| window)
+    }
+
+    /// Get both the GLFW instance and the window mutably (eg: for
+    /// `egui_glfw`), [`None`] if surfaceless.
+    pub fn get_glfw_and_window_mut(&mut self) -> Option<(&mut glfw::Glfw, &mut glfw::Window)> {
+        match &mut self.backend {
+            Backend::Window { glfw, window, .. } => Some((glfw, window)),
+            Backend::Surfaceless(_) => None,
+        }
+    }
+
+    /// Get the [`HeadlessContext`], [`None`] if not surfaceless.
+    pub fn get_headless_context(&self) -> Option<&HeadlessContext> {
+        match &self.backend {
+            Backend::Window { .. } => None,
+            Backend::Surfaceless(context) => Some(context),
+        }
+    }
+
+    /// Get the [`HeadlessContext`] mutably, [`None`] if not
+    /// surfaceless.
+    pub fn get_headless_context_mut(&mut self) -> Option<&mut HeadlessContext> {
+        match &mut self.backend {
+            Backend::Window { .. } => None,
+            Backend::Surfaceless(context) => Some(context),
+        }
+    }
+
+    /// Width and height of what the environment renders to, the
+    /// framebuffer of the window or the render target of the
+    /// [`HeadlessContext`].
+    pub fn get_render_target_dimensions(&self) -> (usize, usize) {
+        self.backend.get_render_target_dimensions()
+    }
+
+    /// Bind what the environment renders to, the default framebuffer
+    /// of the window or the render target of the
+    /// [`HeadlessContext`]. Use instead of
+    /// [`FrameBuffer::activiate_default()`] to go back after
+    /// rendering to another framebuffer.
+    pub fn activate_render_target(&mut self) {
+        match &mut self.backend {
+            Backend::Window { .. } => FrameBuffer::activiate_default(),
+            Backend::Surfaceless(context) => context.activate_render_target(),
+        }
+    }
+
+    /// Is the environment recording? See
+    /// [`EnvironmentSettings::recording`].
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Get the number of frames written to disk by the recording, if
+    /// recording.
+    pub fn get_recorded_frames(&self) -> Option<usize> {
+        self.recording
+            .as_ref()
+            .map(|recording| recording.get_frames_written())
+    }
+
+    /// Write the current frame (back buffer or render target) of the
+    /// recording if it is due, see [`RecordingSettings::frame_step`].
+    fn record_frame(&mut self) -> Result<(), Error> {
+        let recording = match &mut self.recording {
+            Some(recording) => recording,
+            None => return Ok(()),
+        };
+
+        let path = match recording.next_frame_path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let (width, height) = self.backend.get_render_target_dimensions();
+        let framebuffer = match &self.backend {
+            Backend::Window { .. } => None,
+            Backend::Surfaceless(context) => Some(context.get_render_target()),
+        };
+        let mut texture = capture_framebuffer(framebuffer, width, height);
+        let (output_width, output_height) = recording.get_settings().output_dimensions;
+        if (width, height) != (output_width as _, output_height as _) {
+            texture = texture.to_resized(output_width as _, output_height as _);
+        }
+
+        texture.save_to_disk(path).map_err(Error::Recording)?;
+        recording.frame_written();
+
+        Ok(())
+    }
+
     /// Run the environment with the given [`App`]. The [`App`] is
     /// given through a generic argument.
     ///
@@ -177,10 +439,18 @@ impl Environment {
     /// or [`Err`]`(_)` in its [`App::update()`] routine. Also exits
     /// if the window of the application closes.
     ///
+    /// When surfaceless, the render target of the
+    /// [`HeadlessContext`] is bound before every [`App::update()`]
+    /// and the app must exit through [`MaybeContinue::Exit`].
+    ///
     /// Upon the [`App`] exiting, the [`App`] is returned. If the
     /// [`App`] exited with [`MaybeContinue::Exit`], the given data is
     /// returned too.
     ///
+    /// When recording, the frame is written after [`App::update()`]
+    /// and before the buffers are swapped (also for the update that
+    /// exits), see [`EnvironmentSettings::recording`].
+    ///
     /// # Example
     ///
     /// ```ignore
@@ -194,30 +464,53 @@ impl Environment {
 
         let mut app = T::init(self, init_extra).map_err(Error::App)?;
 
-        while !self.window.should_close() {
-            self.glfw.poll_events();
-
-            let events_receiver = &self.events_receiver;
-            let window = &mut self.window;
-
-            glfw::flush_messages(events_receiver).for_each(|(_, event)| {
-                match event {
-                    glfw::WindowEvent::Key(_, _, glfw::Action::Press, mods) => key_mods |= mods,
-                    glfw::WindowEvent::Key(_, _, glfw::Action::Release, mods) => key_mods &= !mods,
-                    glfw::WindowEvent::CharModifiers(_, mods) => key_mods |= mods,
-                    glfw::WindowEvent::MouseButton(_, glfw::Action::Press, mods) => {
-                        key_mods |= mods
+        loop {
+            match &mut self.backend {
+                Backend::Window {
+                    glfw,
+                    window,
+                    events_receiver,
+                } => {
+                    if window.should_close() {
+                        break;
                     }
-                    glfw::WindowEvent::MouseButton(_, glfw::Action::Release, mods) => {
-                        key_mods &= !mods
-                    }
-                    _ => {}
+
+                    glfw.poll_events();
+
+                    glfw::flush_messages(events_receiver).for_each(|(_, event)| {
+                        match event {
+                            glfw::WindowEvent::Key(_, _, glfw::Action::Press, mods) => {
+                                key_mods |= mods
+                            }
+                            glfw::WindowEvent::Key(_, _, glfw::Action::Release, mods) => {
+                                key_mods &= !mods
+                            }
+                            glfw::WindowEvent::CharModifiers(_, mods) => key_mods |= mods,
+                            glfw::WindowEvent::MouseButton(_, glfw::Action::Press, mods) => {
+                                key_mods |= mods
+                            }
+                            glfw::WindowEvent::MouseButton(_, glfw::Action::Release, mods) => {
+                                key_mods &= !mods
+                            }
+                            _ => {}
+                        }
+
+                        app.handle_window_event(&event, window, &key_mods);
+                    });
                 }
+                Backend::Surfaceless(context) => {
+                    // the default framebuffer of a window stays
+                    // bound unless the app binds another one, the
+                    // render target takes its place
+                    context.activate_render_target();
+                }
+            }
 
-                app.handle_window_event(&event, window, &key_mods);
-            });
+            let maybe_continue = app.update(self).map_err(Error::App)?;
+
+            self.record_frame()?;
 
-            match app.update(self).map_err(Error::App)? {
+            match maybe_continue {
                 MaybeContinue::Continue => {
                     // continue to next frame
                 }
@@ -227,7 +520,9 @@ impl Environment {
             }
 
             // Swap front and back buffers
-            self.window.swap_buffers();
+            if let Backend::Window { window, .. } = &mut self.backend {
+                window.swap_buffers();
+            }
         }
 
         Ok((app, None))
@@ -285,6 +580,30 @@ pub struct EnvironmentSettings {
 
     /// Load OpenGL?
     pub load_opengl: bool,
+
+    /// Create an invisible window, for rendering without showing
+    /// anything (eg: on CI with a virtual display). The app must exit
+    /// through [`MaybeContinue::Exit`] since the window cannot be
+    /// closed by the user.
+    ///
+    /// A display server is still needed, see [`Environment::new()`].
+    pub headless: bool,
+
+    /// Run on a [`HeadlessContext`] instead of a window, for
+    /// rendering without a display server. The render target has the
+    /// [`Self::window_dimensions`] (or the output dimensions of
+    /// [`Self::recording`]), the app must exit through
+    /// [`MaybeContinue::Exit`] and doesn't get any window events.
+    ///
+    /// The context always has the core profile and loads OpenGL, the
+    /// polling settings, [`Self::opengl_profile_hint`] and
+    /// [`Self::load_opengl`] are ignored.
+    pub surfaceless: bool,
+
+    /// Record the frames to a PNG sequence, see
+    /// [`RecordingSettings`]. Often used together with
+    /// [`Self::headless`] or [`Self::surfaceless`].
+    pub recording: Option<RecordingSettings>,
 }
 
 impl EnvironmentSettings {
@@ -333,6 +652,15 @@ impl EnvironmentSettings {
 
     /// Default [`Self::load_opengl`].
     pub const DEFAULT_LOAD_OPENGL: bool = true;
+
+    /// Default [`Self::headless`].
+    pub const DEFAULT_HEADLESS: bool = false;
+
+    /// Default [`Self::surfaceless`].
+    pub const DEFAULT_SURFACELESS: bool = false;
+
+    /// Default [`Self::recording`].
+    pub const DEFAULT_RECORDING: Option<RecordingSettings> = None;
 }
 
 impl Default for EnvironmentSettings {
@@ -359,6 +687,9 @@ impl Default for EnvironmentSettings {
             context_version: Self::DEFAULT_CONTEXT_VERSION,
             opengl_profile_hint: Self::DEFAULT_OPENGL_PROFILE_HINT,
             load_opengl: Self::DEFAULT_LOAD_OPENGL,
+            headless: Self::DEFAULT_HEADLESS,
+            surfaceless: Self::DEFAULT_SURFACELESS,
+            recording: Self::DEFAULT_RECORDING,
         }
     }
 }
@@ -489,4 +820,132 @@ impl EnvironmentSettings {
         self.load_opengl = load_opengl;
         self
     }
+
+    /// Set [`Self::headless`].
+    pub fn headless(mut self, headless: bool) -> Self {
+        self.headless = headless;
+        self
+    }
+
+    /// Set [`Self::surfaceless`].
+    pub fn surfaceless(mut self, surfaceless: bool) -> Self {
+        self.surfaceless = surfaceless;
+        self
+    }
+
+    /// Set [`Self::recording`].
+    pub fn recording(mut self, recording: Option<RecordingSettings>) -> Self {
+        self.recording = recording;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glm;
+    use crate::texture::TextureRGBAFloat;
+
+    /// Clears to red on even and to green on odd frames, then leaves
+    /// the default framebuffer bound. Exits after the given number of
+    /// frames.
+    struct ClearApp {
+        num_frames: usize,
+        frame: usize,
+    }
+
+    impl ClearApp {
+        fn clear_color(frame: usize) -> glm::Vec4 {
+            if frame % 2 == 0 {
+                glm::vec4(1.0, 0.0, 0.0, 1.0)
+            } else {
+                glm::vec4(0.0, 1.0, 0.0, 1.0)
+            }
+        }
+    }
+
+    impl App for ClearApp {
+        type InitData = usize;
+
+        fn init(
+            environment: &mut Environment,
+            num_frames: Self::InitData,
+        ) -> Result<Self, Box<dyn std::error::Error>> {
+            assert!(environment.is_surfaceless());
+            Ok(Self {
+                num_frames,
+                frame: 0,
+            })
+        }
+
+        type ExitData = usize;
+
+        fn update(
+            &mut self,
+            environment: &mut Environment,
+        ) -> Result<MaybeContinue<Self::ExitData>, Box<dyn std::error::Error>> {
+            assert_eq!(environment.get_render_target_dimensions(), (4, 2));
+
+            let color = Self::clear_color(self.frame);
+            unsafe {
+                gl::ClearColor(color[0], color[1], color[2], color[3]);
+                gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+            }
+            // the environment must bind the render target again
+            // before the next frame
+            FrameBuffer::activiate_default();
+
+            self.frame += 1;
+            if self.frame == self.num_frames {
+                Ok(MaybeContinue::Exit(self.frame))
+            } else {
+                Ok(MaybeContinue::Continue)
+            }
+        }
+
+        fn handle_window_event(
+            &mut self,
+            _event: &glfw::WindowEvent,
+            _window: &mut glfw::Window,
+            _key_mods: &glfw::Modifiers,
+        ) {
+            unreachable!("no window events when surfaceless");
+        }
+    }
+
+    #[test]
+    #[ignore = "needs libEGL with surfaceless support, run with cargo test -- --ignored"]
+    fn environment_surfaceless_run() {
+        let recording = RecordingSettings::new(
+            std::env::temp_dir().join("quick_renderer_environment_surfaceless_test"),
+        )
+        .output_dimensions((4, 2));
+        let settings = EnvironmentSettings::default()
+            .context_version((3, 3))
+            .surfaceless(true)
+            .recording(Some(recording.clone()));
+        let mut environment = Environment::new("surfaceless", &settings).unwrap_or_else(|err| {
+            panic!(
+                "surfaceless environment not available ({}), the OpenGL tests need libEGL with \
+                 surfaceless support (eg: Mesa)",
+                err
+            )
+        });
+        assert!(environment.is_headless());
+        assert!(environment.get_window().is_none());
+
+        let (_, exit_data) = environment.run::<ClearApp>(3).unwrap();
+        assert_eq!(exit_data, Some(3));
+        assert_eq!(environment.get_recorded_frames(), Some(3));
+
+        for frame in 0..3 {
+            let texture = TextureRGBAFloat::load_from_disk(recording.frame_path(frame)).unwrap();
+            assert_eq!((texture.get_width(), texture.get_height()), (4, 2));
+            texture.get_pixels().iter().for_each(|pixel| {
+                assert!((pixel - ClearApp::clear_color(frame)).abs().max() < 0.01);
+            });
+        }
+
+        std::fs::remove_dir_all(&recording.output_directory).unwrap();
+    }
 }
@@ -8,15 +8,26 @@ use serde::Serialize;
 
 use std::cell::RefCell;
 use std::cmp::PartialOrd;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fmt::Debug;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 use crate::drawable::Drawable;
 use crate::drawable::NoSpecificDrawError;
 use crate::gpu_immediate::*;
 use crate::shader;
+use crate::util::vec3_apply_model_matrix;
 
 const MAX_TREETYPE: u8 = 32;
+/// Number of values of the largest kdop BV (26-DOP).
+const MAX_KDOP_BV_LEN: usize = 26;
+
+/// Minimum number of leafs in the tree for
+/// [`BVHTree::balance_multithreaded()`] to use multiple threads,
+/// smaller trees are faster to build serially.
+pub const BVH_MULTITHREADED_BALANCE_MIN_LEAFS: usize = 1024;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 struct BVHNodeIndex(pub Index);
@@ -27,6 +38,23 @@ impl BVHNodeIndex {
     }
 }
 
+/// Handle to an element of a [`BVHTree`] for the incremental updates,
+/// see [`BVHTree::insert_incremental()`]. The handle of a removed
+/// element never becomes valid again, even when its node is reused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BVHLeafHandle(Index);
+
+/// Node of the hierarchy of a [`BVHTree`] flattened by
+/// [`BVHTree::flatten_hierarchy()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BVHFlatNode<E> {
+    /// Leaf storing the element.
+    Leaf(E),
+    /// Branch with the positions of its children in the flattened
+    /// hierarchy.
+    Branch(Vec<usize>),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct BVHNode<T, E>
 where
@@ -77,11 +105,7 @@ where
     }
 
     fn min_max_init(&mut self, start_axis: u8, stop_axis: u8) {
-        let bv = &mut self.bv;
-        for axis_iter in start_axis..stop_axis {
-            bv[(2 * axis_iter) as usize] = <T as Bounded>::max_value();
-            bv[((2 * axis_iter) + 1) as usize] = <T as Bounded>::min_value();
-        }
+        kdop_min_max_init(&mut self.bv, start_axis, stop_axis);
     }
 
     fn create_kdop_hull(
@@ -94,43 +118,27 @@ where
         if !moving {
             self.min_max_init(start_axis, stop_axis);
         }
-        let bv = &mut self.bv;
-
-        let bvhtree_kdop_axes = bvhtree_kdop_axes();
 
-        assert_eq!(bv.len(), (stop_axis * 2) as usize);
-        for co in co_many {
-            for axis_iter in start_axis..stop_axis {
-                let axis_iter = axis_iter as usize;
-                let new_min_max = glm::dot(co, &bvhtree_kdop_axes[axis_iter]);
-                if new_min_max < bv[2 * axis_iter] {
-                    bv[2 * axis_iter] = new_min_max;
-                }
-                if new_min_max > bv[(2 * axis_iter) + 1] {
-                    bv[(2 * axis_iter) + 1] = new_min_max;
-                }
-            }
-        }
+        assert_eq!(self.bv.len(), (stop_axis * 2) as usize);
+        kdop_extend(&mut self.bv, start_axis, stop_axis, co_many);
     }
 
     fn overlap_test(&self, other: &BVHNode<T, E>, start_axis: u8, stop_axis: u8) -> bool {
-        let bv1 = &self.bv;
-        let bv2 = &other.bv;
-        for axis_iter in start_axis..stop_axis {
-            let axis_iter = axis_iter as usize;
-            if bv1[2 * axis_iter] > bv2[(2 * axis_iter) + 1]
-                || bv2[2 * axis_iter] > bv1[(2 * axis_iter) + 1]
-            {
-                return false;
-            }
-        }
-
-        true
+        kdop_overlap(&self.bv, &other.bv, start_axis, stop_axis)
     }
 
     /// Tests if ray hits the node. On hit it returns the distance.
+    ///
+    /// The BV is inflated by the swept shape of `data` (if any).
     fn ray_hit(&self, data: &RayCastData<T>, dist: T) -> Option<T> {
-        let bv = &self.bv;
+        let bv = [
+            self.bv[0] - data.bv_inflate_min[0],
+            self.bv[1] + data.bv_inflate_max[0],
+            self.bv[2] - data.bv_inflate_min[1],
+            self.bv[3] + data.bv_inflate_max[1],
+            self.bv[4] - data.bv_inflate_min[2],
+            self.bv[5] + data.bv_inflate_max[2],
+        ];
 
         let t1x = (bv[data.index[0]] - data.co[0]) * data.idot_axis[0];
         let t2x = (bv[data.index[1]] - data.co[0]) * data.idot_axis[0];
@@ -174,6 +182,7 @@ where
 pub enum BVHError {
     IndexOutOfRange,
     DifferentNumPoints,
+    ElementNotFound,
 }
 
 impl std::fmt::Display for BVHError {
@@ -181,12 +190,138 @@ impl std::fmt::Display for BVHError {
         match self {
             BVHError::IndexOutOfRange => write!(f, "Index given is out of range"),
             BVHError::DifferentNumPoints => write!(f, "Different number of points given"),
+            BVHError::ElementNotFound => write!(f, "Element of the tree not found"),
         }
     }
 }
 
 impl std::error::Error for BVHError {}
 
+/// How [`BVHTree::balance()`] divides the elements amongst the
+/// children of a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum BVHBuildMode {
+    /// Split at the median along the largest axis of the node (like
+    /// Blender does). Fast to build, the tree is always balanced.
+    #[default]
+    Median,
+    /// Split to minimize the surface area heuristic (SAH), the
+    /// expected cost of traversing the tree. Slower to build and the
+    /// tree may be unbalanced but gives faster queries (especially
+    /// ray casts) for non uniformly distributed elements.
+    SAH,
+}
+
+/// Counters of the work done by the queries, see
+/// [`BVHTree::enable_query_counters()`]. Atomic so that the
+/// multithreaded queries can update them.
+#[derive(Debug, Default)]
+struct BVHQueryCounters {
+    nodes_visited: AtomicUsize,
+    primitives_tested: AtomicUsize,
+}
+
+impl Clone for BVHQueryCounters {
+    fn clone(&self) -> Self {
+        Self {
+            nodes_visited: AtomicUsize::new(self.nodes_visited.load(Ordering::Relaxed)),
+            primitives_tested: AtomicUsize::new(self.primitives_tested.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Nodes visited by the last ray cast or nearest query, see
+/// [`BVHTree::enable_query_record()`].
+#[derive(Debug, Default)]
+struct BVHQueryRecord {
+    nodes_visited: Mutex<Vec<BVHNodeIndex>>,
+}
+
+impl Clone for BVHQueryRecord {
+    fn clone(&self) -> Self {
+        Self {
+            nodes_visited: Mutex::new(self.nodes_visited.lock().unwrap().clone()),
+        }
+    }
+}
+
+/// Work done by the queries of a [`BVHTree`] since the counters were
+/// enabled or reset, see [`BVHTree::enable_query_counters()`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BVHQueryStats {
+    /// Number of nodes (branches and leafs) whose BV was tested.
+    pub nodes_visited: usize,
+    /// Number of elements that reached the fine grain test (the
+    /// callback or the primitive test), for overlap queries the
+    /// number of element pairs.
+    pub primitives_tested: usize,
+}
+
+/// Statistics of a level of the tree, see [`BVHStats`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct BVHLevelStats<T> {
+    pub num_nodes: usize,
+    pub num_leafs: usize,
+    /// Sum of the volumes of the hulls of the nodes.
+    pub volume: T,
+    /// Sum of the surface areas of the hulls of the nodes.
+    pub surface_area: T,
+}
+
+/// Statistics about the structure and quality of a [`BVHTree`], see
+/// [`BVHTree::stats()`].
+///
+/// The hulls are measured as the boxes given by the x, y and z axes
+/// of the BVs (the axes the queries use), including the `epsilon`
+/// inflation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BVHStats<T> {
+    /// Number of levels of the tree, a tree with only the root
+    /// (with leafs as its children) has a depth of 2.
+    pub depth: usize,
+    pub num_leafs: usize,
+    pub num_branches: usize,
+    /// Every leaf stores a single element, so the occupancy is that
+    /// of the branches, the average fraction of the `tree_type`
+    /// child slots that are used.
+    pub average_branch_occupancy: f64,
+    /// Sum of the volumes of the hulls of all the nodes.
+    pub total_volume: T,
+    /// Sum of the surface areas of the hulls of all the nodes, the
+    /// ray cast cost of the tree is proportional to it.
+    pub total_surface_area: T,
+    /// Statistics per level, starting from the root.
+    pub levels: Vec<BVHLevelStats<T>>,
+    /// Sum of the volumes of the intersections of the hulls of every
+    /// pair of siblings divided by the sum of the volumes of the
+    /// hulls of the children. 0 when no siblings overlap, higher
+    /// means more subtrees are visited by the queries.
+    pub sibling_overlap_ratio: T,
+}
+
+impl<T: glm::RealNumber> std::fmt::Display for BVHStats<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "depth: {}, leafs: {}, branches: {}, average branch occupancy: {:.3}",
+            self.depth, self.num_leafs, self.num_branches, self.average_branch_occupancy
+        )?;
+        writeln!(
+            f,
+            "total volume: {}, total surface area: {}, sibling overlap ratio: {}",
+            self.total_volume, self.total_surface_area, self.sibling_overlap_ratio
+        )?;
+        for (i, level) in self.levels.iter().enumerate() {
+            writeln!(
+                f,
+                "level {}: nodes: {}, leafs: {}, volume: {}, surface area: {}",
+                i, level.num_nodes, level.num_leafs, level.volume, level.surface_area
+            )?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BVHTree<T, E>
 where
@@ -202,6 +337,17 @@ where
     stop_axis: u8,
     axis: u8,      // kdop type (6 => OBB, 8 => AABB, etc.)
     tree_type: u8, // Type of tree (4 => QuadTree, etc.)
+    #[serde(default)]
+    build_mode: BVHBuildMode,
+    // position of the leafs in `nodes` by their slot in
+    // `node_array`, only tracked once the tree is updated
+    // incrementally
+    #[serde(default)]
+    leaf_positions: Option<Vec<usize>>,
+    #[serde(skip)]
+    query_counters: Option<BVHQueryCounters>,
+    #[serde(skip)]
+    query_record: Option<BVHQueryRecord>,
 }
 
 struct BVHBuildHelper {
@@ -269,6 +415,35 @@ impl<'a> BVHDivNodesData<'a> {
     }
 }
 
+/// Changes to a branch (and its children) after dividing its leafs.
+struct BVHDivNodesResult<T> {
+    parent_index: BVHNodeIndex,
+    bv: Vec<T>,
+    main_axis: u8,
+    children: Vec<BVHNodeIndex>,
+}
+
+/// Divides all the branches of a level of the tree, see
+/// `BVHTree::non_recursive_bvh_div_nodes_level()`.
+type BVHDivNodesLevelFn<T, E> = fn(
+    &Arena<BVHNode<T, E>>,
+    &mut [BVHNodeIndex],
+    &BVHDivNodesData,
+    usize,
+    usize,
+    u8,
+    u8,
+    u8,
+) -> Vec<BVHDivNodesResult<T>>;
+
+/// Containment of a BV within a query volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BVContainment {
+    Outside,
+    Intersect,
+    Inside,
+}
+
 pub struct BVHTreeOverlap<E>
 where
     E: Copy,
@@ -346,6 +521,11 @@ struct RayCastData<T> {
     ray_dot_axis: [T; 13],
     idot_axis: [T; 13],
     index: [usize; 6],
+
+    // Amount by which the BVs are inflated (on the min and max side
+    // of each axis) to account for the shape swept along the ray
+    bv_inflate_min: glm::TVec3<T>,
+    bv_inflate_max: glm::TVec3<T>,
 }
 
 impl<T: glm::Number> RayCastData<T> {
@@ -381,6 +561,38 @@ impl<T: glm::Number> RayCastData<T> {
             ray_dot_axis,
             idot_axis,
             index,
+            bv_inflate_min: glm::zero(),
+            bv_inflate_max: glm::zero(),
+        }
+    }
+
+    /// Ray cast data for a sphere of `radius` swept along the ray.
+    fn new_sphere(co: glm::TVec3<T>, dir: glm::TVec3<T>, radius: T) -> Self {
+        Self {
+            bv_inflate_min: glm::TVec3::repeat(radius),
+            bv_inflate_max: glm::TVec3::repeat(radius),
+            ..Self::new(co, dir)
+        }
+    }
+
+    /// Ray cast data for a capsule (segment from `co_1` to `co_2`
+    /// with `radius`) swept along the ray that starts at `co_1`.
+    ///
+    /// The BVs are inflated by the bounding box of the capsule
+    /// relative to `co_1`.
+    fn new_capsule(
+        co_1: glm::TVec3<T>,
+        co_2: glm::TVec3<T>,
+        dir: glm::TVec3<T>,
+        radius: T,
+    ) -> Self {
+        let segment = co_2 - co_1;
+        let bv_inflate_min = segment.map(|val| radius + glm::max2_scalar(val, T::zero()));
+        let bv_inflate_max = segment.map(|val| radius - glm::min2_scalar(val, T::zero()));
+        Self {
+            bv_inflate_min,
+            bv_inflate_max,
+            ..Self::new(co_1, dir)
         }
     }
 }
@@ -443,6 +655,34 @@ where
     }
 }
 
+/// Entry of the bounded priority queue of
+/// [`BVHTree::find_k_nearest()`], ordered by the squared distance so
+/// that the furthest of the k nearest is at the top.
+struct KNearestEntry<T, E: Copy>(NearestData<T, E>);
+
+impl<T: glm::Number, E: Copy> PartialEq for KNearestEntry<T, E> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl<T: glm::Number, E: Copy> Eq for KNearestEntry<T, E> {}
+
+impl<T: glm::Number, E: Copy> PartialOrd for KNearestEntry<T, E> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: glm::Number, E: Copy> Ord for KNearestEntry<T, E> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0
+            .get_dist_sq()
+            .partial_cmp(&other.0.get_dist_sq())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    }
+}
+
 impl<T: glm::RealNumber, E> BVHTree<T, E>
 where
     E: Copy,
@@ -462,6 +702,23 @@ where
     ///
     /// * When invalid `tree_type` is given.
     pub fn new(max_size: usize, epsilon: T, tree_type: u8, axis: u8) -> Self {
+        Self::new_with_build_mode(max_size, epsilon, tree_type, axis, BVHBuildMode::Median)
+    }
+
+    /// Create new BVH that is balanced using the given
+    /// `build_mode`. See [`Self::new()`] for the other parameters.
+    ///
+    /// # panics
+    /// * When invalid `axis` is given.
+    ///
+    /// * When invalid `tree_type` is given.
+    pub fn new_with_build_mode(
+        max_size: usize,
+        epsilon: T,
+        tree_type: u8,
+        axis: u8,
+        build_mode: BVHBuildMode,
+    ) -> Self {
         assert!(
             (2..=MAX_TREETYPE).contains(&tree_type),
             "tree_type must be >= 2 and <= {}",
@@ -522,7 +779,170 @@ where
             stop_axis,
             axis,
             tree_type,
+            build_mode,
+            leaf_positions: None,
+            query_counters: None,
+            query_record: None,
+        }
+    }
+
+    pub fn get_build_mode(&self) -> BVHBuildMode {
+        self.build_mode
+    }
+
+    /// Start counting the work done by the queries (nodes visited
+    /// and elements tested), for profiling. Has a small cost on
+    /// every query while enabled. See [`Self::get_query_stats()`].
+    pub fn enable_query_counters(&mut self) {
+        if self.query_counters.is_none() {
+            self.query_counters = Some(BVHQueryCounters::default());
+        }
+    }
+
+    pub fn disable_query_counters(&mut self) {
+        self.query_counters = None;
+    }
+
+    /// Reset the query counters to zero, does nothing if they are not
+    /// enabled.
+    pub fn reset_query_counters(&self) {
+        if let Some(counters) = &self.query_counters {
+            counters.nodes_visited.store(0, Ordering::Relaxed);
+            counters.primitives_tested.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Work done by the queries since the counters were enabled or
+    /// reset, [`None`] if the counters are not enabled.
+    ///
+    /// For queries between 2 trees (eg: [`Self::overlap()`]), the
+    /// work is counted on `self`.
+    pub fn get_query_stats(&self) -> Option<BVHQueryStats> {
+        self.query_counters.as_ref().map(|counters| BVHQueryStats {
+            nodes_visited: counters.nodes_visited.load(Ordering::Relaxed),
+            primitives_tested: counters.primitives_tested.load(Ordering::Relaxed),
+        })
+    }
+
+    #[inline]
+    fn count_node_visit(&self) {
+        if let Some(counters) = &self.query_counters {
+            counters.nodes_visited.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    fn count_primitive_test(&self) {
+        if let Some(counters) = &self.query_counters {
+            counters.primitives_tested.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Start recording the nodes visited by the last ray cast or
+    /// nearest query, for debugging the queries with
+    /// [`BVHDrawMode::QueryHighlight`]. Has a small cost on every
+    /// query while enabled.
+    pub fn enable_query_record(&mut self) {
+        if self.query_record.is_none() {
+            self.query_record = Some(BVHQueryRecord::default());
+        }
+    }
+
+    pub fn disable_query_record(&mut self) {
+        self.query_record = None;
+    }
+
+    /// Number of nodes visited by the last ray cast or nearest query,
+    /// [`None`] if the record is not enabled.
+    pub fn get_query_record_len(&self) -> Option<usize> {
+        self.query_record
+            .as_ref()
+            .map(|record| record.nodes_visited.lock().unwrap().len())
+    }
+
+    #[inline]
+    fn begin_query_record(&self) {
+        if let Some(record) = &self.query_record {
+            record.nodes_visited.lock().unwrap().clear();
+        }
+    }
+
+    #[inline]
+    fn record_node_visit(&self, node_index: BVHNodeIndex) {
+        if let Some(record) = &self.query_record {
+            record.nodes_visited.lock().unwrap().push(node_index);
+        }
+    }
+
+    /// Statistics about the structure and quality of the tree, see
+    /// [`BVHStats`]. Only the leaf counts are given if the tree is
+    /// not balanced yet.
+    pub fn stats(&self) -> BVHStats<T> {
+        let mut stats = BVHStats {
+            depth: 0,
+            num_leafs: self.totleaf,
+            num_branches: self.totbranch,
+            average_branch_occupancy: 0.0,
+            total_volume: T::zero(),
+            total_surface_area: T::zero(),
+            levels: Vec::new(),
+            sibling_overlap_ratio: T::zero(),
+        };
+        if self.totleaf == 0 || self.totbranch == 0 {
+            return stats;
+        }
+
+        let mut occupancy_sum = 0.0;
+        let mut children_volume = T::zero();
+        let mut siblings_overlap_volume = T::zero();
+
+        let mut level = vec![self.nodes[self.totleaf]];
+        while !level.is_empty() {
+            let mut level_stats = BVHLevelStats {
+                num_nodes: 0,
+                num_leafs: 0,
+                volume: T::zero(),
+                surface_area: T::zero(),
+            };
+            let mut next_level = Vec::new();
+
+            for node_index in level {
+                let node = self.node_array.get(node_index.0).unwrap();
+                level_stats.num_nodes += 1;
+                level_stats.volume += bv_volume(&node.bv);
+                level_stats.surface_area += bv_surface_area(&node.bv);
+
+                if node.totnode == 0 {
+                    level_stats.num_leafs += 1;
+                    continue;
+                }
+
+                occupancy_sum += node.totnode as f64 / self.tree_type as f64;
+                let children = &node.children[..node.totnode as usize];
+                for (i, child_1_index) in children.iter().enumerate() {
+                    let child_1 = self.node_array.get(child_1_index.0).unwrap();
+                    children_volume += bv_volume(&child_1.bv);
+                    for child_2_index in &children[(i + 1)..] {
+                        let child_2 = self.node_array.get(child_2_index.0).unwrap();
+                        siblings_overlap_volume += bv_intersection_volume(&child_1.bv, &child_2.bv);
+                    }
+                }
+                next_level.extend_from_slice(children);
+            }
+
+            stats.total_volume += level_stats.volume;
+            stats.total_surface_area += level_stats.surface_area;
+            stats.levels.push(level_stats);
+            level = next_level;
+        }
+
+        stats.depth = stats.levels.len();
+        stats.average_branch_occupancy = occupancy_sum / self.totbranch as f64;
+        if children_volume > T::zero() {
+            stats.sibling_overlap_ratio = siblings_overlap_volume / children_volume;
         }
+
+        stats
     }
 
     /// Insert new node
@@ -551,32 +971,35 @@ where
         }
     }
 
-    fn refit_kdop_hull(&mut self, node_index: BVHNodeIndex, start: usize, end: usize) {
-        {
-            let node = self.node_array.get_mut(node_index.0).unwrap();
-            node.min_max_init(self.start_axis, self.stop_axis);
+    /// Insert new node whose BV encloses both `co_many` and
+    /// `co_moving_many`, eg: the start and end positions of a moving
+    /// element for continuous collision detection. See
+    /// [`Self::insert()`].
+    pub fn insert_moving(
+        &mut self,
+        index: E,
+        co_many: &[glm::TVec3<T>],
+        co_moving_many: &[glm::TVec3<T>],
+    ) -> Result<(), BVHError> {
+        if co_many.len() != co_moving_many.len() {
+            return Err(BVHError::DifferentNumPoints);
         }
 
-        for j in start..end {
-            let (node, node_2) = self.node_array.get2_mut(node_index.0, self.nodes[j].0);
-            let node = node.unwrap();
-            let bv = &mut node.bv;
-            let node_bv = &mut node_2.unwrap().bv;
-
-            for axis_iter in self.start_axis..self.stop_axis {
-                let axis_iter = axis_iter as usize;
-
-                let new_min = node_bv[2 * axis_iter];
-                if new_min < bv[2 * axis_iter] {
-                    bv[2 * axis_iter] = new_min;
-                }
+        self.insert(index, co_many);
+        self.update_node(self.totleaf - 1, co_many, co_moving_many)
+    }
 
-                let new_max = node_bv[(2 * axis_iter) + 1];
-                if new_max > bv[(2 * axis_iter) + 1] {
-                    bv[(2 * axis_iter) + 1] = new_max;
-                }
-            }
-        }
+    fn refit_kdop_hull(&mut self, node_index: BVHNodeIndex, start: usize, end: usize) {
+        let node = self.node_array.get_mut(node_index.0).unwrap();
+        let mut bv = std::mem::take(&mut node.bv);
+        refit_kdop_bv(
+            &mut bv,
+            &self.node_array,
+            &self.nodes[start..end],
+            self.start_axis,
+            self.stop_axis,
+        );
+        self.node_array.get_mut(node_index.0).unwrap().bv = bv;
     }
 
     fn build_implicit_helper(&self) -> BVHBuildHelper {
@@ -606,27 +1029,34 @@ where
         BVHBuildHelper::new(totleafs, leafs_per_child, branches_on_level, remain_leafs)
     }
 
-    fn bvh_insertion_sort(&mut self, lo: usize, hi: usize, axis: usize) {
+    fn bvh_insertion_sort(
+        node_array: &Arena<BVHNode<T, E>>,
+        nodes: &mut [BVHNodeIndex],
+        lo: usize,
+        hi: usize,
+        axis: usize,
+    ) {
         for i in lo..hi {
             let mut j = i;
-            let node_t_index = self.nodes[i];
-            let node_t = self.node_array.get(node_t_index.0).unwrap();
+            let node_t_index = nodes[i];
+            let node_t = node_array.get(node_t_index.0).unwrap();
             if j != lo {
-                let mut node_j_minus_one = self.node_array.get(self.nodes[j - 1].0).unwrap();
+                let mut node_j_minus_one = node_array.get(nodes[j - 1].0).unwrap();
                 while (j != lo) && (node_t.bv[axis] < node_j_minus_one.bv[axis]) {
-                    self.nodes[j] = self.nodes[j - 1];
+                    nodes[j] = nodes[j - 1];
                     j -= 1;
                     if j != 0 {
-                        node_j_minus_one = self.node_array.get(self.nodes[j - 1].0).unwrap();
+                        node_j_minus_one = node_array.get(nodes[j - 1].0).unwrap();
                     }
                 }
             }
-            self.nodes[j] = node_t_index;
+            nodes[j] = node_t_index;
         }
     }
 
     fn bvh_partition(
-        &mut self,
+        node_array: &Arena<BVHNode<T, E>>,
+        nodes: &mut [BVHNodeIndex],
         lo: usize,
         hi: usize,
         node_x_index: BVHNodeIndex,
@@ -634,61 +1064,77 @@ where
     ) -> usize {
         let mut i = lo;
         let mut j = hi;
-        let node_x = self.node_array.get(node_x_index.0).unwrap();
+        let node_x = node_array.get(node_x_index.0).unwrap();
         loop {
-            let mut node_a_i = self.node_array.get(self.nodes[i].0).unwrap();
+            let mut node_a_i = node_array.get(nodes[i].0).unwrap();
             while node_a_i.bv[axis] < node_x.bv[axis] {
                 i += 1;
-                node_a_i = self.node_array.get(self.nodes[i].0).unwrap();
+                node_a_i = node_array.get(nodes[i].0).unwrap();
             }
 
             j -= 1;
-            let mut node_a_j = self.node_array.get(self.nodes[j].0).unwrap();
+            let mut node_a_j = node_array.get(nodes[j].0).unwrap();
             while node_x.bv[axis] < node_a_j.bv[axis] {
                 j -= 1;
-                node_a_j = self.node_array.get(self.nodes[j].0).unwrap();
+                node_a_j = node_array.get(nodes[j].0).unwrap();
             }
 
             if i >= j {
                 return i;
             }
 
-            self.nodes.swap(i, j);
+            nodes.swap(i, j);
 
             i += 1;
         }
     }
 
-    fn bvh_median_of_3(&self, lo: usize, mid: usize, hi: usize, axis: usize) -> BVHNodeIndex {
-        let node_lo = self.node_array.get(self.nodes[lo].0).unwrap();
-        let node_mid = self.node_array.get(self.nodes[mid].0).unwrap();
-        let node_hi = self.node_array.get(self.nodes[hi].0).unwrap();
+    fn bvh_median_of_3(
+        node_array: &Arena<BVHNode<T, E>>,
+        nodes: &[BVHNodeIndex],
+        lo: usize,
+        mid: usize,
+        hi: usize,
+        axis: usize,
+    ) -> BVHNodeIndex {
+        let node_lo = node_array.get(nodes[lo].0).unwrap();
+        let node_mid = node_array.get(nodes[mid].0).unwrap();
+        let node_hi = node_array.get(nodes[hi].0).unwrap();
 
         if node_mid.bv[axis] < node_lo.bv[axis] {
             if node_hi.bv[axis] < node_mid.bv[axis] {
-                self.nodes[mid]
+                nodes[mid]
             } else if node_hi.bv[axis] < node_lo.bv[axis] {
-                self.nodes[hi]
+                nodes[hi]
             } else {
-                self.nodes[lo]
+                nodes[lo]
             }
         } else if node_hi.bv[axis] < node_mid.bv[axis] {
             if node_hi.bv[axis] < node_lo.bv[axis] {
-                self.nodes[lo]
+                nodes[lo]
             } else {
-                self.nodes[hi]
+                nodes[hi]
             }
         } else {
-            self.nodes[mid]
+            nodes[mid]
         }
     }
 
-    fn partition_nth_element(&mut self, mut begin: usize, mut end: usize, n: usize, axis: usize) {
+    fn partition_nth_element(
+        node_array: &Arena<BVHNode<T, E>>,
+        nodes: &mut [BVHNodeIndex],
+        mut begin: usize,
+        mut end: usize,
+        n: usize,
+        axis: usize,
+    ) {
         while (end - begin) > 3 {
-            let cut = self.bvh_partition(
+            let cut = Self::bvh_partition(
+                node_array,
+                nodes,
                 begin,
                 end,
-                self.bvh_median_of_3(begin, (begin + end) / 2, end - 1, axis),
+                Self::bvh_median_of_3(node_array, nodes, begin, (begin + end) / 2, end - 1, axis),
                 axis,
             );
 
@@ -699,20 +1145,48 @@ where
             }
         }
 
-        self.bvh_insertion_sort(begin, end, axis);
+        Self::bvh_insertion_sort(node_array, nodes, begin, end, axis);
     }
 
-    fn split_leafs(&mut self, nth: &[usize], partitions: usize, split_axis: usize) {
+    fn split_leafs(
+        node_array: &Arena<BVHNode<T, E>>,
+        nodes: &mut [BVHNodeIndex],
+        nth: &[usize],
+        partitions: usize,
+        split_axis: usize,
+    ) {
         for i in 0..(partitions - 1) {
             if nth[i] >= nth[partitions] {
                 break;
             }
 
-            self.partition_nth_element(nth[i], nth[partitions], nth[i + 1], split_axis);
+            Self::partition_nth_element(
+                node_array,
+                nodes,
+                nth[i],
+                nth[partitions],
+                nth[i + 1],
+                split_axis,
+            );
         }
     }
 
-    fn non_recursive_bvh_div_nodes_task_cb(&mut self, data: &BVHDivNodesData, j: usize) {
+    /// Divide the leafs of the branch `j` amongst its children.
+    ///
+    /// `parent_leafs` must be the leafs of the branch, ie. the slice
+    /// of `self.nodes` from the first to the last leaf index
+    /// achievable from the branch. It only reads `node_array`, the
+    /// changes to the nodes are returned as [`BVHDivNodesResult`] so
+    /// that all the branches of a level can be divided in parallel.
+    fn non_recursive_bvh_div_nodes_task_cb(
+        node_array: &Arena<BVHNode<T, E>>,
+        parent_leafs: &mut [BVHNodeIndex],
+        data: &BVHDivNodesData,
+        j: usize,
+        tree_type: u8,
+        start_axis: u8,
+        stop_axis: u8,
+    ) -> BVHDivNodesResult<T> {
         let parent_level_index = j - data.i;
 
         let mut nth_positions: [usize; (MAX_TREETYPE + 1) as usize] =
@@ -724,20 +1198,18 @@ where
         let parent_leafs_end = data
             .data
             .implicit_leafs_index(data.depth, parent_level_index + 1);
+        debug_assert_eq!(parent_leafs.len(), parent_leafs_end - parent_leafs_begin);
 
-        let parent_index = BVHNodeIndex(
-            self.node_array
-                .get_unknown_index(data.brances_array_start + j),
-        );
+        let parent_index = BVHNodeIndex(node_array.get_unknown_index(data.brances_array_start + j));
 
         // calculate the bounding box of this branch and chooses the
         // longest axis as the axis to divide the leaves
-        self.refit_kdop_hull(parent_index, parent_leafs_begin, parent_leafs_end);
-        let parent = self.node_array.get_mut(parent_index.0).unwrap();
-        let split_axis = get_largest_axis(&parent.bv);
+        let mut bv = node_array.get(parent_index.0).unwrap().bv.clone();
+        refit_kdop_bv(&mut bv, node_array, parent_leafs, start_axis, stop_axis);
+        let split_axis = get_largest_axis(&bv);
 
         // Save split axis (this can be used on raytracing to speedup the query time)
-        parent.main_axis = split_axis / 2;
+        let main_axis = split_axis / 2;
 
         // Split the childs along the split_axis, note: its not needed
         // to sort the whole leafs array.
@@ -745,26 +1217,36 @@ where
         // that each child takes the elements it would take in case
         // the whole array was sorted.
         // Split_leafs takes care of that "sort" problem.
-        nth_positions[0] = parent_leafs_begin;
-        nth_positions[self.tree_type as usize] = parent_leafs_end;
-        for k in 1..self.tree_type {
+        //
+        // `parent_leafs` starts at `parent_leafs_begin` so the
+        // positions are relative to it.
+        nth_positions[0] = 0;
+        nth_positions[tree_type as usize] = parent_leafs_end - parent_leafs_begin;
+        for k in 1..tree_type {
             let k = k as usize;
             let child_index =
-                ((j * self.tree_type as usize) as isize + data.tree_offset + k as isize) as usize;
+                ((j * tree_type as usize) as isize + data.tree_offset + k as isize) as usize;
             let child_level_index = child_index - data.first_of_next_level;
             nth_positions[k] = data
                 .data
-                .implicit_leafs_index(data.depth + 1, child_level_index);
+                .implicit_leafs_index(data.depth + 1, child_level_index)
+                - parent_leafs_begin;
         }
 
-        self.split_leafs(&nth_positions, self.tree_type.into(), split_axis.into());
+        Self::split_leafs(
+            node_array,
+            parent_leafs,
+            &nth_positions,
+            tree_type.into(),
+            split_axis.into(),
+        );
 
-        // setup children and totnode counters
-        let mut totnode = 0;
-        for k in 0..self.tree_type {
+        // setup children
+        let mut children = Vec::with_capacity(tree_type.into());
+        for k in 0..tree_type {
             let k = k as usize;
             let child_index =
-                ((j * self.tree_type as usize) as isize + data.tree_offset + k as isize) as usize;
+                ((j * tree_type as usize) as isize + data.tree_offset + k as isize) as usize;
             let child_level_index = child_index - data.first_of_next_level;
 
             let child_leafs_begin = data
@@ -776,31 +1258,80 @@ where
 
             #[allow(clippy::comparison_chain)]
             if child_leafs_end - child_leafs_begin > 1 {
-                let child_index = BVHNodeIndex(
-                    self.node_array
-                        .get_unknown_index(data.brances_array_start + child_index),
-                );
-                let parent = self.node_array.get_mut(parent_index.0).unwrap();
-                parent.children[k] = child_index;
-                let child = self.node_array.get_mut(child_index.0).unwrap();
-                child.parent = Some(parent_index);
+                children.push(BVHNodeIndex(
+                    node_array.get_unknown_index(data.brances_array_start + child_index),
+                ));
             } else if child_leafs_end - child_leafs_begin == 1 {
-                let child_index = self.nodes[child_leafs_begin];
-                let parent = self.node_array.get_mut(parent_index.0).unwrap();
-                parent.children[k] = child_index;
-                let child = self.node_array.get_mut(child_index.0).unwrap();
-                child.parent = Some(parent_index);
+                children.push(parent_leafs[child_leafs_begin - parent_leafs_begin]);
             } else {
                 break;
             }
-            totnode += 1;
         }
 
-        let parent = self.node_array.get_mut(parent_index.0).unwrap();
-        parent.totnode = totnode;
+        BVHDivNodesResult {
+            parent_index,
+            bv,
+            main_axis,
+            children,
+        }
+    }
+
+    /// Divide the branches `i_start..i_stop` of a level serially.
+    #[allow(clippy::too_many_arguments)]
+    fn non_recursive_bvh_div_nodes_level(
+        node_array: &Arena<BVHNode<T, E>>,
+        leafs: &mut [BVHNodeIndex],
+        data: &BVHDivNodesData,
+        i_start: usize,
+        i_stop: usize,
+        tree_type: u8,
+        start_axis: u8,
+        stop_axis: u8,
+    ) -> Vec<BVHDivNodesResult<T>> {
+        (i_start..i_stop)
+            .map(|j| {
+                let parent_level_index = j - data.i;
+                let parent_leafs_begin = data
+                    .data
+                    .implicit_leafs_index(data.depth, parent_level_index);
+                let parent_leafs_end = data
+                    .data
+                    .implicit_leafs_index(data.depth, parent_level_index + 1);
+                Self::non_recursive_bvh_div_nodes_task_cb(
+                    node_array,
+                    &mut leafs[parent_leafs_begin..parent_leafs_end],
+                    data,
+                    j,
+                    tree_type,
+                    start_axis,
+                    stop_axis,
+                )
+            })
+            .collect()
     }
 
-    fn non_recursive_bvh_div_nodes(&mut self, branches_array_start: usize, num_leafs: usize) {
+    /// Apply the result of dividing a branch to the tree.
+    fn apply_bvh_div_nodes_result(&mut self, result: BVHDivNodesResult<T>) {
+        let parent = self.node_array.get_mut(result.parent_index.0).unwrap();
+        parent.bv = result.bv;
+        parent.main_axis = result.main_axis;
+        parent.totnode = result.children.len() as u8;
+        parent.children[..result.children.len()].copy_from_slice(&result.children);
+
+        for child_index in result.children {
+            let child = self.node_array.get_mut(child_index.0).unwrap();
+            child.parent = Some(result.parent_index);
+        }
+    }
+
+    /// `div_nodes_level` divides all the branches of a level, see
+    /// [`Self::non_recursive_bvh_div_nodes_level()`].
+    fn non_recursive_bvh_div_nodes(
+        &mut self,
+        branches_array_start: usize,
+        num_leafs: usize,
+        div_nodes_level: BVHDivNodesLevelFn<T, E>,
+    ) {
         let tree_type = self.tree_type;
         let tree_offset: isize = 2 - tree_type as isize;
         let num_branches = implicit_needed_branches(tree_type, num_leafs);
@@ -846,10 +1377,21 @@ where
             cb_data.i = i;
             cb_data.depth = depth;
 
-            // TODO(ish): make this parallel, refer to Blender's code
-            for i_task in i..i_stop {
-                self.non_recursive_bvh_div_nodes_task_cb(&cb_data, i_task);
-            }
+            // the branches of a level only read the leaf nodes, the
+            // results can be applied once all of them are divided
+            let results = div_nodes_level(
+                &self.node_array,
+                &mut self.nodes[..num_leafs],
+                &cb_data,
+                i,
+                i_stop,
+                self.tree_type,
+                self.start_axis,
+                self.stop_axis,
+            );
+            results
+                .into_iter()
+                .for_each(|result| self.apply_bvh_div_nodes_result(result));
 
             i = first_of_next_level;
             depth += 1;
@@ -861,7 +1403,131 @@ where
     ///
     /// # panics
     /// * When function called more than once
+    ///
+    /// The elements are divided as per the [`BVHBuildMode`] given
+    /// at construction.
+    ///
+    /// See [`Self::balance_multithreaded()`] for the multithreaded
+    /// version.
     pub fn balance(&mut self) {
+        match self.build_mode {
+            BVHBuildMode::Median => self.balance_with(Self::non_recursive_bvh_div_nodes_level),
+            BVHBuildMode::SAH => self.balance_sah(),
+        }
+    }
+
+    /// Get the branch node of the tree at `branch_index`, allocates
+    /// the node if the tree doesn't have enough nodes.
+    ///
+    /// Unlike the implicit tree built by the median split, the number
+    /// of branches of the SAH tree is not known upfront.
+    fn sah_branch(&mut self, branch_index: usize) -> BVHNodeIndex {
+        let nodes_index = self.totleaf + branch_index;
+        if nodes_index >= self.nodes.len() {
+            let node_index = self.new_node();
+            self.nodes.push(node_index);
+        } else {
+            self.nodes[nodes_index] = BVHNodeIndex(self.node_array.get_unknown_index(nodes_index));
+        }
+        self.nodes[nodes_index]
+    }
+
+    /// Build the tree top down, see [`BVHBuildMode::SAH`].
+    ///
+    /// Each branch gets up to `tree_type` children by repeatedly
+    /// splitting the child with the largest surface area in 2 (like
+    /// a binary SAH build collapsed into a `tree_type` tree). The
+    /// branches are created breadth first so that the children of a
+    /// branch always come after it in `self.nodes`.
+    fn balance_sah(&mut self) {
+        assert_eq!(self.totbranch, 0);
+
+        if self.totleaf == 0 {
+            // no need to balance the bvh when there are no elements
+            // in the bvh
+            return;
+        }
+
+        // only the x, y and z axes are considered for the split (like
+        // `get_largest_axis()`)
+        let leafs: Vec<BVHNodeIndex> = self.nodes[..self.totleaf].to_vec();
+        let leaf_bvs: Vec<[T; 6]> = leafs
+            .iter()
+            .map(|leaf_index| {
+                let bv = &self.node_array.get(leaf_index.0).unwrap().bv;
+                [bv[0], bv[1], bv[2], bv[3], bv[4], bv[5]]
+            })
+            .collect();
+
+        let mut num_branches = 0;
+        let mut branches = std::collections::VecDeque::new();
+        branches.push_back((self.sah_branch(num_branches), (0..leafs.len()).collect()));
+        num_branches += 1;
+
+        while let Some((parent_index, parent_leafs)) = branches.pop_front() {
+            let parent_leafs: Vec<usize> = parent_leafs;
+
+            let mut bv = self.node_array.get(parent_index.0).unwrap().bv.clone();
+            let parent_leaf_nodes: Vec<BVHNodeIndex> =
+                parent_leafs.iter().map(|i| leafs[*i]).collect();
+            refit_kdop_bv(
+                &mut bv,
+                &self.node_array,
+                &parent_leaf_nodes,
+                self.start_axis,
+                self.stop_axis,
+            );
+
+            let mut children_leafs = vec![parent_leafs];
+            let mut main_axis = None;
+            while children_leafs.len() < self.tree_type as usize {
+                let to_split = children_leafs
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, child_leafs)| child_leafs.len() > 1)
+                    .map(|(i, child_leafs)| {
+                        (i, sah_bv_half_area(&sah_leafs_bv(&leaf_bvs, child_leafs)))
+                    })
+                    .max_by(|(_, a), (_, b)| total_cmp(a, b));
+                let i = match to_split {
+                    Some((i, _)) => i,
+                    None => break,
+                };
+
+                let (left, right, split_axis) = sah_split(&leaf_bvs, &children_leafs[i]);
+                children_leafs[i] = left;
+                children_leafs.insert(i + 1, right);
+                main_axis.get_or_insert(split_axis);
+            }
+
+            let children = children_leafs
+                .into_iter()
+                .map(|child_leafs| {
+                    if child_leafs.len() == 1 {
+                        leafs[child_leafs[0]]
+                    } else {
+                        let child_index = self.sah_branch(num_branches);
+                        num_branches += 1;
+                        branches.push_back((child_index, child_leafs));
+                        child_index
+                    }
+                })
+                .collect();
+
+            let main_axis = main_axis.unwrap_or_else(|| get_largest_axis(&bv) / 2);
+
+            self.apply_bvh_div_nodes_result(BVHDivNodesResult {
+                parent_index,
+                bv,
+                main_axis,
+                children,
+            });
+        }
+
+        self.totbranch = num_branches;
+    }
+
+    fn balance_with(&mut self, div_nodes_level: BVHDivNodesLevelFn<T, E>) {
         assert_eq!(self.totbranch, 0);
 
         if self.totleaf == 0 {
@@ -870,7 +1536,7 @@ where
             return;
         }
 
-        self.non_recursive_bvh_div_nodes(self.totleaf - 1, self.totleaf);
+        self.non_recursive_bvh_div_nodes(self.totleaf - 1, self.totleaf, div_nodes_level);
 
         self.totbranch = implicit_needed_branches(self.tree_type, self.totleaf);
         for i in 0..self.totbranch {
@@ -892,7 +1558,7 @@ where
         co_many: &[glm::TVec3<T>],
         co_moving_many: &[glm::TVec3<T>],
     ) -> Result<(), BVHError> {
-        if node_index > self.totleaf {
+        if !self.is_leaf_slot(node_index) {
             return Err(BVHError::IndexOutOfRange);
         }
         if !co_moving_many.is_empty() && co_many.len() != co_moving_many.len() {
@@ -917,8 +1583,21 @@ where
         Ok(())
     }
 
-    fn node_join(&mut self, nodes_index: usize) {
-        let node_index = self.nodes[nodes_index];
+    /// Check if `node_index` (the index used by `update_node()`)
+    /// refers to a leaf node that is part of the tree.
+    fn is_leaf_slot(&self, node_index: usize) -> bool {
+        match self.node_array.get_unknown_gen(node_index) {
+            Some((node, _)) => node.totnode == 0 && node.elem_index.is_some(),
+            None => false,
+        }
+    }
+
+    /// Get the index of the leaf node to use with `update_node()`.
+    fn leaf_slot(node_index: BVHNodeIndex) -> usize {
+        node_index.0.into_raw_parts().0
+    }
+
+    fn node_join(&mut self, node_index: BVHNodeIndex) {
         {
             let node = self.node_array.get_mut(node_index.0).unwrap();
             node.min_max_init(self.start_axis, self.stop_axis);
@@ -958,48 +1637,544 @@ where
             return;
         }
 
-        let root_start = self.totleaf;
-        let mut index = self.totleaf + self.totbranch - 1;
+        if self.totbranch == 0 {
+            return;
+        }
 
-        while index >= root_start {
-            self.node_join(index);
-            index -= 1;
+        // post order traversal so that the children are joined
+        // before their parent, the order of the branches in
+        // `self.nodes` isn't known after incremental updates
+        let mut stack = vec![(self.nodes[self.totleaf], false)];
+        while let Some((node_index, children_done)) = stack.pop() {
+            if children_done {
+                self.node_join(node_index);
+                continue;
+            }
+            stack.push((node_index, true));
+            let node = self.node_array.get(node_index.0).unwrap();
+            stack.extend(
+                node.children[..node.totnode as usize]
+                    .iter()
+                    .filter(|child_index| self.node_array.get(child_index.0).unwrap().totnode != 0)
+                    .map(|child_index| (*child_index, false)),
+            );
         }
     }
 
-    fn overlap_thread_num(&self) -> usize {
-        let node = self.node_array.get(self.nodes[self.totleaf].0).unwrap();
-        self.tree_type.min(node.totnode).into()
+    /// Create a node that isn't part of the tree yet.
+    fn new_node(&mut self) -> BVHNodeIndex {
+        let mut node = BVHNode::new();
+        node.bv.resize(self.axis.into(), T::zero());
+        node.children
+            .resize(self.tree_type.into(), BVHNodeIndex::unknown());
+        BVHNodeIndex(self.node_array.insert(node))
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn overlap_traverse_callback<F>(
-        &self,
-        other: &BVHTree<T, E>,
-        node_1_index: BVHNodeIndex,
-        node_2_index: BVHNodeIndex,
-        start_axis: u8,
-        stop_axis: u8,
-        callback: &F,
-        r_overlap_pairs: &mut Vec<BVHTreeOverlap<E>>,
-    ) where
-        F: Fn(E, E) -> bool,
-    {
-        let node_1 = self.node_array.get(node_1_index.0).unwrap();
-        let node_2 = other.node_array.get(node_2_index.0).unwrap();
-        if node_1.overlap_test(node_2, start_axis, stop_axis) {
-            // check if node_1 is a leaf node
-            if node_1.totnode == 0 {
-                // check if node_2 is a leaf node
-                if node_2.totnode == 0 {
-                    // the two nodes if equal, all the children will
+    /// Get the x, y, z part of the BV of the node.
+    fn node_bv_xyz(&self, node_index: BVHNodeIndex) -> [T; 6] {
+        let bv = &self.node_array.get(node_index.0).unwrap().bv;
+        [bv[0], bv[1], bv[2], bv[3], bv[4], bv[5]]
+    }
+
+    fn is_branch(&self, node_index: BVHNodeIndex) -> bool {
+        self.node_array.get(node_index.0).unwrap().totnode != 0
+    }
+
+    /// Prepare the tree for an incremental update, the tree is
+    /// balanced if it isn't already. Only the leafs and the root are
+    /// kept in `self.nodes` from then on, the branches are reached
+    /// through the root, and the positions of the leafs are tracked
+    /// so that they can be removed in constant time.
+    fn prepare_incremental(&mut self) {
+        if self.leaf_positions.is_some() {
+            return;
+        }
+        if self.totbranch == 0 && self.totleaf != 0 {
+            self.balance();
+        }
+        self.nodes.truncate(self.totleaf + 1);
+        self.leaf_positions = Some(Vec::with_capacity(self.node_array.capacity()));
+        for position in 0..self.totleaf {
+            self.set_leaf_position(self.nodes[position], position);
+        }
+    }
+
+    fn set_leaf_position(&mut self, leaf_index: BVHNodeIndex, position: usize) {
+        let leaf_positions = self.leaf_positions.as_mut().unwrap();
+        let slot = Self::leaf_slot(leaf_index);
+        if slot >= leaf_positions.len() {
+            leaf_positions.resize(slot + 1, usize::MAX);
+        }
+        leaf_positions[slot] = position;
+    }
+
+    /// Remove the branch (not the root) from the tree, the branch
+    /// must already be unlinked from its parent and children.
+    fn remove_branch(&mut self, branch_index: BVHNodeIndex) {
+        self.totbranch -= 1;
+        self.node_array.remove(branch_index.0);
+    }
+
+    /// Reset the tree to a newly created tree (with at least the same
+    /// capacity). The generations of `self.node_array` keep
+    /// increasing, so handles of removed elements stay invalid.
+    fn reset(&mut self) {
+        let numnodes = self.node_array.capacity();
+        self.node_array.clear();
+        self.nodes.clear();
+        self.nodes.resize(numnodes, BVHNodeIndex::unknown());
+        for _ in 0..numnodes {
+            self.new_node();
+        }
+        self.totleaf = 0;
+        self.totbranch = 0;
+        self.leaf_positions = None;
+    }
+
+    fn is_leaf(&self, handle: BVHLeafHandle) -> bool {
+        match self.node_array.get(handle.0) {
+            Some(node) => node.totnode == 0 && node.elem_index.is_some(),
+            None => false,
+        }
+    }
+
+    /// Get the handle of the element inserted with [`Self::insert()`]
+    /// at `node_index` (the insertion order), to use with the
+    /// incremental updates. [`None`] if there is no such element.
+    pub fn get_leaf_handle(&self, node_index: usize) -> Option<BVHLeafHandle> {
+        if self.is_leaf_slot(node_index) {
+            Some(BVHLeafHandle(self.node_array.get_unknown_index(node_index)))
+        } else {
+            None
+        }
+    }
+
+    /// Get the node index of the element to use with
+    /// [`Self::update_node()`]. [`None`] if the element has been
+    /// removed.
+    pub fn get_leaf_node_index(&self, handle: BVHLeafHandle) -> Option<usize> {
+        if self.is_leaf(handle) {
+            Some(handle.0.into_raw_parts().0)
+        } else {
+            None
+        }
+    }
+
+    /// Insert a new element into an already balanced tree without
+    /// rebuilding it.
+    ///
+    /// The leaf is added where it enlarges the tree the least, the
+    /// BVs of its ancestors are updated and tree rotations are
+    /// applied along the way to keep the tree from degrading. The
+    /// tree is balanced first if it hasn't been yet, so
+    /// [`Self::balance()`] must not be called after any incremental
+    /// update.
+    ///
+    /// See [`Self::insert()`] for `index` and `co_many`.
+    ///
+    /// Returns the handle of the element, to use with
+    /// [`Self::update_node_incremental()`] and
+    /// [`Self::remove_incremental()`].
+    pub fn insert_incremental(&mut self, index: E, co_many: &[glm::TVec3<T>]) -> BVHLeafHandle {
+        self.prepare_incremental();
+
+        let leaf_index = self.new_node();
+        self.node_array.get_mut(leaf_index.0).unwrap().elem_index = Some(index);
+        self.update_node(Self::leaf_slot(leaf_index), co_many, &[])
+            .unwrap();
+
+        // the leaf goes at the end of the leafs, right before the
+        // root
+        self.nodes.insert(self.totleaf, leaf_index);
+        self.set_leaf_position(leaf_index, self.totleaf);
+        self.totleaf += 1;
+
+        if self.totbranch == 0 {
+            let root_index = self.new_node();
+            self.nodes[self.totleaf] = root_index;
+            self.totbranch = 1;
+            self.add_child(root_index, leaf_index);
+            self.node_join(root_index);
+            let root = self.node_array.get_mut(root_index.0).unwrap();
+            root.main_axis = get_largest_axis(&root.bv) / 2;
+            return BVHLeafHandle(leaf_index.0);
+        }
+
+        let leaf_bv = self.node_bv_xyz(leaf_index);
+        let mut node_index = self.nodes[self.totleaf];
+        let parent_index = loop {
+            let node = self.node_array.get(node_index.0).unwrap();
+            let children = node.children[..node.totnode as usize].to_vec();
+            let is_full = node.totnode == self.tree_type;
+
+            // (enlargement, area) of the child when the leaf is added
+            // to it
+            let child_costs: Vec<_> = children
+                .iter()
+                .map(|child_index| {
+                    let child_bv = self.node_bv_xyz(*child_index);
+                    let mut joined_bv = child_bv;
+                    sah_bv_join(&mut joined_bv, &leaf_bv);
+                    let area = sah_bv_half_area(&child_bv);
+                    (sah_bv_half_area(&joined_bv) - area, area)
+                })
+                .collect();
+
+            // prefer a branch that already contains the leaf, then a
+            // free slot, then the least enlargement
+            let containing_child = children
+                .iter()
+                .zip(child_costs.iter())
+                .filter(|(child_index, (enlargement, _))| {
+                    *enlargement <= T::zero() && self.is_branch(**child_index)
+                })
+                .min_by(|(_, (_, area_1)), (_, (_, area_2))| total_cmp(area_1, area_2))
+                .map(|(child_index, _)| *child_index);
+            if let Some(child_index) = containing_child {
+                node_index = child_index;
+                continue;
+            }
+
+            if !is_full {
+                self.add_child(node_index, leaf_index);
+                break node_index;
+            }
+
+            let (best, _) = child_costs
+                .iter()
+                .enumerate()
+                .min_by(
+                    |(_, (enlargement_1, area_1)), (_, (enlargement_2, area_2))| {
+                        total_cmp(enlargement_1, enlargement_2)
+                            .then_with(|| total_cmp(area_1, area_2))
+                    },
+                )
+                .unwrap();
+            let child_index = children[best];
+
+            if self.is_branch(child_index) {
+                node_index = child_index;
+                continue;
+            }
+
+            // replace the leaf child with a new branch holding both
+            // leafs
+            let branch_index = self.new_node();
+            self.totbranch += 1;
+            let node = self.node_array.get_mut(node_index.0).unwrap();
+            node.children[best] = branch_index;
+            self.node_array.get_mut(branch_index.0).unwrap().parent = Some(node_index);
+            self.add_child(branch_index, child_index);
+            self.add_child(branch_index, leaf_index);
+            self.node_join(branch_index);
+            let branch = self.node_array.get_mut(branch_index.0).unwrap();
+            branch.main_axis = get_largest_axis(&branch.bv) / 2;
+            break node_index;
+        };
+
+        self.refit_ancestors(parent_index);
+
+        BVHLeafHandle(leaf_index.0)
+    }
+
+    /// Remove the element of `handle` (returned by
+    /// [`Self::insert_incremental()`] or [`Self::get_leaf_handle()`])
+    /// from the tree without rebuilding it.
+    ///
+    /// Branches left with a single child are collapsed, the BVs of
+    /// the ancestors are updated and tree rotations are applied
+    /// along the way. The tree is balanced first if it hasn't been
+    /// yet.
+    ///
+    /// Returns the `index` of the removed element. When the last
+    /// element is removed, the tree is reset to a newly created tree.
+    pub fn remove_incremental(&mut self, handle: BVHLeafHandle) -> Result<E, BVHError> {
+        if !self.is_leaf(handle) {
+            return Err(BVHError::IndexOutOfRange);
+        }
+        self.prepare_incremental();
+
+        let leaf_index = BVHNodeIndex(handle.0);
+        let parent_index = self.node_array.get(leaf_index.0).unwrap().parent.unwrap();
+        self.remove_child(parent_index, leaf_index);
+
+        // the last leaf takes the place of the leaf, only the root
+        // comes after the leafs
+        let leaf_position = self.leaf_positions.as_ref().unwrap()[Self::leaf_slot(leaf_index)];
+        let last_leaf_index = self.nodes[self.totleaf - 1];
+        self.nodes.swap(leaf_position, self.totleaf - 1);
+        self.set_leaf_position(last_leaf_index, leaf_position);
+        self.nodes.remove(self.totleaf - 1);
+        self.totleaf -= 1;
+        let elem_index = self
+            .node_array
+            .remove(leaf_index.0)
+            .unwrap()
+            .elem_index
+            .unwrap();
+
+        let parent = self.node_array.get(parent_index.0).unwrap();
+        let parent_totnode = parent.totnode;
+        let grandparent_index = parent.parent;
+
+        if parent_totnode == 0 {
+            // the root had a single leaf, the tree is empty now
+            debug_assert_eq!(self.totleaf, 0);
+            self.reset();
+            return Ok(elem_index);
+        }
+
+        let mut start_index = parent_index;
+        if parent_totnode == 1 {
+            let child_index = self.node_array.get(parent_index.0).unwrap().children[0];
+            match grandparent_index {
+                Some(grandparent_index) => {
+                    // collapse the parent, the child takes its place
+                    let grandparent = self.node_array.get_mut(grandparent_index.0).unwrap();
+                    let child_pos = grandparent.children[..grandparent.totnode as usize]
+                        .iter()
+                        .position(|index| *index == parent_index)
+                        .unwrap();
+                    grandparent.children[child_pos] = child_index;
+                    self.node_array.get_mut(child_index.0).unwrap().parent =
+                        Some(grandparent_index);
+                    self.remove_branch(parent_index);
+                    start_index = grandparent_index;
+                }
+                None => {
+                    // a root with a single branch child, the child
+                    // becomes the root, a single leaf child stays
+                    // like a tree with a single element
+                    if self.is_branch(child_index) {
+                        self.node_array.get_mut(child_index.0).unwrap().parent = None;
+                        self.nodes[self.totleaf] = child_index;
+                        self.remove_branch(parent_index);
+                        start_index = child_index;
+                    }
+                }
+            }
+        }
+
+        self.refit_ancestors(start_index);
+
+        Ok(elem_index)
+    }
+
+    /// Update the element of `handle` like [`Self::update_node()`] and
+    /// then only its ancestors, cheaper than [`Self::update_tree()`] when
+    /// few elements have moved. Tree rotations are applied along the
+    /// way to keep the tree from degrading.
+    pub fn update_node_incremental(
+        &mut self,
+        handle: BVHLeafHandle,
+        co_many: &[glm::TVec3<T>],
+        co_moving_many: &[glm::TVec3<T>],
+    ) -> Result<(), BVHError> {
+        let node_index = self
+            .get_leaf_node_index(handle)
+            .ok_or(BVHError::IndexOutOfRange)?;
+        self.update_node(node_index, co_many, co_moving_many)?;
+        self.prepare_incremental();
+
+        if let Some(parent_index) = self.node_array.get(handle.0).unwrap().parent {
+            self.refit_ancestors(parent_index);
+        }
+
+        Ok(())
+    }
+
+    fn add_child(&mut self, node_index: BVHNodeIndex, child_index: BVHNodeIndex) {
+        let node = self.node_array.get_mut(node_index.0).unwrap();
+        node.children[node.totnode as usize] = child_index;
+        node.totnode += 1;
+        self.node_array.get_mut(child_index.0).unwrap().parent = Some(node_index);
+    }
+
+    fn remove_child(&mut self, node_index: BVHNodeIndex, child_index: BVHNodeIndex) {
+        let node = self.node_array.get_mut(node_index.0).unwrap();
+        let totnode = node.totnode as usize;
+        let child_pos = node.children[..totnode]
+            .iter()
+            .position(|index| *index == child_index)
+            .unwrap();
+        // keep the order of the remaining children, the unused
+        // children must be unknown for `node_join()`
+        node.children[child_pos..totnode].rotate_left(1);
+        node.children[totnode - 1] = BVHNodeIndex::unknown();
+        node.totnode -= 1;
+    }
+
+    /// Update the BVs of the node and its ancestors, rotating the
+    /// tree at each of them.
+    fn refit_ancestors(&mut self, node_index: BVHNodeIndex) {
+        let mut node_index = Some(node_index);
+        while let Some(index) = node_index {
+            self.node_join(index);
+            self.rotate(index);
+            node_index = self.node_array.get(index.0).unwrap().parent;
+        }
+    }
+
+    /// Tree rotation at the node, swaps a child of the node with a
+    /// grandchild (a child of another child branch of the node) if
+    /// it reduces the surface area of that child branch the most.
+    /// The BV of the node itself doesn't change.
+    fn rotate(&mut self, node_index: BVHNodeIndex) {
+        let node = self.node_array.get(node_index.0).unwrap();
+        let children = node.children[..node.totnode as usize].to_vec();
+        if children.len() < 2 {
+            return;
+        }
+
+        // (gain, child branch, grandchild position, other child
+        // position)
+        let mut best: Option<(T, BVHNodeIndex, usize, usize)> = None;
+        for branch_index in children.iter().filter(|index| self.is_branch(**index)) {
+            let branch = self.node_array.get(branch_index.0).unwrap();
+            let grandchildren = &branch.children[..branch.totnode as usize];
+            let area = sah_bv_half_area(&self.node_bv_xyz(*branch_index));
+            for grandchild_pos in 0..grandchildren.len() {
+                // BV of the branch without the grandchild
+                let rest_bv = grandchildren
+                    .iter()
+                    .enumerate()
+                    .filter(|(pos, _)| *pos != grandchild_pos)
+                    .map(|(_, index)| self.node_bv_xyz(*index))
+                    .reduce(|mut bv, other| {
+                        sah_bv_join(&mut bv, &other);
+                        bv
+                    });
+                for (other_pos, other_index) in children.iter().enumerate() {
+                    if other_index == branch_index {
+                        continue;
+                    }
+                    let mut new_bv = self.node_bv_xyz(*other_index);
+                    if let Some(rest_bv) = &rest_bv {
+                        sah_bv_join(&mut new_bv, rest_bv);
+                    }
+                    let gain = area - sah_bv_half_area(&new_bv);
+                    let is_better = match best {
+                        Some((best_gain, ..)) => gain > best_gain,
+                        None => true,
+                    };
+                    if gain > T::zero() && is_better {
+                        best = Some((gain, *branch_index, grandchild_pos, other_pos));
+                    }
+                }
+            }
+        }
+
+        if let Some((_, branch_index, grandchild_pos, other_pos)) = best {
+            let other_index = children[other_pos];
+            let branch = self.node_array.get_mut(branch_index.0).unwrap();
+            let grandchild_index = branch.children[grandchild_pos];
+            branch.children[grandchild_pos] = other_index;
+            self.node_array.get_mut(other_index.0).unwrap().parent = Some(branch_index);
+            self.node_array.get_mut(node_index.0).unwrap().children[other_pos] = grandchild_index;
+            self.node_array.get_mut(grandchild_index.0).unwrap().parent = Some(node_index);
+            self.node_join(branch_index);
+        }
+    }
+
+    /// Update all the leaf nodes with the points given by `co_many`
+    /// for the element stored in the node and then update the tree.
+    ///
+    /// `co_many` must return the points and the moving points (can
+    /// be empty, see [`Self::update_node()`]) of the element, or
+    /// [`None`] if the element doesn't exist anymore,
+    /// [`BVHError::ElementNotFound`] is returned then.
+    pub(crate) fn refit_with<F>(&mut self, co_many: F) -> Result<(), BVHError>
+    where
+        F: Fn(E) -> Option<(Vec<glm::TVec3<T>>, Vec<glm::TVec3<T>>)>,
+    {
+        for i in 0..self.totleaf {
+            let leaf_index = self.nodes[i];
+            let elem_index = self
+                .node_array
+                .get(leaf_index.0)
+                .unwrap()
+                .elem_index
+                .unwrap();
+            let (co, co_moving) = co_many(elem_index).ok_or(BVHError::ElementNotFound)?;
+            self.update_node(Self::leaf_slot(leaf_index), &co, &co_moving)?;
+        }
+
+        self.update_tree();
+
+        Ok(())
+    }
+
+    /// Flatten the hierarchy of the tree so that data can be
+    /// accumulated per node outside of the tree. The children of a
+    /// node are placed before it, so the root is the last node.
+    ///
+    /// Empty if the tree isn't built, see [`Self::balance()`].
+    pub(crate) fn flatten_hierarchy(&self) -> Vec<BVHFlatNode<E>> {
+        let mut flat_nodes = Vec::with_capacity(self.totleaf + self.totbranch);
+        if let Some(root_index) = self.nodes.get(self.totleaf) {
+            if self.node_array.get(root_index.0).is_some() {
+                self.flatten_hierarchy_recursive(*root_index, &mut flat_nodes);
+            }
+        }
+        flat_nodes
+    }
+
+    fn flatten_hierarchy_recursive(
+        &self,
+        node_index: BVHNodeIndex,
+        r_flat_nodes: &mut Vec<BVHFlatNode<E>>,
+    ) -> usize {
+        let node = self.node_array.get(node_index.0).unwrap();
+        let flat_node = match node.elem_index {
+            Some(elem_index) if node.totnode == 0 => BVHFlatNode::Leaf(elem_index),
+            _ => BVHFlatNode::Branch(
+                node.children[..node.totnode as usize]
+                    .iter()
+                    .map(|child_index| self.flatten_hierarchy_recursive(*child_index, r_flat_nodes))
+                    .collect(),
+            ),
+        };
+        r_flat_nodes.push(flat_node);
+        r_flat_nodes.len() - 1
+    }
+
+    fn overlap_thread_num(&self) -> usize {
+        let node = self.node_array.get(self.nodes[self.totleaf].0).unwrap();
+        self.tree_type.min(node.totnode).into()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn overlap_traverse_callback<F>(
+        &self,
+        other: &BVHTree<T, E>,
+        node_1_index: BVHNodeIndex,
+        node_2_index: BVHNodeIndex,
+        start_axis: u8,
+        stop_axis: u8,
+        callback: &F,
+        r_overlap_pairs: &mut Vec<BVHTreeOverlap<E>>,
+    ) where
+        F: Fn(E, E) -> bool,
+    {
+        let node_1 = self.node_array.get(node_1_index.0).unwrap();
+        let node_2 = other.node_array.get(node_2_index.0).unwrap();
+        self.count_node_visit();
+        if node_1.overlap_test(node_2, start_axis, stop_axis) {
+            // check if node_1 is a leaf node
+            if node_1.totnode == 0 {
+                // check if node_2 is a leaf node
+                if node_2.totnode == 0 {
+                    // the two nodes if equal, all the children will
                     // also match. This happens when overlap between
-                    // the same tree is checked for.
-                    if node_1_index == node_2_index {
+                    // the same tree is checked for, node indices of
+                    // different trees can be equal without the
+                    // nodes being the same.
+                    if std::ptr::eq(self, other) && node_1_index == node_2_index {
                         return;
                     }
 
                     // Only difference to BVHTree::overlap_traverse
+                    self.count_primitive_test();
                     if callback(node_1.elem_index.unwrap(), node_2.elem_index.unwrap()) {
                         let overlap = BVHTreeOverlap::new(
                             node_1.elem_index.unwrap(),
@@ -1053,6 +2228,7 @@ where
     ) {
         let node_1 = self.node_array.get(node_1_index.0).unwrap();
         let node_2 = other.node_array.get(node_2_index.0).unwrap();
+        self.count_node_visit();
         if node_1.overlap_test(node_2, start_axis, stop_axis) {
             // check if node_1 is a leaf node
             if node_1.totnode == 0 {
@@ -1060,11 +2236,14 @@ where
                 if node_2.totnode == 0 {
                     // the two nodes if equal, all the children will
                     // also match. This happens when overlap between
-                    // the same tree is checked for.
-                    if node_1_index == node_2_index {
+                    // the same tree is checked for, node indices of
+                    // different trees can be equal without the
+                    // nodes being the same.
+                    if std::ptr::eq(self, other) && node_1_index == node_2_index {
                         return;
                     }
 
+                    self.count_primitive_test();
                     let overlap =
                         BVHTreeOverlap::new(node_1.elem_index.unwrap(), node_2.elem_index.unwrap());
                     r_overlap_pairs.push(overlap);
@@ -1101,30 +2280,54 @@ where
         }
     }
 
-    /// Tests for overlap between the 2 BVH with an optional callback
-    /// to decide if that overlap of the BVs should be considered.
-    ///
-    /// `callback` is given the indices of the 2 elements of the
-    /// overlapping BVs, must return if the overlap should be
-    /// considered.
-    pub fn overlap<F>(
+    /// Traverse for overlap with or without the callback.
+    #[allow(clippy::too_many_arguments)]
+    fn overlap_traverse_optional_callback<F>(
         &self,
         other: &BVHTree<T, E>,
+        node_1_index: BVHNodeIndex,
+        node_2_index: BVHNodeIndex,
+        start_axis: u8,
+        stop_axis: u8,
         callback: Option<&F>,
-    ) -> Option<Vec<BVHTreeOverlap<E>>>
-    where
+        r_overlap_pairs: &mut Vec<BVHTreeOverlap<E>>,
+    ) where
         F: Fn(E, E) -> bool,
     {
-        if self.totleaf == 0 {
+        if let Some(callback) = callback {
+            self.overlap_traverse_callback(
+                other,
+                node_1_index,
+                node_2_index,
+                start_axis,
+                stop_axis,
+                callback,
+                r_overlap_pairs,
+            );
+        } else {
+            self.overlap_traverse(
+                other,
+                node_1_index,
+                node_2_index,
+                start_axis,
+                stop_axis,
+                r_overlap_pairs,
+            );
+        }
+    }
+
+    /// Checks if the trees can be tested for overlap and if their
+    /// roots overlap.
+    ///
+    /// Returns the root indices along with the `start_axis` and
+    /// `stop_axis` to use for the traversal, [`None`] if no overlap
+    /// is possible.
+    fn overlap_begin(&self, other: &BVHTree<T, E>) -> Option<(BVHNodeIndex, BVHNodeIndex, u8, u8)> {
+        if self.totleaf == 0 || other.totleaf == 0 {
             // no elements so no overlap possible
             return None;
         }
 
-        // TODO(ish): add multithreading support
-        let use_threading = false;
-        let root_node_len = self.overlap_thread_num();
-        let _thread_num = if use_threading { root_node_len } else { 1 };
-
         assert!(
             !(self.axis != other.axis
                 && (self.axis == 14 || other.axis == 14)
@@ -1145,55 +2348,289 @@ where
             return None;
         }
 
-        if use_threading {
-            panic!("Multithreading not implemented yet for BVHTree::overlap()");
+        Some((root_1_index, root_2_index, start_axis, stop_axis))
+    }
+
+    /// Tests for overlap between the 2 BVH with an optional callback
+    /// to decide if that overlap of the BVs should be considered.
+    ///
+    /// `callback` is given the indices of the 2 elements of the
+    /// overlapping BVs, must return if the overlap should be
+    /// considered.
+    ///
+    /// See [`Self::overlap_multithreaded()`] for the multithreaded
+    /// version.
+    pub fn overlap<F>(
+        &self,
+        other: &BVHTree<T, E>,
+        callback: Option<&F>,
+    ) -> Option<Vec<BVHTreeOverlap<E>>>
+    where
+        F: Fn(E, E) -> bool,
+    {
+        let (root_1_index, root_2_index, start_axis, stop_axis) = self.overlap_begin(other)?;
+
+        let mut overlap_pairs = Vec::new();
+        self.overlap_traverse_optional_callback(
+            other,
+            root_1_index,
+            root_2_index,
+            start_axis,
+            stop_axis,
+            callback,
+            &mut overlap_pairs,
+        );
+
+        if overlap_pairs.is_empty() {
+            None
+        } else {
+            Some(overlap_pairs)
+        }
+    }
+
+    /// Get the BV of `node` (of another tree) moved into the space of
+    /// `self` by the rigid `transform`.
+    ///
+    /// The BV is conservative, it is the k-DOP hull (over the axes of
+    /// `self`) of the transformed corners of the bounding box of
+    /// `node`.
+    fn transform_node_bv(
+        &self,
+        node: &BVHNode<T, E>,
+        transform: &glm::TMat4<T>,
+    ) -> [T; MAX_KDOP_BV_LEN] {
+        let bv = &node.bv;
+        let mut corners = [glm::TVec3::zeros(); 8];
+        corners.iter_mut().enumerate().for_each(|(i, corner)| {
+            let co = glm::vec3(bv[i & 1], bv[2 + ((i >> 1) & 1)], bv[4 + ((i >> 2) & 1)]);
+            *corner = vec3_apply_model_matrix(&co, transform);
+        });
+
+        let mut transformed = [T::zero(); MAX_KDOP_BV_LEN];
+        kdop_min_max_init(&mut transformed, self.start_axis, self.stop_axis);
+        kdop_extend(&mut transformed, self.start_axis, self.stop_axis, &corners);
+        transformed
+    }
+
+    /// `transformed_bvs` caches the BVs of the nodes of `other`
+    /// transformed into the space of `self`, only the nodes that are
+    /// visited are transformed, see `transform_node_bv()`.
+    #[allow(clippy::too_many_arguments)]
+    fn overlap_transformed_traverse<F>(
+        &self,
+        other: &BVHTree<T, E>,
+        node_1_index: BVHNodeIndex,
+        node_2_index: BVHNodeIndex,
+        transform: &glm::TMat4<T>,
+        transformed_bvs: &mut HashMap<BVHNodeIndex, [T; MAX_KDOP_BV_LEN]>,
+        callback: Option<&F>,
+        r_overlap_pairs: &mut Vec<BVHTreeOverlap<E>>,
+    ) where
+        F: Fn(E, E) -> bool,
+    {
+        let node_1 = self.node_array.get(node_1_index.0).unwrap();
+        let node_2 = other.node_array.get(node_2_index.0).unwrap();
+        self.count_node_visit();
+        let node_2_bv = transformed_bvs
+            .entry(node_2_index)
+            .or_insert_with(|| self.transform_node_bv(node_2, transform));
+        if !kdop_overlap(&node_1.bv, node_2_bv, self.start_axis, self.stop_axis) {
+            return;
+        }
+
+        if node_1.totnode == 0 {
+            if node_2.totnode == 0 {
+                self.count_primitive_test();
+                let elem_1 = node_1.elem_index.unwrap();
+                let elem_2 = node_2.elem_index.unwrap();
+                let is_overlap = match callback {
+                    Some(callback) => callback(elem_1, elem_2),
+                    None => true,
+                };
+                if is_overlap {
+                    r_overlap_pairs.push(BVHTreeOverlap::new(elem_1, elem_2));
+                }
+            } else {
+                for child_index in &node_2.children[..node_2.totnode as usize] {
+                    self.overlap_transformed_traverse(
+                        other,
+                        node_1_index,
+                        *child_index,
+                        transform,
+                        transformed_bvs,
+                        callback,
+                        r_overlap_pairs,
+                    );
+                }
+            }
         } else {
-            let mut overlap_pairs = Vec::new();
-            if let Some(callback) = callback {
-                self.overlap_traverse_callback(
+            for child_index in &node_1.children[..node_1.totnode as usize] {
+                self.overlap_transformed_traverse(
                     other,
-                    root_1_index,
-                    root_2_index,
-                    start_axis,
-                    stop_axis,
+                    *child_index,
+                    node_2_index,
+                    transform,
+                    transformed_bvs,
                     callback,
-                    &mut overlap_pairs,
+                    r_overlap_pairs,
                 );
-            } else {
-                self.overlap_traverse(
-                    other,
-                    root_1_index,
-                    root_2_index,
-                    start_axis,
-                    stop_axis,
-                    &mut overlap_pairs,
-                );
-            }
-            if overlap_pairs.is_empty() {
-                None
-            } else {
-                Some(overlap_pairs)
             }
         }
     }
 
-    fn ray_cast_traverse<F, ExtraData>(
+    /// Tests for overlap between the 2 BVH where `other` is placed in
+    /// the space of `self` by the rigid `transform`, without
+    /// rebuilding `other`. Useful for moving rigid objects, each
+    /// tree is built once in its local space.
+    ///
+    /// The BVs of `other` are transformed on the fly, conservatively
+    /// (see [`Self::overlap()`] for `callback`), so more pairs than
+    /// with a tree built in the space of `self` can be reported,
+    /// `callback` should do the exact test. `other` must contain the
+    /// x, y, z axes (every kdop type except 18).
+    pub fn overlap_transformed<F>(
         &self,
-        node_index: BVHNodeIndex,
-        data: &RayCastData<T>,
-        callback: Option<F>,
-        r_hit_data: &mut RayHitData<T, E, ExtraData>,
-    ) where
-        ExtraData: Copy,
-        F: FnMut(E) -> Option<RayHitData<T, E, ExtraData>> + std::marker::Copy,
+        other: &BVHTree<T, E>,
+        transform: &glm::TMat4<T>,
+        callback: Option<&F>,
+    ) -> Option<Vec<BVHTreeOverlap<E>>>
+    where
+        F: Fn(E, E) -> bool,
     {
-        let node = self.node_array.get(node_index.0).unwrap();
-        if let Some(dist) = node.ray_hit(data, r_hit_data.dist) {
+        if self.totleaf == 0 || other.totleaf == 0 {
+            // no elements so no overlap possible
+            return None;
+        }
+
+        assert_eq!(
+            other.start_axis, 0,
+            "trees not compatible for transformed overlap check"
+        );
+
+        let root_1_index = self.nodes[self.totleaf];
+        let root_2_index = other.nodes[other.totleaf];
+
+        let mut transformed_bvs = HashMap::new();
+        let mut overlap_pairs = Vec::new();
+        self.overlap_transformed_traverse(
+            other,
+            root_1_index,
+            root_2_index,
+            transform,
+            &mut transformed_bvs,
+            callback,
+            &mut overlap_pairs,
+        );
+
+        if overlap_pairs.is_empty() {
+            None
+        } else {
+            Some(overlap_pairs)
+        }
+    }
+
+    fn self_overlap_traverse<F>(
+        &self,
+        node_index: BVHNodeIndex,
+        start_axis: u8,
+        stop_axis: u8,
+        callback: Option<&F>,
+        r_overlap_pairs: &mut Vec<BVHTreeOverlap<E>>,
+    ) where
+        F: Fn(E, E) -> bool,
+    {
+        let node = self.node_array.get(node_index.0).unwrap();
+        // a leaf node only overlaps with itself, which is skipped
+        if node.totnode == 0 {
+            return;
+        }
+
+        let children = &node.children[..node.totnode as usize];
+        for (i, child_1_index) in children.iter().enumerate() {
+            // overlaps within the child
+            self.self_overlap_traverse(
+                *child_1_index,
+                start_axis,
+                stop_axis,
+                callback,
+                r_overlap_pairs,
+            );
+
+            // overlaps between the child and its later siblings, so
+            // every pair is visited only once
+            for child_2_index in &children[(i + 1)..] {
+                self.overlap_traverse_optional_callback(
+                    self,
+                    *child_1_index,
+                    *child_2_index,
+                    start_axis,
+                    stop_axis,
+                    callback,
+                    r_overlap_pairs,
+                );
+            }
+        }
+    }
+
+    /// Tests for overlap between the elements of the BVH with an
+    /// optional callback to decide if that overlap of the BVs should
+    /// be considered.
+    ///
+    /// Unlike `self.overlap(self, callback)`, every pair of
+    /// overlapping elements is reported only once and an element is
+    /// never paired with itself.
+    ///
+    /// `callback` is given the indices of the 2 elements of the
+    /// overlapping BVs, must return if the overlap should be
+    /// considered. Useful to skip adjacent elements (eg: triangles
+    /// sharing a node).
+    pub fn self_overlap<F>(&self, callback: Option<&F>) -> Option<Vec<BVHTreeOverlap<E>>>
+    where
+        F: Fn(E, E) -> bool,
+    {
+        if self.totleaf == 0 {
+            // no elements so no overlap possible
+            return None;
+        }
+
+        let root_index = self.nodes[self.totleaf];
+
+        let mut overlap_pairs = Vec::new();
+        self.self_overlap_traverse(
+            root_index,
+            self.start_axis,
+            self.stop_axis,
+            callback,
+            &mut overlap_pairs,
+        );
+
+        if overlap_pairs.is_empty() {
+            None
+        } else {
+            Some(overlap_pairs)
+        }
+    }
+
+    fn ray_cast_traverse<F, ExtraData>(
+        &self,
+        node_index: BVHNodeIndex,
+        data: &RayCastData<T>,
+        callback: Option<F>,
+        r_hit_data: &mut RayHitData<T, E, ExtraData>,
+    ) where
+        ExtraData: Copy,
+        F: FnMut(E) -> Option<RayHitData<T, E, ExtraData>> + std::marker::Copy,
+    {
+        let node = self.node_array.get(node_index.0).unwrap();
+        self.count_node_visit();
+        self.record_node_visit(node_index);
+        if let Some(dist) = node.ray_hit(data, r_hit_data.dist) {
             if dist >= r_hit_data.dist {
                 return;
             }
 
             if node.totnode == 0 {
+                self.count_primitive_test();
                 if let Some(mut callback) = callback {
                     if let Some(hit_data) = callback(node.elem_index.unwrap()) {
                         // update r_hit_data only if the current
@@ -1261,6 +2698,51 @@ where
         self.ray_cast_optional_callback::<fn(E) -> Option<RayHitData<T, E, _>>, _>(co, dir, None)
     }
 
+    /// Casts a ray starting at `co` in the direction `dir` against
+    /// the tree placed in the space of the ray by the rigid
+    /// `transform` (tree space to ray space), without rebuilding the
+    /// tree.
+    ///
+    /// `callback` is same as in [`Self::ray_cast()`] but works in
+    /// tree space, it is given the element index along with the ray
+    /// (`co` and `dir`) in tree space. The hit point and normal of
+    /// the result are transformed back to the space of the ray, the
+    /// distance is unchanged by a rigid transform.
+    pub fn ray_cast_transformed<F, ExtraData>(
+        &self,
+        co: glm::TVec3<T>,
+        dir: glm::TVec3<T>,
+        transform: &glm::TMat4<T>,
+        mut callback: F,
+    ) -> Option<RayHitData<T, E, ExtraData>>
+    where
+        ExtraData: Copy,
+        F: FnMut(E, &glm::TVec3<T>, &glm::TVec3<T>) -> Option<RayHitData<T, E, ExtraData>>
+            + std::marker::Copy,
+    {
+        let inverse = glm::inverse(transform);
+        let co = vec3_apply_model_matrix(&co, &inverse);
+        let dir = rigid_transform_dir(&dir, &inverse);
+        self.ray_cast(co, dir, move |elem_index| callback(elem_index, &co, &dir))
+            .map(|hit_data| ray_hit_data_transform(hit_data, transform))
+    }
+
+    /// Easy call when no callback needed for
+    /// `ray_cast_transformed()`.
+    pub fn ray_cast_transformed_no_callback(
+        &self,
+        co: glm::TVec3<T>,
+        dir: glm::TVec3<T>,
+        transform: &glm::TMat4<T>,
+    ) -> Option<RayHitData<T, E, ()>> {
+        let inverse = glm::inverse(transform);
+        self.ray_cast_no_callback(
+            vec3_apply_model_matrix(&co, &inverse),
+            rigid_transform_dir(&dir, &inverse),
+        )
+        .map(|hit_data| ray_hit_data_transform(hit_data, transform))
+    }
+
     /// Casts a ray starting at `co` in the direction `dir` with an
     /// optional callback for finer precision ray intersection
     /// testing.
@@ -1274,6 +2756,27 @@ where
         ExtraData: Copy,
         F: FnMut(E) -> Option<RayHitData<T, E, ExtraData>> + std::marker::Copy,
     {
+        self.ray_cast_data(
+            &RayCastData::new(co, dir),
+            <T as Bounded>::max_value(),
+            callback,
+        )
+    }
+
+    /// Casts the ray given by `data`, only hits within `max_dist` are
+    /// considered.
+    fn ray_cast_data<F, ExtraData>(
+        &self,
+        data: &RayCastData<T>,
+        max_dist: T,
+        callback: Option<F>,
+    ) -> Option<RayHitData<T, E, ExtraData>>
+    where
+        ExtraData: Copy,
+        F: FnMut(E) -> Option<RayHitData<T, E, ExtraData>> + std::marker::Copy,
+    {
+        self.begin_query_record();
+
         if self.totleaf == 0 {
             // no elements so no ray intersection possible
             return None;
@@ -1281,11 +2784,9 @@ where
 
         let root_index = self.nodes[self.totleaf];
 
-        let data = RayCastData::new(co, dir);
-
-        let mut hit_data = RayHitData::new(<T as Bounded>::max_value());
+        let mut hit_data = RayHitData::new(max_dist);
 
-        self.ray_cast_traverse(root_index, &data, callback, &mut hit_data);
+        self.ray_cast_traverse(root_index, data, callback, &mut hit_data);
 
         if hit_data.data.is_some() {
             Some(hit_data)
@@ -1294,6 +2795,194 @@ where
         }
     }
 
+    /// Same as [`Self::ray_cast()`] but only hits within `max_dist`
+    /// (in units of the length of `dir`) are considered.
+    pub fn ray_cast_max_dist<F, ExtraData>(
+        &self,
+        co: glm::TVec3<T>,
+        dir: glm::TVec3<T>,
+        max_dist: T,
+        callback: F,
+    ) -> Option<RayHitData<T, E, ExtraData>>
+    where
+        ExtraData: Copy,
+        F: FnMut(E) -> Option<RayHitData<T, E, ExtraData>> + std::marker::Copy,
+    {
+        self.ray_cast_data(&RayCastData::new(co, dir), max_dist, Some(callback))
+    }
+
+    /// Casts a sphere of `radius` starting at `co` in the direction
+    /// `dir`. Gives the nearest hit within `max_dist`.
+    ///
+    /// `callback` is called for every element whose (inflated) BV is
+    /// hit, it must do the fine grain sphere cast against the
+    /// element, see [`Self::ray_cast()`].
+    pub fn sphere_cast<F, ExtraData>(
+        &self,
+        co: glm::TVec3<T>,
+        dir: glm::TVec3<T>,
+        radius: T,
+        max_dist: T,
+        callback: F,
+    ) -> Option<RayHitData<T, E, ExtraData>>
+    where
+        ExtraData: Copy,
+        F: FnMut(E) -> Option<RayHitData<T, E, ExtraData>> + std::marker::Copy,
+    {
+        self.ray_cast_data(
+            &RayCastData::new_sphere(co, dir, radius),
+            max_dist,
+            Some(callback),
+        )
+    }
+
+    /// Casts a capsule, the segment from `co_1` to `co_2` with
+    /// `radius`, in the direction `dir`. Gives the nearest hit within
+    /// `max_dist`.
+    ///
+    /// `callback` is called for every element whose (inflated) BV is
+    /// hit, it must do the fine grain capsule cast against the
+    /// element, see [`Self::ray_cast()`].
+    pub fn capsule_cast<F, ExtraData>(
+        &self,
+        co_1: glm::TVec3<T>,
+        co_2: glm::TVec3<T>,
+        radius: T,
+        dir: glm::TVec3<T>,
+        max_dist: T,
+        callback: F,
+    ) -> Option<RayHitData<T, E, ExtraData>>
+    where
+        ExtraData: Copy,
+        F: FnMut(E) -> Option<RayHitData<T, E, ExtraData>> + std::marker::Copy,
+    {
+        self.ray_cast_data(
+            &RayCastData::new_capsule(co_1, co_2, dir, radius),
+            max_dist,
+            Some(callback),
+        )
+    }
+
+    fn ray_cast_all_traverse<F, ExtraData>(
+        &self,
+        node_index: BVHNodeIndex,
+        data: &RayCastData<T>,
+        max_dist: T,
+        callback: Option<F>,
+        r_hits: &mut Vec<RayHitData<T, E, ExtraData>>,
+    ) where
+        ExtraData: Copy,
+        F: FnMut(E) -> Option<RayHitData<T, E, ExtraData>> + std::marker::Copy,
+    {
+        let node = self.node_array.get(node_index.0).unwrap();
+        self.count_node_visit();
+        self.record_node_visit(node_index);
+        if let Some(dist) = node.ray_hit(data, max_dist) {
+            if dist > max_dist {
+                return;
+            }
+
+            if node.totnode == 0 {
+                self.count_primitive_test();
+                if let Some(mut callback) = callback {
+                    if let Some(hit_data) = callback(node.elem_index.unwrap()) {
+                        if hit_data.dist <= max_dist {
+                            r_hits.push(hit_data);
+                        }
+                    }
+                } else {
+                    let mut hit_data = RayHitData::new(dist);
+                    hit_data.set_data(RayHitOptionalData::new(
+                        node.elem_index.unwrap(),
+                        data.co + data.dir * dist,
+                    ));
+                    r_hits.push(hit_data);
+                }
+            } else {
+                for i in 0..node.totnode {
+                    self.ray_cast_all_traverse(
+                        node.children[i as usize],
+                        data,
+                        max_dist,
+                        callback,
+                        r_hits,
+                    );
+                }
+            }
+        }
+    }
+
+    fn ray_cast_all_optional_callback<F, ExtraData>(
+        &self,
+        co: glm::TVec3<T>,
+        dir: glm::TVec3<T>,
+        max_dist: T,
+        callback: Option<F>,
+    ) -> Vec<RayHitData<T, E, ExtraData>>
+    where
+        ExtraData: Copy,
+        F: FnMut(E) -> Option<RayHitData<T, E, ExtraData>> + std::marker::Copy,
+    {
+        self.begin_query_record();
+
+        if self.totleaf == 0 {
+            // no elements so no ray intersection possible
+            return Vec::new();
+        }
+
+        let root_index = self.nodes[self.totleaf];
+
+        let data = RayCastData::new(co, dir);
+
+        let mut hits = Vec::new();
+        self.ray_cast_all_traverse(root_index, &data, max_dist, callback, &mut hits);
+
+        hits.sort_by(|hit_1, hit_2| {
+            hit_1
+                .dist
+                .partial_cmp(&hit_2.dist)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        hits
+    }
+
+    /// Casts a ray starting at `co` in the direction `dir` and gives
+    /// all the hits within `max_dist` sorted by their distance along
+    /// the ray (nearest first).
+    ///
+    /// `callback` is same as in [`Self::ray_cast()`].
+    pub fn ray_cast_all<F, ExtraData>(
+        &self,
+        co: glm::TVec3<T>,
+        dir: glm::TVec3<T>,
+        max_dist: T,
+        callback: F,
+    ) -> Vec<RayHitData<T, E, ExtraData>>
+    where
+        ExtraData: Copy,
+        F: FnMut(E) -> Option<RayHitData<T, E, ExtraData>> + std::marker::Copy,
+    {
+        self.ray_cast_all_optional_callback(co, dir, max_dist, Some(callback))
+    }
+
+    /// Casts a ray starting at `co` in the direction `dir` and gives
+    /// all the BVs hit within `max_dist` sorted by their distance
+    /// along the ray (nearest first).
+    ///
+    /// It is recommeded to use [`Self::ray_cast_all()`] and provide a
+    /// callback to be more precise than just the BVH level
+    /// intersection test.
+    pub fn ray_cast_all_no_callback(
+        &self,
+        co: glm::TVec3<T>,
+        dir: glm::TVec3<T>,
+        max_dist: T,
+    ) -> Vec<RayHitData<T, E, ()>> {
+        self.ray_cast_all_optional_callback::<fn(E) -> Option<RayHitData<T, E, _>>, _>(
+            co, dir, max_dist, None,
+        )
+    }
+
     fn find_nearest_dfs<F>(
         &self,
         node_index: BVHNodeIndex,
@@ -1305,9 +2994,12 @@ where
         F: Fn(E, &glm::TVec3<T>, &mut NearestData<T, E>),
     {
         let node = self.node_array.get(node_index.0).unwrap();
+        self.count_node_visit();
+        self.record_node_visit(node_index);
         let proj_v3 = glm::vec3(proj[0], proj[1], proj[2]);
 
         if node.totnode == 0 {
+            self.count_primitive_test();
             match callback {
                 Some(callback) => {
                     callback(node.elem_index.unwrap(), co, r_nearest_data);
@@ -1408,6 +3100,8 @@ where
     where
         F: Fn(E, &glm::TVec3<T>, &mut NearestData<T, E>),
     {
+        self.begin_query_record();
+
         let bvhtree_kdop_axes = bvhtree_kdop_axes();
 
         let root_index = self.nodes[self.totleaf];
@@ -1421,67 +3115,1112 @@ where
         self.find_nearest_dfs_begin(root_index, dist_sq, &co, &proj, callback)
     }
 
-    /// Easy call when no callback needed for `find_nearest()`.
-    pub fn find_nearest_no_callback(
+    /// Largest squared distance an element can have to be part of
+    /// the k nearest elements found so far.
+    fn k_nearest_bound(heap: &BinaryHeap<KNearestEntry<T, E>>, k: usize, dist_sq: T) -> T {
+        if heap.len() < k {
+            dist_sq
+        } else {
+            heap.peek().unwrap().0.get_dist_sq()
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn find_k_nearest_dfs<F>(
         &self,
-        co: glm::TVec3<T>,
+        node_index: BVHNodeIndex,
+        co: &glm::TVec3<T>,
+        proj_v3: &glm::TVec3<T>,
+        k: usize,
         dist_sq: T,
-    ) -> Option<NearestData<T, E>> {
-        self.find_nearest::<fn(E, &glm::TVec3<T>, &mut NearestData<T, E>)>(co, dist_sq, &None)
+        callback: &Option<F>,
+        r_heap: &mut BinaryHeap<KNearestEntry<T, E>>,
+    ) where
+        F: Fn(E, &glm::TVec3<T>, &mut NearestData<T, E>),
+    {
+        let node = self.node_array.get(node_index.0).unwrap();
+        self.count_node_visit();
+        self.record_node_visit(node_index);
+
+        if node.totnode == 0 {
+            self.count_primitive_test();
+            let bound = Self::k_nearest_bound(r_heap, k, dist_sq);
+            let mut nearest_data = NearestData::new(None, None, None, bound);
+            match callback {
+                Some(callback) => {
+                    callback(node.elem_index.unwrap(), co, &mut nearest_data);
+                }
+                None => {
+                    let nearest = node.cal_nearest_point_squared(proj_v3);
+                    let dist_sq = glm::distance2(proj_v3, &nearest);
+                    if dist_sq < bound {
+                        nearest_data.set_info(node.elem_index, Some(nearest), None, dist_sq);
+                    }
+                }
+            }
+
+            if nearest_data.get_elem_index().is_some() {
+                r_heap.push(KNearestEntry(nearest_data));
+                if r_heap.len() > k {
+                    r_heap.pop();
+                }
+            }
+        } else {
+            // dive into the closest child first so that the bound
+            // shrinks as early as possible
+            let mut children: Vec<_> = node.children[..node.totnode as usize]
+                .iter()
+                .map(|child_index| {
+                    let child = self.node_array.get(child_index.0).unwrap();
+                    let nearest = child.cal_nearest_point_squared(proj_v3);
+                    (glm::distance2(proj_v3, &nearest), *child_index)
+                })
+                .collect();
+            children.sort_by(|(dist_sq_1, _), (dist_sq_2, _)| {
+                dist_sq_1
+                    .partial_cmp(dist_sq_2)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+
+            for (child_dist_sq, child_index) in children {
+                if child_dist_sq >= Self::k_nearest_bound(r_heap, k, dist_sq) {
+                    break;
+                }
+
+                self.find_k_nearest_dfs(child_index, co, proj_v3, k, dist_sq, callback, r_heap);
+            }
+        }
     }
 
-    pub fn get_min_max_bounds(&self) -> (glm::TVec3<T>, glm::TVec3<T>) {
-        let root_index = self.nodes[self.totleaf];
-        let root = &self.node_array.get(root_index.0).unwrap();
+    /// Finds the `k` nearest elements to the given point `co` that
+    /// are within the squared distance `dist_sq` with an optional
+    /// callback. The results are sorted by distance (nearest first),
+    /// there can be fewer than `k` results.
+    ///
+    /// `callback` is same as in [`Self::find_nearest()`], the nearest
+    /// data given to it starts with the squared distance of the
+    /// current `k`th nearest element (or `dist_sq`), it must update
+    /// the nearest data only if the element is nearer than that, eg:
+    /// by using [`nearest_point_to_tri()`].
+    pub fn find_k_nearest<F>(
+        &self,
+        co: glm::TVec3<T>,
+        k: usize,
+        dist_sq: T,
+        callback: &Option<F>,
+    ) -> Vec<NearestData<T, E>>
+    where
+        F: Fn(E, &glm::TVec3<T>, &mut NearestData<T, E>),
+    {
+        self.begin_query_record();
+
+        if self.totleaf == 0 || k == 0 {
+            return Vec::new();
+        }
+
+        let bvhtree_kdop_axes = bvhtree_kdop_axes();
+
+        let root_index = self.nodes[self.totleaf];
+
+        let mut proj: [T; 13] = [T::zero(); 13];
+        (self.start_axis..self.stop_axis).for_each(|axis_iter| {
+            proj[axis_iter as usize] = glm::dot(&co, &bvhtree_kdop_axes[axis_iter as usize]);
+        });
+        let proj_v3 = glm::vec3(proj[0], proj[1], proj[2]);
+
+        let root = self.node_array.get(root_index.0).unwrap();
+        let nearest = root.cal_nearest_point_squared(&proj_v3);
+        if glm::distance2(&proj_v3, &nearest) >= dist_sq {
+            return Vec::new();
+        }
+
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        self.find_k_nearest_dfs(root_index, &co, &proj_v3, k, dist_sq, callback, &mut heap);
+
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|entry| entry.0)
+            .collect()
+    }
+
+    /// Easy call when no callback needed for `find_k_nearest()`.
+    pub fn find_k_nearest_no_callback(
+        &self,
+        co: glm::TVec3<T>,
+        k: usize,
+        dist_sq: T,
+    ) -> Vec<NearestData<T, E>> {
+        self.find_k_nearest::<fn(E, &glm::TVec3<T>, &mut NearestData<T, E>)>(co, k, dist_sq, &None)
+    }
+
+    /// Easy call when no callback needed for `find_nearest()`.
+    pub fn find_nearest_no_callback(
+        &self,
+        co: glm::TVec3<T>,
+        dist_sq: T,
+    ) -> Option<NearestData<T, E>> {
+        self.find_nearest::<fn(E, &glm::TVec3<T>, &mut NearestData<T, E>)>(co, dist_sq, &None)
+    }
+
+    /// Finds the nearest point to the given point `co` like
+    /// [`Self::find_nearest()`] on the tree placed in the space of
+    /// `co` by the rigid `transform` (tree space to the space of
+    /// `co`), without rebuilding the tree.
+    ///
+    /// `callback` works in tree space, the point it is given is `co`
+    /// transformed into tree space. The nearest point and normal of
+    /// the result are transformed back to the space of `co`.
+    pub fn find_nearest_transformed<F>(
+        &self,
+        co: glm::TVec3<T>,
+        dist_sq: T,
+        transform: &glm::TMat4<T>,
+        callback: &Option<F>,
+    ) -> Option<NearestData<T, E>>
+    where
+        F: Fn(E, &glm::TVec3<T>, &mut NearestData<T, E>),
+    {
+        let co = vec3_apply_model_matrix(&co, &glm::inverse(transform));
+        let mut nearest = self.find_nearest(co, dist_sq, callback)?;
+        nearest.co = nearest.co.map(|co| vec3_apply_model_matrix(&co, transform));
+        nearest.normal = nearest
+            .normal
+            .map(|normal| rigid_transform_dir(&normal, transform));
+        Some(nearest)
+    }
+
+    fn find_within_radius_traverse<F>(
+        &self,
+        node_index: BVHNodeIndex,
+        co: &glm::TVec3<T>,
+        radius_sq: T,
+        callback: &mut F,
+    ) where
+        F: FnMut(E, T),
+    {
+        let node = self.node_array.get(node_index.0).unwrap();
+        self.count_node_visit();
+        let nearest = node.cal_nearest_point_squared(co);
+        let dist_sq = glm::distance2(&nearest, co);
+        if dist_sq > radius_sq {
+            return;
+        }
+
+        if node.totnode == 0 {
+            self.count_primitive_test();
+            callback(node.elem_index.unwrap(), dist_sq);
+        } else {
+            for i in 0..node.totnode {
+                self.find_within_radius_traverse(
+                    node.children[i as usize],
+                    co,
+                    radius_sq,
+                    callback,
+                );
+            }
+        }
+    }
+
+    /// Find the elements whose BV is within `radius` of `co`.
+    ///
+    /// `callback` is given the index of the element and the squared
+    /// distance of `co` to the BV of the element. The BV test is
+    /// conservative, `callback` must do the fine grain test if
+    /// needed.
+    pub fn find_within_radius<F>(&self, co: &glm::TVec3<T>, radius: T, mut callback: F)
+    where
+        F: FnMut(E, T),
+    {
+        if self.totleaf == 0 {
+            return;
+        }
+
+        let root_index = self.nodes[self.totleaf];
+        self.find_within_radius_traverse(root_index, co, radius * radius, &mut callback);
+    }
+
+    fn find_in_volume_traverse<V, F>(
+        &self,
+        node_index: BVHNodeIndex,
+        classify: &V,
+        fully_inside: bool,
+        callback: &mut F,
+    ) where
+        V: Fn(&[T]) -> BVContainment,
+        F: FnMut(E),
+    {
+        let node = self.node_array.get(node_index.0).unwrap();
+        self.count_node_visit();
+        // once a BV is fully inside the volume, all of its children
+        // are as well, no need to test them
+        let fully_inside = fully_inside
+            || match classify(&node.bv) {
+                BVContainment::Outside => return,
+                BVContainment::Intersect => false,
+                BVContainment::Inside => true,
+            };
+
+        if node.totnode == 0 {
+            self.count_primitive_test();
+            callback(node.elem_index.unwrap());
+        } else {
+            for i in 0..node.totnode {
+                self.find_in_volume_traverse(
+                    node.children[i as usize],
+                    classify,
+                    fully_inside,
+                    callback,
+                );
+            }
+        }
+    }
+
+    /// Find the elements whose BV overlaps the axis aligned box given
+    /// by `min` and `max`.
+    ///
+    /// Every axis of the k-DOP is tested against the box. `callback`
+    /// is given the index of the element. The BV test is
+    /// conservative, `callback` must do the fine grain test if
+    /// needed.
+    pub fn find_in_aabb<F>(&self, min: &glm::TVec3<T>, max: &glm::TVec3<T>, mut callback: F)
+    where
+        F: FnMut(E),
+    {
+        if self.totleaf == 0 {
+            return;
+        }
+
+        let bvhtree_kdop_axes = bvhtree_kdop_axes::<T>();
+        let classify = |bv: &[T]| {
+            let mut containment = BVContainment::Inside;
+            for axis in 0..3 {
+                let (bv_min, bv_max) = (bv[2 * axis], bv[2 * axis + 1]);
+                if bv_max < min[axis] || bv_min > max[axis] {
+                    return BVContainment::Outside;
+                }
+                if bv_min < min[axis] || bv_max > max[axis] {
+                    containment = BVContainment::Intersect;
+                }
+            }
+
+            // the other axes can only separate the BV from the box,
+            // the BV is within its x, y, z extent
+            for axis_iter in self.start_axis.max(3)..self.stop_axis {
+                let axis_iter = axis_iter as usize;
+                let kdop_axis = &bvhtree_kdop_axes[axis_iter];
+                // extent of the box along the axis
+                let (mut box_min, mut box_max) = (T::zero(), T::zero());
+                for axis in 0..3 {
+                    if kdop_axis[axis] >= T::zero() {
+                        box_min += kdop_axis[axis] * min[axis];
+                        box_max += kdop_axis[axis] * max[axis];
+                    } else {
+                        box_min += kdop_axis[axis] * max[axis];
+                        box_max += kdop_axis[axis] * min[axis];
+                    }
+                }
+                if bv[2 * axis_iter + 1] < box_min || bv[2 * axis_iter] > box_max {
+                    return BVContainment::Outside;
+                }
+            }
+
+            containment
+        };
+
+        let root_index = self.nodes[self.totleaf];
+        self.find_in_volume_traverse(root_index, &classify, false, &mut callback);
+    }
+
+    /// Find the elements whose BV is (at least partially) inside the
+    /// frustum given by `planes`.
+    ///
+    /// Each plane is `(normal, d)` such that a point `p` is inside
+    /// the plane if `dot(normal, p) + d >= 0`. Any number of planes
+    /// can be given, eg: the 6 planes of the camera frustum.
+    ///
+    /// Only the axis aligned bounding box of the k-DOP (its x, y, z
+    /// axes) is tested against the planes, the other axes are
+    /// ignored. `callback` is given the index of the element. The BV
+    /// test is conservative, `callback` must do the fine grain test
+    /// if needed.
+    pub fn find_in_frustum<F>(&self, planes: &[glm::TVec4<T>], mut callback: F)
+    where
+        F: FnMut(E),
+    {
+        if self.totleaf == 0 {
+            return;
+        }
+
+        let classify = |bv: &[T]| {
+            let mut containment = BVContainment::Inside;
+            for plane in planes {
+                // corners of the BV furthest along and against the
+                // normal of the plane
+                let mut furthest_inside = glm::TVec3::zeros();
+                let mut furthest_outside = glm::TVec3::zeros();
+                for axis in 0..3 {
+                    if plane[axis] >= T::zero() {
+                        furthest_inside[axis] = bv[2 * axis + 1];
+                        furthest_outside[axis] = bv[2 * axis];
+                    } else {
+                        furthest_inside[axis] = bv[2 * axis];
+                        furthest_outside[axis] = bv[2 * axis + 1];
+                    }
+                }
+
+                let normal = plane.xyz();
+                if glm::dot(&normal, &furthest_inside) + plane[3] < T::zero() {
+                    return BVContainment::Outside;
+                }
+                if glm::dot(&normal, &furthest_outside) + plane[3] < T::zero() {
+                    containment = BVContainment::Intersect;
+                }
+            }
+            containment
+        };
+
+        let root_index = self.nodes[self.totleaf];
+        self.find_in_volume_traverse(root_index, &classify, false, &mut callback);
+    }
+
+    pub fn get_min_max_bounds(&self) -> (glm::TVec3<T>, glm::TVec3<T>) {
+        let root_index = self.nodes[self.totleaf];
+        let root = &self.node_array.get(root_index.0).unwrap();
+
+        (
+            glm::vec3(root.bv[0], root.bv[2], root.bv[4]),
+            glm::vec3(root.bv[1], root.bv[3], root.bv[5]),
+        )
+    }
+}
+
+impl<T: glm::RealNumber, E> BVHTree<T, E>
+where
+    T: Send + Sync,
+    E: Copy + Send + Sync,
+{
+    /// Multithreaded version of [`Self::balance()`].
+    ///
+    /// The branches of each level of the tree are divided in
+    /// parallel, only if the tree has at least
+    /// [`BVH_MULTITHREADED_BALANCE_MIN_LEAFS`] elements. The
+    /// resulting tree is identical to the one built by
+    /// [`Self::balance()`].
+    ///
+    /// [`BVHBuildMode::SAH`] is always built serially.
+    ///
+    /// # panics
+    /// * When function called more than once
+    pub fn balance_multithreaded(&mut self) {
+        if self.totleaf < BVH_MULTITHREADED_BALANCE_MIN_LEAFS
+            || self.build_mode == BVHBuildMode::SAH
+        {
+            self.balance();
+        } else {
+            self.balance_with(Self::non_recursive_bvh_div_nodes_level_multithreaded);
+        }
+    }
+
+    /// Multithreaded version of
+    /// [`Self::non_recursive_bvh_div_nodes_level()`]. The branches are
+    /// split into contiguous chunks, one per thread.
+    #[allow(clippy::too_many_arguments)]
+    fn non_recursive_bvh_div_nodes_level_multithreaded(
+        node_array: &Arena<BVHNode<T, E>>,
+        leafs: &mut [BVHNodeIndex],
+        data: &BVHDivNodesData,
+        i_start: usize,
+        i_stop: usize,
+        tree_type: u8,
+        start_axis: u8,
+        stop_axis: u8,
+    ) -> Vec<BVHDivNodesResult<T>> {
+        let num_tasks = i_stop - i_start;
+        let thread_num = std::thread::available_parallelism()
+            .map(|num| num.get())
+            .unwrap_or(1)
+            .min(num_tasks);
+        if thread_num <= 1 {
+            return Self::non_recursive_bvh_div_nodes_level(
+                node_array, leafs, data, i_start, i_stop, tree_type, start_axis, stop_axis,
+            );
+        }
+
+        let parent_leafs_index = |j: usize| data.data.implicit_leafs_index(data.depth, j - data.i);
+
+        // the leafs of consecutive branches are contiguous, so each
+        // chunk of branches gets its own disjoint slice of the leafs
+        let tasks_per_thread = num_tasks.div_ceil(thread_num);
+        let mut remaining_leafs = &mut leafs[parent_leafs_index(i_start)..];
+        let mut remaining_leafs_begin = parent_leafs_index(i_start);
+        let mut chunks = Vec::with_capacity(thread_num);
+        let mut chunk_start = i_start;
+        while chunk_start < i_stop {
+            let chunk_stop = (chunk_start + tasks_per_thread).min(i_stop);
+            let chunk_leafs_end = parent_leafs_index(chunk_stop);
+            let (chunk_leafs, rest) = std::mem::take(&mut remaining_leafs)
+                .split_at_mut(chunk_leafs_end - remaining_leafs_begin);
+            chunks.push((chunk_start, chunk_stop, remaining_leafs_begin, chunk_leafs));
+            remaining_leafs = rest;
+            remaining_leafs_begin = chunk_leafs_end;
+            chunk_start = chunk_stop;
+        }
+
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = chunks
+                .into_iter()
+                .map(
+                    |(chunk_start, chunk_stop, chunk_leafs_begin, chunk_leafs)| {
+                        scope.spawn(move || {
+                            (chunk_start..chunk_stop)
+                                .map(|j| {
+                                    let parent_leafs_begin =
+                                        parent_leafs_index(j) - chunk_leafs_begin;
+                                    let parent_leafs_end =
+                                        parent_leafs_index(j + 1) - chunk_leafs_begin;
+                                    Self::non_recursive_bvh_div_nodes_task_cb(
+                                        node_array,
+                                        &mut chunk_leafs[parent_leafs_begin..parent_leafs_end],
+                                        data,
+                                        j,
+                                        tree_type,
+                                        start_axis,
+                                        stop_axis,
+                                    )
+                                })
+                                .collect::<Vec<_>>()
+                        })
+                    },
+                )
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        })
+    }
+
+    /// Multithreaded version of [`Self::overlap()`].
+    ///
+    /// The traversal is split at the children of the root of `self`,
+    /// each child is traversed against `other` on its own thread
+    /// (like Blender does). Finds the same overlap pairs as
+    /// [`Self::overlap()`] in the same order.
+    pub fn overlap_multithreaded<F>(
+        &self,
+        other: &BVHTree<T, E>,
+        callback: Option<&F>,
+    ) -> Option<Vec<BVHTreeOverlap<E>>>
+    where
+        F: Fn(E, E) -> bool + Sync,
+    {
+        let (root_1_index, root_2_index, start_axis, stop_axis) = self.overlap_begin(other)?;
+
+        let root_1 = self.node_array.get(root_1_index.0).unwrap();
+        let thread_num = self.overlap_thread_num();
+        if thread_num <= 1 {
+            return self.overlap(other, callback);
+        }
+
+        let traverse_child = |child_index: BVHNodeIndex| {
+            let mut overlap_pairs = Vec::new();
+            self.overlap_traverse_optional_callback(
+                other,
+                child_index,
+                root_2_index,
+                start_axis,
+                stop_axis,
+                callback,
+                &mut overlap_pairs,
+            );
+            overlap_pairs
+        };
+
+        let overlap_pairs = std::thread::scope(|scope| {
+            let children = root_1.children[..thread_num]
+                .iter()
+                .copied()
+                .filter(|child_index| self.node_array.get(child_index.0).is_some());
+
+            // joined in the order of the children so the pairs come out
+            // in the same order as the serial traversal
+            let handles: Vec<_> = children
+                .map(|child_index| scope.spawn(move || traverse_child(child_index)))
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        if overlap_pairs.is_empty() {
+            None
+        } else {
+            Some(overlap_pairs)
+        }
+    }
+}
+
+/// Primitive that the BVH can perform the exact tests on, so that
+/// no callbacks are needed for the queries, see
+/// [`BVHTree::from_primitives()`].
+///
+/// [`BVHTriangle`], [`BVHSegment`] and [`BVHSphere`] are provided.
+pub trait BVHPrimitive<T: glm::RealNumber> {
+    /// Points whose k-DOP hull encloses the primitive, forms the BV
+    /// of the primitive.
+    fn bounding_points(&self) -> Vec<glm::TVec3<T>>;
+
+    /// Intersect the ray starting at `co` in the direction `dir`.
+    ///
+    /// Returns the distance along the ray (in units of `dir`, like
+    /// [`BVHTree::ray_cast()`]) to the nearest intersection and the
+    /// normal at the intersection if the primitive has one, [`None`]
+    /// if there is no intersection.
+    fn ray_intersect(
+        &self,
+        co: &glm::TVec3<T>,
+        dir: &glm::TVec3<T>,
+    ) -> Option<(T, Option<glm::TVec3<T>>)>;
+
+    /// Point on the primitive closest to `co`.
+    fn closest_point(&self, co: &glm::TVec3<T>) -> glm::TVec3<T>;
+}
+
+/// Triangle primitive, see [`BVHPrimitive`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BVHTriangle<T: glm::RealNumber> {
+    pub points: [glm::TVec3<T>; 3],
+}
+
+impl<T: glm::RealNumber> BVHTriangle<T> {
+    pub fn new(p1: glm::TVec3<T>, p2: glm::TVec3<T>, p3: glm::TVec3<T>) -> Self {
+        Self {
+            points: [p1, p2, p3],
+        }
+    }
+}
+
+impl<T: glm::RealNumber> BVHPrimitive<T> for BVHTriangle<T> {
+    fn bounding_points(&self) -> Vec<glm::TVec3<T>> {
+        self.points.to_vec()
+    }
+
+    /// Möller–Trumbore intersection, the normal follows the winding
+    /// of the points (it is not flipped to face the ray).
+    fn ray_intersect(
+        &self,
+        co: &glm::TVec3<T>,
+        dir: &glm::TVec3<T>,
+    ) -> Option<(T, Option<glm::TVec3<T>>)> {
+        let edge_1 = self.points[1] - self.points[0];
+        let edge_2 = self.points[2] - self.points[0];
+        let p = glm::cross(dir, &edge_2);
+        let det = glm::dot(&edge_1, &p);
+        if det.abs() <= T::default_epsilon() {
+            // ray is parallel to the triangle (or it is degenerate)
+            return None;
+        }
+        let inv_det = T::one() / det;
+
+        let t_vec = co - self.points[0];
+        let u = glm::dot(&t_vec, &p) * inv_det;
+        if u < T::zero() || u > T::one() {
+            return None;
+        }
+
+        let q = glm::cross(&t_vec, &edge_1);
+        let v = glm::dot(dir, &q) * inv_det;
+        if v < T::zero() || u + v > T::one() {
+            return None;
+        }
+
+        let dist = glm::dot(&edge_2, &q) * inv_det;
+        if dist < T::zero() {
+            return None;
+        }
+
+        Some((dist, Some(glm::normalize(&glm::cross(&edge_1, &edge_2)))))
+    }
+
+    fn closest_point(&self, co: &glm::TVec3<T>) -> glm::TVec3<T> {
+        nearest_point_to_tri(co, [&self.points[0], &self.points[1], &self.points[2]])
+    }
+}
+
+/// Segment primitive, see [`BVHPrimitive`].
+///
+/// A segment has no area, so a ray hits it if the ray passes within
+/// `radius` of it, the distance of the hit is that of the point of
+/// closest approach along the ray and there is no normal. The
+/// nearest queries treat it as a capsule of `radius`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BVHSegment<T: glm::RealNumber> {
+    pub points: [glm::TVec3<T>; 2],
+    pub radius: T,
+}
+
+impl<T: glm::RealNumber> BVHSegment<T> {
+    pub fn new(p1: glm::TVec3<T>, p2: glm::TVec3<T>, radius: T) -> Self {
+        Self {
+            points: [p1, p2],
+            radius,
+        }
+    }
+
+    /// Parameter of the point on the segment closest to `co`.
+    fn closest_param(&self, co: &glm::TVec3<T>) -> T {
+        let d = self.points[1] - self.points[0];
+        let len_sq = glm::dot(&d, &d);
+        if len_sq <= T::default_epsilon() {
+            return T::zero();
+        }
+        glm::clamp_scalar(
+            glm::dot(&(co - self.points[0]), &d) / len_sq,
+            T::zero(),
+            T::one(),
+        )
+    }
+}
+
+impl<T: glm::RealNumber> BVHPrimitive<T> for BVHSegment<T> {
+    fn bounding_points(&self) -> Vec<glm::TVec3<T>> {
+        let r = glm::TVec3::repeat(self.radius);
+        vec![
+            self.points[0] - r,
+            self.points[0] + r,
+            self.points[1] - r,
+            self.points[1] + r,
+        ]
+    }
+
+    fn ray_intersect(
+        &self,
+        co: &glm::TVec3<T>,
+        dir: &glm::TVec3<T>,
+    ) -> Option<(T, Option<glm::TVec3<T>>)> {
+        // closest points between the ray `co + s * dir` (s >= 0) and
+        // the segment `p + t * d` (t in [0, 1]), from Real-Time
+        // Collision Detection (Christer Ericson)
+        let d = self.points[1] - self.points[0];
+        let r = co - self.points[0];
+        let a = glm::dot(dir, dir);
+        let b = glm::dot(dir, &d);
+        let c = glm::dot(dir, &r);
+        let e = glm::dot(&d, &d);
+        let f = glm::dot(&d, &r);
+        if a <= T::default_epsilon() {
+            return None;
+        }
+
+        let ray_param = |t: T| glm::max2_scalar((b * t - c) / a, T::zero());
+        let denom = a * e - b * b;
+        let (s, t) = if e <= T::default_epsilon() {
+            (ray_param(T::zero()), T::zero())
+        } else {
+            let s = if denom > T::default_epsilon() {
+                glm::max2_scalar((b * f - c * e) / denom, T::zero())
+            } else {
+                ray_param(T::zero())
+            };
+            let t = (b * s + f) / e;
+            if t < T::zero() {
+                (ray_param(T::zero()), T::zero())
+            } else if t > T::one() {
+                (ray_param(T::one()), T::one())
+            } else {
+                (s, t)
+            }
+        };
+
+        let dist_sq = glm::distance2(&(co + dir * s), &(self.points[0] + d * t));
+        if dist_sq <= self.radius * self.radius {
+            Some((s, None))
+        } else {
+            None
+        }
+    }
+
+    /// Closest point on the surface of the capsule of `radius`
+    /// around the segment, like [`BVHSphere`].
+    fn closest_point(&self, co: &glm::TVec3<T>) -> glm::TVec3<T> {
+        let axis_point =
+            self.points[0] + (self.points[1] - self.points[0]) * self.closest_param(co);
+        let dir = co - axis_point;
+        let len = glm::length(&dir);
+        if len <= T::default_epsilon() {
+            // `co` is on the segment, closest surface point is not
+            // unique
+            let d = self.points[1] - self.points[0];
+            let perpendicular = glm::cross(&d, &glm::vec3(T::one(), T::zero(), T::zero()));
+            let perpendicular = if glm::length2(&perpendicular) <= T::default_epsilon() {
+                glm::cross(&d, &glm::vec3(T::zero(), T::one(), T::zero()))
+            } else {
+                perpendicular
+            };
+            return if glm::length2(&perpendicular) <= T::default_epsilon() {
+                // zero length segment
+                axis_point + glm::vec3(self.radius, T::zero(), T::zero())
+            } else {
+                axis_point + glm::normalize(&perpendicular) * self.radius
+            };
+        }
+        axis_point + dir * (self.radius / len)
+    }
+}
+
+/// Sphere primitive, see [`BVHPrimitive`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BVHSphere<T: glm::RealNumber> {
+    pub center: glm::TVec3<T>,
+    pub radius: T,
+}
+
+impl<T: glm::RealNumber> BVHSphere<T> {
+    pub fn new(center: glm::TVec3<T>, radius: T) -> Self {
+        Self { center, radius }
+    }
+}
+
+impl<T: glm::RealNumber> BVHPrimitive<T> for BVHSphere<T> {
+    fn bounding_points(&self) -> Vec<glm::TVec3<T>> {
+        let r = glm::TVec3::repeat(self.radius);
+        vec![self.center - r, self.center + r]
+    }
+
+    /// If `co` is inside the sphere, the ray hits the sphere from
+    /// the inside.
+    fn ray_intersect(
+        &self,
+        co: &glm::TVec3<T>,
+        dir: &glm::TVec3<T>,
+    ) -> Option<(T, Option<glm::TVec3<T>>)> {
+        let m = co - self.center;
+        let a = glm::dot(dir, dir);
+        let b = glm::dot(&m, dir);
+        let c = glm::dot(&m, &m) - self.radius * self.radius;
+        if a <= T::default_epsilon() {
+            return None;
+        }
+
+        let discriminant = b * b - a * c;
+        if discriminant < T::zero() {
+            return None;
+        }
+        let sqrt_discriminant = discriminant.sqrt();
+
+        let dist = if -b - sqrt_discriminant >= T::zero() {
+            (-b - sqrt_discriminant) / a
+        } else if -b + sqrt_discriminant >= T::zero() {
+            (-b + sqrt_discriminant) / a
+        } else {
+            return None;
+        };
+
+        let normal = (co + dir * dist - self.center) / self.radius;
+        Some((dist, Some(normal)))
+    }
+
+    /// Closest point on the surface of the sphere.
+    fn closest_point(&self, co: &glm::TVec3<T>) -> glm::TVec3<T> {
+        let dir = co - self.center;
+        let len = glm::length(&dir);
+        if len <= T::default_epsilon() {
+            // any point on the surface is the closest
+            return self.center + glm::vec3(self.radius, T::zero(), T::zero());
+        }
+        self.center + dir * (self.radius / len)
+    }
+}
+
+/// Queries over [`BVHPrimitive`]s where the elements of the tree are
+/// the indices of the primitives, the primitives must be the same
+/// (and in the same order) as the ones the tree was built with.
+impl<T: glm::RealNumber> BVHTree<T, usize> {
+    /// Build the BVH over the given primitives, the element index of
+    /// a primitive is its index in `primitives`. See
+    /// [`Self::new_with_build_mode()`] for the other parameters.
+    pub fn from_primitives<P: BVHPrimitive<T>>(
+        primitives: &[P],
+        epsilon: T,
+        tree_type: u8,
+        axis: u8,
+        build_mode: BVHBuildMode,
+    ) -> Self {
+        let mut bvh =
+            Self::new_with_build_mode(primitives.len(), epsilon, tree_type, axis, build_mode);
+        primitives.iter().enumerate().for_each(|(i, primitive)| {
+            bvh.insert(i, &primitive.bounding_points());
+        });
+        bvh.balance();
+        bvh
+    }
+
+    /// Update the BVs of all the primitives, use when the primitives
+    /// move. The number of primitives must not change.
+    pub fn refit_primitives<P: BVHPrimitive<T>>(
+        &mut self,
+        primitives: &[P],
+    ) -> Result<(), BVHError> {
+        self.refit_with(|i| Some((primitives.get(i)?.bounding_points(), Vec::new())))
+    }
+
+    /// Cast a ray against the primitives, gives the nearest hit
+    /// within `max_dist`. See [`Self::ray_cast()`].
+    pub fn ray_cast_primitives<P: BVHPrimitive<T>>(
+        &self,
+        primitives: &[P],
+        co: glm::TVec3<T>,
+        dir: glm::TVec3<T>,
+        max_dist: T,
+    ) -> Option<RayHitData<T, usize, ()>> {
+        self.ray_cast_max_dist(co, dir, max_dist, |i| {
+            primitive_ray_hit(&primitives[i], i, &co, &dir)
+        })
+    }
+
+    /// Cast a ray against the primitives, gives all the hits within
+    /// `max_dist` sorted by distance. See [`Self::ray_cast_all()`].
+    pub fn ray_cast_all_primitives<P: BVHPrimitive<T>>(
+        &self,
+        primitives: &[P],
+        co: glm::TVec3<T>,
+        dir: glm::TVec3<T>,
+        max_dist: T,
+    ) -> Vec<RayHitData<T, usize, ()>> {
+        self.ray_cast_all(co, dir, max_dist, |i| {
+            primitive_ray_hit(&primitives[i], i, &co, &dir)
+        })
+    }
+
+    /// Find the primitive nearest to `co` within `sqrt(dist_sq)`,
+    /// the nearest data has the closest point on the primitive. See
+    /// [`Self::find_nearest()`].
+    pub fn find_nearest_primitive<P: BVHPrimitive<T>>(
+        &self,
+        primitives: &[P],
+        co: glm::TVec3<T>,
+        dist_sq: T,
+    ) -> Option<NearestData<T, usize>> {
+        self.find_nearest(co, dist_sq, &Some(primitive_nearest_callback(primitives)))
+    }
+
+    /// Find the `k` primitives nearest to `co` within
+    /// `sqrt(dist_sq)`, sorted by distance. See
+    /// [`Self::find_k_nearest()`].
+    pub fn find_k_nearest_primitives<P: BVHPrimitive<T>>(
+        &self,
+        primitives: &[P],
+        co: glm::TVec3<T>,
+        k: usize,
+        dist_sq: T,
+    ) -> Vec<NearestData<T, usize>> {
+        self.find_k_nearest(
+            co,
+            k,
+            dist_sq,
+            &Some(primitive_nearest_callback(primitives)),
+        )
+    }
+
+    /// Find the primitives within `radius` of `co`. `callback` is
+    /// given the index of the primitive and the squared distance of
+    /// `co` to the closest point on it. See
+    /// [`Self::find_within_radius()`].
+    pub fn find_primitives_within_radius<P, F>(
+        &self,
+        primitives: &[P],
+        co: &glm::TVec3<T>,
+        radius: T,
+        mut callback: F,
+    ) where
+        P: BVHPrimitive<T>,
+        F: FnMut(usize, T),
+    {
+        self.find_within_radius(co, radius, |i, _| {
+            let dist_sq = glm::distance2(co, &primitives[i].closest_point(co));
+            if dist_sq <= radius * radius {
+                callback(i, dist_sq);
+            }
+        });
+    }
+}
+
+/// Apply the rotation of the rigid `transform` to the direction.
+fn rigid_transform_dir<T: glm::RealNumber>(
+    dir: &glm::TVec3<T>,
+    transform: &glm::TMat4<T>,
+) -> glm::TVec3<T> {
+    glm::mat4_to_mat3(transform) * dir
+}
+
+/// Transform the hit point and normal of the hit data by the rigid
+/// `transform`.
+fn ray_hit_data_transform<T: glm::RealNumber, E: Copy, ExtraData: Copy>(
+    mut hit_data: RayHitData<T, E, ExtraData>,
+    transform: &glm::TMat4<T>,
+) -> RayHitData<T, E, ExtraData> {
+    if let Some(data) = &mut hit_data.data {
+        data.co = vec3_apply_model_matrix(&data.co, transform);
+    }
+    hit_data.normal = hit_data
+        .normal
+        .map(|normal| rigid_transform_dir(&normal, transform));
+    hit_data
+}
+
+fn primitive_ray_hit<T: glm::RealNumber, P: BVHPrimitive<T>>(
+    primitive: &P,
+    elem_index: usize,
+    co: &glm::TVec3<T>,
+    dir: &glm::TVec3<T>,
+) -> Option<RayHitData<T, usize, ()>> {
+    let (dist, normal) = primitive.ray_intersect(co, dir)?;
+    let mut hit_data = RayHitData::new(dist);
+    hit_data.set_data(RayHitOptionalData::new(elem_index, co + dir * dist));
+    hit_data.normal = normal;
+    Some(hit_data)
+}
+
+fn primitive_nearest_callback<T: glm::RealNumber, P: BVHPrimitive<T>>(
+    primitives: &[P],
+) -> impl Fn(usize, &glm::TVec3<T>, &mut NearestData<T, usize>) + '_ {
+    move |i, co, nearest| {
+        let closest_point = primitives[i].closest_point(co);
+        let dist_sq = glm::distance2(co, &closest_point);
+        if dist_sq < nearest.get_dist_sq() {
+            nearest.set_info(Some(i), Some(closest_point), None, dist_sq);
+        }
+    }
+}
+
+impl<T: glm::Number + num_traits::AsPrimitive<f32>, E: std::marker::Copy> BVHTree<T, E> {
+    /// Draw the nodes below (and including) the given node for which
+    /// `node_color` gives a color, does not go below `max_level`
+    /// (if any).
+    #[allow(clippy::too_many_arguments)]
+    fn recursive_draw<F>(
+        &self,
+        node_index: BVHNodeIndex,
+        pos_attr: usize,
+        color_attr: usize,
+        imm: &mut GPUImmediate,
+        node_color: &F,
+        max_level: Option<usize>,
+        current_level: usize,
+    ) where
+        F: Fn(BVHNodeIndex, &BVHNode<T, E>, usize) -> Option<glm::Vec4>,
+    {
+        let node = self.node_array.get(node_index.0).unwrap();
+
+        if let Some(color) = node_color(node_index, node, current_level) {
+            let x1: f32 = node.bv[0].as_();
+            let x2: f32 = node.bv[1].as_();
+            let y1: f32 = node.bv[2].as_();
+            let y2: f32 = node.bv[(2) + 1].as_();
+            let z1: f32 = node.bv[2 * 2].as_();
+            let z2: f32 = node.bv[(2 * 2) + 1].as_();
+
+            draw_box(imm, x1, x2, y1, y2, z1, z2, pos_attr, color_attr, &color);
+        }
+
+        if max_level == Some(current_level) {
+            return; // don't need to go below this level anyway to render
+        }
+
+        for child_index in &node.children[..node.totnode as usize] {
+            self.recursive_draw(
+                *child_index,
+                pos_attr,
+                color_attr,
+                imm,
+                node_color,
+                max_level,
+                current_level + 1,
+            );
+        }
+    }
+
+    /// Number of levels below (and including) the given node.
+    fn node_depth(&self, node_index: BVHNodeIndex) -> usize {
+        let node = self.node_array.get(node_index.0).unwrap();
+        1 + node.children[..node.totnode as usize]
+            .iter()
+            .map(|child_index| self.node_depth(*child_index))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Initialize the kdop `bv` so that any point expands it.
+fn kdop_min_max_init<T: glm::RealNumber>(bv: &mut [T], start_axis: u8, stop_axis: u8) {
+    for axis_iter in start_axis..stop_axis {
+        bv[(2 * axis_iter) as usize] = <T as Bounded>::max_value();
+        bv[((2 * axis_iter) + 1) as usize] = <T as Bounded>::min_value();
+    }
+}
+
+/// Extend the kdop `bv` to contain the points `co_many`.
+fn kdop_extend<T: glm::RealNumber>(
+    bv: &mut [T],
+    start_axis: u8,
+    stop_axis: u8,
+    co_many: &[glm::TVec3<T>],
+) {
+    let bvhtree_kdop_axes = bvhtree_kdop_axes();
+
+    for co in co_many {
+        for axis_iter in start_axis..stop_axis {
+            let axis_iter = axis_iter as usize;
+            let new_min_max = glm::dot(co, &bvhtree_kdop_axes[axis_iter]);
+            if new_min_max < bv[2 * axis_iter] {
+                bv[2 * axis_iter] = new_min_max;
+            }
+            if new_min_max > bv[(2 * axis_iter) + 1] {
+                bv[(2 * axis_iter) + 1] = new_min_max;
+            }
+        }
+    }
+}
 
-        (
-            glm::vec3(root.bv[0], root.bv[2], root.bv[4]),
-            glm::vec3(root.bv[1], root.bv[3], root.bv[5]),
-        )
+fn kdop_overlap<T: glm::RealNumber>(bv1: &[T], bv2: &[T], start_axis: u8, stop_axis: u8) -> bool {
+    for axis_iter in start_axis..stop_axis {
+        let axis_iter = axis_iter as usize;
+        if bv1[2 * axis_iter] > bv2[(2 * axis_iter) + 1]
+            || bv2[2 * axis_iter] > bv1[(2 * axis_iter) + 1]
+        {
+            return false;
+        }
     }
+
+    true
 }
 
-impl<T: glm::Number + num_traits::AsPrimitive<f32>, E: std::marker::Copy> BVHTree<T, E> {
-    #[allow(clippy::too_many_arguments)]
-    fn recursive_draw(
-        &self,
-        node_index: BVHNodeIndex,
-        pos_attr: usize,
-        color_attr: usize,
-        color: &glm::Vec4,
-        imm: &mut GPUImmediate,
-        draw_level: usize,
-        current_level: usize,
-    ) {
-        let node = self.node_array.get(node_index.0).unwrap();
+/// Refit the kdop `bv` to contain the BVs of the given `nodes`.
+fn refit_kdop_bv<T: glm::RealNumber, E: Copy>(
+    bv: &mut [T],
+    node_array: &Arena<BVHNode<T, E>>,
+    nodes: &[BVHNodeIndex],
+    start_axis: u8,
+    stop_axis: u8,
+) {
+    kdop_min_max_init(bv, start_axis, stop_axis);
 
-        if current_level == draw_level {
-            let x1: f32 = node.bv[0].as_();
-            let x2: f32 = node.bv[1].as_();
-            let y1: f32 = node.bv[2].as_();
-            let y2: f32 = node.bv[(2) + 1].as_();
-            let z1: f32 = node.bv[2 * 2].as_();
-            let z2: f32 = node.bv[(2 * 2) + 1].as_();
+    for node_index in nodes {
+        let node_bv = &node_array.get(node_index.0).unwrap().bv;
 
-            draw_box(imm, x1, x2, y1, y2, z1, z2, pos_attr, color_attr, color);
+        for axis_iter in start_axis..stop_axis {
+            let axis_iter = axis_iter as usize;
 
-            return; // don't need to go below this level anyway to render
-        }
+            let new_min = node_bv[2 * axis_iter];
+            if new_min < bv[2 * axis_iter] {
+                bv[2 * axis_iter] = new_min;
+            }
 
-        if node.totnode != 0 {
-            for i in 0..self.tree_type {
-                let child_index = node.children[i as usize];
-                if self.node_array.get(child_index.0).is_some() {
-                    self.recursive_draw(
-                        child_index,
-                        pos_attr,
-                        color_attr,
-                        color,
-                        imm,
-                        draw_level,
-                        current_level + 1,
-                    );
-                }
+            let new_max = node_bv[(2 * axis_iter) + 1];
+            if new_max > bv[(2 * axis_iter) + 1] {
+                bv[(2 * axis_iter) + 1] = new_max;
             }
         }
     }
@@ -1509,6 +4248,136 @@ fn get_largest_axis<T: glm::Number>(bv: &[T]) -> u8 {
     }
 }
 
+/// Volume of the box given by the x, y and z axes of `bv`.
+fn bv_volume<T: glm::Number>(bv: &[T]) -> T {
+    (bv[1] - bv[0]) * (bv[3] - bv[2]) * (bv[5] - bv[4])
+}
+
+/// Surface area of the box given by the x, y and z axes of `bv`.
+fn bv_surface_area<T: glm::Number>(bv: &[T]) -> T {
+    let dx = bv[1] - bv[0];
+    let dy = bv[3] - bv[2];
+    let dz = bv[5] - bv[4];
+    (dx * dy + dy * dz + dz * dx) * (T::one() + T::one())
+}
+
+/// Volume of the intersection of the boxes given by the x, y and z
+/// axes of `bv_1` and `bv_2`.
+fn bv_intersection_volume<T: glm::Number>(bv_1: &[T], bv_2: &[T]) -> T {
+    (0..3)
+        .map(|axis| {
+            let min = glm::max2_scalar(bv_1[2 * axis], bv_2[2 * axis]);
+            let max = glm::min2_scalar(bv_1[2 * axis + 1], bv_2[2 * axis + 1]);
+            glm::max2_scalar(max - min, T::zero())
+        })
+        .fold(T::one(), |volume, extent| volume * extent)
+}
+
+/// Total order of the scalars like [`f64::total_cmp()`], NaN is
+/// ordered after every other value, so degenerate elements do not
+/// cause a panic.
+fn total_cmp<T: PartialOrd>(a: &T, b: &T) -> std::cmp::Ordering {
+    a.partial_cmp(b).unwrap_or_else(|| {
+        let is_nan = |val: &T| val.partial_cmp(val).is_none();
+        is_nan(a).cmp(&is_nan(b))
+    })
+}
+
+/// Half of the surface area of the box given by the x, y and z axes
+/// of `bv`.
+fn sah_bv_half_area<T: glm::Number>(bv: &[T; 6]) -> T {
+    let dx = bv[1] - bv[0];
+    let dy = bv[3] - bv[2];
+    let dz = bv[5] - bv[4];
+    dx * dy + dy * dz + dz * dx
+}
+
+/// Grow `bv` (x, y and z axes only) to include `other`.
+fn sah_bv_join<T: glm::Number>(bv: &mut [T; 6], other: &[T; 6]) {
+    for axis in 0..3 {
+        if other[2 * axis] < bv[2 * axis] {
+            bv[2 * axis] = other[2 * axis];
+        }
+        if other[2 * axis + 1] > bv[2 * axis + 1] {
+            bv[2 * axis + 1] = other[2 * axis + 1];
+        }
+    }
+}
+
+/// BV (x, y and z axes only) of the given leafs.
+fn sah_leafs_bv<T: glm::Number>(leaf_bvs: &[[T; 6]], leafs: &[usize]) -> [T; 6] {
+    let mut bv = leaf_bvs[leafs[0]];
+    leafs
+        .iter()
+        .skip(1)
+        .for_each(|leaf| sah_bv_join(&mut bv, &leaf_bvs[*leaf]));
+    bv
+}
+
+/// Split the `leafs` (at least 2) in 2 such that
+/// `area(left) * num(left) + area(right) * num(right)` is minimum.
+/// The leafs are sorted by their centroid along x, y and z and every
+/// split position is tried. Ties are broken in favour of the more
+/// balanced split so that coincident elements don't form a linear
+/// tree.
+///
+/// Returns the left leafs, the right leafs and the axis of the
+/// split.
+fn sah_split<T: glm::RealNumber>(
+    leaf_bvs: &[[T; 6]],
+    leafs: &[usize],
+) -> (Vec<usize>, Vec<usize>, u8) {
+    debug_assert!(leafs.len() > 1);
+    let num_leafs = leafs.len();
+
+    // sum of min and max is twice the centroid, gives the same order
+    let sort_leafs = |axis: usize| {
+        let centroid = |leaf: usize| leaf_bvs[leaf][2 * axis] + leaf_bvs[leaf][2 * axis + 1];
+        let mut sorted_leafs = leafs.to_vec();
+        sorted_leafs.sort_by(|a, b| total_cmp(&centroid(*a), &centroid(*b)));
+        sorted_leafs
+    };
+
+    // (cost, imbalance, split, axis)
+    let mut best: Option<(T, usize, usize, usize)> = None;
+    for axis in 0..3 {
+        let sorted_leafs = sort_leafs(axis);
+
+        // right_areas[i] is the area of the leafs `i..`
+        let mut right_areas = vec![T::zero(); num_leafs];
+        let mut right_bv = leaf_bvs[sorted_leafs[num_leafs - 1]];
+        for i in (1..num_leafs).rev() {
+            sah_bv_join(&mut right_bv, &leaf_bvs[sorted_leafs[i]]);
+            right_areas[i] = sah_bv_half_area(&right_bv);
+        }
+
+        let mut left_bv = leaf_bvs[sorted_leafs[0]];
+        for i in 1..num_leafs {
+            sah_bv_join(&mut left_bv, &leaf_bvs[sorted_leafs[i - 1]]);
+            let cost = sah_bv_half_area(&left_bv) * glm::convert::<f64, T>(i as f64)
+                + right_areas[i] * glm::convert::<f64, T>((num_leafs - i) as f64);
+            let imbalance = (2 * i).max(num_leafs) - (2 * i).min(num_leafs);
+
+            let is_better = match best {
+                Some((best_cost, best_imbalance, _, _)) => match total_cmp(&cost, &best_cost) {
+                    std::cmp::Ordering::Less => true,
+                    std::cmp::Ordering::Equal => imbalance < best_imbalance,
+                    std::cmp::Ordering::Greater => false,
+                },
+                None => true,
+            };
+            if is_better {
+                best = Some((cost, imbalance, i, axis));
+            }
+        }
+    }
+
+    let (_, _, split, axis) = best.unwrap();
+    let mut left = sort_leafs(axis);
+    let right = left.split_off(split);
+    (left, right, axis as u8)
+}
+
 fn draw_line(
     imm: &mut GPUImmediate,
     p1: &glm::Vec3,
@@ -1561,22 +4430,57 @@ fn draw_box(
     draw_line(imm, &v4, &v8, pos_attr, color_attr, color);
 }
 
+/// What [`BVHTree`] draws, see [`BVHDrawData`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BVHDrawMode {
+    /// The nodes at `draw_level` (the root is at level 0) in the
+    /// draw color.
+    Level,
+    /// Only the leafs in the draw color.
+    Leafs,
+    /// All the nodes colored by their level, from blue at the root
+    /// to red at the deepest level. The alpha of the draw color is
+    /// used.
+    DepthHeatMap,
+    /// The nodes visited by the last ray cast or nearest query in the
+    /// draw color, needs [`BVHTree::enable_query_record()`].
+    QueryHighlight,
+}
+
 pub struct BVHDrawData {
     imm: Rc<RefCell<GPUImmediate>>,
     draw_level: usize,
     color: glm::DVec4,
+    mode: BVHDrawMode,
 }
 
 impl BVHDrawData {
     pub fn new(imm: Rc<RefCell<GPUImmediate>>, draw_level: usize, color: glm::DVec4) -> Self {
+        Self::new_with_mode(imm, draw_level, color, BVHDrawMode::Level)
+    }
+
+    pub fn new_with_mode(
+        imm: Rc<RefCell<GPUImmediate>>,
+        draw_level: usize,
+        color: glm::DVec4,
+        mode: BVHDrawMode,
+    ) -> Self {
         Self {
             imm,
             draw_level,
             color,
+            mode,
         }
     }
 }
 
+/// Color of the level for [`BVHDrawMode::DepthHeatMap`], `depth` is
+/// the number of levels.
+fn depth_heat_map_color(level: usize, depth: usize, alpha: f32) -> glm::Vec4 {
+    let t = level as f32 / (depth.max(2) - 1) as f32;
+    glm::vec4(t, 0.0, 1.0 - t, alpha)
+}
+
 impl<T: glm::Number + num_traits::AsPrimitive<f32>, E> Drawable for BVHTree<T, E>
 where
     E: Copy,
@@ -1585,6 +4489,12 @@ where
     type Error = NoSpecificDrawError;
 
     fn draw(&self, draw_data: &BVHDrawData) -> Result<(), Self::Error> {
+        let root_index = self.nodes[self.totleaf];
+        if self.node_array.get(root_index.0).is_none() {
+            // nothing to draw, the tree is empty or not balanced
+            return Ok(());
+        }
+
         let imm = &mut draw_data.imm.borrow_mut();
         let smooth_color_3d_shader = shader::builtins::get_smooth_color_3d_shader()
             .as_ref()
@@ -1610,19 +4520,66 @@ where
 
         imm.begin_at_most(
             GPUPrimType::Lines,
-            self.nodes.len() * 12 * 2,
+            (self.totleaf + self.totbranch) * 12 * 2,
             smooth_color_3d_shader,
         );
 
-        self.recursive_draw(
-            self.nodes[self.totleaf],
-            pos_attr,
-            color_attr,
-            &color,
-            imm,
-            draw_level,
-            0,
-        );
+        match draw_data.mode {
+            BVHDrawMode::Level => self.recursive_draw(
+                root_index,
+                pos_attr,
+                color_attr,
+                imm,
+                &|_, _, level| (level == draw_level).then_some(color),
+                Some(draw_level),
+                0,
+            ),
+            BVHDrawMode::Leafs => self.recursive_draw(
+                root_index,
+                pos_attr,
+                color_attr,
+                imm,
+                &|_, node, _| (node.totnode == 0).then_some(color),
+                None,
+                0,
+            ),
+            BVHDrawMode::DepthHeatMap => {
+                let depth = self.node_depth(root_index);
+                self.recursive_draw(
+                    root_index,
+                    pos_attr,
+                    color_attr,
+                    imm,
+                    &|_, _, level| Some(depth_heat_map_color(level, depth, color[3])),
+                    None,
+                    0,
+                );
+            }
+            BVHDrawMode::QueryHighlight => {
+                let nodes_visited: HashSet<_> = self
+                    .query_record
+                    .as_ref()
+                    .map(|record| {
+                        record
+                            .nodes_visited
+                            .lock()
+                            .unwrap()
+                            .iter()
+                            .copied()
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                self.recursive_draw(
+                    root_index,
+                    pos_attr,
+                    color_attr,
+                    imm,
+                    &|node_index, _, _| nodes_visited.contains(&node_index).then_some(color),
+                    None,
+                    0,
+                );
+            }
+        }
 
         imm.end();
 
@@ -1783,4 +4740,1005 @@ mod tests {
             vec![-0.001, 0.001, -0.001, 0.001, -1.001, 1.001]
         );
     }
+
+    fn random_tris_bvh(num_tris: usize, seed: u64) -> super::BVHTree<f64, usize> {
+        let mut bvh = random_tris_bvh_unbalanced(num_tris, seed, 4);
+        bvh.balance();
+        bvh
+    }
+
+    fn random_tris_bvh_unbalanced(
+        num_tris: usize,
+        seed: u64,
+        tree_type: u8,
+    ) -> super::BVHTree<f64, usize> {
+        use super::BVHPrimitive;
+        let mut bvh = super::BVHTree::new(num_tris, 0.0, tree_type, 6);
+        random_triangles(num_tris, seed, 0.05)
+            .iter()
+            .enumerate()
+            .for_each(|(i, tri)| bvh.insert(i, &tri.bounding_points()));
+        bvh
+    }
+
+    fn random_point(rng: &mut rand::rngs::StdRng, range: f64) -> nalgebra_glm::DVec3 {
+        use rand::Rng;
+        nalgebra_glm::vec3(
+            rng.gen_range(-range..range),
+            rng.gen_range(-range..range),
+            rng.gen_range(-range..range),
+        )
+    }
+
+    /// Small random triangles, the centers are within the unit cube
+    /// (-1 to 1) and the corners within `size` of the center (along
+    /// each axis).
+    fn random_triangles(num_tris: usize, seed: u64, size: f64) -> Vec<super::BVHTriangle<f64>> {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        (0..num_tris)
+            .map(|_| {
+                let center = random_point(&mut rng, 1.0);
+                super::BVHTriangle::new(
+                    center + random_point(&mut rng, size),
+                    center + random_point(&mut rng, size),
+                    center + random_point(&mut rng, size),
+                )
+            })
+            .collect()
+    }
+
+    /// Random points in the unit cube (-1 to 1) along with a balanced
+    /// tree with a leaf for every point.
+    fn random_points_bvh(
+        num_points: usize,
+        seed: u64,
+    ) -> (Vec<nalgebra_glm::DVec3>, super::BVHTree<f64, usize>) {
+        use nalgebra_glm as glm;
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let points: Vec<glm::DVec3> = (0..num_points)
+            .map(|_| {
+                glm::vec3(
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                )
+            })
+            .collect();
+        let mut bvh = super::BVHTree::new(points.len(), 0.0, 4, 6);
+        points.iter().enumerate().for_each(|(i, point)| {
+            bvh.insert(i, &[*point]);
+        });
+        bvh.balance();
+        (points, bvh)
+    }
+
+    fn overlap_pairs_to_vec(
+        pairs: Option<Vec<super::BVHTreeOverlap<usize>>>,
+    ) -> Vec<(usize, usize)> {
+        pairs
+            .unwrap_or_default()
+            .iter()
+            .map(|pair| (pair.index_1, pair.index_2))
+            .collect()
+    }
+
+    #[test]
+    fn bvh_overlap_multithreaded() {
+        let bvh_1 = random_tris_bvh(2000, 1);
+        let bvh_2 = random_tris_bvh(1500, 2);
+        let callback = |index_1: usize, index_2: usize| index_1 <= index_2;
+
+        for (bvh_1, bvh_2) in [(&bvh_1, &bvh_2), (&bvh_1, &bvh_1)] {
+            for callback in [None, Some(&callback)] {
+                let serial = overlap_pairs_to_vec(bvh_1.overlap(bvh_2, callback));
+                assert!(!serial.is_empty());
+
+                let multithreaded =
+                    overlap_pairs_to_vec(bvh_1.overlap_multithreaded(bvh_2, callback));
+                assert_eq!(serial, multithreaded);
+            }
+        }
+    }
+
+    #[test]
+    fn bvh_overlap_separate_identical_trees() {
+        // same leaf node indices in different trees must not be
+        // mistaken for the same node
+        let bvh_1 = random_tris_bvh(500, 3);
+        let bvh_2 = random_tris_bvh(500, 3);
+        let callback = |index_1: usize, index_2: usize| index_1 == index_2;
+
+        let pairs = overlap_pairs_to_vec(bvh_1.overlap(&bvh_2, Some(&callback)));
+        let mut indices: Vec<usize> = pairs
+            .iter()
+            .map(|(index_1, index_2)| {
+                assert_eq!(index_1, index_2);
+                *index_1
+            })
+            .collect();
+        indices.sort_unstable();
+        assert_eq!(indices, (0..500).collect::<Vec<_>>());
+
+        let pairs = overlap_pairs_to_vec(bvh_1.overlap(&bvh_2, None::<&fn(usize, usize) -> bool>));
+        assert!((0..500).all(|i| pairs.contains(&(i, i))));
+    }
+
+    #[test]
+    fn bvh_balance_multithreaded() {
+        for tree_type in [2, 4, 7] {
+            let num_tris = super::BVH_MULTITHREADED_BALANCE_MIN_LEAFS * 5 + 3;
+            let mut bvh_serial = random_tris_bvh_unbalanced(num_tris, 3, tree_type);
+            let mut bvh_multithreaded = bvh_serial.clone();
+            bvh_serial.balance();
+            bvh_multithreaded.balance_multithreaded();
+
+            assert_eq!(bvh_serial.totbranch, bvh_multithreaded.totbranch);
+            assert_eq!(bvh_serial.nodes, bvh_multithreaded.nodes);
+            bvh_serial
+                .node_array
+                .iter()
+                .zip(bvh_multithreaded.node_array.iter())
+                .for_each(|((index_1, node_1), (index_2, node_2))| {
+                    assert_eq!(index_1, index_2);
+                    assert_eq!(node_1.children, node_2.children);
+                    assert_eq!(node_1.parent, node_2.parent);
+                    assert_eq!(node_1.bv, node_2.bv);
+                    assert_eq!(node_1.elem_index, node_2.elem_index);
+                    assert_eq!(node_1.totnode, node_2.totnode);
+                    assert_eq!(node_1.main_axis, node_2.main_axis);
+                });
+        }
+    }
+
+    #[test]
+    fn bvh_sah_build() {
+        use nalgebra_glm as glm;
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        for tree_type in [2, 4, 7] {
+            let mut bvh_median = random_tris_bvh_unbalanced(3000, 8, tree_type);
+            let mut bvh_sah = bvh_median.clone();
+            bvh_sah.build_mode = super::BVHBuildMode::SAH;
+            bvh_median.balance();
+            bvh_sah.balance_multithreaded();
+
+            // every leaf must be reachable exactly once
+            let other = random_tris_bvh(500, 9);
+            let mut pairs_median =
+                overlap_pairs_to_vec(bvh_median.overlap::<fn(usize, usize) -> bool>(&other, None));
+            let mut pairs_sah =
+                overlap_pairs_to_vec(bvh_sah.overlap::<fn(usize, usize) -> bool>(&other, None));
+            pairs_median.sort_unstable();
+            pairs_sah.sort_unstable();
+            assert!(!pairs_median.is_empty());
+            assert_eq!(pairs_median, pairs_sah);
+
+            let mut cast_rays =
+                |bvh_1: &super::BVHTree<f64, usize>, bvh_2: &super::BVHTree<f64, usize>| {
+                    for _ in 0..100 {
+                        let co = glm::vec3(
+                            rng.gen_range(-2.0..2.0),
+                            rng.gen_range(-2.0..2.0),
+                            rng.gen_range(-2.0..2.0),
+                        );
+                        let dir = glm::normalize(&-co);
+                        let hit_1 = bvh_1.ray_cast_no_callback(co, dir).map(|hit| hit.dist);
+                        let hit_2 = bvh_2.ray_cast_no_callback(co, dir).map(|hit| hit.dist);
+                        assert_eq!(hit_1, hit_2);
+                    }
+                };
+            cast_rays(&bvh_median, &bvh_sah);
+
+            // the tree can be refit after moving the elements
+            let offset = glm::vec3(0.5, 0.0, 0.0);
+            for bvh in [&mut bvh_median, &mut bvh_sah] {
+                for i in 0..bvh.totleaf {
+                    let bv = bvh.node_array.get_unknown_gen(i).unwrap().0.bv.clone();
+                    let min = glm::vec3(bv[0], bv[2], bv[4]) + offset;
+                    let max = glm::vec3(bv[1], bv[3], bv[5]) + offset;
+                    bvh.update_node(i, &[min, max], &[]).unwrap();
+                }
+                bvh.update_tree();
+            }
+            cast_rays(&bvh_median, &bvh_sah);
+        }
+
+        // degenerate element with a NaN BV, the median build doesn't
+        // panic either
+        let mut bvh = super::BVHTree::new_with_build_mode(4, 0.0, 4, 6, super::BVHBuildMode::SAH);
+        for (i, y) in [0.0, 1.0, 10.0, 11.0].iter().enumerate() {
+            bvh.insert(i, &[glm::vec3(0.0, *y, 0.0), glm::vec3(1.0, y + 0.1, 1.0)]);
+        }
+        bvh.node_array.get_unknown_gen_mut(2).unwrap().0.bv[1] = f64::NAN;
+        bvh.balance();
+        assert_eq!(bvh.stats().num_leafs, 4);
+    }
+
+    #[test]
+    fn bvh_incremental() {
+        use super::{BVHBuildMode, BVHPrimitive, BVHTree, BVHTriangle};
+        use nalgebra_glm as glm;
+        use rand::{Rng, SeedableRng};
+        let mut tris = random_triangles(1000, 12, 0.05);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(17);
+        let queries: Vec<_> = (0..100).map(|_| random_point(&mut rng, 1.5)).collect();
+        let other = random_tris_bvh(300, 13);
+        let mut ops_rng = rand::rngs::StdRng::seed_from_u64(14);
+
+        // the results must be the same as a tree rebuilt from the
+        // elements in the tree
+        let check = |bvh: &BVHTree<f64, usize>, tris: &[BVHTriangle<f64>], elems: &[usize]| {
+            let mut rebuilt = BVHTree::new(elems.len(), 0.0, 4, 6);
+            elems
+                .iter()
+                .for_each(|elem| rebuilt.insert(*elem, &tris[*elem].bounding_points()));
+            rebuilt.balance();
+
+            let stats = bvh.stats();
+            assert_eq!(stats.num_leafs, elems.len());
+            assert_eq!(stats.num_branches, bvh.totbranch);
+            assert_eq!(
+                stats
+                    .levels
+                    .iter()
+                    .map(|level| level.num_leafs)
+                    .sum::<usize>(),
+                elems.len()
+            );
+
+            let mut pairs_1 =
+                overlap_pairs_to_vec(bvh.overlap::<fn(usize, usize) -> bool>(&other, None));
+            let mut pairs_2 =
+                overlap_pairs_to_vec(rebuilt.overlap::<fn(usize, usize) -> bool>(&other, None));
+            pairs_1.sort_unstable();
+            pairs_2.sort_unstable();
+            assert_eq!(pairs_1, pairs_2);
+
+            for co in &queries {
+                let dir = glm::normalize(&-co);
+                let hit_1 = bvh.ray_cast_primitives(tris, *co, dir, f64::MAX);
+                let hit_2 = rebuilt.ray_cast_primitives(tris, *co, dir, f64::MAX);
+                assert_eq!(hit_1.map(|hit| hit.dist), hit_2.map(|hit| hit.dist));
+
+                let nearest_1 = bvh.find_nearest_primitive(tris, *co, f64::MAX);
+                let nearest_2 = rebuilt.find_nearest_primitive(tris, *co, f64::MAX);
+                assert_eq!(
+                    nearest_1.map(|nearest| nearest.dist_sq),
+                    nearest_2.map(|nearest| nearest.dist_sq)
+                );
+
+                let mut within_1 = Vec::new();
+                let mut within_2 = Vec::new();
+                bvh.find_primitives_within_radius(tris, co, 0.3, |elem, _| within_1.push(elem));
+                rebuilt.find_primitives_within_radius(tris, co, 0.3, |elem, _| within_2.push(elem));
+                within_1.sort_unstable();
+                within_2.sort_unstable();
+                assert_eq!(within_1, within_2);
+            }
+        };
+
+        for build_mode in [BVHBuildMode::Median, BVHBuildMode::SAH] {
+            let mut bvh = BVHTree::from_primitives(&tris[..500], 0.0, 4, 6, build_mode);
+            // (element, handle) of the elements in the tree
+            let mut elems: Vec<_> = (0..500)
+                .map(|i| (i, bvh.get_leaf_handle(i).unwrap()))
+                .collect();
+            let mut free_elems: Vec<_> = (500..tris.len()).collect();
+
+            for step in 0..1500 {
+                if ops_rng.gen_bool(0.5) && !free_elems.is_empty() || elems.is_empty() {
+                    let elem = free_elems.swap_remove(ops_rng.gen_range(0..free_elems.len()));
+                    let handle = bvh.insert_incremental(elem, &tris[elem].bounding_points());
+                    elems.push((elem, handle));
+                } else {
+                    let (elem, handle) = elems.swap_remove(ops_rng.gen_range(0..elems.len()));
+                    assert_eq!(bvh.remove_incremental(handle).unwrap(), elem);
+                    assert!(bvh.remove_incremental(handle).is_err());
+                    assert!(bvh.get_leaf_node_index(handle).is_none());
+                    free_elems.push(elem);
+                }
+
+                if step % 300 == 299 {
+                    let elem_indices: Vec<_> = elems.iter().map(|(elem, _)| *elem).collect();
+                    check(&bvh, &tris, &elem_indices);
+                }
+            }
+
+            // move some of the elements
+            for (elem, handle) in elems.iter().take(50) {
+                let offset = random_point(&mut rng, 0.5);
+                let tri = &mut tris[*elem];
+                tri.points.iter_mut().for_each(|point| *point += offset);
+                bvh.update_node_incremental(*handle, &tri.bounding_points(), &[])
+                    .unwrap();
+            }
+            let elem_indices: Vec<_> = elems.iter().map(|(elem, _)| *elem).collect();
+            check(&bvh, &tris, &elem_indices);
+
+            // a full refit gives the same tree
+            for (elem, handle) in elems.iter().skip(50).take(50) {
+                let offset = random_point(&mut rng, 0.5);
+                let tri = &mut tris[*elem];
+                tri.points.iter_mut().for_each(|point| *point += offset);
+                let node_index = bvh.get_leaf_node_index(*handle).unwrap();
+                bvh.update_node(node_index, &tri.bounding_points(), &[])
+                    .unwrap();
+            }
+            bvh.update_tree();
+            check(&bvh, &tris, &elem_indices);
+
+            // the tree can be emptied and filled again
+            let handles: Vec<_> = elems.iter().map(|(_, handle)| *handle).collect();
+            for (elem, handle) in elems.drain(..) {
+                assert_eq!(bvh.remove_incremental(handle).unwrap(), elem);
+            }
+            assert_eq!(bvh.totleaf, 0);
+            assert_eq!(bvh.totbranch, 0);
+            assert!(bvh
+                .ray_cast_primitives(&tris, queries[0], glm::normalize(&-queries[0]), f64::MAX)
+                .is_none());
+            let elems: Vec<_> = (0..10).collect();
+            let new_handles: Vec<_> = elems
+                .iter()
+                .map(|elem| bvh.insert_incremental(*elem, &tris[*elem].bounding_points()))
+                .collect();
+            check(&bvh, &tris, &elems);
+
+            // handles of removed elements stay invalid when their
+            // nodes are reused
+            handles.iter().for_each(|handle| {
+                assert!(bvh.get_leaf_node_index(*handle).is_none());
+                assert!(bvh.remove_incremental(*handle).is_err());
+            });
+            check(&bvh, &tris, &elems);
+
+            // an emptied tree is like a new tree, it can be filled
+            // with `insert()` and balanced
+            for handle in new_handles {
+                bvh.remove_incremental(handle).unwrap();
+            }
+            let elems: Vec<_> = (10..60).collect();
+            for elem in &elems {
+                bvh.insert(*elem, &tris[*elem].bounding_points());
+            }
+            bvh.balance();
+            check(&bvh, &tris, &elems);
+        }
+
+        // degenerate element with a NaN BV, the children costs cannot
+        // be ordered
+        let mut bvh = BVHTree::new(20, 0.0, 4, 6);
+        let handle = bvh.insert_incremental(0, &tris[0].bounding_points());
+        for (elem, tri) in tris.iter().enumerate().take(4).skip(1) {
+            bvh.insert_incremental(elem, &tri.bounding_points());
+        }
+        bvh.node_array.get_mut(handle.0).unwrap().bv[1] = f64::NAN;
+        for (elem, tri) in tris.iter().enumerate().take(20).skip(4) {
+            bvh.insert_incremental(elem, &tri.bounding_points());
+        }
+        assert_eq!(bvh.stats().num_leafs, 20);
+    }
+
+    #[test]
+    fn bvh_transformed_queries() {
+        use super::{primitive_nearest_callback, primitive_ray_hit};
+        use super::{BVHBuildMode, BVHTree, BVHTriangle};
+        use crate::util::vec3_apply_model_matrix;
+        use nalgebra_glm as glm;
+        use rand::SeedableRng;
+        let tris_1 = random_triangles(500, 15, 0.1);
+        let tris_2_local = random_triangles(500, 18, 0.1);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(19);
+
+        // tree 2 in the space of tree 1
+        let transform = glm::translation(&glm::vec3(0.3, -0.2, 0.5))
+            * glm::rotation(0.7, &glm::normalize(&glm::vec3(1.0, 2.0, -0.5)));
+        let tris_2: Vec<_> = tris_2_local
+            .iter()
+            .map(|tri| {
+                BVHTriangle::new(
+                    vec3_apply_model_matrix(&tri.points[0], &transform),
+                    vec3_apply_model_matrix(&tri.points[1], &transform),
+                    vec3_apply_model_matrix(&tri.points[2], &transform),
+                )
+            })
+            .collect();
+
+        let bvh_1 = BVHTree::from_primitives(&tris_1, 0.0, 4, 6, BVHBuildMode::Median);
+        let bvh_2_local = BVHTree::from_primitives(&tris_2_local, 0.0, 4, 6, BVHBuildMode::Median);
+        let bvh_2 = BVHTree::from_primitives(&tris_2, 0.0, 4, 6, BVHBuildMode::Median);
+
+        // the exact test of the elements must give the same pairs as
+        // the tree built in the space of tree 1
+        let bounding_boxes_overlap = |elem_1: usize, elem_2: usize| {
+            let points_1 = &tris_1[elem_1].points;
+            let points_2 = &tris_2[elem_2].points;
+            (0..3).all(|axis| {
+                let min = |points: &[glm::DVec3; 3]| {
+                    points.iter().map(|p| p[axis]).fold(f64::MAX, f64::min)
+                };
+                let max = |points: &[glm::DVec3; 3]| {
+                    points.iter().map(|p| p[axis]).fold(f64::MIN, f64::max)
+                };
+                min(points_1) <= max(points_2) && min(points_2) <= max(points_1)
+            })
+        };
+        let mut pairs_transformed_all = overlap_pairs_to_vec(
+            bvh_1.overlap_transformed::<fn(usize, usize) -> bool>(&bvh_2_local, &transform, None),
+        );
+        let mut pairs_transformed = overlap_pairs_to_vec(bvh_1.overlap_transformed(
+            &bvh_2_local,
+            &transform,
+            Some(&bounding_boxes_overlap),
+        ));
+        let mut pairs =
+            overlap_pairs_to_vec(bvh_1.overlap::<fn(usize, usize) -> bool>(&bvh_2, None));
+        pairs_transformed_all.sort_unstable();
+        pairs_transformed.sort_unstable();
+        pairs.sort_unstable();
+        assert!(!pairs.is_empty());
+        assert_eq!(pairs_transformed, pairs);
+        // conservative
+        assert!(pairs
+            .iter()
+            .all(|pair| pairs_transformed_all.binary_search(pair).is_ok()));
+
+        for _ in 0..100 {
+            let co = random_point(&mut rng, 3.0);
+            let dir = glm::normalize(&(random_point(&mut rng, 0.5) - co));
+
+            let hit = bvh_2.ray_cast_primitives(&tris_2, co, dir, f64::MAX);
+            let hit_transformed =
+                bvh_2_local.ray_cast_transformed(co, dir, &transform, |elem, co, dir| {
+                    primitive_ray_hit(&tris_2_local[elem], elem, co, dir)
+                });
+            assert_eq!(hit.is_some(), hit_transformed.is_some());
+            if let (Some(hit), Some(hit_transformed)) = (hit, hit_transformed) {
+                let data = hit.data.unwrap();
+                let data_transformed = hit_transformed.data.unwrap();
+                assert_eq!(data.elem_index, data_transformed.elem_index);
+                assert!((hit.dist - hit_transformed.dist).abs() < 1e-9);
+                assert!(glm::distance(&data.co, &data_transformed.co) < 1e-9);
+                assert!(
+                    glm::distance(&hit.normal.unwrap(), &hit_transformed.normal.unwrap()) < 1e-9
+                );
+            }
+
+            let nearest = bvh_2.find_nearest_primitive(&tris_2, co, f64::MAX).unwrap();
+            let nearest_transformed = bvh_2_local
+                .find_nearest_transformed(
+                    co,
+                    f64::MAX,
+                    &transform,
+                    &Some(primitive_nearest_callback(&tris_2_local)),
+                )
+                .unwrap();
+            assert_eq!(
+                nearest.get_elem_index(),
+                nearest_transformed.get_elem_index()
+            );
+            assert!((nearest.get_dist_sq() - nearest_transformed.get_dist_sq()).abs() < 1e-9);
+            assert!(
+                glm::distance(
+                    &nearest.get_co().unwrap(),
+                    &nearest_transformed.get_co().unwrap()
+                ) < 1e-9
+            );
+        }
+    }
+
+    #[test]
+    fn bvh_primitives() {
+        use super::{BVHPrimitive, BVHSegment, BVHSphere};
+        use nalgebra_glm as glm;
+        use rand::SeedableRng;
+        let tris = random_triangles(500, 10, 0.1);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(20);
+        let spheres: Vec<_> = (0..500)
+            .map(|i| BVHSphere::new(random_point(&mut rng, 1.0), 0.01 + 0.0001 * i as f64))
+            .collect();
+        let tris_bvh =
+            super::BVHTree::from_primitives(&tris, 0.0, 4, 6, super::BVHBuildMode::Median);
+        let spheres_bvh =
+            super::BVHTree::from_primitives(&spheres, 0.0, 4, 6, super::BVHBuildMode::SAH);
+
+        // compare against brute force
+        for _ in 0..50 {
+            let co = random_point(&mut rng, 2.0);
+            let dir = glm::normalize(&(random_point(&mut rng, 0.5) - co));
+
+            let expected = tris
+                .iter()
+                .enumerate()
+                .filter_map(|(i, tri)| tri.ray_intersect(&co, &dir).map(|(dist, _)| (i, dist)))
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+            let hit = tris_bvh.ray_cast_primitives(&tris, co, dir, f64::MAX);
+            assert_eq!(
+                expected.map(|(i, _)| i),
+                hit.map(|hit| hit.data.unwrap().elem_index)
+            );
+
+            let num_hits = spheres
+                .iter()
+                .filter(|sphere| sphere.ray_intersect(&co, &dir).is_some())
+                .count();
+            let hits = spheres_bvh.ray_cast_all_primitives(&spheres, co, dir, f64::MAX);
+            assert_eq!(num_hits, hits.len());
+            hits.iter().for_each(|hit| {
+                let sphere = &spheres[hit.data.as_ref().unwrap().elem_index];
+                let pos = co + dir * hit.dist;
+                assert!((glm::distance(&pos, &sphere.center) - sphere.radius).abs() < 1e-9);
+            });
+
+            let (expected_index, expected_dist_sq) = spheres
+                .iter()
+                .enumerate()
+                .map(|(i, sphere)| (i, glm::distance2(&co, &sphere.closest_point(&co))))
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                .unwrap();
+            let nearest = spheres_bvh
+                .find_nearest_primitive(&spheres, co, f64::MAX)
+                .unwrap();
+            assert_eq!(nearest.get_elem_index().unwrap(), expected_index);
+            assert!((nearest.get_dist_sq() - expected_dist_sq).abs() < 1e-12);
+
+            let k_nearest = spheres_bvh.find_k_nearest_primitives(&spheres, co, 5, f64::MAX);
+            assert_eq!(k_nearest.len(), 5);
+            assert_eq!(k_nearest[0].get_elem_index().unwrap(), expected_index);
+
+            let radius = 0.3;
+            let mut expected_within: Vec<_> = spheres
+                .iter()
+                .enumerate()
+                .filter(|(_, sphere)| glm::distance(&co, &sphere.closest_point(&co)) <= radius)
+                .map(|(i, _)| i)
+                .collect();
+            let mut within = Vec::new();
+            spheres_bvh.find_primitives_within_radius(&spheres, &co, radius, |i, _| within.push(i));
+            expected_within.sort_unstable();
+            within.sort_unstable();
+            assert_eq!(expected_within, within);
+        }
+
+        let segments = vec![
+            BVHSegment::new(glm::vec3(0.0, -1.0, 0.0), glm::vec3(0.0, 1.0, 0.0), 0.01),
+            BVHSegment::new(glm::vec3(2.0, -1.0, 0.0), glm::vec3(2.0, 1.0, 0.0), 0.01),
+        ];
+        let mut segments_bvh =
+            super::BVHTree::from_primitives(&segments, 0.0, 2, 6, super::BVHBuildMode::Median);
+        let co = glm::vec3(-1.0, 0.5, 0.0);
+        let hits =
+            segments_bvh.ray_cast_all_primitives(&segments, co, glm::vec3(1.0, 0.0, 0.0), f64::MAX);
+        let hits: Vec<_> = hits
+            .iter()
+            .map(|hit| (hit.data.as_ref().unwrap().elem_index, hit.dist))
+            .collect();
+        assert_eq!(hits, vec![(0, 1.0), (1, 3.0)]);
+
+        // refit after moving the segments
+        let segments: Vec<_> = segments
+            .iter()
+            .map(|segment| {
+                BVHSegment::new(
+                    segment.points[0] + glm::vec3(0.0, 0.0, 5.0),
+                    segment.points[1] + glm::vec3(0.0, 0.0, 5.0),
+                    segment.radius,
+                )
+            })
+            .collect();
+        segments_bvh.refit_primitives(&segments).unwrap();
+        assert!(segments_bvh
+            .ray_cast_primitives(&segments, co, glm::vec3(1.0, 0.0, 0.0), f64::MAX)
+            .is_none());
+        let nearest = segments_bvh
+            .find_nearest_primitive(&segments, glm::vec3(-1.0, 2.0, 5.0), f64::MAX)
+            .unwrap();
+        assert_eq!(nearest.get_elem_index().unwrap(), 0);
+        // on the surface of the capsule, not on the axis
+        let expected = glm::vec3(0.0, 1.0, 5.0) + glm::vec3(-1.0, 1.0, 0.0).normalize() * 0.01;
+        assert!((nearest.get_co().unwrap() - expected).norm() < 1e-12);
+
+        let segment = BVHSegment::new(glm::vec3(0.0, -1.0, 0.0), glm::vec3(0.0, 1.0, 0.0), 0.5_f64);
+        let co = segment.closest_point(&glm::vec3(0.0, 0.5, 0.0));
+        assert!((glm::length(&co.xz()) - 0.5).abs() < 1e-12);
+        assert_eq!(co[1], 0.5);
+    }
+
+    #[test]
+    fn bvh_stats() {
+        let mut bvh = random_tris_bvh_unbalanced(1000, 11, 4);
+        let stats = bvh.stats();
+        assert_eq!(stats.num_leafs, 1000);
+        assert_eq!(stats.depth, 0);
+        bvh.balance();
+
+        let stats = bvh.stats();
+        assert_eq!(stats.num_leafs, 1000);
+        assert_eq!(stats.num_branches, bvh.totbranch);
+        assert_eq!(stats.depth, stats.levels.len());
+        assert_eq!(stats.levels[0].num_nodes, 1);
+        assert_eq!(
+            stats
+                .levels
+                .iter()
+                .map(|level| level.num_leafs)
+                .sum::<usize>(),
+            1000
+        );
+        assert_eq!(
+            stats
+                .levels
+                .iter()
+                .map(|level| level.num_nodes)
+                .sum::<usize>(),
+            1000 + bvh.totbranch
+        );
+        assert!(stats.average_branch_occupancy > 0.0 && stats.average_branch_occupancy <= 1.0);
+        assert!(stats.sibling_overlap_ratio >= 0.0);
+        assert!(stats.total_surface_area > stats.levels[0].surface_area);
+        // root encloses all the tris, centers within [-1, 1]
+        assert!(stats.levels[0].volume > 7.0 && stats.levels[0].volume < 10.0);
+        assert!(!stats.to_string().is_empty());
+
+        // disjoint boxes in a line don't overlap
+        let (bvh, _) = boxes_bvh();
+        assert_eq!(bvh.stats().sibling_overlap_ratio, 0.0);
+    }
+
+    #[test]
+    fn bvh_query_record() {
+        use nalgebra_glm as glm;
+        let mut bvh = random_tris_bvh(1000, 16);
+        assert_eq!(bvh.get_query_record_len(), None);
+        bvh.enable_query_record();
+        bvh.enable_query_counters();
+        assert_eq!(bvh.get_query_record_len(), Some(0));
+
+        // the record only keeps the last query
+        let co = glm::vec3(2.0, 0.1, 0.2);
+        bvh.ray_cast_no_callback(co, glm::normalize(&-co));
+        bvh.reset_query_counters();
+        bvh.find_nearest_no_callback(co, f64::MAX);
+        let num_visited = bvh.get_query_stats().unwrap().nodes_visited;
+        assert!(num_visited > 0);
+        assert_eq!(bvh.get_query_record_len(), Some(num_visited));
+
+        let root_index = bvh.nodes[bvh.totleaf];
+        assert_eq!(bvh.node_depth(root_index), bvh.stats().depth);
+        assert_eq!(
+            super::depth_heat_map_color(0, 5, 1.0),
+            glm::vec4(0.0, 0.0, 1.0, 1.0)
+        );
+        assert_eq!(
+            super::depth_heat_map_color(4, 5, 1.0),
+            glm::vec4(1.0, 0.0, 0.0, 1.0)
+        );
+
+        bvh.disable_query_record();
+        assert_eq!(bvh.get_query_record_len(), None);
+    }
+
+    #[test]
+    fn bvh_query_counters() {
+        use nalgebra_glm as glm;
+        let mut bvh = random_tris_bvh(1000, 12);
+        assert!(bvh.get_query_stats().is_none());
+
+        bvh.enable_query_counters();
+        assert_eq!(bvh.get_query_stats(), Some(super::BVHQueryStats::default()));
+
+        bvh.find_nearest_no_callback(glm::vec3(0.0, 0.0, 0.0), f64::MAX)
+            .unwrap();
+        let query_stats = bvh.get_query_stats().unwrap();
+        assert!(query_stats.nodes_visited > 0);
+        assert!(query_stats.primitives_tested > 0);
+        assert!(query_stats.primitives_tested < 1000);
+        assert!(query_stats.primitives_tested < query_stats.nodes_visited);
+
+        // visits every node
+        bvh.reset_query_counters();
+        let mut num_found = 0;
+        bvh.find_in_aabb(
+            &glm::vec3(-2.0, -2.0, -2.0),
+            &glm::vec3(2.0, 2.0, 2.0),
+            |_| num_found += 1,
+        );
+        assert_eq!(num_found, 1000);
+        let query_stats = bvh.get_query_stats().unwrap();
+        assert_eq!(query_stats.primitives_tested, 1000);
+        assert_eq!(query_stats.nodes_visited, 1000 + bvh.totbranch);
+
+        // counted across threads
+        bvh.reset_query_counters();
+        bvh.overlap_multithreaded::<fn(usize, usize) -> bool>(&bvh, None);
+        let multithreaded_stats = bvh.get_query_stats().unwrap();
+        bvh.reset_query_counters();
+        bvh.overlap::<fn(usize, usize) -> bool>(&bvh, None);
+        let serial_stats = bvh.get_query_stats().unwrap();
+        assert_eq!(
+            serial_stats.primitives_tested,
+            multithreaded_stats.primitives_tested
+        );
+        // the multithreaded version starts at the children of the root
+        assert!(
+            serial_stats
+                .nodes_visited
+                .abs_diff(multithreaded_stats.nodes_visited)
+                <= 1
+        );
+
+        bvh.disable_query_counters();
+        assert!(bvh.get_query_stats().is_none());
+    }
+
+    #[test]
+    fn bvh_self_overlap() {
+        let bvh = random_tris_bvh(2000, 4);
+
+        // expected from the overlap of the tree against itself
+        let mut expected: Vec<(usize, usize)> =
+            overlap_pairs_to_vec(bvh.overlap::<fn(usize, usize) -> bool>(&bvh, None))
+                .into_iter()
+                .filter(|(index_1, index_2)| index_1 < index_2)
+                .collect();
+        expected.sort_unstable();
+        assert!(!expected.is_empty());
+
+        let self_overlap = overlap_pairs_to_vec(bvh.self_overlap::<fn(usize, usize) -> bool>(None));
+        assert!(self_overlap
+            .iter()
+            .all(|(index_1, index_2)| index_1 != index_2));
+        let mut self_overlap: Vec<_> = self_overlap
+            .into_iter()
+            .map(|(index_1, index_2)| (index_1.min(index_2), index_1.max(index_2)))
+            .collect();
+        self_overlap.sort_unstable();
+        let num_pairs = self_overlap.len();
+        self_overlap.dedup();
+        assert_eq!(num_pairs, self_overlap.len());
+        assert_eq!(expected, self_overlap);
+
+        // callback filters out the pairs
+        let callback = |index_1: usize, index_2: usize| index_1 < 1000 && index_2 < 1000;
+        let filtered = overlap_pairs_to_vec(bvh.self_overlap(Some(&callback)));
+        assert_eq!(
+            filtered.len(),
+            expected
+                .iter()
+                .filter(|(index_1, index_2)| callback(*index_1, *index_2))
+                .count()
+        );
+    }
+
+    /// Small boxes along the x axis at `(2 * i, 0, 0)` for `i` in
+    /// `0..5` and one box at `(4, 3, 0)` with index `5`.
+    fn boxes_bvh() -> (super::BVHTree<f64, usize>, Vec<nalgebra_glm::DVec3>) {
+        use nalgebra_glm as glm;
+        let centers: Vec<glm::DVec3> = (0..5)
+            .map(|i| glm::vec3(2.0 * i as f64, 0.0, 0.0))
+            .chain(std::iter::once(glm::vec3(4.0, 3.0, 0.0)))
+            .collect();
+        let mut bvh = super::BVHTree::new(centers.len(), 0.0, 2, 6);
+        centers.iter().enumerate().for_each(|(i, center)| {
+            bvh.insert(
+                i,
+                &[
+                    center - glm::DVec3::repeat(0.25),
+                    center + glm::DVec3::repeat(0.25),
+                ],
+            );
+        });
+        bvh.balance();
+        (bvh, centers)
+    }
+
+    #[test]
+    fn bvh_ray_cast_all() {
+        use nalgebra_glm as glm;
+        let (bvh, _) = boxes_bvh();
+        let co = glm::vec3(-5.0, 0.0, 0.0);
+        let dir = glm::vec3(1.0, 0.0, 0.0);
+
+        let hits = bvh.ray_cast_all_no_callback(co, dir, f64::MAX);
+        let hit_elems: Vec<_> = hits
+            .iter()
+            .map(|hit| hit.data.as_ref().unwrap().elem_index)
+            .collect();
+        assert_eq!(hit_elems, vec![0, 1, 2, 3, 4]);
+        assert!((hits[0].dist - 4.75).abs() < 1e-6);
+
+        let hits = bvh.ray_cast_all_no_callback(co, dir, 8.0);
+        assert_eq!(hits.len(), 2);
+
+        assert_eq!(
+            bvh.ray_cast_no_callback(co, dir)
+                .unwrap()
+                .data
+                .unwrap()
+                .elem_index,
+            0
+        );
+        let hit = bvh.ray_cast_max_dist(
+            co,
+            -dir,
+            100.0,
+            |_| -> Option<super::RayHitData<f64, usize, ()>> { unreachable!() },
+        );
+        assert!(hit.is_none());
+    }
+
+    #[test]
+    fn bvh_sphere_capsule_cast() {
+        use nalgebra_glm as glm;
+        let (bvh, centers) = boxes_bvh();
+        let co = glm::vec3(-5.0, 0.0, 0.0);
+        let dir = glm::vec3(1.0, 0.0, 0.0);
+
+        // the callback only considers the box at `(4, 3, 0)` so the
+        // hit is only possible if its inflated BV is hit
+        let callback = |elem_index: usize| {
+            if elem_index == 5 {
+                let mut hit_data = super::RayHitData::<f64, usize, ()>::new(centers[5][0] - co[0]);
+                hit_data.set_data(super::RayHitOptionalData::new(elem_index, centers[5]));
+                Some(hit_data)
+            } else {
+                None
+            }
+        };
+
+        assert!(bvh.sphere_cast(co, dir, 1.0, f64::MAX, callback).is_none());
+        let hit = bvh.sphere_cast(co, dir, 3.0, f64::MAX, callback).unwrap();
+        assert_eq!(hit.data.unwrap().elem_index, 5);
+        assert!(bvh.sphere_cast(co, dir, 3.0, 8.0, callback).is_none());
+
+        let co_2 = glm::vec3(-5.0, 3.0, 0.0);
+        let hit = bvh
+            .capsule_cast(co, co_2, 0.1, dir, f64::MAX, callback)
+            .unwrap();
+        assert_eq!(hit.data.unwrap().elem_index, 5);
+        let co_2 = glm::vec3(-5.0, -3.0, 0.0);
+        assert!(bvh
+            .capsule_cast(co, co_2, 0.1, dir, f64::MAX, callback)
+            .is_none());
+    }
+
+    #[test]
+    fn bvh_range_queries() {
+        use nalgebra_glm as glm;
+        let (points, bvh) = random_points_bvh(3000, 5);
+
+        let sorted = |mut elems: Vec<usize>| {
+            elems.sort_unstable();
+            elems
+        };
+
+        // radius
+        let co = glm::vec3(0.2, -0.1, 0.3);
+        let radius = 0.4;
+        let mut found = Vec::new();
+        bvh.find_within_radius(&co, radius, |elem_index, dist_sq| {
+            assert!(dist_sq <= radius * radius);
+            if glm::distance(&points[elem_index], &co) <= radius {
+                found.push(elem_index);
+            }
+        });
+        let expected: Vec<usize> = (0..points.len())
+            .filter(|i| glm::distance(&points[*i], &co) <= radius)
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(sorted(found), expected);
+
+        // aabb
+        let min = glm::vec3(-0.5, -0.2, 0.0);
+        let max = glm::vec3(0.3, 0.6, 0.9);
+        let inside_aabb = |point: &glm::DVec3| {
+            (0..3).all(|axis| point[axis] >= min[axis] && point[axis] <= max[axis])
+        };
+        let mut found = Vec::new();
+        bvh.find_in_aabb(&min, &max, |elem_index| {
+            if inside_aabb(&points[elem_index]) {
+                found.push(elem_index);
+            }
+        });
+        let expected: Vec<usize> = (0..points.len())
+            .filter(|i| inside_aabb(&points[*i]))
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(sorted(found), expected);
+
+        // the diagonal axes of the k-DOP separate a diagonal segment
+        // from a box in the corner of its bounding box
+        let segment = [glm::vec3(0.0, 0.0, 0.0), glm::vec3(1.0, 1.0, 0.0)];
+        let min = glm::vec3(0.8, 0.0, -0.1);
+        let max = glm::vec3(1.0, 0.2, 0.1);
+        for (axis, expected_count) in [(6, 1), (14, 0), (26, 0)] {
+            let mut segment_bvh = super::BVHTree::new(1, 0.0, 4, axis);
+            segment_bvh.insert(0, &segment);
+            segment_bvh.balance();
+            let mut count = 0;
+            segment_bvh.find_in_aabb(&min, &max, |_| count += 1);
+            assert_eq!(count, expected_count);
+        }
+
+        // frustum, pyramid along -z with the apex at (0, 0, 2)
+        let planes = [
+            glm::vec4(0.0, 0.0, -1.0, 1.5),
+            glm::vec4(1.0, 0.0, -0.5, 1.0),
+            glm::vec4(-1.0, 0.0, -0.5, 1.0),
+            glm::vec4(0.0, 1.0, -0.5, 1.0),
+            glm::vec4(0.0, -1.0, -0.5, 1.0),
+        ];
+        let inside_frustum = |point: &glm::DVec3| {
+            planes
+                .iter()
+                .all(|plane| glm::dot(&plane.xyz(), point) + plane[3] >= 0.0)
+        };
+        let mut found = Vec::new();
+        bvh.find_in_frustum(&planes, |elem_index| {
+            if inside_frustum(&points[elem_index]) {
+                found.push(elem_index);
+            }
+        });
+        let expected: Vec<usize> = (0..points.len())
+            .filter(|i| inside_frustum(&points[*i]))
+            .collect();
+        assert!(!expected.is_empty());
+        assert!(expected.len() < points.len());
+        assert_eq!(sorted(found), expected);
+    }
+
+    #[test]
+    fn bvh_find_k_nearest() {
+        use nalgebra_glm as glm;
+        use rand::{Rng, SeedableRng};
+        let (points, bvh) = random_points_bvh(3000, 6);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(16);
+
+        let callback = |elem_index: usize,
+                        co: &glm::DVec3,
+                        nearest_data: &mut super::NearestData<f64, usize>| {
+            let dist_sq = glm::distance2(co, &points[elem_index]);
+            if dist_sq < nearest_data.get_dist_sq() {
+                nearest_data.set_info(Some(elem_index), Some(points[elem_index]), None, dist_sq);
+            }
+        };
+
+        for _ in 0..20 {
+            let co = glm::vec3(
+                rng.gen_range(-1.5..1.5),
+                rng.gen_range(-1.5..1.5),
+                rng.gen_range(-1.5..1.5),
+            );
+
+            let mut expected: Vec<usize> = (0..points.len()).collect();
+            expected.sort_by(|i, j| {
+                glm::distance2(&co, &points[*i])
+                    .partial_cmp(&glm::distance2(&co, &points[*j]))
+                    .unwrap()
+            });
+            expected.truncate(10);
+
+            let found: Vec<usize> = bvh
+                .find_k_nearest(co, 10, f64::MAX, &Some(callback))
+                .iter()
+                .map(|nearest_data| nearest_data.get_elem_index().unwrap())
+                .collect();
+            assert_eq!(found, expected);
+
+            let found = bvh.find_k_nearest_no_callback(co, 10, f64::MAX);
+            assert_eq!(found.len(), 10);
+            assert_eq!(found[0].get_elem_index().unwrap(), expected[0]);
+
+            // within distance
+            let dist_sq = glm::distance2(&co, &points[expected[4]]);
+            let found = bvh.find_k_nearest(co, 10, dist_sq, &Some(callback));
+            assert_eq!(found.len(), 4);
+        }
+
+        assert!(bvh
+            .find_k_nearest_no_callback(glm::zero(), 0, f64::MAX)
+            .is_empty());
+    }
 }
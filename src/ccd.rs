@@ -0,0 +1,582 @@
+//! Continuous collision detection.
+//!
+//! Elements move linearly from their start position (at `t = 0`) to
+//! their end position (at `t = 1`). The time of impact of a
+//! vertex/triangle or edge/edge pair is found from the roots of the
+//! cubic that gives when the 4 points become coplanar.
+//!
+//! [`MeshSweptBVH`] finds the first impact between two moving meshes
+//! using BVHs over the swept elements.
+
+use std::collections::HashSet;
+
+use crate::bvh::{nearest_point_to_tri, BVHError, BVHTree};
+use crate::glm;
+use crate::mesh::{EdgeIndex, FaceIndex, Mesh, NodeIndex};
+
+/// Relative tolerance, coefficients and lengths below this times the
+/// scale of the elements are considered to be zero, so the results
+/// do not depend on the scale of the scene.
+const CCD_EPSILON: f64 = 1e-12;
+
+/// Number of bisection iterations when finding a root, enough to
+/// reach the precision of [`f64`] within `[0, 1]`.
+const CCD_BISECTION_ITERATIONS: usize = 64;
+
+/// Position of the point moving linearly from `co[0]` to `co[1]` at
+/// time `t`.
+fn lerp_point(co: &[glm::DVec3; 2], t: f64) -> glm::DVec3 {
+    co[0] + (co[1] - co[0]) * t
+}
+
+/// Coefficients (constant term first) of the cubic
+/// `dot(cross(e1(t), e2(t)), w(t))` where each of the vectors moves
+/// linearly from `[0]` to `[1]`, along with the scale of the
+/// coefficients (product of the lengths of the vectors).
+fn coplanarity_cubic(
+    e1: &[glm::DVec3; 2],
+    e2: &[glm::DVec3; 2],
+    w: &[glm::DVec3; 2],
+) -> ([f64; 4], f64) {
+    let d1 = e1[1] - e1[0];
+    let d2 = e2[1] - e2[0];
+    let dw = w[1] - w[0];
+
+    let c0 = glm::cross(&e1[0], &e2[0]);
+    let c1 = glm::cross(&e1[0], &d2) + glm::cross(&d1, &e2[0]);
+    let c2 = glm::cross(&d1, &d2);
+
+    let max_len = |v: &[glm::DVec3; 2]| glm::length(&v[0]).max(glm::length(&v[1]));
+    let scale = max_len(e1) * max_len(e2) * max_len(w);
+
+    (
+        [
+            glm::dot(&c0, &w[0]),
+            glm::dot(&c1, &w[0]) + glm::dot(&c0, &dw),
+            glm::dot(&c2, &w[0]) + glm::dot(&c1, &dw),
+            glm::dot(&c2, &dw),
+        ],
+        scale,
+    )
+}
+
+/// Roots of the quadratic `a * t^2 + b * t + c` in ascending order,
+/// coefficients below `tolerance` are considered to be zero.
+fn quadratic_roots(a: f64, b: f64, c: f64, tolerance: f64) -> Vec<f64> {
+    if a.abs() <= tolerance {
+        if b.abs() <= tolerance {
+            return Vec::new();
+        }
+        return vec![-c / b];
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return Vec::new();
+    }
+
+    // numerically stable form, avoids cancellation
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    let mut roots = if q.abs() <= tolerance {
+        vec![-b / (2.0 * a)]
+    } else {
+        vec![q / a, c / q]
+    };
+    roots.sort_by(f64::total_cmp);
+    roots
+}
+
+/// Roots of the cubic given by `coeffs` (constant term first) within
+/// `[0, 1]` in ascending order, `scale` is the magnitude of the
+/// coefficients (see `coplanarity_cubic()`).
+///
+/// If the cubic is (almost) zero everywhere, only `0` and `1` are
+/// returned.
+fn cubic_roots_in_unit_interval(coeffs: [f64; 4], scale: f64) -> Vec<f64> {
+    let tolerance = CCD_EPSILON * scale;
+    if coeffs.iter().all(|coeff| coeff.abs() <= tolerance) {
+        return vec![0.0, 1.0];
+    }
+
+    let eval = |t: f64| ((coeffs[3] * t + coeffs[2]) * t + coeffs[1]) * t + coeffs[0];
+
+    // split the interval at the critical points so that the cubic
+    // is monotonic within each sub interval
+    let mut splits = vec![0.0];
+    splits.extend(
+        quadratic_roots(3.0 * coeffs[3], 2.0 * coeffs[2], coeffs[1], tolerance)
+            .into_iter()
+            .filter(|t| *t > 0.0 && *t < 1.0),
+    );
+    splits.push(1.0);
+
+    let mut roots: Vec<f64> = Vec::new();
+    for (&lo, &hi) in splits.iter().zip(splits.iter().skip(1)) {
+        let (f_lo, f_hi) = (eval(lo), eval(hi));
+        let root = if f_lo == 0.0 {
+            lo
+        } else if f_hi == 0.0 {
+            hi
+        } else if f_lo.signum() != f_hi.signum() {
+            let (mut lo, mut hi) = (lo, hi);
+            for _ in 0..CCD_BISECTION_ITERATIONS {
+                let mid = 0.5 * (lo + hi);
+                if eval(mid).signum() == f_lo.signum() {
+                    lo = mid;
+                } else {
+                    hi = mid;
+                }
+            }
+            0.5 * (lo + hi)
+        } else {
+            continue;
+        };
+
+        match roots.last() {
+            Some(last) if (root - last).abs() < CCD_EPSILON => {}
+            _ => roots.push(root),
+        }
+    }
+
+    roots
+}
+
+/// Time of impact (within `[0, 1]`) of the point `p` with the
+/// triangle (`a`, `b`, `c`). Each point moves linearly from `[0]` to
+/// `[1]`.
+///
+/// The impact is considered at the times the point becomes coplanar
+/// with the triangle and is within `thickness` of it. If the point
+/// moves within the plane of the triangle the whole time, only the
+/// start and the end are checked.
+pub fn vertex_triangle_time_of_impact(
+    p: &[glm::DVec3; 2],
+    a: &[glm::DVec3; 2],
+    b: &[glm::DVec3; 2],
+    c: &[glm::DVec3; 2],
+    thickness: f64,
+) -> Option<f64> {
+    let (coeffs, scale) = coplanarity_cubic(
+        &[b[0] - a[0], b[1] - a[1]],
+        &[c[0] - a[0], c[1] - a[1]],
+        &[p[0] - a[0], p[1] - a[1]],
+    );
+
+    cubic_roots_in_unit_interval(coeffs, scale)
+        .into_iter()
+        .find(|t| {
+            let p = lerp_point(p, *t);
+            let nearest = nearest_point_to_tri(
+                &p,
+                [&lerp_point(a, *t), &lerp_point(b, *t), &lerp_point(c, *t)],
+            );
+            glm::distance(&p, &nearest) <= thickness
+        })
+}
+
+/// Closest points between the segments (`p1`, `q1`) and (`p2`,
+/// `q2`).
+///
+/// From Real-Time Collision Detection (Christer Ericson)
+/// `ClosestPtSegmentSegment`.
+pub fn segment_segment_closest_points(
+    p1: &glm::DVec3,
+    q1: &glm::DVec3,
+    p2: &glm::DVec3,
+    q2: &glm::DVec3,
+) -> (glm::DVec3, glm::DVec3) {
+    let d1 = q1 - p1;
+    let d2 = q2 - p2;
+    let r = p1 - p2;
+    let a = glm::dot(&d1, &d1);
+    let e = glm::dot(&d2, &d2);
+    let f = glm::dot(&d2, &r);
+    // a segment is degenerate relative to the length of the other
+    let tolerance = CCD_EPSILON * a.max(e);
+
+    let (s, t) = if a <= tolerance && e <= tolerance {
+        // both segments degenerate into points
+        (0.0, 0.0)
+    } else if a <= tolerance {
+        // first segment degenerates into a point
+        (0.0, (f / e).clamp(0.0, 1.0))
+    } else {
+        let c = glm::dot(&d1, &r);
+        if e <= tolerance {
+            // second segment degenerates into a point
+            ((-c / a).clamp(0.0, 1.0), 0.0)
+        } else {
+            let b = glm::dot(&d1, &d2);
+            let denom = a * e - b * b;
+
+            // pick arbitrary s if the segments are parallel
+            let s = if denom != 0.0 {
+                ((b * f - c * e) / denom).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+
+            let t = (b * s + f) / e;
+            if t < 0.0 {
+                ((-c / a).clamp(0.0, 1.0), 0.0)
+            } else if t > 1.0 {
+                (((b - c) / a).clamp(0.0, 1.0), 1.0)
+            } else {
+                (s, t)
+            }
+        }
+    };
+
+    (p1 + d1 * s, p2 + d2 * t)
+}
+
+/// Time of impact (within `[0, 1]`) of the edge (`a`, `b`) with the
+/// edge (`c`, `d`). Each point moves linearly from `[0]` to `[1]`.
+///
+/// The impact is considered at the times the edges become coplanar
+/// and are within `thickness` of each other. If the edges move within
+/// the same plane the whole time, only the start and the end are
+/// checked.
+pub fn edge_edge_time_of_impact(
+    a: &[glm::DVec3; 2],
+    b: &[glm::DVec3; 2],
+    c: &[glm::DVec3; 2],
+    d: &[glm::DVec3; 2],
+    thickness: f64,
+) -> Option<f64> {
+    let (coeffs, scale) = coplanarity_cubic(
+        &[b[0] - a[0], b[1] - a[1]],
+        &[d[0] - c[0], d[1] - c[1]],
+        &[c[0] - a[0], c[1] - a[1]],
+    );
+
+    cubic_roots_in_unit_interval(coeffs, scale)
+        .into_iter()
+        .find(|t| {
+            let (p1, p2) = segment_segment_closest_points(
+                &lerp_point(a, *t),
+                &lerp_point(b, *t),
+                &lerp_point(c, *t),
+                &lerp_point(d, *t),
+            );
+            glm::distance(&p1, &p2) <= thickness
+        })
+}
+
+/// Elements involved in an [`Impact`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImpactElements {
+    /// Node of the first mesh with face of the second mesh.
+    NodeFace(NodeIndex, FaceIndex),
+    /// Face of the first mesh with node of the second mesh.
+    FaceNode(FaceIndex, NodeIndex),
+    /// Edge of the first mesh with edge of the second mesh.
+    EdgeEdge(EdgeIndex, EdgeIndex),
+}
+
+/// Impact between two moving meshes, see
+/// [`MeshSweptBVH::time_of_impact()`].
+#[derive(Debug, Clone, Copy)]
+pub struct Impact {
+    /// Time of impact within `[0, 1]`.
+    pub time: f64,
+    pub elements: ImpactElements,
+}
+
+/// BVHs over the faces and edges of a mesh swept from its start to
+/// its end positions.
+pub struct MeshSweptBVH {
+    faces: BVHTree<f64, FaceIndex>,
+    edges: BVHTree<f64, EdgeIndex>,
+}
+
+/// Mesh at the start and at the end of the motion.
+type SweptMesh<'a, END, EVD, EED, EFD> =
+    (&'a Mesh<END, EVD, EED, EFD>, &'a Mesh<END, EVD, EED, EFD>);
+
+/// Start and end positions of the node.
+fn swept_node_positions<END, EVD, EED, EFD>(
+    mesh_start: &Mesh<END, EVD, EED, EFD>,
+    mesh_end: &Mesh<END, EVD, EED, EFD>,
+    node_index: NodeIndex,
+) -> [glm::DVec3; 2] {
+    [
+        mesh_start.get_node(node_index).unwrap().pos,
+        mesh_end.get_node(node_index).unwrap().pos,
+    ]
+}
+
+/// Nodes of the face.
+fn face_node_indices<END, EVD, EED, EFD>(
+    mesh: &Mesh<END, EVD, EED, EFD>,
+    face_index: FaceIndex,
+) -> Vec<NodeIndex> {
+    mesh.get_nodes_of_face(mesh.get_face(face_index).unwrap())
+        .into_iter()
+        .map(|node_index| node_index.unwrap())
+        .collect()
+}
+
+/// Nodes of the edge.
+fn edge_node_indices<END, EVD, EED, EFD>(
+    mesh: &Mesh<END, EVD, EED, EFD>,
+    edge_index: EdgeIndex,
+) -> [NodeIndex; 2] {
+    let (v1_index, v2_index) = mesh.get_edge(edge_index).unwrap().get_verts().unwrap();
+    [v1_index, v2_index].map(|vert_index| mesh.get_vert(vert_index).unwrap().get_node().unwrap())
+}
+
+/// Earliest time of impact of the moving node with the moving face
+/// (fan triangulated).
+fn node_face_time_of_impact<END, EVD, EED, EFD>(
+    node_mesh: SweptMesh<END, EVD, EED, EFD>,
+    node_index: NodeIndex,
+    face_mesh: SweptMesh<END, EVD, EED, EFD>,
+    face_index: FaceIndex,
+    thickness: f64,
+) -> Option<f64> {
+    let p = swept_node_positions(node_mesh.0, node_mesh.1, node_index);
+    let face_positions: Vec<_> = face_node_indices(face_mesh.0, face_index)
+        .into_iter()
+        .map(|node_index| swept_node_positions(face_mesh.0, face_mesh.1, node_index))
+        .collect();
+
+    (1..face_positions.len().saturating_sub(1))
+        .filter_map(|i| {
+            vertex_triangle_time_of_impact(
+                &p,
+                &face_positions[0],
+                &face_positions[i],
+                &face_positions[i + 1],
+                thickness,
+            )
+        })
+        .min_by(f64::total_cmp)
+}
+
+impl MeshSweptBVH {
+    /// Build the swept BVHs of the mesh moving from `mesh_start` to
+    /// `mesh_end`, both must have the same topology.
+    ///
+    /// `epsilon` inflates the BVs, so elements within `2 * epsilon`
+    /// of each other are tested for impact. See [`BVHTree::new()`]
+    /// for the other parameters.
+    pub fn new<END, EVD, EED, EFD>(
+        mesh_start: &Mesh<END, EVD, EED, EFD>,
+        mesh_end: &Mesh<END, EVD, EED, EFD>,
+        epsilon: f64,
+        tree_type: u8,
+        axis: u8,
+    ) -> Result<Self, BVHError> {
+        Ok(Self {
+            faces: BVHTree::from_mesh_swept(mesh_start, mesh_end, epsilon, tree_type, axis)?,
+            edges: BVHTree::from_mesh_edges_swept(mesh_start, mesh_end, epsilon, tree_type, axis)?,
+        })
+    }
+
+    /// Refit the BVHs for the next time step, the topology of the
+    /// mesh must not change.
+    pub fn refit<END, EVD, EED, EFD>(
+        &mut self,
+        mesh_start: &Mesh<END, EVD, EED, EFD>,
+        mesh_end: &Mesh<END, EVD, EED, EFD>,
+    ) -> Result<(), BVHError> {
+        self.faces.refit_from_mesh_swept(mesh_start, mesh_end)?;
+        self.edges.refit_from_mesh_swept(mesh_start, mesh_end)
+    }
+
+    pub fn get_faces_bvh(&self) -> &BVHTree<f64, FaceIndex> {
+        &self.faces
+    }
+
+    pub fn get_edges_bvh(&self) -> &BVHTree<f64, EdgeIndex> {
+        &self.edges
+    }
+
+    /// Find the earliest impact between the mesh of `self` (moving
+    /// from `mesh_start` to `mesh_end`) and the mesh of `other`
+    /// (moving from `other_mesh_start` to `other_mesh_end`).
+    ///
+    /// Node/face pairs of both the meshes and edge/edge pairs are
+    /// tested, see [`vertex_triangle_time_of_impact()`] and
+    /// [`edge_edge_time_of_impact()`] for `thickness`.
+    pub fn time_of_impact<END, EVD, EED, EFD>(
+        &self,
+        mesh_start: &Mesh<END, EVD, EED, EFD>,
+        mesh_end: &Mesh<END, EVD, EED, EFD>,
+        other: &MeshSweptBVH,
+        other_mesh_start: &Mesh<END, EVD, EED, EFD>,
+        other_mesh_end: &Mesh<END, EVD, EED, EFD>,
+        thickness: f64,
+    ) -> Option<Impact> {
+        let mesh = (mesh_start, mesh_end);
+        let other_mesh = (other_mesh_start, other_mesh_end);
+
+        let mut impact: Option<Impact> = None;
+        let mut update_impact = |time: Option<f64>, elements: ImpactElements| {
+            if let Some(time) = time {
+                let is_earlier = match &impact {
+                    Some(impact) => time < impact.time,
+                    None => true,
+                };
+                if is_earlier {
+                    impact = Some(Impact { time, elements });
+                }
+            }
+        };
+
+        // node/face pairs, the nodes of overlapping faces
+        let face_pairs = self
+            .faces
+            .overlap::<fn(FaceIndex, FaceIndex) -> bool>(&other.faces, None)
+            .unwrap_or_default();
+        let mut node_face_pairs = HashSet::new();
+        let mut face_node_pairs = HashSet::new();
+        face_pairs.iter().for_each(|pair| {
+            face_node_indices(mesh_start, pair.index_1)
+                .into_iter()
+                .for_each(|node_index| {
+                    node_face_pairs.insert((node_index, pair.index_2));
+                });
+            face_node_indices(other_mesh_start, pair.index_2)
+                .into_iter()
+                .for_each(|node_index| {
+                    face_node_pairs.insert((pair.index_1, node_index));
+                });
+        });
+        node_face_pairs
+            .into_iter()
+            .for_each(|(node_index, face_index)| {
+                update_impact(
+                    node_face_time_of_impact(mesh, node_index, other_mesh, face_index, thickness),
+                    ImpactElements::NodeFace(node_index, face_index),
+                );
+            });
+        face_node_pairs
+            .into_iter()
+            .for_each(|(face_index, node_index)| {
+                update_impact(
+                    node_face_time_of_impact(other_mesh, node_index, mesh, face_index, thickness),
+                    ImpactElements::FaceNode(face_index, node_index),
+                );
+            });
+
+        // edge/edge pairs
+        self.edges
+            .overlap::<fn(EdgeIndex, EdgeIndex) -> bool>(&other.edges, None)
+            .unwrap_or_default()
+            .iter()
+            .for_each(|pair| {
+                let [a, b] = edge_node_indices(mesh_start, pair.index_1)
+                    .map(|node_index| swept_node_positions(mesh_start, mesh_end, node_index));
+                let [c, d] = edge_node_indices(other_mesh_start, pair.index_2).map(|node_index| {
+                    swept_node_positions(other_mesh_start, other_mesh_end, node_index)
+                });
+                update_impact(
+                    edge_edge_time_of_impact(&a, &b, &c, &d, thickness),
+                    ImpactElements::EdgeEdge(pair.index_1, pair.index_2),
+                );
+            });
+
+        impact
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::simple;
+
+    #[test]
+    fn ccd_vertex_triangle() {
+        let a = [glm::vec3(0.0, 0.0, 0.0); 2];
+        let b = [glm::vec3(1.0, 0.0, 0.0); 2];
+        let c = [glm::vec3(0.0, 1.0, 0.0); 2];
+
+        let p = [glm::vec3(0.2, 0.2, 1.0), glm::vec3(0.2, 0.2, -3.0)];
+        let t = vertex_triangle_time_of_impact(&p, &a, &b, &c, 1e-9).unwrap();
+        assert!((t - 0.25).abs() < 1e-9);
+
+        // independent of the scale of the scene
+        for scale in [1e-5, 1e5] {
+            let scaled = |co: &[glm::DVec3; 2]| [co[0] * scale, co[1] * scale];
+            let t = vertex_triangle_time_of_impact(
+                &scaled(&p),
+                &scaled(&a),
+                &scaled(&b),
+                &scaled(&c),
+                1e-9 * scale,
+            )
+            .unwrap();
+            assert!((t - 0.25).abs() < 1e-9);
+        }
+
+        // degenerate input doesn't panic
+        let nan = [glm::vec3(f64::NAN, 0.0, 0.0); 2];
+        assert!(vertex_triangle_time_of_impact(&nan, &a, &b, &c, 1e-9).is_none());
+
+        // misses the triangle
+        let p = [glm::vec3(2.0, 2.0, 1.0), glm::vec3(2.0, 2.0, -3.0)];
+        assert!(vertex_triangle_time_of_impact(&p, &a, &b, &c, 1e-9).is_none());
+
+        // triangle rotates into the static point
+        let p = [glm::vec3(0.2, 0.2, 0.5); 2];
+        let c = [glm::vec3(0.0, 1.0, 0.0), glm::vec3(0.0, 0.0, 1.0)];
+        let b = [glm::vec3(1.0, 0.0, 0.0), glm::vec3(1.0, 0.0, 1.0)];
+        let t = vertex_triangle_time_of_impact(&p, &a, &b, &c, 1e-9).unwrap();
+        assert!(t > 0.0 && t < 1.0);
+        let nearest = nearest_point_to_tri(
+            &p[0],
+            [&lerp_point(&a, t), &lerp_point(&b, t), &lerp_point(&c, t)],
+        );
+        assert!(glm::distance(&p[0], &nearest) < 1e-6);
+    }
+
+    #[test]
+    fn ccd_edge_edge() {
+        let a = [glm::vec3(-1.0, 0.0, 1.0), glm::vec3(-1.0, 0.0, -1.0)];
+        let b = [glm::vec3(1.0, 0.0, 1.0), glm::vec3(1.0, 0.0, -1.0)];
+        let c = [glm::vec3(0.0, -1.0, 0.0); 2];
+        let d = [glm::vec3(0.0, 1.0, 0.0); 2];
+        let t = edge_edge_time_of_impact(&a, &b, &c, &d, 1e-9).unwrap();
+        assert!((t - 0.5).abs() < 1e-9);
+
+        // parallel edges never meet
+        let c = [glm::vec3(-1.0, 1.0, 0.0); 2];
+        let d = [glm::vec3(1.0, 1.0, 0.0); 2];
+        assert!(edge_edge_time_of_impact(&a, &b, &c, &d, 1e-9).is_none());
+    }
+
+    fn triangle_mesh(offset: glm::DVec3) -> simple::Mesh {
+        simple::triangles_mesh(
+            &[
+                glm::vec3(0.0, 0.0, 0.0) + offset,
+                glm::vec3(1.0, 0.0, 0.0) + offset,
+                glm::vec3(0.0, 1.0, 0.0) + offset,
+            ],
+            &[[0, 1, 2]],
+        )
+    }
+
+    #[test]
+    fn ccd_mesh_swept_bvh() {
+        let mesh = triangle_mesh(glm::zero());
+        let mesh_bvh = MeshSweptBVH::new(&mesh, &mesh, 0.01, 4, 8).unwrap();
+
+        let other_start = triangle_mesh(glm::vec3(0.2, 0.2, 1.0));
+        let other_end = triangle_mesh(glm::vec3(0.2, 0.2, -1.0));
+        let mut other_bvh = MeshSweptBVH::new(&other_start, &other_end, 0.01, 4, 8).unwrap();
+
+        let impact = mesh_bvh
+            .time_of_impact(&mesh, &mesh, &other_bvh, &other_start, &other_end, 1e-6)
+            .unwrap();
+        assert!((impact.time - 0.5).abs() < 1e-9);
+
+        // moving away, no impact
+        let other_end = triangle_mesh(glm::vec3(0.2, 0.2, 2.0));
+        other_bvh.refit(&other_start, &other_end).unwrap();
+        assert!(mesh_bvh
+            .time_of_impact(&mesh, &mesh, &other_bvh, &other_start, &other_end, 1e-6)
+            .is_none());
+    }
+}
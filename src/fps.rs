@@ -3,6 +3,7 @@ pub struct FPS {
     previous_time: std::time::Instant,
     frames: usize,
     fps: f64,
+    fixed_fps: Option<f64>,
 }
 
 impl FPS {
@@ -11,13 +12,40 @@ impl FPS {
             previous_time: std::time::Instant::now(),
             frames: 0,
             fps: f64::NAN,
+            fixed_fps: None,
         }
     }
 
+    /// FPS that is independent of wall-clock time, it always reports
+    /// `fps` and never waits. Useful for offline rendering (eg:
+    /// recording) where a frame may take any amount of real time but
+    /// must advance the simulation by `1.0 / fps` seconds.
+    pub fn new_fixed(fps: f64) -> Self {
+        assert!(fps > 0.0);
+        Self {
+            fixed_fps: Some(fps),
+            ..Self::new()
+        }
+    }
+
+    /// Get the fixed fps, if the FPS was created through
+    /// [`Self::new_fixed()`].
+    pub fn get_fixed(&self) -> Option<f64> {
+        self.fixed_fps
+    }
+
     /// Update and return current fps
+    ///
+    /// `limit_fps` is ignored if the fps is fixed, see
+    /// [`Self::new_fixed()`].
     pub fn update_and_get(&mut self, limit_fps: Option<f64>) -> f64 {
         self.frames += 1;
 
+        if let Some(fixed_fps) = self.fixed_fps {
+            self.fps = fixed_fps;
+            return self.fps;
+        }
+
         if let Some(limit_fps) = limit_fps {
             let expected_time = self.frames as f64 * 1.0 / limit_fps;
             let current = std::time::Instant::now();
@@ -55,3 +83,23 @@ impl Default for FPS {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fps_fixed() {
+        let mut fps = FPS::new_fixed(24.0);
+        assert_eq!(fps.get_fixed(), Some(24.0));
+        let start = std::time::Instant::now();
+        for _ in 0..10 {
+            // the limit is ignored, no waiting
+            assert_eq!(fps.update_and_get(Some(1.0)), 24.0);
+        }
+        assert!(start.elapsed().as_secs_f64() < 1.0);
+        assert_eq!(fps.get_last_processed(), 24.0);
+
+        assert_eq!(FPS::new().get_fixed(), None);
+    }
+}
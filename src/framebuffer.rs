@@ -1,7 +1,11 @@
+use std::convert::TryInto;
+
 use gl::types::GLuint;
 
+use crate::glm;
 use crate::renderbuffer::RenderBuffer;
 use crate::texture::TextureRGBAFloat;
+use crate::util::srgb_to_linear;
 
 pub struct FrameBuffer {
     gl_framebuffer: GLuint,
@@ -58,6 +62,86 @@ impl FrameBuffer {
     }
 }
 
+/// Read back the pixels of `framebuffer` (the default framebuffer
+/// if [`None`]) from (0, 0) to (`width`, `height`) into a
+/// [`TextureRGBAFloat`] of linear values.
+///
+/// The rows are stored bottom up like the rest of the texture, see
+/// [`TextureRGBAFloat::write_png()`] and
+/// [`TextureRGBAFloat::write_exr()`] to write them top down to a
+/// file.
+///
+/// `gl::FRAMEBUFFER_SRGB` (enabled by
+/// [`crate::app::Environment::new()`]) is disabled during the read
+/// so that the stored values are read as is, then restored. Values
+/// stored in an sRGB encoded attachment are converted to linear. The
+/// default framebuffer holds the values that are displayed, so they
+/// are always treated as sRGB. The float textures used as render
+/// targets by [`FrameBuffer::activate()`] store linear values which
+/// are returned unchanged.
+///
+/// For the default framebuffer the back buffer is read, so capture
+/// after drawing but before swapping the buffers.
+pub fn capture_framebuffer(
+    framebuffer: Option<&FrameBuffer>,
+    width: usize,
+    height: usize,
+) -> TextureRGBAFloat {
+    let (gl_framebuffer, read_buffer, attachment) = match framebuffer {
+        Some(framebuffer) => (
+            framebuffer.gl_framebuffer,
+            gl::COLOR_ATTACHMENT0,
+            gl::COLOR_ATTACHMENT0,
+        ),
+        None => (0, gl::BACK, gl::BACK_LEFT),
+    };
+
+    let mut pixels = vec![glm::zero::<glm::Vec4>(); width * height];
+    let mut previous_read_framebuffer = 0;
+    let mut previous_read_buffer = 0;
+    let mut encoding = 0;
+    unsafe {
+        gl::GetIntegerv(gl::READ_FRAMEBUFFER_BINDING, &mut previous_read_framebuffer);
+        let framebuffer_srgb = gl::IsEnabled(gl::FRAMEBUFFER_SRGB) == gl::TRUE;
+
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, gl_framebuffer);
+        gl::GetIntegerv(gl::READ_BUFFER, &mut previous_read_buffer);
+        gl::ReadBuffer(read_buffer);
+        gl::GetFramebufferAttachmentParameteriv(
+            gl::READ_FRAMEBUFFER,
+            attachment,
+            gl::FRAMEBUFFER_ATTACHMENT_COLOR_ENCODING,
+            &mut encoding,
+        );
+
+        gl::Disable(gl::FRAMEBUFFER_SRGB);
+        gl::PixelStorei(gl::PACK_ALIGNMENT, 4);
+        gl::ReadPixels(
+            0,
+            0,
+            width.try_into().unwrap(),
+            height.try_into().unwrap(),
+            gl::RGBA,
+            gl::FLOAT,
+            pixels.as_mut_ptr() as *mut gl::types::GLvoid,
+        );
+
+        if framebuffer_srgb {
+            gl::Enable(gl::FRAMEBUFFER_SRGB);
+        }
+        gl::ReadBuffer(previous_read_buffer as _);
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, previous_read_framebuffer as _);
+    }
+
+    if framebuffer.is_none() || encoding == gl::SRGB as gl::types::GLint {
+        pixels.iter_mut().for_each(|pixel| {
+            *pixel = glm::convert(srgb_to_linear(&glm::convert::<_, glm::DVec4>(*pixel)));
+        });
+    }
+
+    TextureRGBAFloat::from_pixels(width, height, pixels)
+}
+
 impl Default for FrameBuffer {
     fn default() -> Self {
         Self::new()
@@ -71,3 +155,39 @@ impl Drop for FrameBuffer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::{expect_test_context, HeadlessContext};
+
+    #[test]
+    #[ignore = "needs libEGL with surfaceless support, run with cargo test -- --ignored"]
+    fn framebuffer_capture() {
+        let mut context = expect_test_context(HeadlessContext::new((4, 3), (3, 3)));
+
+        context.activate_render_target();
+        unsafe {
+            gl::Enable(gl::FRAMEBUFFER_SRGB);
+            gl::ClearColor(0.25, 0.5, 0.75, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+            // top row only
+            gl::Enable(gl::SCISSOR_TEST);
+            gl::Scissor(0, 2, 4, 1);
+            gl::ClearColor(1.0, 0.0, 0.0, 0.5);
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+            gl::Disable(gl::SCISSOR_TEST);
+        }
+
+        let texture = capture_framebuffer(Some(context.get_render_target()), 4, 3);
+        unsafe {
+            assert_eq!(gl::IsEnabled(gl::FRAMEBUFFER_SRGB), gl::TRUE);
+        }
+        assert_eq!(texture.get_width(), 4);
+        assert_eq!(texture.get_height(), 3);
+        // float render target stores linear values, bottom row first
+        assert_eq!(*texture.get_pixel(0, 0), glm::vec4(0.25, 0.5, 0.75, 1.0));
+        assert_eq!(*texture.get_pixel(3, 1), glm::vec4(0.25, 0.5, 0.75, 1.0));
+        assert_eq!(*texture.get_pixel(2, 2), glm::vec4(1.0, 0.0, 0.0, 0.5));
+    }
+}
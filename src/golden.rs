@@ -0,0 +1,626 @@
+//! Golden image regression testing, compares rendered images against
+//! stored reference images.
+//!
+//! Images are compared as 8 bit sRGB (what is written to the PNG
+//! references). A pixel fails if any channel differs by more than
+//! [`GoldenSettings::tolerance`] and the perceptual difference (CIE76
+//! Delta E in CIELAB) is more than
+//! [`GoldenSettings::perceptual_threshold`], so small rasterization
+//! differences between drivers do not fail the comparison but
+//! visible changes do. On failure the rendered image and a diff image
+//! are written for inspection.
+//!
+//! References are rendered with Mesa's llvmpipe through
+//! [`new_software_context()`], on machines with a GPU the tests must
+//! be run with `LIBGL_ALWAYS_SOFTWARE=1`. The tests are ignored by
+//! default, CI runs them with
+//! `LIBGL_ALWAYS_SOFTWARE=1 cargo test -- --ignored`. To create or
+//! update the references, run the tests with the [`UPDATE_ENV_VAR`]
+//! environment variable set.
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use crate::glm;
+use crate::headless::{self, HeadlessContext};
+use crate::texture::{TextureRGBAFloat, TextureSaveError};
+use crate::util::srgb_to_linear;
+
+/// When set (to anything but empty), [`check_golden()`] writes the
+/// reference images instead of comparing against them.
+pub const UPDATE_ENV_VAR: &str = "QUICK_RENDERER_UPDATE_GOLDEN";
+
+#[derive(Debug)]
+pub enum GoldenError {
+    /// Headless context could not be created.
+    Context(headless::Error),
+    /// Context does not render with llvmpipe, contains the
+    /// `GL_RENDERER` string.
+    NotSoftwareRenderer(String),
+    /// Reference image doesn't exist, see [`UPDATE_ENV_VAR`].
+    MissingReference(PathBuf),
+    /// Reference image cannot be read.
+    Image(image::ImageError),
+    /// Writing the reference, result or diff image failed.
+    Save(TextureSaveError),
+    DimensionMismatch {
+        reference: (usize, usize),
+        result: (usize, usize),
+    },
+    /// Too many pixels differ, the result and diff images are written
+    /// to `result` and `diff`.
+    Mismatch {
+        failing_pixels: usize,
+        total_pixels: usize,
+        max_perceptual_difference: f64,
+        result: PathBuf,
+        diff: PathBuf,
+    },
+}
+
+impl From<headless::Error> for GoldenError {
+    fn from(err: headless::Error) -> Self {
+        GoldenError::Context(err)
+    }
+}
+
+impl From<image::ImageError> for GoldenError {
+    fn from(err: image::ImageError) -> Self {
+        GoldenError::Image(err)
+    }
+}
+
+impl From<TextureSaveError> for GoldenError {
+    fn from(err: TextureSaveError) -> Self {
+        GoldenError::Save(err)
+    }
+}
+
+impl Display for GoldenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GoldenError::Context(err) => write!(f, "context error {}", err),
+            GoldenError::NotSoftwareRenderer(renderer) => write!(
+                f,
+                "renderer is {} not llvmpipe, set LIBGL_ALWAYS_SOFTWARE=1",
+                renderer
+            ),
+            GoldenError::MissingReference(path) => write!(
+                f,
+                "missing reference {}, set {} to create it",
+                path.display(),
+                UPDATE_ENV_VAR
+            ),
+            GoldenError::Image(err) => write!(f, "reference image error {}", err),
+            GoldenError::Save(err) => write!(f, "save error {}", err),
+            GoldenError::DimensionMismatch { reference, result } => write!(
+                f,
+                "reference is {}x{} but result is {}x{}",
+                reference.0, reference.1, result.0, result.1
+            ),
+            GoldenError::Mismatch {
+                failing_pixels,
+                total_pixels,
+                max_perceptual_difference,
+                result,
+                diff,
+            } => write!(
+                f,
+                "{} of {} pixels differ (max delta E {:.2}), result: {}, diff: {}",
+                failing_pixels,
+                total_pixels,
+                max_perceptual_difference,
+                result.display(),
+                diff.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GoldenError {}
+
+/// Settings for the golden image comparison.
+#[derive(Debug, Clone)]
+pub struct GoldenSettings {
+    /// Per channel tolerance of the 8 bit sRGB values (alpha
+    /// included).
+    pub tolerance: u8,
+    /// Perceptual difference (CIE76 Delta E) above which a pixel
+    /// that is outside [`Self::tolerance`] fails, around 2.3 is just
+    /// noticeable.
+    pub perceptual_threshold: f64,
+    /// Fraction of the pixels that may fail before the comparison
+    /// fails.
+    pub max_failing_fraction: f64,
+}
+
+impl GoldenSettings {
+    /// Default [`Self::tolerance`].
+    pub const DEFAULT_TOLERANCE: u8 = 2;
+    /// Default [`Self::perceptual_threshold`].
+    pub const DEFAULT_PERCEPTUAL_THRESHOLD: f64 = 2.3;
+    /// Default [`Self::max_failing_fraction`].
+    pub const DEFAULT_MAX_FAILING_FRACTION: f64 = 0.001;
+}
+
+impl Default for GoldenSettings {
+    fn default() -> Self {
+        Self {
+            tolerance: Self::DEFAULT_TOLERANCE,
+            perceptual_threshold: Self::DEFAULT_PERCEPTUAL_THRESHOLD,
+            max_failing_fraction: Self::DEFAULT_MAX_FAILING_FRACTION,
+        }
+    }
+}
+
+impl GoldenSettings {
+    /// Set [`Self::tolerance`].
+    pub fn tolerance(mut self, tolerance: u8) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Set [`Self::perceptual_threshold`].
+    pub fn perceptual_threshold(mut self, perceptual_threshold: f64) -> Self {
+        self.perceptual_threshold = perceptual_threshold;
+        self
+    }
+
+    /// Set [`Self::max_failing_fraction`].
+    pub fn max_failing_fraction(mut self, max_failing_fraction: f64) -> Self {
+        self.max_failing_fraction = max_failing_fraction;
+        self
+    }
+}
+
+/// Result of [`compare()`].
+pub struct GoldenComparison {
+    /// Largest per channel difference of the 8 bit sRGB values.
+    pub max_difference: u8,
+    /// Largest perceptual difference (CIE76 Delta E).
+    pub max_perceptual_difference: f64,
+    /// Number of pixels that failed.
+    pub failing_pixels: usize,
+    pub total_pixels: usize,
+    /// Is the number of failing pixels within
+    /// [`GoldenSettings::max_failing_fraction`]?
+    pub passed: bool,
+    /// Diff image, failing pixels are red, pixels outside the
+    /// tolerance that are not perceptibly different are yellow, the
+    /// rest is the dimmed reference.
+    pub diff: TextureRGBAFloat,
+}
+
+/// Create a headless context that renders through Mesa's software
+/// rasterizer (llvmpipe), the renderer the references are made with.
+///
+/// Mesa picks the hardware driver when there is one, set
+/// `LIBGL_ALWAYS_SOFTWARE=1` in the environment of the process (eg:
+/// on CI) to force llvmpipe. Returns
+/// [`GoldenError::NotSoftwareRenderer`] if the context is not using
+/// llvmpipe.
+pub fn new_software_context(dimensions: (usize, usize)) -> Result<HeadlessContext, GoldenError> {
+    let context = HeadlessContext::new(dimensions, (3, 3))?;
+    let renderer = unsafe {
+        let renderer = gl::GetString(gl::RENDERER);
+        if renderer.is_null() {
+            String::new()
+        } else {
+            std::ffi::CStr::from_ptr(renderer as *const std::os::raw::c_char)
+                .to_string_lossy()
+                .into_owned()
+        }
+    };
+    if !renderer.contains("llvmpipe") {
+        return Err(GoldenError::NotSoftwareRenderer(renderer));
+    }
+    Ok(context)
+}
+
+/// Convert an 8 bit sRGB color to CIELAB (D65 white point).
+///
+/// reference: <https://en.wikipedia.org/wiki/CIELAB_color_space>
+fn srgb8_to_lab(srgb: &[u8]) -> glm::DVec3 {
+    let linear = srgb_to_linear(&glm::vec3(
+        srgb[0] as f64 / 255.0,
+        srgb[1] as f64 / 255.0,
+        srgb[2] as f64 / 255.0,
+    ));
+    let xyz = glm::mat3(
+        0.4124, 0.3576, 0.1805, //
+        0.2126, 0.7152, 0.0722, //
+        0.0193, 0.1192, 0.9505,
+    ) * linear;
+    let white = glm::vec3(0.95047, 1.0, 1.08883);
+
+    let f = |t: f64| {
+        let delta: f64 = 6.0 / 29.0;
+        if t > delta.powi(3) {
+            t.cbrt()
+        } else {
+            t / (3.0 * delta * delta) + 4.0 / 29.0
+        }
+    };
+    let fx = f(xyz[0] / white[0]);
+    let fy = f(xyz[1] / white[1]);
+    let fz = f(xyz[2] / white[2]);
+
+    glm::vec3(116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+/// Compare `result` against `reference` (both top row first 8 bit
+/// sRGB RGBA of `width` x `height`).
+fn compare_srgb8(
+    reference: &[u8],
+    result: &[u8],
+    width: usize,
+    height: usize,
+    settings: &GoldenSettings,
+) -> GoldenComparison {
+    assert_eq!(reference.len(), width * height * 4);
+    assert_eq!(result.len(), width * height * 4);
+
+    let mut max_difference = 0;
+    let mut max_perceptual_difference: f64 = 0.0;
+    let mut failing_pixels = 0;
+
+    let diff_pixels: Vec<glm::Vec4> = reference
+        .chunks(4)
+        .zip(result.chunks(4))
+        .map(|(reference, result)| {
+            let difference = reference
+                .iter()
+                .zip(result.iter())
+                .map(|(reference, result)| reference.abs_diff(*result))
+                .max()
+                .unwrap();
+            max_difference = max_difference.max(difference);
+
+            if difference <= settings.tolerance {
+                let linear = srgb_to_linear(&glm::vec3(
+                    reference[0] as f64 / 255.0,
+                    reference[1] as f64 / 255.0,
+                    reference[2] as f64 / 255.0,
+                ));
+                let luminance = glm::dot(&linear, &glm::vec3(0.2126, 0.7152, 0.0722)) as f32;
+                return glm::vec4(luminance, luminance, luminance, 1.0) * 0.25;
+            }
+
+            let perceptual_difference =
+                glm::distance(&srgb8_to_lab(reference), &srgb8_to_lab(result));
+            max_perceptual_difference = max_perceptual_difference.max(perceptual_difference);
+
+            // alpha is not part of the perceptual difference
+            let alpha_difference = reference[3].abs_diff(result[3]);
+            if perceptual_difference > settings.perceptual_threshold
+                || alpha_difference > settings.tolerance
+            {
+                failing_pixels += 1;
+                glm::vec4(1.0, 0.0, 0.0, 1.0)
+            } else {
+                glm::vec4(1.0, 1.0, 0.0, 1.0)
+            }
+        })
+        .collect();
+
+    // the texture is stored bottom row first
+    let diff_pixels = diff_pixels
+        .chunks(width.max(1))
+        .rev()
+        .flatten()
+        .copied()
+        .collect();
+
+    let total_pixels = width * height;
+    GoldenComparison {
+        max_difference,
+        max_perceptual_difference,
+        failing_pixels,
+        total_pixels,
+        passed: failing_pixels as f64 <= settings.max_failing_fraction * total_pixels as f64,
+        diff: TextureRGBAFloat::from_pixels(width, height, diff_pixels),
+    }
+}
+
+/// Compare `result` (linear values, as returned by
+/// [`crate::framebuffer::capture_framebuffer()`]) against
+/// `reference`, see module documentation.
+pub fn compare(
+    reference: &TextureRGBAFloat,
+    result: &TextureRGBAFloat,
+    settings: &GoldenSettings,
+) -> Result<GoldenComparison, GoldenError> {
+    let reference_dimensions = (reference.get_width(), reference.get_height());
+    let result_dimensions = (result.get_width(), result.get_height());
+    if reference_dimensions != result_dimensions {
+        return Err(GoldenError::DimensionMismatch {
+            reference: reference_dimensions,
+            result: result_dimensions,
+        });
+    }
+
+    Ok(compare_srgb8(
+        &reference.to_srgb8(),
+        &result.to_srgb8(),
+        result.get_width(),
+        result.get_height(),
+        settings,
+    ))
+}
+
+/// Compare `result` (linear values) against the PNG reference image
+/// at `reference_path`, see module documentation.
+pub fn compare_with_reference<P: AsRef<Path>>(
+    reference_path: P,
+    result: &TextureRGBAFloat,
+    settings: &GoldenSettings,
+) -> Result<GoldenComparison, GoldenError> {
+    let reference_path = reference_path.as_ref();
+    if !reference_path.exists() {
+        return Err(GoldenError::MissingReference(reference_path.to_path_buf()));
+    }
+
+    // read the 8 bit values directly, no conversion to float and back
+    let reference = image::open(reference_path)?.to_rgba8();
+    let reference_dimensions = (reference.width() as usize, reference.height() as usize);
+    let result_dimensions = (result.get_width(), result.get_height());
+    if reference_dimensions != result_dimensions {
+        return Err(GoldenError::DimensionMismatch {
+            reference: reference_dimensions,
+            result: result_dimensions,
+        });
+    }
+
+    Ok(compare_srgb8(
+        reference.as_raw(),
+        &result.to_srgb8(),
+        result.get_width(),
+        result.get_height(),
+        settings,
+    ))
+}
+
+/// Compare `result` against the reference image
+/// `{reference_directory}/{name}.png`.
+///
+/// On failure, `{output_directory}/{name}.png` and
+/// `{output_directory}/{name}_diff.png` are written. If
+/// [`UPDATE_ENV_VAR`] is set the reference is written instead.
+pub fn check_golden<P, Q>(
+    name: &str,
+    result: &TextureRGBAFloat,
+    reference_directory: P,
+    output_directory: Q,
+    settings: &GoldenSettings,
+) -> Result<GoldenComparison, GoldenError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let reference_path = reference_directory.as_ref().join(format!("{}.png", name));
+
+    if matches!(std::env::var_os(UPDATE_ENV_VAR), Some(value) if !value.is_empty()) {
+        std::fs::create_dir_all(reference_directory.as_ref()).map_err(TextureSaveError::from)?;
+        result.save_to_disk(&reference_path)?;
+    }
+
+    let comparison = compare_with_reference(&reference_path, result, settings)?;
+
+    if !comparison.passed {
+        std::fs::create_dir_all(output_directory.as_ref()).map_err(TextureSaveError::from)?;
+        let result_path = output_directory.as_ref().join(format!("{}.png", name));
+        let diff_path = output_directory.as_ref().join(format!("{}_diff.png", name));
+        result.save_to_disk(&result_path)?;
+        comparison.diff.save_to_disk(&diff_path)?;
+        return Err(GoldenError::Mismatch {
+            failing_pixels: comparison.failing_pixels,
+            total_pixels: comparison.total_pixels,
+            max_perceptual_difference: comparison.max_perceptual_difference,
+            result: result_path,
+            diff: diff_path,
+        });
+    }
+
+    Ok(comparison)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::camera::Camera;
+    use crate::drawable::Drawable;
+    use crate::framebuffer::capture_framebuffer;
+    use crate::gpu_immediate::GPUImmediate;
+    use crate::gpu_utils::draw_smooth_sphere_at;
+    use crate::headless::expect_test_context;
+    use crate::infinite_grid::{InfiniteGrid, InfiniteGridDrawData};
+    use crate::mesh::{builtins, MeshDrawData, MeshUseShader};
+    use crate::shader;
+
+    #[test]
+    fn golden_compare() {
+        let reference = TextureRGBAFloat::from_pixels(4, 2, vec![glm::vec4(0.2, 0.4, 0.6, 1.0); 8]);
+        let settings = GoldenSettings::default().max_failing_fraction(0.0);
+
+        let comparison = compare(&reference, &reference, &settings).unwrap();
+        assert!(comparison.passed);
+        assert_eq!(comparison.max_difference, 0);
+        assert_eq!(comparison.failing_pixels, 0);
+
+        // barely different pixel is within the perceptual threshold,
+        // top right pixel is clearly different
+        let mut result =
+            TextureRGBAFloat::from_pixels(4, 2, vec![glm::vec4(0.2, 0.4, 0.6, 1.0); 8]);
+        result.set_pixel(0, 0, glm::vec4(0.2, 0.4, 0.62, 1.0));
+        let comparison = compare(&reference, &result, &settings).unwrap();
+        assert!(comparison.passed);
+        assert!(comparison.max_difference > settings.tolerance);
+        assert!(comparison.max_perceptual_difference < settings.perceptual_threshold);
+        assert_eq!(
+            *comparison.diff.get_pixel(0, 0),
+            glm::vec4(1.0, 1.0, 0.0, 1.0)
+        );
+
+        result.set_pixel(3, 1, glm::vec4(0.8, 0.4, 0.6, 1.0));
+        let comparison = compare(&reference, &result, &settings).unwrap();
+        assert!(!comparison.passed);
+        assert_eq!(comparison.failing_pixels, 1);
+        assert_eq!(
+            *comparison.diff.get_pixel(3, 1),
+            glm::vec4(1.0, 0.0, 0.0, 1.0)
+        );
+        assert!(
+            compare(&reference, &result, &settings.max_failing_fraction(0.2))
+                .unwrap()
+                .passed
+        );
+
+        let small = TextureRGBAFloat::from_pixels(2, 2, vec![glm::zero(); 4]);
+        assert!(matches!(
+            compare(&reference, &small, &GoldenSettings::default()),
+            Err(GoldenError::DimensionMismatch { .. })
+        ));
+    }
+
+    /// Render the scene drawn by `draw` with `camera` and capture it.
+    fn render_scene<F>(
+        context: &mut HeadlessContext,
+        camera: &Camera,
+        imm: &Rc<RefCell<GPUImmediate>>,
+        draw: F,
+    ) -> TextureRGBAFloat
+    where
+        F: FnOnce(&Rc<RefCell<GPUImmediate>>),
+    {
+        let (width, height) = context.get_dimensions();
+        context.activate_render_target();
+        unsafe {
+            gl::Disable(gl::BLEND);
+            gl::ClearColor(0.1, 0.1, 0.1, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        }
+        shader::builtins::setup_shaders(camera, width, height);
+
+        draw(imm);
+
+        capture_framebuffer(Some(context.get_render_target()), width, height)
+    }
+
+    #[test]
+    #[ignore = "needs llvmpipe, run with LIBGL_ALWAYS_SOFTWARE=1 cargo test -- --ignored"]
+    fn golden_builtin_shaders() {
+        // the builtin shaders are created for the first context that
+        // uses them, so all the scenes must be rendered with the same
+        // context
+        let mut context = expect_test_context(new_software_context((128, 128)));
+
+        let reference_directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        let output_directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("target/golden");
+        let settings = GoldenSettings::default();
+
+        let imm = Rc::new(RefCell::new(GPUImmediate::new()));
+        let camera = Camera::new(
+            glm::vec3(0.0, 0.0, 3.0),
+            glm::vec3(0.0, 1.0, 0.0),
+            -90.0,
+            0.0,
+            45.0,
+            None,
+        );
+        let grid_camera = Camera::new(
+            glm::vec3(0.0, 1.5, 4.0),
+            glm::vec3(0.0, 1.0, 0.0),
+            -90.0,
+            -20.0,
+            45.0,
+            None,
+        );
+
+        let draw_mesh = |use_shader, color| {
+            move |imm: &Rc<RefCell<GPUImmediate>>| {
+                builtins::get_monkey_subd_01()
+                    .draw(&MeshDrawData::new(imm.clone(), use_shader, color))
+                    .unwrap();
+            }
+        };
+
+        let scenes: Vec<(&str, TextureRGBAFloat)> = vec![
+            (
+                "directional_light",
+                render_scene(
+                    &mut context,
+                    &camera,
+                    &imm,
+                    draw_mesh(MeshUseShader::DirectionalLight, None),
+                ),
+            ),
+            (
+                "smooth_color_3d",
+                render_scene(
+                    &mut context,
+                    &camera,
+                    &imm,
+                    draw_mesh(
+                        MeshUseShader::SmoothColor3D,
+                        Some(glm::vec4(1.0, 0.2, 0.5, 1.0)),
+                    ),
+                ),
+            ),
+            (
+                "face_orientation",
+                render_scene(
+                    &mut context,
+                    &camera,
+                    &imm,
+                    draw_mesh(MeshUseShader::FaceOrientation, None),
+                ),
+            ),
+            (
+                "infinite_grid",
+                render_scene(&mut context, &grid_camera, &imm, |imm| {
+                    unsafe {
+                        gl::Enable(gl::BLEND);
+                        gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+                    }
+                    InfiniteGrid::default()
+                        .draw(&InfiniteGridDrawData::new(
+                            imm.clone(),
+                            glm::vec4(0.2, 0.2, 0.2, 1.0),
+                        ))
+                        .unwrap();
+                }),
+            ),
+            (
+                "smooth_sphere",
+                render_scene(&mut context, &camera, &imm, |imm| {
+                    draw_smooth_sphere_at(
+                        glm::vec3(0.0, 0.0, 0.0),
+                        0.8,
+                        glm::vec4(1.0, 0.6, 0.2, 1.0),
+                        glm::vec4(0.2, 0.6, 1.0, 1.0),
+                        &mut imm.borrow_mut(),
+                    );
+                }),
+            ),
+        ];
+
+        let failures: Vec<String> = scenes
+            .iter()
+            .filter_map(|(name, result)| {
+                check_golden(
+                    name,
+                    result,
+                    &reference_directory,
+                    &output_directory,
+                    &settings,
+                )
+                .err()
+                .map(|err| format!("{}: {}", name, err))
+            })
+            .collect();
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+}
@@ -0,0 +1,483 @@
+//! Headless OpenGL context that does not need a window or a display
+//! server, for rendering on CI or GPU-less render servers.
+//!
+//! The context is created through surfaceless EGL (works with Mesa's
+//! llvmpipe), `libEGL` is loaded at runtime so there is no link time
+//! dependency on it. An [`crate::app::App`] can be run on this
+//! context with [`crate::app::EnvironmentSettings::surfaceless`].
+//! When a display server is available,
+//! [`crate::app::EnvironmentSettings::headless`] can be used instead
+//! to run it with an invisible window.
+//!
+//! There is no default framebuffer without a window, so the context
+//! comes with a render target [`FrameBuffer`] of the requested size
+//! that is bound after creation, see
+//! [`HeadlessContext::activate_render_target()`].
+
+use std::fmt::Display;
+
+use crate::framebuffer::FrameBuffer;
+use crate::renderbuffer::RenderBuffer;
+use crate::texture::TextureRGBAFloat;
+
+#[derive(Debug)]
+pub enum Error {
+    /// `libEGL` could not be loaded.
+    LibraryNotFound,
+    /// `libEGL` doesn't provide the function.
+    MissingFunction(&'static str),
+    NoDisplay,
+    Initialize,
+    BindApi,
+    ChooseConfig,
+    ContextCreation,
+    MakeCurrent,
+    /// Headless contexts are not supported on this platform.
+    Unsupported,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::LibraryNotFound => write!(f, "EGL library not found"),
+            Error::MissingFunction(name) => write!(f, "EGL function {} not found", name),
+            Error::NoDisplay => write!(f, "No EGL display"),
+            Error::Initialize => write!(f, "EGL initialization"),
+            Error::BindApi => write!(f, "EGL bind OpenGL API"),
+            Error::ChooseConfig => write!(f, "No EGL config for OpenGL"),
+            Error::ContextCreation => write!(f, "EGL context creation"),
+            Error::MakeCurrent => write!(f, "EGL make current"),
+            Error::Unsupported => write!(f, "Headless context not supported on this platform"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Headless OpenGL context, see module documentation.
+///
+/// The context is current on the thread that created it, OpenGL
+/// calls must be made from that thread.
+pub struct HeadlessContext {
+    // field order matters, the render target must be dropped while
+    // the context still exists
+    render_target: FrameBuffer,
+    texture: TextureRGBAFloat,
+    renderbuffer: RenderBuffer,
+    dimensions: (usize, usize),
+    egl: egl::Context,
+}
+
+impl HeadlessContext {
+    /// Create a new headless context with an OpenGL core profile of
+    /// at least `context_version`, loads the `gl` function pointers
+    /// and binds a render target of `dimensions` (width, height).
+    pub fn new(dimensions: (usize, usize), context_version: (u32, u32)) -> Result<Self, Error> {
+        let egl = egl::Context::new(context_version)?;
+
+        gl::load_with(|symbol| egl.get_proc_address(symbol));
+
+        // same state as `Environment::new()`
+        unsafe {
+            gl::Disable(gl::CULL_FACE);
+            gl::Enable(gl::DEPTH_TEST);
+            gl::Enable(gl::MULTISAMPLE);
+            gl::Enable(gl::FRAMEBUFFER_SRGB);
+        }
+
+        let mut texture = TextureRGBAFloat::new_empty(dimensions.0, dimensions.1);
+        let renderbuffer = RenderBuffer::new(dimensions.0, dimensions.1);
+        let render_target = FrameBuffer::new();
+        render_target.activate(&mut texture, &renderbuffer);
+        unsafe {
+            gl::Viewport(0, 0, dimensions.0 as _, dimensions.1 as _);
+        }
+
+        Ok(Self {
+            render_target,
+            texture,
+            renderbuffer,
+            dimensions,
+            egl,
+        })
+    }
+
+    /// Make the context current on the calling thread.
+    pub fn make_current(&self) -> Result<(), Error> {
+        self.egl.make_current()
+    }
+
+    /// Bind the render target, takes the place of the default
+    /// framebuffer ([`FrameBuffer::activiate_default()`]) of a
+    /// window.
+    pub fn activate_render_target(&mut self) {
+        self.render_target
+            .activate(&mut self.texture, &self.renderbuffer);
+    }
+
+    /// Get the render target.
+    pub fn get_render_target(&self) -> &FrameBuffer {
+        &self.render_target
+    }
+
+    /// Get the color texture of the render target.
+    pub fn get_render_target_texture(&mut self) -> &mut TextureRGBAFloat {
+        &mut self.texture
+    }
+
+    /// Width and height of the render target.
+    pub fn get_dimensions(&self) -> (usize, usize) {
+        self.dimensions
+    }
+}
+
+#[cfg(unix)]
+mod egl {
+    use std::ffi::{c_void, CString};
+    use std::os::raw::c_char;
+
+    use super::Error;
+
+    type EGLDisplay = *mut c_void;
+    type EGLConfig = *mut c_void;
+    type EGLContext = *mut c_void;
+    type EGLSurface = *mut c_void;
+    type EGLint = i32;
+    type EGLBoolean = u32;
+    type EGLenum = u32;
+
+    const EGL_FALSE: EGLBoolean = 0;
+    const EGL_NONE: EGLint = 0x3038;
+    const EGL_SURFACE_TYPE: EGLint = 0x3033;
+    const EGL_PBUFFER_BIT: EGLint = 0x0001;
+    const EGL_RENDERABLE_TYPE: EGLint = 0x3040;
+    const EGL_OPENGL_BIT: EGLint = 0x0008;
+    const EGL_OPENGL_API: EGLenum = 0x30A2;
+    const EGL_CONTEXT_MAJOR_VERSION: EGLint = 0x3098;
+    const EGL_CONTEXT_MINOR_VERSION: EGLint = 0x30FB;
+    const EGL_CONTEXT_OPENGL_PROFILE_MASK: EGLint = 0x30FD;
+    const EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT: EGLint = 0x0001;
+    const EGL_PLATFORM_SURFACELESS_MESA: EGLenum = 0x31DD;
+
+    type GetProcAddress = unsafe extern "C" fn(*const c_char) -> *const c_void;
+    type GetDisplay = unsafe extern "C" fn(*mut c_void) -> EGLDisplay;
+    type GetPlatformDisplay =
+        unsafe extern "C" fn(EGLenum, *mut c_void, *const EGLint) -> EGLDisplay;
+    type Initialize = unsafe extern "C" fn(EGLDisplay, *mut EGLint, *mut EGLint) -> EGLBoolean;
+    type Terminate = unsafe extern "C" fn(EGLDisplay) -> EGLBoolean;
+    type BindApi = unsafe extern "C" fn(EGLenum) -> EGLBoolean;
+    type ChooseConfig = unsafe extern "C" fn(
+        EGLDisplay,
+        *const EGLint,
+        *mut EGLConfig,
+        EGLint,
+        *mut EGLint,
+    ) -> EGLBoolean;
+    type CreateContext =
+        unsafe extern "C" fn(EGLDisplay, EGLConfig, EGLContext, *const EGLint) -> EGLContext;
+    type DestroyContext = unsafe extern "C" fn(EGLDisplay, EGLContext) -> EGLBoolean;
+    type MakeCurrent =
+        unsafe extern "C" fn(EGLDisplay, EGLSurface, EGLSurface, EGLContext) -> EGLBoolean;
+
+    /// `libEGL` loaded at runtime.
+    struct Library {
+        handle: *mut c_void,
+        get_proc_address: GetProcAddress,
+        get_display: GetDisplay,
+        initialize: Initialize,
+        terminate: Terminate,
+        bind_api: BindApi,
+        choose_config: ChooseConfig,
+        create_context: CreateContext,
+        destroy_context: DestroyContext,
+        make_current: MakeCurrent,
+    }
+
+    impl Library {
+        fn load() -> Result<Self, Error> {
+            let handle = ["libEGL.so.1\0", "libEGL.so\0"]
+                .iter()
+                .map(|name| unsafe { libc::dlopen(name.as_ptr() as *const c_char, libc::RTLD_NOW) })
+                .find(|handle| !handle.is_null())
+                .ok_or(Error::LibraryNotFound)?;
+
+            let symbol = |name: &'static str| {
+                let ptr = unsafe { libc::dlsym(handle, name.as_ptr() as *const c_char) };
+                if ptr.is_null() {
+                    unsafe { libc::dlclose(handle) };
+                    Err(Error::MissingFunction(name.trim_end_matches('\0')))
+                } else {
+                    Ok(ptr)
+                }
+            };
+
+            unsafe {
+                Ok(Self {
+                    get_proc_address: std::mem::transmute::<*mut c_void, GetProcAddress>(symbol(
+                        "eglGetProcAddress\0",
+                    )?),
+                    get_display: std::mem::transmute::<*mut c_void, GetDisplay>(symbol(
+                        "eglGetDisplay\0",
+                    )?),
+                    initialize: std::mem::transmute::<*mut c_void, Initialize>(symbol(
+                        "eglInitialize\0",
+                    )?),
+                    terminate: std::mem::transmute::<*mut c_void, Terminate>(symbol(
+                        "eglTerminate\0",
+                    )?),
+                    bind_api: std::mem::transmute::<*mut c_void, BindApi>(symbol("eglBindAPI\0")?),
+                    choose_config: std::mem::transmute::<*mut c_void, ChooseConfig>(symbol(
+                        "eglChooseConfig\0",
+                    )?),
+                    create_context: std::mem::transmute::<*mut c_void, CreateContext>(symbol(
+                        "eglCreateContext\0",
+                    )?),
+                    destroy_context: std::mem::transmute::<*mut c_void, DestroyContext>(symbol(
+                        "eglDestroyContext\0",
+                    )?),
+                    make_current: std::mem::transmute::<*mut c_void, MakeCurrent>(symbol(
+                        "eglMakeCurrent\0",
+                    )?),
+                    handle,
+                })
+            }
+        }
+
+        fn get_proc_address(&self, name: &str) -> *const c_void {
+            let name = CString::new(name).unwrap();
+            unsafe { (self.get_proc_address)(name.as_ptr()) }
+        }
+    }
+
+    impl Drop for Library {
+        fn drop(&mut self) {
+            unsafe {
+                libc::dlclose(self.handle);
+            }
+        }
+    }
+
+    /// Surfaceless EGL context.
+    pub(super) struct Context {
+        display: EGLDisplay,
+        context: EGLContext,
+        library: Library,
+    }
+
+    impl Context {
+        pub(super) fn new(context_version: (u32, u32)) -> Result<Self, Error> {
+            let library = Library::load()?;
+
+            // prefer the surfaceless platform, it doesn't need any
+            // display server, the default display may need one
+            let display = {
+                let get_platform_display = library.get_proc_address("eglGetPlatformDisplayEXT");
+                let display = if get_platform_display.is_null() {
+                    std::ptr::null_mut()
+                } else {
+                    unsafe {
+                        let get_platform_display = std::mem::transmute::<
+                            *const c_void,
+                            GetPlatformDisplay,
+                        >(get_platform_display);
+                        get_platform_display(
+                            EGL_PLATFORM_SURFACELESS_MESA,
+                            std::ptr::null_mut(),
+                            std::ptr::null(),
+                        )
+                    }
+                };
+                if display.is_null() {
+                    unsafe { (library.get_display)(std::ptr::null_mut()) }
+                } else {
+                    display
+                }
+            };
+            if display.is_null() {
+                return Err(Error::NoDisplay);
+            }
+
+            let (mut major, mut minor) = (0, 0);
+            if unsafe { (library.initialize)(display, &mut major, &mut minor) } == EGL_FALSE {
+                return Err(Error::Initialize);
+            }
+
+            // from here on the display must be terminated on error
+            let context = Self {
+                display,
+                context: std::ptr::null_mut(),
+                library,
+            };
+            context.create_context(context_version)
+        }
+
+        fn create_context(mut self, context_version: (u32, u32)) -> Result<Self, Error> {
+            let library = &self.library;
+            if unsafe { (library.bind_api)(EGL_OPENGL_API) } == EGL_FALSE {
+                return Err(Error::BindApi);
+            }
+
+            // surfaceless displays have no window configs, which is
+            // the default surface type
+            let config_attribs = [
+                EGL_SURFACE_TYPE,
+                EGL_PBUFFER_BIT,
+                EGL_RENDERABLE_TYPE,
+                EGL_OPENGL_BIT,
+                EGL_NONE,
+            ];
+            let mut config = std::ptr::null_mut();
+            let mut num_configs = 0;
+            let res = unsafe {
+                (library.choose_config)(
+                    self.display,
+                    config_attribs.as_ptr(),
+                    &mut config,
+                    1,
+                    &mut num_configs,
+                )
+            };
+            if res == EGL_FALSE || num_configs == 0 {
+                return Err(Error::ChooseConfig);
+            }
+
+            let context_attribs = [
+                EGL_CONTEXT_MAJOR_VERSION,
+                context_version.0 as EGLint,
+                EGL_CONTEXT_MINOR_VERSION,
+                context_version.1 as EGLint,
+                EGL_CONTEXT_OPENGL_PROFILE_MASK,
+                EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT,
+                EGL_NONE,
+            ];
+            self.context = unsafe {
+                (library.create_context)(
+                    self.display,
+                    config,
+                    std::ptr::null_mut(),
+                    context_attribs.as_ptr(),
+                )
+            };
+            if self.context.is_null() {
+                return Err(Error::ContextCreation);
+            }
+
+            self.make_current()?;
+
+            Ok(self)
+        }
+
+        pub(super) fn make_current(&self) -> Result<(), Error> {
+            let res = unsafe {
+                (self.library.make_current)(
+                    self.display,
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut(),
+                    self.context,
+                )
+            };
+            if res == EGL_FALSE {
+                Err(Error::MakeCurrent)
+            } else {
+                Ok(())
+            }
+        }
+
+        pub(super) fn get_proc_address(&self, name: &str) -> *const c_void {
+            self.library.get_proc_address(name)
+        }
+    }
+
+    impl Drop for Context {
+        fn drop(&mut self) {
+            let library = &self.library;
+            unsafe {
+                (library.make_current)(
+                    self.display,
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut(),
+                );
+                if !self.context.is_null() {
+                    (library.destroy_context)(self.display, self.context);
+                }
+                (library.terminate)(self.display);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod egl {
+    use std::ffi::c_void;
+
+    use super::Error;
+
+    pub(super) struct Context;
+
+    impl Context {
+        pub(super) fn new(_context_version: (u32, u32)) -> Result<Self, Error> {
+            Err(Error::Unsupported)
+        }
+
+        pub(super) fn make_current(&self) -> Result<(), Error> {
+            Err(Error::Unsupported)
+        }
+
+        pub(super) fn get_proc_address(&self, _name: &str) -> *const c_void {
+            std::ptr::null()
+        }
+    }
+}
+
+/// Context for the tests that need OpenGL, panics when the context
+/// cannot be created so the tests do not silently pass. The tests
+/// are ignored by default, run them with `cargo test -- --ignored`
+/// on a machine with surfaceless EGL.
+#[cfg(test)]
+pub(crate) fn expect_test_context<E: Display>(
+    context: Result<HeadlessContext, E>,
+) -> HeadlessContext {
+    context.unwrap_or_else(|err| {
+        panic!(
+            "headless context not available ({}), the OpenGL tests need libEGL with surfaceless \
+             support (eg: Mesa)",
+            err
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs libEGL with surfaceless support, run with cargo test -- --ignored"]
+    fn headless_context_render_target() {
+        let mut context = expect_test_context(HeadlessContext::new((8, 4), (3, 3)));
+        assert_eq!(context.get_dimensions(), (8, 4));
+
+        context.activate_render_target();
+        let mut pixels = vec![0.0_f32; 8 * 4 * 4];
+        unsafe {
+            gl::Disable(gl::FRAMEBUFFER_SRGB);
+            gl::ClearColor(0.25, 0.5, 0.75, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+            gl::ReadPixels(
+                0,
+                0,
+                8,
+                4,
+                gl::RGBA,
+                gl::FLOAT,
+                pixels.as_mut_ptr() as *mut std::ffi::c_void,
+            );
+        }
+        pixels.chunks(4).for_each(|pixel| {
+            assert!((pixel[0] - 0.25).abs() < 0.01);
+            assert!((pixel[1] - 0.5).abs() < 0.01);
+            assert!((pixel[2] - 0.75).abs() < 0.01);
+            assert!((pixel[3] - 1.0).abs() < 0.01);
+        });
+    }
+}
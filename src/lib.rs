@@ -1,19 +1,25 @@
 pub mod app;
 pub mod bvh;
 pub mod camera;
+pub mod ccd;
 pub mod drawable;
 pub mod fps;
 pub mod framebuffer;
 pub mod gl_mesh;
+pub mod golden;
 pub mod gpu_immediate;
 pub mod gpu_utils;
+pub mod headless;
 pub mod infinite_grid;
 pub mod jfa;
 pub mod mesh;
 pub mod meshio;
 pub mod rasterize;
+pub mod recording;
 pub mod renderbuffer;
 pub mod shader;
+pub mod software_rasterizer;
+pub mod spatial_hash;
 pub mod texture;
 pub mod util;
 
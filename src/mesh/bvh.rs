@@ -0,0 +1,313 @@
+//! Building and refitting [`BVHTree`]s over the faces, edges and
+//! nodes of a [`Mesh`], keeps [`crate::bvh`] independent of the mesh.
+
+use super::{Edge, EdgeIndex, Face, FaceIndex, Mesh, NodeIndex};
+use crate::bvh::{BVHError, BVHTree};
+use crate::glm;
+
+/// Positions of the nodes of the face.
+fn mesh_face_positions<END, EVD, EED, EFD>(
+    mesh: &Mesh<END, EVD, EED, EFD>,
+    face: &Face<EFD>,
+) -> Vec<glm::DVec3> {
+    mesh.get_nodes_of_face(face)
+        .iter()
+        .map(|node_index| mesh.get_node(node_index.unwrap()).unwrap().pos)
+        .collect()
+}
+
+/// Positions of the nodes of the edge.
+fn mesh_edge_positions<END, EVD, EED, EFD>(
+    mesh: &Mesh<END, EVD, EED, EFD>,
+    edge: &Edge<EED>,
+) -> Vec<glm::DVec3> {
+    let (v1_index, v2_index) = edge.get_verts().unwrap();
+    [v1_index, v2_index]
+        .iter()
+        .map(|vert_index| {
+            let vert = mesh.get_vert(*vert_index).unwrap();
+            mesh.get_node(vert.get_node().unwrap()).unwrap().pos
+        })
+        .collect()
+}
+
+impl BVHTree<f64, FaceIndex> {
+    /// Build the BVH over the faces of `mesh`, see [`Self::new()`]
+    /// for the other parameters.
+    pub fn from_mesh<END, EVD, EED, EFD>(
+        mesh: &Mesh<END, EVD, EED, EFD>,
+        epsilon: f64,
+        tree_type: u8,
+        axis: u8,
+    ) -> Self {
+        let mut bvh = Self::new(mesh.get_faces().len(), epsilon, tree_type, axis);
+
+        mesh.get_faces().iter().for_each(|(_, face)| {
+            bvh.insert(face.get_self_index(), &mesh_face_positions(mesh, face));
+        });
+
+        bvh.balance_multithreaded();
+
+        bvh
+    }
+
+    /// Refit the BVH after the nodes of `mesh` are moved. The
+    /// topology of `mesh` must not change.
+    pub fn refit_from_mesh<END, EVD, EED, EFD>(
+        &mut self,
+        mesh: &Mesh<END, EVD, EED, EFD>,
+    ) -> Result<(), BVHError> {
+        self.refit_with(|face_index| {
+            mesh.get_face(face_index)
+                .map(|face| (mesh_face_positions(mesh, face), Vec::new()))
+        })
+    }
+
+    /// Build the BVH over the faces of the mesh swept from
+    /// `mesh_start` to `mesh_end`, both must have the same topology.
+    /// See [`Self::new()`] for the other parameters.
+    ///
+    /// Returns [`BVHError::ElementNotFound`] if a face of
+    /// `mesh_start` doesn't exist in `mesh_end`.
+    pub fn from_mesh_swept<END, EVD, EED, EFD>(
+        mesh_start: &Mesh<END, EVD, EED, EFD>,
+        mesh_end: &Mesh<END, EVD, EED, EFD>,
+        epsilon: f64,
+        tree_type: u8,
+        axis: u8,
+    ) -> Result<Self, BVHError> {
+        let mut bvh = Self::new(mesh_start.get_faces().len(), epsilon, tree_type, axis);
+
+        for (_, face) in mesh_start.get_faces() {
+            let face_end = mesh_end
+                .get_face(face.get_self_index())
+                .ok_or(BVHError::ElementNotFound)?;
+            bvh.insert_moving(
+                face.get_self_index(),
+                &mesh_face_positions(mesh_start, face),
+                &mesh_face_positions(mesh_end, face_end),
+            )?;
+        }
+
+        bvh.balance_multithreaded();
+
+        Ok(bvh)
+    }
+
+    /// Refit the BVH built by [`Self::from_mesh_swept()`] for the
+    /// next time step.
+    pub fn refit_from_mesh_swept<END, EVD, EED, EFD>(
+        &mut self,
+        mesh_start: &Mesh<END, EVD, EED, EFD>,
+        mesh_end: &Mesh<END, EVD, EED, EFD>,
+    ) -> Result<(), BVHError> {
+        self.refit_with(|face_index| {
+            Some((
+                mesh_face_positions(mesh_start, mesh_start.get_face(face_index)?),
+                mesh_face_positions(mesh_end, mesh_end.get_face(face_index)?),
+            ))
+        })
+    }
+}
+
+impl BVHTree<f64, EdgeIndex> {
+    /// Build the BVH over the edges of `mesh`, see [`Self::new()`]
+    /// for the other parameters.
+    pub fn from_mesh_edges<END, EVD, EED, EFD>(
+        mesh: &Mesh<END, EVD, EED, EFD>,
+        epsilon: f64,
+        tree_type: u8,
+        axis: u8,
+    ) -> Self {
+        let mut bvh = Self::new(mesh.get_edges().len(), epsilon, tree_type, axis);
+
+        mesh.get_edges().iter().for_each(|(_, edge)| {
+            bvh.insert(edge.get_self_index(), &mesh_edge_positions(mesh, edge));
+        });
+
+        bvh.balance_multithreaded();
+
+        bvh
+    }
+
+    /// Refit the BVH after the nodes of `mesh` are moved. The
+    /// topology of `mesh` must not change.
+    pub fn refit_from_mesh<END, EVD, EED, EFD>(
+        &mut self,
+        mesh: &Mesh<END, EVD, EED, EFD>,
+    ) -> Result<(), BVHError> {
+        self.refit_with(|edge_index| {
+            mesh.get_edge(edge_index)
+                .map(|edge| (mesh_edge_positions(mesh, edge), Vec::new()))
+        })
+    }
+
+    /// Build the BVH over the edges of the mesh swept from
+    /// `mesh_start` to `mesh_end`, both must have the same topology.
+    /// See [`Self::new()`] for the other parameters.
+    ///
+    /// Returns [`BVHError::ElementNotFound`] if an edge of
+    /// `mesh_start` doesn't exist in `mesh_end`.
+    pub fn from_mesh_edges_swept<END, EVD, EED, EFD>(
+        mesh_start: &Mesh<END, EVD, EED, EFD>,
+        mesh_end: &Mesh<END, EVD, EED, EFD>,
+        epsilon: f64,
+        tree_type: u8,
+        axis: u8,
+    ) -> Result<Self, BVHError> {
+        let mut bvh = Self::new(mesh_start.get_edges().len(), epsilon, tree_type, axis);
+
+        for (_, edge) in mesh_start.get_edges() {
+            let edge_end = mesh_end
+                .get_edge(edge.get_self_index())
+                .ok_or(BVHError::ElementNotFound)?;
+            bvh.insert_moving(
+                edge.get_self_index(),
+                &mesh_edge_positions(mesh_start, edge),
+                &mesh_edge_positions(mesh_end, edge_end),
+            )?;
+        }
+
+        bvh.balance_multithreaded();
+
+        Ok(bvh)
+    }
+
+    /// Refit the BVH built by [`Self::from_mesh_edges_swept()`] for
+    /// the next time step.
+    pub fn refit_from_mesh_swept<END, EVD, EED, EFD>(
+        &mut self,
+        mesh_start: &Mesh<END, EVD, EED, EFD>,
+        mesh_end: &Mesh<END, EVD, EED, EFD>,
+    ) -> Result<(), BVHError> {
+        self.refit_with(|edge_index| {
+            Some((
+                mesh_edge_positions(mesh_start, mesh_start.get_edge(edge_index)?),
+                mesh_edge_positions(mesh_end, mesh_end.get_edge(edge_index)?),
+            ))
+        })
+    }
+}
+
+impl BVHTree<f64, NodeIndex> {
+    /// Build the BVH over the nodes of `mesh`, see [`Self::new()`]
+    /// for the other parameters.
+    pub fn from_mesh_nodes<END, EVD, EED, EFD>(
+        mesh: &Mesh<END, EVD, EED, EFD>,
+        epsilon: f64,
+        tree_type: u8,
+        axis: u8,
+    ) -> Self {
+        let mut bvh = Self::new(mesh.get_nodes().len(), epsilon, tree_type, axis);
+
+        mesh.get_nodes().iter().for_each(|(_, node)| {
+            bvh.insert(node.get_self_index(), &[node.pos]);
+        });
+
+        bvh.balance_multithreaded();
+
+        bvh
+    }
+
+    /// Refit the BVH after the nodes of `mesh` are moved. The
+    /// topology of `mesh` must not change.
+    pub fn refit_from_mesh<END, EVD, EED, EFD>(
+        &mut self,
+        mesh: &Mesh<END, EVD, EED, EFD>,
+    ) -> Result<(), BVHError> {
+        self.refit_with(|node_index| {
+            mesh.get_node(node_index)
+                .map(|node| (vec![node.pos], Vec::new()))
+        })
+    }
+
+    /// Build the BVH over the nodes of the mesh swept from
+    /// `mesh_start` to `mesh_end`, both must have the same topology.
+    /// See [`Self::new()`] for the other parameters.
+    ///
+    /// Returns [`BVHError::ElementNotFound`] if a node of
+    /// `mesh_start` doesn't exist in `mesh_end`.
+    pub fn from_mesh_nodes_swept<END, EVD, EED, EFD>(
+        mesh_start: &Mesh<END, EVD, EED, EFD>,
+        mesh_end: &Mesh<END, EVD, EED, EFD>,
+        epsilon: f64,
+        tree_type: u8,
+        axis: u8,
+    ) -> Result<Self, BVHError> {
+        let mut bvh = Self::new(mesh_start.get_nodes().len(), epsilon, tree_type, axis);
+
+        for (_, node) in mesh_start.get_nodes() {
+            let node_end = mesh_end
+                .get_node(node.get_self_index())
+                .ok_or(BVHError::ElementNotFound)?;
+            bvh.insert_moving(node.get_self_index(), &[node.pos], &[node_end.pos])?;
+        }
+
+        bvh.balance_multithreaded();
+
+        Ok(bvh)
+    }
+
+    /// Refit the BVH built by [`Self::from_mesh_nodes_swept()`] for
+    /// the next time step.
+    pub fn refit_from_mesh_swept<END, EVD, EED, EFD>(
+        &mut self,
+        mesh_start: &Mesh<END, EVD, EED, EFD>,
+        mesh_end: &Mesh<END, EVD, EED, EFD>,
+    ) -> Result<(), BVHError> {
+        self.refit_with(|node_index| {
+            Some((
+                vec![mesh_start.get_node(node_index)?.pos],
+                vec![mesh_end.get_node(node_index)?.pos],
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::mesh::simple;
+
+    #[test]
+    fn bvh_from_mesh() {
+        let mut mesh = simple::Mesh::read_from_file(Path::new("models/cube_subd_00.obj")).unwrap();
+
+        let mut face_bvh = BVHTree::from_mesh(&mesh, 0.0, 4, 8);
+        let mut edge_bvh = BVHTree::from_mesh_edges(&mesh, 0.01, 4, 8);
+        let mut node_bvh = BVHTree::from_mesh_nodes(&mesh, 0.0, 4, 8);
+        assert_eq!(face_bvh.stats().num_leafs, mesh.get_faces().len());
+        assert_eq!(edge_bvh.stats().num_leafs, mesh.get_edges().len());
+        assert_eq!(node_bvh.stats().num_leafs, mesh.get_nodes().len());
+
+        let co = glm::vec3(0.0, 0.0, 10.0);
+        let dir = glm::vec3(0.0, 0.0, -1.0);
+        let hit = face_bvh.ray_cast_no_callback(co, dir).unwrap();
+        let face_index = hit.data.unwrap().elem_index;
+        assert!(mesh.get_face(face_index).is_some());
+
+        // move the mesh away from the ray
+        let offset = glm::vec3(5.0, 0.0, 0.0);
+        mesh.get_nodes_mut().iter_mut().for_each(|(_, node)| {
+            node.pos += offset;
+        });
+        face_bvh.refit_from_mesh(&mesh).unwrap();
+        edge_bvh.refit_from_mesh(&mesh).unwrap();
+        node_bvh.refit_from_mesh(&mesh).unwrap();
+
+        assert!(face_bvh.ray_cast_no_callback(co, dir).is_none());
+        assert!(face_bvh.ray_cast_no_callback(co + offset, dir).is_some());
+        // edges are only along the sides of the cube
+        assert!(edge_bvh.ray_cast_no_callback(co + offset, dir).is_none());
+        assert!(edge_bvh
+            .ray_cast_no_callback(co + offset + glm::vec3(1.0, 1.0, 0.0), dir)
+            .is_some());
+        let nearest = node_bvh.find_nearest_no_callback(offset, f64::MAX).unwrap();
+        assert!(mesh.get_node(nearest.get_elem_index().unwrap()).is_some());
+        let (min, max) = node_bvh.get_min_max_bounds();
+        assert!((min - (offset - glm::DVec3::repeat(1.0))).norm() < 1e-6);
+        assert!((max - (offset + glm::DVec3::repeat(1.0))).norm() < 1e-6);
+    }
+}
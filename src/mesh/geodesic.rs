@@ -0,0 +1,363 @@
+//! Geodesic distance computation on [`Mesh`].
+//!
+//! Geodesic distances are computed with the fast marching method on
+//! the triangles of the faces (ngons are fan triangulated). The
+//! shortest path along the edges is computed using Dijkstra's
+//! algorithm.
+
+use itertools::Itertools;
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use super::{Mesh, NodeIndex};
+use crate::glm;
+
+/// Entry of the min heap used by the fast marching method and
+/// Dijkstra's algorithm.
+#[derive(Debug, Clone, Copy)]
+struct HeapEntry {
+    dist: f64,
+    node_index: NodeIndex,
+}
+
+impl HeapEntry {
+    fn new(dist: f64, node_index: NodeIndex) -> Self {
+        Self { dist, node_index }
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed so that BinaryHeap acts as a min heap
+        other
+            .dist
+            .partial_cmp(&self.dist)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.node_index.cmp(&self.node_index))
+    }
+}
+
+impl<END, EVD, EED, EFD> Mesh<END, EVD, EED, EFD> {
+    /// Get the nodes that are connected to the given node by an
+    /// edge, each node is given only once.
+    pub fn get_adjacent_node_indices(&self, node_index: NodeIndex) -> Vec<NodeIndex> {
+        self.get_node(node_index)
+            .unwrap()
+            .get_verts()
+            .iter()
+            .flat_map(|vert_index| self.get_vert(*vert_index).unwrap().get_edges().iter())
+            .filter_map(|edge_index| {
+                let (v1_index, v2_index) = self.get_edge(*edge_index).unwrap().get_verts().unwrap();
+                let n1_index = self.get_vert(v1_index).unwrap().get_node().unwrap();
+                let n2_index = self.get_vert(v2_index).unwrap().get_node().unwrap();
+                if n1_index == node_index {
+                    Some(n2_index)
+                } else if n2_index == node_index {
+                    Some(n1_index)
+                } else {
+                    None
+                }
+            })
+            .filter(|other_index| *other_index != node_index)
+            .unique()
+            .collect()
+    }
+
+    /// Compute the geodesic distance of every node reachable from
+    /// the `sources` (over the faces of the mesh) using the fast
+    /// marching method.
+    ///
+    /// Nodes that are not reachable from the `sources` are not part
+    /// of the returned map. Loose edges are not considered.
+    pub fn compute_geodesic_distances(&self, sources: &[NodeIndex]) -> HashMap<NodeIndex, f64> {
+        // triangles (as nodes) incident on each node
+        let mut node_tris: HashMap<NodeIndex, Vec<[NodeIndex; 3]>> = HashMap::new();
+        self.faces.iter().for_each(|(_, face)| {
+            let nodes: Vec<NodeIndex> = self
+                .get_nodes_of_face(face)
+                .into_iter()
+                .map(|node_index| node_index.unwrap())
+                .collect();
+            nodes
+                .iter()
+                .skip(1)
+                .tuple_windows()
+                .for_each(|(n2_index, n3_index)| {
+                    let tri = [nodes[0], *n2_index, *n3_index];
+                    tri.iter()
+                        .for_each(|node_index| node_tris.entry(*node_index).or_default().push(tri));
+                });
+        });
+
+        let mut dists: HashMap<NodeIndex, f64> = HashMap::new();
+        let mut frozen: HashSet<NodeIndex> = HashSet::new();
+        let mut heap = BinaryHeap::new();
+
+        sources.iter().for_each(|node_index| {
+            dists.insert(*node_index, 0.0);
+            heap.push(HeapEntry::new(0.0, *node_index));
+        });
+
+        while let Some(HeapEntry { dist, node_index }) = heap.pop() {
+            if !frozen.insert(node_index) || dist > dists[&node_index] {
+                continue;
+            }
+
+            let tris = match node_tris.get(&node_index) {
+                Some(tris) => tris,
+                None => continue,
+            };
+
+            for tri in tris {
+                for target_index in tri.iter().filter(|n_index| !frozen.contains(n_index)) {
+                    let others: Vec<NodeIndex> = tri
+                        .iter()
+                        .copied()
+                        .filter(|n_index| n_index != target_index)
+                        .collect();
+                    let target_pos = &self.get_node(*target_index).unwrap().pos;
+
+                    let new_dist = others
+                        .iter()
+                        .filter(|n_index| frozen.contains(n_index))
+                        .map(|n_index| {
+                            dists[n_index]
+                                + glm::distance(&self.get_node(*n_index).unwrap().pos, target_pos)
+                        })
+                        .chain(
+                            (frozen.contains(&others[0]) && frozen.contains(&others[1]))
+                                .then(|| {
+                                    fast_marching_tri_update(
+                                        &self.get_node(others[0]).unwrap().pos,
+                                        dists[&others[0]],
+                                        &self.get_node(others[1]).unwrap().pos,
+                                        dists[&others[1]],
+                                        target_pos,
+                                    )
+                                })
+                                .flatten(),
+                        )
+                        .fold(f64::INFINITY, f64::min);
+
+                    if new_dist < dists.get(target_index).copied().unwrap_or(f64::INFINITY) {
+                        dists.insert(*target_index, new_dist);
+                        heap.push(HeapEntry::new(new_dist, *target_index));
+                    }
+                }
+            }
+        }
+
+        dists
+    }
+
+    /// Find the shortest path from `start` to `end` along the edges
+    /// of the mesh using Dijkstra's algorithm.
+    ///
+    /// Returns the nodes of the path (including `start` and `end`)
+    /// or [`None`] if `end` is not reachable from `start`.
+    pub fn shortest_path_along_edges(
+        &self,
+        start: NodeIndex,
+        end: NodeIndex,
+    ) -> Option<Vec<NodeIndex>> {
+        let mut dists: HashMap<NodeIndex, f64> = HashMap::new();
+        let mut prev: HashMap<NodeIndex, NodeIndex> = HashMap::new();
+        let mut visited: HashSet<NodeIndex> = HashSet::new();
+        let mut heap = BinaryHeap::new();
+
+        dists.insert(start, 0.0);
+        heap.push(HeapEntry::new(0.0, start));
+
+        while let Some(HeapEntry { dist, node_index }) = heap.pop() {
+            if node_index == end {
+                let mut path = vec![end];
+                while let Some(prev_index) = prev.get(path.last().unwrap()) {
+                    path.push(*prev_index);
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            if !visited.insert(node_index) {
+                continue;
+            }
+
+            let pos = self.get_node(node_index).unwrap().pos;
+            for other_index in self.get_adjacent_node_indices(node_index) {
+                let new_dist = dist + glm::distance(&pos, &self.get_node(other_index).unwrap().pos);
+                if new_dist < dists.get(&other_index).copied().unwrap_or(f64::INFINITY) {
+                    dists.insert(other_index, new_dist);
+                    prev.insert(other_index, node_index);
+                    heap.push(HeapEntry::new(new_dist, other_index));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Distance at `c` given the distances at `a` and `b` of the
+/// triangle assuming a planar wavefront.
+///
+/// The virtual source of the wavefront is found by unfolding the
+/// triangle onto the plane, it must lie on the opposite side of `ab`
+/// from `c` and the ray from it to `c` must pass through `ab`.
+/// Returns [`None`] if no such source exists, the distance must then
+/// be computed along the edges.
+fn fast_marching_tri_update(
+    a: &glm::DVec3,
+    dist_a: f64,
+    b: &glm::DVec3,
+    dist_b: f64,
+    c: &glm::DVec3,
+) -> Option<f64> {
+    let ab = b - a;
+    let ab_len = glm::length(&ab);
+    if ab_len < f64::EPSILON {
+        return None;
+    }
+    let ab_dir = ab / ab_len;
+
+    // c in the 2D coordinate system with a at the origin and b on
+    // the positive x axis
+    let ac = c - a;
+    let c_x = glm::dot(&ac, &ab_dir);
+    let c_y = glm::length(&(ac - ab_dir * c_x));
+    if c_y < f64::EPSILON {
+        return None;
+    }
+
+    // virtual source
+    let s_x = (dist_a * dist_a - dist_b * dist_b + ab_len * ab_len) / (2.0 * ab_len);
+    let s_y_sq = dist_a * dist_a - s_x * s_x;
+    if s_y_sq < 0.0 {
+        return None;
+    }
+    let s_y = -s_y_sq.sqrt();
+
+    // ray from the source to c must pass through ab
+    let t = -s_y / (c_y - s_y);
+    let x = s_x + t * (c_x - s_x);
+    if !(0.0..=ab_len).contains(&x) {
+        return None;
+    }
+
+    let dist = ((c_x - s_x) * (c_x - s_x) + (c_y - s_y) * (c_y - s_y)).sqrt();
+    if dist < dist_a.max(dist_b) {
+        return None;
+    }
+    Some(dist)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::mesh::simple;
+
+    /// Triangulated grid of `n` x `n` quads on the XY plane from
+    /// (0, 0) to (1, 1).
+    fn grid_mesh(n: usize) -> simple::Mesh {
+        let mut positions = Vec::new();
+        for j in 0..=n {
+            for i in 0..=n {
+                positions.push(glm::vec3(i as f64 / n as f64, j as f64 / n as f64, 0.0));
+            }
+        }
+        let index = |i: usize, j: usize| j * (n + 1) + i;
+        let mut triangles = Vec::new();
+        for j in 0..n {
+            for i in 0..n {
+                triangles.push([index(i, j), index(i + 1, j), index(i + 1, j + 1)]);
+                triangles.push([index(i, j), index(i + 1, j + 1), index(i, j + 1)]);
+            }
+        }
+        simple::triangles_mesh(&positions, &triangles)
+    }
+
+    fn find_node(mesh: &simple::Mesh, pos: glm::DVec3) -> NodeIndex {
+        mesh.get_nodes()
+            .iter()
+            .find(|(_, node)| glm::distance(&node.pos, &pos) < 1e-9)
+            .unwrap()
+            .1
+            .get_self_index()
+    }
+
+    #[test]
+    fn geodesic_distances_plane() {
+        let mesh = grid_mesh(10);
+        let source = find_node(&mesh, glm::vec3(0.0, 0.0, 0.0));
+        let dists = mesh.compute_geodesic_distances(&[source]);
+        assert_eq!(dists.len(), mesh.get_nodes().len());
+        assert_eq!(dists[&source], 0.0);
+
+        mesh.get_nodes().iter().for_each(|(_, node)| {
+            let expected = glm::length(&node.pos);
+            assert!(
+                (dists[&node.get_self_index()] - expected).abs() < 0.05 * expected + 1e-9,
+                "{} {}",
+                dists[&node.get_self_index()],
+                expected
+            );
+        });
+    }
+
+    #[test]
+    fn geodesic_distances_sphere() {
+        let mesh =
+            simple::Mesh::read_from_file(Path::new("models/ico_sphere_subd_02.obj")).unwrap();
+        let (_, source) = mesh.get_nodes().iter().next().unwrap();
+        let dists = mesh.compute_geodesic_distances(&[source.get_self_index()]);
+        assert_eq!(dists.len(), mesh.get_nodes().len());
+
+        // geodesic distance is never shorter than the chord
+        mesh.get_nodes().iter().for_each(|(_, node)| {
+            assert!(dists[&node.get_self_index()] >= glm::distance(&source.pos, &node.pos) - 1e-9);
+        });
+
+        // antipodal node is half the circumference away
+        let max_dist = dists.values().copied().fold(0.0, f64::max);
+        assert!((max_dist - std::f64::consts::PI).abs() < 0.05 * std::f64::consts::PI);
+    }
+
+    #[test]
+    fn shortest_path_along_edges() {
+        let mesh = grid_mesh(4);
+        let start = find_node(&mesh, glm::vec3(0.0, 0.0, 0.0));
+        let end = find_node(&mesh, glm::vec3(1.0, 1.0, 0.0));
+        let path = mesh.shortest_path_along_edges(start, end).unwrap();
+
+        // the grid diagonals connect the two corners directly
+        assert_eq!(path.len(), 5);
+        assert_eq!(path.first(), Some(&start));
+        assert_eq!(path.last(), Some(&end));
+        path.iter()
+            .tuple_windows()
+            .for_each(|(n1_index, n2_index)| {
+                assert!(mesh.get_adjacent_node_indices(*n1_index).contains(n2_index));
+            });
+
+        assert_eq!(
+            mesh.shortest_path_along_edges(start, start),
+            Some(vec![start])
+        );
+    }
+}
@@ -13,8 +13,16 @@ use crate::glm;
 use crate::gpu_immediate::*;
 use crate::meshio::{MeshIO, MeshIOError};
 use crate::shader;
+use crate::spatial_hash::SpatialHash;
+use qrmesh::QRMeshError;
 
 pub mod builtins;
+pub mod bvh;
+pub mod geodesic;
+pub mod qrmesh;
+pub mod sampling;
+pub mod sdf;
+pub mod uv_lookup;
 
 /// Node stores the world (3D) space coordinates
 ///
@@ -105,6 +113,7 @@ type AdjacentVerts = IncidentVerts;
 #[derive(Debug)]
 pub enum MeshError {
     MeshIO(MeshIOError),
+    QRMesh(QRMeshError),
     NoUV,
 }
 
@@ -114,10 +123,17 @@ impl From<MeshIOError> for MeshError {
     }
 }
 
+impl From<QRMeshError> for MeshError {
+    fn from(err: QRMeshError) -> MeshError {
+        MeshError::QRMesh(err)
+    }
+}
+
 impl std::fmt::Display for MeshError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             MeshError::MeshIO(error) => write!(f, "{}", error),
+            MeshError::QRMesh(error) => write!(f, "{}", error),
             MeshError::NoUV => write!(f, "No UV information found"),
         }
     }
@@ -640,7 +656,15 @@ impl<END, EVD, EED, EFD> Mesh<END, EVD, EED, EFD> {
         Ok(mesh)
     }
 
+    /// Read the mesh from the file, `.qrmesh` files are read with
+    /// [`Self::read_qrmesh()`], other formats are read through
+    /// [`MeshIO`].
     pub fn read_from_file(path: &Path) -> Result<Self, MeshError> {
+        if path.extension().and_then(|extension| extension.to_str()) == Some("qrmesh") {
+            let file = std::io::BufReader::new(std::fs::File::open(path).map_err(QRMeshError::Io)?);
+            return Ok(Self::read_qrmesh(file)?);
+        }
+
         let data = MeshIO::read(path)?;
         Self::read(&data)
     }
@@ -680,6 +704,517 @@ impl<END, EVD, EED, EFD> Mesh<END, EVD, EED, EFD> {
             .collect()
     }
 
+    /// Compute the normal of the face from the positions of its
+    /// nodes, works for non planar ngons as well.
+    ///
+    /// Returns [`None`] if the face is degenerate.
+    pub fn compute_face_normal(&self, face: &Face<EFD>) -> Option<glm::DVec3> {
+        let positions: Vec<glm::DVec3> = self
+            .get_nodes_of_face(face)
+            .iter()
+            .map(|node_index| self.get_node(node_index.unwrap()).unwrap().pos)
+            .collect();
+
+        if positions.len() < 3 {
+            return None;
+        }
+
+        let normal = positions.iter().skip(1).tuple_windows().fold(
+            glm::zero(),
+            |acc: glm::DVec3, (p2, p3)| {
+                acc + glm::cross(&(p2 - positions[0]), &(p3 - positions[0]))
+            },
+        );
+
+        let len = glm::length(&normal);
+        if len < f64::EPSILON {
+            None
+        } else {
+            Some(normal / len)
+        }
+    }
+
+    /// Weld (merge) the nodes that are within `distance` of each
+    /// other.
+    ///
+    /// Of the nodes that are welded together, the node that comes
+    /// first in the nodes [`Arena`] is kept and the verts of the
+    /// other nodes are moved to it. Verts of the kept node that have
+    /// identical UVs are merged (along with their edges) so that the
+    /// faces become connected, verts with distinct UVs are kept
+    /// separate so that seams stay seams.
+    ///
+    /// Faces that degenerate (less than 3 distinct nodes) due to the
+    /// weld are removed along with the edges and verts that are not
+    /// used by any other face, and the nodes that are left without
+    /// any vert.
+    ///
+    /// Returns the number of nodes removed.
+    pub fn weld_nodes(&mut self, distance: f64) -> usize {
+        let spatial_hash = SpatialHash::with_elements(
+            distance.max(f64::EPSILON.sqrt()),
+            self.nodes
+                .iter()
+                .map(|(_, node)| (node.self_index, node.pos)),
+        );
+
+        // map from node to the node that it is welded to, the kept
+        // nodes map to themselves
+        let mut weld_map: HashMap<NodeIndex, NodeIndex> = HashMap::new();
+        let mut welds = Vec::new();
+        let mut kept_nodes = Vec::new();
+        let node_indices: Vec<_> = self.nodes.iter().map(|(_, node)| node.self_index).collect();
+        for node_index in node_indices {
+            if weld_map.contains_key(&node_index) {
+                continue;
+            }
+            weld_map.insert(node_index, node_index);
+
+            let pos = self.get_node(node_index).unwrap().pos;
+            let num_welds = welds.len();
+            spatial_hash.find_within_radius_callback(&pos, distance, |other_index, _| {
+                if let std::collections::hash_map::Entry::Vacant(entry) =
+                    weld_map.entry(other_index)
+                {
+                    entry.insert(node_index);
+                    welds.push((other_index, node_index));
+                }
+            });
+            if welds.len() != num_welds {
+                kept_nodes.push(node_index);
+            }
+        }
+
+        // move the verts of the welded nodes to the kept node
+        welds.iter().for_each(|(from, to)| {
+            let from_node = self.nodes.remove(from.0).unwrap();
+            from_node.verts.iter().for_each(|vert_index| {
+                self.get_vert_mut(*vert_index).unwrap().node = Some(*to);
+            });
+            self.get_node_mut(*to)
+                .unwrap()
+                .verts
+                .extend(from_node.verts.iter());
+        });
+
+        // merge the verts with identical UVs
+        let mut num_removed = welds.len();
+        kept_nodes.into_iter().for_each(|node_index| {
+            // removed as an orphan by an earlier degenerate face
+            let node = match self.get_node_mut(node_index) {
+                Some(node) => node,
+                None => return,
+            };
+            node.verts = node.verts.iter().copied().unique().collect();
+
+            let mut i = 0;
+            while let Some(vert_index) = self.get_node(node_index).unwrap().verts.get(i).copied() {
+                let uv = self.get_vert(vert_index).unwrap().uv;
+                let duplicates: Vec<_> = self
+                    .get_node(node_index)
+                    .unwrap()
+                    .verts
+                    .iter()
+                    .skip(i + 1)
+                    .copied()
+                    .filter(|other_index| self.get_vert(*other_index).unwrap().uv == uv)
+                    .collect();
+                duplicates
+                    .into_iter()
+                    .for_each(|other_index| self.merge_vert_into(other_index, vert_index));
+                i += 1;
+            }
+
+            let faces: Vec<FaceIndex> = self
+                .get_node(node_index)
+                .unwrap()
+                .verts
+                .iter()
+                .flat_map(|vert_index| self.get_vert(*vert_index).unwrap().edges.iter())
+                .flat_map(|edge_index| self.get_edge(*edge_index).unwrap().faces.iter())
+                .copied()
+                .unique()
+                .collect();
+            faces.into_iter().for_each(|face_index| {
+                let face = self.get_face(face_index).unwrap();
+                let num_nodes = face
+                    .verts
+                    .iter()
+                    .map(|vert_index| self.get_vert(*vert_index).unwrap().node)
+                    .unique()
+                    .count();
+                if num_nodes < 3 {
+                    num_removed += self.remove_face_and_orphans(face_index);
+                }
+            });
+        });
+
+        num_removed
+    }
+
+    /// Remove the face along with its edges that are not part of any
+    /// other face, then the verts that are left without edges and
+    /// then the nodes that are left without verts.
+    ///
+    /// Returns the number of nodes removed.
+    fn remove_face_and_orphans(&mut self, face_index: FaceIndex) -> usize {
+        let face = self.faces.remove(face_index.0).unwrap();
+        let edges: Vec<EdgeIndex> = face
+            .verts
+            .iter()
+            .flat_map(|vert_index| self.get_vert(*vert_index).unwrap().edges.iter())
+            .copied()
+            .unique()
+            .collect();
+        for edge_index in edges {
+            let edge = self.get_edge_mut(edge_index).unwrap();
+            if !edge.faces.contains(&face_index) {
+                continue;
+            }
+            edge.faces.retain(|f_index| *f_index != face_index);
+            if edge.faces.is_empty() {
+                let (v1_index, v2_index) = self.edges.remove(edge_index.0).unwrap().verts.unwrap();
+                for vert_index in [v1_index, v2_index] {
+                    self.get_vert_mut(vert_index)
+                        .unwrap()
+                        .edges
+                        .retain(|e_index| *e_index != edge_index);
+                }
+            }
+        }
+
+        let mut num_removed = 0;
+        for vert_index in face.verts.iter().unique() {
+            if !self.get_vert(*vert_index).unwrap().edges.is_empty() {
+                continue;
+            }
+            let vert = self.verts.remove(vert_index.0).unwrap();
+            if let Some(node_index) = vert.node {
+                let node = self.get_node_mut(node_index).unwrap();
+                node.verts.retain(|v_index| v_index != vert_index);
+                if node.verts.is_empty() {
+                    self.nodes.remove(node_index.0);
+                    num_removed += 1;
+                }
+            }
+        }
+
+        num_removed
+    }
+
+    /// Merge the vert `from` into the vert `to`, `from` is removed
+    /// from the mesh. The edges of `from` are moved to `to` (or
+    /// merged with the existing edges of `to`).
+    ///
+    /// Both verts must refer to the same node.
+    fn merge_vert_into(&mut self, from: VertIndex, to: VertIndex) {
+        let from_vert = self.verts.remove(from.0).unwrap();
+        if let Some(node_index) = from_vert.node {
+            self.get_node_mut(node_index)
+                .unwrap()
+                .verts
+                .retain(|vert_index| *vert_index != from);
+        }
+
+        let faces: Vec<FaceIndex> = from_vert
+            .edges
+            .iter()
+            .flat_map(|edge_index| self.get_edge(*edge_index).unwrap().faces.iter().copied())
+            .unique()
+            .collect();
+        faces.iter().for_each(|face_index| {
+            self.get_face_mut(*face_index)
+                .unwrap()
+                .verts
+                .iter_mut()
+                .filter(|vert_index| **vert_index == from)
+                .for_each(|vert_index| *vert_index = to);
+        });
+
+        for edge_index in from_vert.edges {
+            let (v1_index, v2_index) = self.get_edge(edge_index).unwrap().verts.unwrap();
+            let other_index = if v1_index == from { v2_index } else { v1_index };
+
+            if other_index == to {
+                // edge collapses
+                self.edges.remove(edge_index.0).unwrap();
+                self.get_vert_mut(to)
+                    .unwrap()
+                    .edges
+                    .retain(|e_index| *e_index != edge_index);
+            } else if let Some(existing_index) = self.get_connecting_edge_index(to, other_index) {
+                let edge = self.edges.remove(edge_index.0).unwrap();
+                self.get_vert_mut(other_index)
+                    .unwrap()
+                    .edges
+                    .retain(|e_index| *e_index != edge_index);
+                let existing_edge = self.get_edge_mut(existing_index).unwrap();
+                edge.faces
+                    .into_iter()
+                    .for_each(|face_index| _add_as_set(&mut existing_edge.faces, face_index));
+            } else {
+                let edge = self.get_edge_mut(edge_index).unwrap();
+                edge.verts = if v1_index == from {
+                    Some((to, other_index))
+                } else {
+                    Some((other_index, to))
+                };
+                self.get_vert_mut(to).unwrap().edges.push(edge_index);
+            }
+        }
+
+        // consecutive verts of the faces that were connected by the
+        // collapsed edges are now the same vert
+        faces.into_iter().for_each(|face_index| {
+            let face = self.get_face_mut(face_index).unwrap();
+            face.verts.dedup();
+            if face.verts.len() > 1 && face.verts.first() == face.verts.last() {
+                face.verts.pop();
+            }
+        });
+    }
+
+    /// Split the nodes along the sharp edges, edges where the angle
+    /// (in radians) between the normals of the adjacent faces is
+    /// greater than `angle`.
+    ///
+    /// Each fan of faces around a node that is separated from the
+    /// other fans by sharp edges gets its own node (at the same
+    /// position). Verts and edges that are shared across the sharp
+    /// edges are duplicated. This is the complement of
+    /// [`Self::weld_nodes()`], commonly needed for flat shading.
+    ///
+    /// Returns the number of nodes added.
+    pub fn split_by_sharp_edges(&mut self, angle: f64) -> usize {
+        let face_normals: HashMap<FaceIndex, Option<glm::DVec3>> = self
+            .faces
+            .iter()
+            .map(|(_, face)| (face.self_index, self.compute_face_normal(face)))
+            .collect();
+        let is_sharp = |f1_index: FaceIndex, f2_index: FaceIndex| match (
+            face_normals[&f1_index],
+            face_normals[&f2_index],
+        ) {
+            (Some(n1), Some(n2)) => glm::dot(&n1, &n2).clamp(-1.0, 1.0).acos() > angle,
+            _ => false,
+        };
+
+        let node_indices: Vec<_> = self.nodes.iter().map(|(_, node)| node.self_index).collect();
+
+        let mut num_nodes_added = 0;
+        for node_index in node_indices {
+            // faces around the node along with the nodes adjacent to
+            // the node in the face
+            let faces: Vec<(FaceIndex, [NodeIndex; 2])> = self
+                .get_node(node_index)
+                .unwrap()
+                .verts
+                .iter()
+                .flat_map(|vert_index| self.get_vert(*vert_index).unwrap().edges.iter())
+                .flat_map(|edge_index| self.get_edge(*edge_index).unwrap().faces.iter())
+                .copied()
+                .unique()
+                .map(|face_index| {
+                    let nodes = self.get_nodes_of_face(self.get_face(face_index).unwrap());
+                    let i = nodes
+                        .iter()
+                        .position(|n_index| *n_index == Some(node_index))
+                        .unwrap();
+                    let prev = nodes[(i + nodes.len() - 1) % nodes.len()].unwrap();
+                    let next = nodes[(i + 1) % nodes.len()].unwrap();
+                    (face_index, [prev, next])
+                })
+                .collect();
+
+            // group the faces into fans, faces sharing a non sharp
+            // edge are part of the same fan
+            let mut face_fan: Vec<Option<usize>> = vec![None; faces.len()];
+            let mut num_fans = 0;
+            for start in 0..faces.len() {
+                if face_fan[start].is_some() {
+                    continue;
+                }
+                face_fan[start] = Some(num_fans);
+                let mut stack = vec![start];
+                while let Some(i) = stack.pop() {
+                    for j in 0..faces.len() {
+                        if face_fan[j].is_none()
+                            && faces[i].1.iter().any(|n| faces[j].1.contains(n))
+                            && !is_sharp(faces[i].0, faces[j].0)
+                        {
+                            face_fan[j] = Some(num_fans);
+                            stack.push(j);
+                        }
+                    }
+                }
+                num_fans += 1;
+            }
+
+            if num_fans <= 1 {
+                continue;
+            }
+
+            // first fan keeps the node, others get a new node each
+            let (pos, normal) = {
+                let node = self.get_node(node_index).unwrap();
+                (node.pos, node.normal)
+            };
+            let fan_nodes: Vec<NodeIndex> = std::iter::once(node_index)
+                .chain((1..num_fans).map(|_| {
+                    let node = self.add_empty_node(pos);
+                    node.normal = normal;
+                    node.self_index
+                }))
+                .collect();
+            num_nodes_added += num_fans - 1;
+
+            let face_fan: HashMap<FaceIndex, usize> = faces
+                .iter()
+                .zip(face_fan.iter())
+                .map(|((face_index, _), fan)| (*face_index, fan.unwrap()))
+                .collect();
+
+            let vert_indices: Vec<_> = self
+                .get_node(node_index)
+                .unwrap()
+                .verts
+                .iter()
+                .copied()
+                .unique()
+                .collect();
+            for vert_index in vert_indices {
+                let vert_faces: Vec<FaceIndex> = self
+                    .get_vert(vert_index)
+                    .unwrap()
+                    .edges
+                    .iter()
+                    .flat_map(|edge_index| self.get_edge(*edge_index).unwrap().faces.iter())
+                    .copied()
+                    .unique()
+                    .collect();
+                let vert_fans: Vec<usize> = vert_faces
+                    .iter()
+                    .map(|face_index| face_fan[face_index])
+                    .unique()
+                    .sorted()
+                    .collect();
+
+                // vert stays with the lowest fan
+                if let Some(fan) = vert_fans.first().copied().filter(|fan| *fan != 0) {
+                    self.get_node_mut(node_index)
+                        .unwrap()
+                        .verts
+                        .retain(|v_index| *v_index != vert_index);
+                    self.get_node_mut(fan_nodes[fan])
+                        .unwrap()
+                        .verts
+                        .push(vert_index);
+                    self.get_vert_mut(vert_index).unwrap().node = Some(fan_nodes[fan]);
+                }
+
+                // other fans get a copy of the vert
+                for fan in vert_fans.into_iter().skip(1) {
+                    let uv = self.get_vert(vert_index).unwrap().uv;
+                    let new_vert_index = self.add_empty_vert_index();
+                    {
+                        let new_vert = self.get_vert_mut(new_vert_index).unwrap();
+                        new_vert.uv = uv;
+                        new_vert.node = Some(fan_nodes[fan]);
+                    }
+                    self.get_node_mut(fan_nodes[fan])
+                        .unwrap()
+                        .verts
+                        .push(new_vert_index);
+
+                    vert_faces
+                        .iter()
+                        .filter(|face_index| face_fan[face_index] == fan)
+                        .for_each(|face_index| {
+                            self.move_face_corner_to_vert(
+                                *face_index,
+                                vert_index,
+                                new_vert_index,
+                                |f_index| face_fan[&f_index] == fan,
+                            )
+                        });
+                }
+            }
+        }
+
+        num_nodes_added
+    }
+
+    /// Replace the vert `from` of the face with the vert `to`. The
+    /// edges of the face at the corner are moved to `to` if all their
+    /// faces are part of `is_same_group()`, otherwise the edge is
+    /// split and the face is moved to a (new or existing) edge of
+    /// `to`.
+    fn move_face_corner_to_vert<F>(
+        &mut self,
+        face_index: FaceIndex,
+        from: VertIndex,
+        to: VertIndex,
+        is_same_group: F,
+    ) where
+        F: Fn(FaceIndex) -> bool,
+    {
+        let face = self.get_face_mut(face_index).unwrap();
+        let i = face
+            .verts
+            .iter()
+            .position(|vert_index| *vert_index == from)
+            .unwrap();
+        face.verts[i] = to;
+        let len = face.verts.len();
+        let adjacent_verts = [face.verts[(i + len - 1) % len], face.verts[(i + 1) % len]];
+
+        for other_index in adjacent_verts {
+            if let Some(edge_index) = self.get_connecting_edge_index(to, other_index) {
+                // a previous face of the group already moved or split
+                // the edge
+                _add_as_set(
+                    &mut self.get_edge_mut(edge_index).unwrap().faces,
+                    face_index,
+                );
+                if let Some(old_edge_index) = self.get_connecting_edge_index(from, other_index) {
+                    self.get_edge_mut(old_edge_index)
+                        .unwrap()
+                        .faces
+                        .retain(|f_index| *f_index != face_index);
+                }
+                continue;
+            }
+
+            let edge_index = self.get_connecting_edge_index(from, other_index).unwrap();
+            let edge = self.get_edge_mut(edge_index).unwrap();
+            if edge.faces.iter().all(|f_index| is_same_group(*f_index)) {
+                let (v1_index, v2_index) = edge.verts.unwrap();
+                edge.verts = if v1_index == from {
+                    Some((to, v2_index))
+                } else {
+                    Some((v1_index, to))
+                };
+                self.get_vert_mut(from)
+                    .unwrap()
+                    .edges
+                    .retain(|e_index| *e_index != edge_index);
+                self.get_vert_mut(to).unwrap().edges.push(edge_index);
+            } else {
+                edge.faces.retain(|f_index| *f_index != face_index);
+
+                let new_edge_index = self.add_empty_edge_index();
+                let new_edge = self.get_edge_mut(new_edge_index).unwrap();
+                new_edge.verts = Some((to, other_index));
+                new_edge.faces.push(face_index);
+                self.get_vert_mut(to).unwrap().edges.push(new_edge_index);
+                self.get_vert_mut(other_index)
+                    .unwrap()
+                    .edges
+                    .push(new_edge_index);
+            }
+        }
+    }
+
     fn draw_face_orientation_shader(&self, draw_data: &MeshDrawData) -> Result<(), MeshDrawError> {
         if self.faces.is_empty() {
             return Ok(());
@@ -1240,6 +1775,26 @@ pub mod simple {
     pub type Edge = super::Edge<()>;
     pub type Face = super::Face<()>;
     pub type Mesh = super::Mesh<(), (), (), ()>;
+
+    /// Flat triangle mesh for tests, the UVs are the XY of the
+    /// positions and every face has a +Z normal.
+    #[cfg(test)]
+    pub(crate) fn triangles_mesh(
+        positions: &[crate::glm::DVec3],
+        triangles: &[[usize; 3]],
+    ) -> Mesh {
+        let mut data = crate::meshio::MeshIO::new();
+        data.positions = positions.to_vec();
+        data.uvs = positions.iter().map(|pos| pos.xy()).collect();
+        data.normals.push(crate::glm::vec3(0.0, 0.0, 1.0));
+        data.face_indices = triangles
+            .iter()
+            .map(|triangle| triangle.iter().map(|i| (*i, *i, 0)).collect())
+            .collect();
+        data.face_has_uv = true;
+        data.face_has_normal = true;
+        Mesh::read(&data).unwrap()
+    }
 }
 
 fn _add_as_set<T>(vec: &mut Vec<T>, val: T)
@@ -1308,4 +1863,106 @@ mod tests {
             unreachable!()
         }
     }
+
+    #[test]
+    fn mesh_weld_nodes() {
+        let mut mesh =
+            simple::Mesh::read_from_file(Path::new("tests/obj_test_07_duplicate_nodes.obj"))
+                .unwrap();
+        assert_eq!(mesh.nodes.len(), 6);
+        assert_eq!(mesh.weld_nodes(0.0001), 2);
+        assert_eq!(mesh.nodes.len(), 4);
+        assert_eq!(mesh.verts.len(), 4);
+        assert_eq!(mesh.edges.len(), 5);
+        assert_eq!(mesh.faces.len(), 2);
+        assert_eq!(
+            mesh.edges
+                .iter()
+                .filter(|(_, edge)| edge.faces.len() == 2)
+                .count(),
+            1
+        );
+        for (_, vert) in &mesh.verts {
+            assert!(mesh
+                .get_node(vert.node.unwrap())
+                .unwrap()
+                .verts
+                .contains(&vert.self_index));
+        }
+    }
+
+    #[test]
+    fn mesh_weld_nodes_keep_seams() {
+        let mut mesh =
+            simple::Mesh::read_from_file(Path::new("tests/obj_test_08_duplicate_nodes_seam.obj"))
+                .unwrap();
+        assert_eq!(mesh.weld_nodes(0.0001), 2);
+        assert_eq!(mesh.nodes.len(), 4);
+        assert_eq!(mesh.verts.len(), 6);
+        assert_eq!(mesh.edges.len(), 6);
+        let seam_edges: Vec<_> = mesh
+            .edges
+            .iter()
+            .filter(|(_, edge)| {
+                let (n1, n2) = mesh.get_checked_nodes_of_edge(edge, false);
+                mesh.get_connecting_edge_indices(n1, n2).len() == 2
+            })
+            .collect();
+        assert_eq!(seam_edges.len(), 2);
+        for (_, edge) in seam_edges {
+            assert!(mesh.is_edge_on_seam(edge));
+            assert!(!mesh.is_edge_on_boundary(edge));
+        }
+    }
+
+    #[test]
+    fn mesh_weld_nodes_degenerate_seam() {
+        let mut mesh =
+            simple::Mesh::read_from_file(Path::new("tests/obj_test_09_weld_degenerate_seam.obj"))
+                .unwrap();
+        assert_eq!(mesh.faces.len(), 3);
+        // 2 welded nodes and the 2 nodes of the third triangle left
+        // without verts
+        assert_eq!(mesh.weld_nodes(0.0001), 4);
+        assert_eq!(mesh.nodes.len(), 3);
+        assert_eq!(mesh.verts.len(), 3);
+        assert_eq!(mesh.edges.len(), 3);
+        assert_eq!(mesh.faces.len(), 1);
+        for (_, edge) in &mesh.edges {
+            assert_eq!(edge.faces.len(), 1);
+        }
+        for (_, node) in &mesh.nodes {
+            assert!(!node.verts.is_empty());
+            assert_eq!(node.verts.iter().unique().count(), 1);
+        }
+    }
+
+    #[test]
+    fn mesh_split_by_sharp_edges() {
+        let mut mesh =
+            simple::Mesh::read_from_file(Path::new("models/cube_subd_00_triangulated.obj"))
+                .unwrap();
+        assert_eq!(mesh.nodes.len(), 8);
+        assert_eq!(mesh.verts.len(), 14);
+        assert_eq!(mesh.edges.len(), 25);
+
+        assert_eq!(mesh.split_by_sharp_edges(30.0_f64.to_radians()), 16);
+        assert_eq!(mesh.nodes.len(), 24);
+        assert_eq!(mesh.verts.len(), 24);
+        assert_eq!(mesh.edges.len(), 30);
+        for (_, node) in &mesh.nodes {
+            assert_eq!(node.verts.iter().unique().count(), 1);
+        }
+        for (_, edge) in &mesh.edges {
+            let (n1, n2) = mesh.get_checked_nodes_of_edge(edge, false);
+            assert_ne!(n1.self_index, n2.self_index);
+        }
+
+        // welding back gives the original topology
+        assert_eq!(mesh.weld_nodes(0.0001), 16);
+        assert_eq!(mesh.nodes.len(), 8);
+        assert_eq!(mesh.verts.len(), 14);
+        assert_eq!(mesh.edges.len(), 25);
+        assert_eq!(mesh.faces.len(), 12);
+    }
 }
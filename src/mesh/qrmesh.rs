@@ -0,0 +1,805 @@
+//! Compact binary file format (`.qrmesh`) for [`Mesh`].
+//!
+//! # Layout
+//!
+//! All values are little endian.
+//!
+//! * Magic bytes [`QRMESH_MAGIC`].
+//! * Major version (`u16`) followed by minor version (`u16`).
+//! * Length of the (uncompressed) chunks that follow (`u64`).
+//! * Chunks, each chunk is a 4 byte tag, the payload length (`u64`)
+//!   and the payload.
+//! * End chunk (tag `b"END\0"`, no payload).
+//!
+//! The mesh elements are stored with dense `u32` indices instead of
+//! the [`generational_arena::Index`], the incidence information
+//! (verts of a node, edges of a vert, faces of an edge) is rebuilt
+//! on load.
+//!
+//! Readers skip chunks with unknown tags. A minor version bump may
+//! only add new chunks so that older readers can still load the
+//! file, a major version bump marks an incompatible layout change.
+//!
+//! The entire file may be compressed with gzip or zstd, this is
+//! detected when reading through the magic bytes of the compression
+//! format. The file is decompressed while reading and never past the
+//! stored length of the chunks, files whose length doesn't match the
+//! stored length are rejected.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+use super::{EdgeIndex, FaceIndex, Mesh, NodeIndex, VertIndex};
+use crate::glm;
+use crate::util;
+
+/// Magic bytes at the start of every (uncompressed) `.qrmesh`
+/// file.
+pub const QRMESH_MAGIC: [u8; 8] = *b"QRMESH\0\0";
+/// Major version of the format written, files with a different
+/// major version cannot be read.
+pub const QRMESH_VERSION_MAJOR: u16 = 1;
+/// Minor version of the format written.
+pub const QRMESH_VERSION_MINOR: u16 = 0;
+
+const CHUNK_NODES: [u8; 4] = *b"NODE";
+const CHUNK_VERTS: [u8; 4] = *b"VERT";
+const CHUNK_EDGES: [u8; 4] = *b"EDGE";
+const CHUNK_FACES: [u8; 4] = *b"FACE";
+const CHUNK_NODES_EXTRA_DATA: [u8; 4] = *b"NDXD";
+const CHUNK_VERTS_EXTRA_DATA: [u8; 4] = *b"VTXD";
+const CHUNK_EDGES_EXTRA_DATA: [u8; 4] = *b"EDXD";
+const CHUNK_FACES_EXTRA_DATA: [u8; 4] = *b"FCXD";
+const CHUNK_END: [u8; 4] = *b"END\0";
+
+/// Dense index used to mark the absence of an element.
+const NONE_INDEX: u32 = u32::MAX;
+
+/// Compression applied to the `.qrmesh` file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QRMeshCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Debug)]
+pub enum QRMeshError {
+    Io(std::io::Error),
+    /// File doesn't start with [`QRMESH_MAGIC`].
+    InvalidMagic,
+    /// File has a major version that is not supported, the major
+    /// version of the file is stored.
+    UnsupportedVersion(u16),
+    /// File is truncated or the data stored in it is not valid.
+    InvalidData,
+    /// Mesh has too many elements to be stored with `u32` indices.
+    TooManyElements,
+    ExtraData(bincode::Error),
+}
+
+impl From<std::io::Error> for QRMeshError {
+    fn from(err: std::io::Error) -> Self {
+        QRMeshError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for QRMeshError {
+    fn from(err: bincode::Error) -> Self {
+        QRMeshError::ExtraData(err)
+    }
+}
+
+impl std::fmt::Display for QRMeshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QRMeshError::Io(error) => write!(f, "io error {}", error),
+            QRMeshError::InvalidMagic => write!(f, "not a qrmesh file"),
+            QRMeshError::UnsupportedVersion(major) => write!(
+                f,
+                "unsupported qrmesh version {}, supported version is {}",
+                major, QRMESH_VERSION_MAJOR
+            ),
+            QRMeshError::InvalidData => write!(f, "invalid or truncated qrmesh data"),
+            QRMeshError::TooManyElements => write!(f, "too many elements for qrmesh"),
+            QRMeshError::ExtraData(error) => write!(f, "extra data error {}", error),
+        }
+    }
+}
+
+impl std::error::Error for QRMeshError {}
+
+/// Chunks read from the file along with the mapping from the dense
+/// indices to the indices of the elements in the [`Mesh`].
+struct QRMeshReadData {
+    chunks: HashMap<[u8; 4], Vec<u8>>,
+    nodes: Vec<NodeIndex>,
+    verts: Vec<VertIndex>,
+    edges: Vec<EdgeIndex>,
+    faces: Vec<FaceIndex>,
+}
+
+impl<END, EVD, EED, EFD> Mesh<END, EVD, EED, EFD> {
+    /// Write the mesh in the `.qrmesh` format. The extra data of the
+    /// elements is not written, see
+    /// [`Self::write_qrmesh_with_extra_data()`].
+    pub fn write_qrmesh<W: Write>(
+        &self,
+        writer: W,
+        compression: QRMeshCompression,
+    ) -> Result<(), QRMeshError> {
+        self.write_qrmesh_chunks(writer, compression, Vec::new())
+    }
+
+    /// Read the mesh from the `.qrmesh` format. The extra data of the
+    /// elements (if stored) is not read, see
+    /// [`Self::read_qrmesh_with_extra_data()`].
+    pub fn read_qrmesh<R: Read>(reader: R) -> Result<Self, QRMeshError> {
+        Self::read_qrmesh_chunks(reader).map(|(mesh, _)| mesh)
+    }
+
+    fn write_qrmesh_chunks<W: Write>(
+        &self,
+        mut writer: W,
+        compression: QRMeshCompression,
+        extra_chunks: Vec<([u8; 4], Vec<u8>)>,
+    ) -> Result<(), QRMeshError> {
+        let num_nodes = num_elements_to_u32(self.nodes.len())?;
+        let num_verts = num_elements_to_u32(self.verts.len())?;
+        let num_edges = num_elements_to_u32(self.edges.len())?;
+        let num_faces = num_elements_to_u32(self.faces.len())?;
+
+        let node_map: HashMap<NodeIndex, u32> = self
+            .nodes
+            .iter()
+            .zip(0..)
+            .map(|((_, node), i)| (node.self_index, i))
+            .collect();
+        let vert_map: HashMap<VertIndex, u32> = self
+            .verts
+            .iter()
+            .zip(0..)
+            .map(|((_, vert), i)| (vert.self_index, i))
+            .collect();
+
+        let mut data = Vec::new();
+        let mut chunk = Vec::new();
+        write_u32(&mut chunk, num_nodes);
+        self.nodes.iter().for_each(|(_, node)| {
+            write_dvec3(&mut chunk, &node.pos);
+            write_optional_dvec3(&mut chunk, &node.normal);
+        });
+        write_chunk(&mut data, CHUNK_NODES, &chunk);
+
+        chunk.clear();
+        write_u32(&mut chunk, num_verts);
+        self.verts.iter().for_each(|(_, vert)| {
+            write_u32(
+                &mut chunk,
+                vert.node
+                    .map_or(NONE_INDEX, |node_index| node_map[&node_index]),
+            );
+            match vert.uv {
+                Some(uv) => {
+                    chunk.push(1);
+                    write_f64(&mut chunk, uv[0]);
+                    write_f64(&mut chunk, uv[1]);
+                }
+                None => chunk.push(0),
+            }
+        });
+        write_chunk(&mut data, CHUNK_VERTS, &chunk);
+
+        chunk.clear();
+        write_u32(&mut chunk, num_edges);
+        self.edges.iter().for_each(|(_, edge)| {
+            let (v1, v2) = edge.verts.map_or((NONE_INDEX, NONE_INDEX), |(v1, v2)| {
+                (vert_map[&v1], vert_map[&v2])
+            });
+            write_u32(&mut chunk, v1);
+            write_u32(&mut chunk, v2);
+        });
+        write_chunk(&mut data, CHUNK_EDGES, &chunk);
+
+        chunk.clear();
+        write_u32(&mut chunk, num_faces);
+        self.faces.iter().for_each(|(_, face)| {
+            write_optional_dvec3(&mut chunk, &face.normal);
+            write_u32(&mut chunk, face.verts.len() as _);
+            face.verts
+                .iter()
+                .for_each(|vert_index| write_u32(&mut chunk, vert_map[vert_index]));
+        });
+        write_chunk(&mut data, CHUNK_FACES, &chunk);
+
+        extra_chunks
+            .iter()
+            .for_each(|(tag, chunk)| write_chunk(&mut data, *tag, chunk));
+
+        write_chunk(&mut data, CHUNK_END, &[]);
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&QRMESH_MAGIC);
+        header.extend_from_slice(&QRMESH_VERSION_MAJOR.to_le_bytes());
+        header.extend_from_slice(&QRMESH_VERSION_MINOR.to_le_bytes());
+        header.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        let data = [header, data].concat();
+
+        match compression {
+            QRMeshCompression::None => writer.write_all(&data)?,
+            QRMeshCompression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+                encoder.write_all(&data)?;
+                encoder.finish()?;
+            }
+            QRMeshCompression::Zstd => {
+                zstd::stream::copy_encode(data.as_slice(), writer, 0)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_qrmesh_chunks<R: Read>(mut reader: R) -> Result<(Self, QRMeshReadData), QRMeshError> {
+        // peek the magic bytes of the compression format
+        let mut magic = Vec::with_capacity(4);
+        (&mut reader).take(4).read_to_end(&mut magic)?;
+        let reader = std::io::Cursor::new(magic.clone()).chain(reader);
+        let mut reader: Box<dyn Read + '_> = if magic.len() >= 4 && util::file_magic_is_zstd(&magic)
+        {
+            Box::new(zstd::stream::read::Decoder::new(reader)?)
+        } else if magic.len() >= 3 && util::file_magic_is_gzip(&magic) {
+            Box::new(flate2::read::GzDecoder::new(reader))
+        } else {
+            Box::new(reader)
+        };
+
+        // magic bytes, version and length of the chunks
+        let header_len = QRMESH_MAGIC.len() + 2 + 2 + 8;
+        let mut header = Vec::with_capacity(header_len);
+        (&mut reader)
+            .take(header_len as u64)
+            .read_to_end(&mut header)?;
+        let mut cursor = Cursor::new(&header);
+        if cursor.read_bytes(QRMESH_MAGIC.len())? != QRMESH_MAGIC {
+            return Err(QRMeshError::InvalidMagic);
+        }
+        let major = cursor.read_u16()?;
+        let _minor = cursor.read_u16()?;
+        if major != QRMESH_VERSION_MAJOR {
+            return Err(QRMeshError::UnsupportedVersion(major));
+        }
+        let len = cursor.read_u64()?;
+
+        // never read past the stored length, so a compressed file
+        // cannot decompress to more than it claims
+        let mut data = Vec::new();
+        (&mut reader).take(len).read_to_end(&mut data)?;
+        if data.len() as u64 != len || reader.read(&mut [0])? != 0 {
+            return Err(QRMeshError::InvalidData);
+        }
+
+        let mut cursor = Cursor::new(&data);
+        let mut chunks = HashMap::new();
+        loop {
+            let tag: [u8; 4] = cursor.read_bytes(4)?.try_into().unwrap();
+            let len = cursor.read_u64()?;
+            let payload =
+                cursor.read_bytes(len.try_into().map_err(|_| QRMeshError::InvalidData)?)?;
+            if tag == CHUNK_END {
+                break;
+            }
+            chunks.insert(tag, payload.to_vec());
+        }
+
+        let mut mesh = Self::new();
+
+        let mut cursor = Cursor::new(chunks.get(&CHUNK_NODES).ok_or(QRMeshError::InvalidData)?);
+        let nodes = (0..cursor.read_u32()?)
+            .map(|_| {
+                let pos = cursor.read_dvec3()?;
+                let normal = cursor.read_optional_dvec3()?;
+                let node = mesh.add_empty_node(pos);
+                node.normal = normal;
+                Ok(node.self_index)
+            })
+            .collect::<Result<Vec<_>, QRMeshError>>()?;
+
+        let mut cursor = Cursor::new(chunks.get(&CHUNK_VERTS).ok_or(QRMeshError::InvalidData)?);
+        let verts = (0..cursor.read_u32()?)
+            .map(|_| {
+                let node_index = cursor.read_optional_index(&nodes)?;
+                let uv = match cursor.read_u8()? {
+                    0 => None,
+                    _ => Some(glm::vec2(cursor.read_f64()?, cursor.read_f64()?)),
+                };
+                let vert_index = mesh.add_empty_vert_index();
+                let vert = mesh.get_vert_mut(vert_index).unwrap();
+                vert.uv = uv;
+                vert.node = node_index;
+                if let Some(node_index) = node_index {
+                    mesh.get_node_mut(node_index)
+                        .unwrap()
+                        .verts
+                        .push(vert_index);
+                }
+                Ok(vert_index)
+            })
+            .collect::<Result<Vec<_>, QRMeshError>>()?;
+
+        let mut cursor = Cursor::new(chunks.get(&CHUNK_EDGES).ok_or(QRMeshError::InvalidData)?);
+        let edges = (0..cursor.read_u32()?)
+            .map(|_| {
+                let v1_index = cursor.read_optional_index(&verts)?;
+                let v2_index = cursor.read_optional_index(&verts)?;
+                let edge_index = mesh.add_empty_edge_index();
+                if let (Some(v1_index), Some(v2_index)) = (v1_index, v2_index) {
+                    mesh.get_edge_mut(edge_index).unwrap().verts = Some((v1_index, v2_index));
+                    mesh.get_vert_mut(v1_index).unwrap().edges.push(edge_index);
+                    mesh.get_vert_mut(v2_index).unwrap().edges.push(edge_index);
+                }
+                Ok(edge_index)
+            })
+            .collect::<Result<Vec<_>, QRMeshError>>()?;
+
+        let mut cursor = Cursor::new(chunks.get(&CHUNK_FACES).ok_or(QRMeshError::InvalidData)?);
+        let faces = (0..cursor.read_u32()?)
+            .map(|_| {
+                let normal = cursor.read_optional_dvec3()?;
+                let face_verts = (0..cursor.read_u32()?)
+                    .map(|_| {
+                        cursor
+                            .read_optional_index(&verts)?
+                            .ok_or(QRMeshError::InvalidData)
+                    })
+                    .collect::<Result<Vec<_>, QRMeshError>>()?;
+
+                let face_index = mesh.add_empty_face_index();
+                for (v1_index, v2_index) in face_verts.iter().zip(face_verts.iter().cycle().skip(1))
+                {
+                    let edge_index = mesh
+                        .get_connecting_edge_index(*v1_index, *v2_index)
+                        .ok_or(QRMeshError::InvalidData)?;
+                    mesh.get_edge_mut(edge_index)
+                        .unwrap()
+                        .faces
+                        .push(face_index);
+                }
+                let face = mesh.get_face_mut(face_index).unwrap();
+                face.normal = normal;
+                face.verts = face_verts;
+                Ok(face_index)
+            })
+            .collect::<Result<Vec<_>, QRMeshError>>()?;
+
+        Ok((
+            mesh,
+            QRMeshReadData {
+                chunks,
+                nodes,
+                verts,
+                edges,
+                faces,
+            },
+        ))
+    }
+}
+
+impl<END, EVD, EED, EFD> Mesh<END, EVD, EED, EFD>
+where
+    END: Serialize,
+    EVD: Serialize,
+    EED: Serialize,
+    EFD: Serialize,
+{
+    /// Write the mesh in the `.qrmesh` format along with the extra
+    /// data of all the elements.
+    pub fn write_qrmesh_with_extra_data<W: Write>(
+        &self,
+        writer: W,
+        compression: QRMeshCompression,
+    ) -> Result<(), QRMeshError> {
+        let extra_chunks = vec![
+            (
+                CHUNK_NODES_EXTRA_DATA,
+                bincode::serialize(
+                    &self
+                        .nodes
+                        .iter()
+                        .map(|(_, node)| &node.extra_data)
+                        .collect::<Vec<_>>(),
+                )?,
+            ),
+            (
+                CHUNK_VERTS_EXTRA_DATA,
+                bincode::serialize(
+                    &self
+                        .verts
+                        .iter()
+                        .map(|(_, vert)| &vert.extra_data)
+                        .collect::<Vec<_>>(),
+                )?,
+            ),
+            (
+                CHUNK_EDGES_EXTRA_DATA,
+                bincode::serialize(
+                    &self
+                        .edges
+                        .iter()
+                        .map(|(_, edge)| &edge.extra_data)
+                        .collect::<Vec<_>>(),
+                )?,
+            ),
+            (
+                CHUNK_FACES_EXTRA_DATA,
+                bincode::serialize(
+                    &self
+                        .faces
+                        .iter()
+                        .map(|(_, face)| &face.extra_data)
+                        .collect::<Vec<_>>(),
+                )?,
+            ),
+        ];
+
+        self.write_qrmesh_chunks(writer, compression, extra_chunks)
+    }
+}
+
+impl<END, EVD, EED, EFD> Mesh<END, EVD, EED, EFD>
+where
+    END: DeserializeOwned,
+    EVD: DeserializeOwned,
+    EED: DeserializeOwned,
+    EFD: DeserializeOwned,
+{
+    /// Read the mesh from the `.qrmesh` format along with the extra
+    /// data of the elements. If the file doesn't store the extra data
+    /// of some element type, the extra data of those elements is
+    /// [`None`].
+    pub fn read_qrmesh_with_extra_data<R: Read>(reader: R) -> Result<Self, QRMeshError> {
+        let (mut mesh, read_data) = Self::read_qrmesh_chunks(reader)?;
+
+        read_extra_data(
+            &read_data,
+            CHUNK_NODES_EXTRA_DATA,
+            &read_data.nodes,
+            |i, data| mesh.get_node_mut(i).unwrap().extra_data = data,
+        )?;
+        read_extra_data(
+            &read_data,
+            CHUNK_VERTS_EXTRA_DATA,
+            &read_data.verts,
+            |i, data| mesh.get_vert_mut(i).unwrap().extra_data = data,
+        )?;
+        read_extra_data(
+            &read_data,
+            CHUNK_EDGES_EXTRA_DATA,
+            &read_data.edges,
+            |i, data| mesh.get_edge_mut(i).unwrap().extra_data = data,
+        )?;
+        read_extra_data(
+            &read_data,
+            CHUNK_FACES_EXTRA_DATA,
+            &read_data.faces,
+            |i, data| mesh.get_face_mut(i).unwrap().extra_data = data,
+        )?;
+
+        Ok(mesh)
+    }
+}
+
+/// Deserialize the extra data stored in the chunk `tag` (if it
+/// exists) and hand it over to `set_extra_data` along with the index
+/// of the element.
+fn read_extra_data<I, X, F>(
+    read_data: &QRMeshReadData,
+    tag: [u8; 4],
+    indices: &[I],
+    mut set_extra_data: F,
+) -> Result<(), QRMeshError>
+where
+    I: Copy,
+    X: DeserializeOwned,
+    F: FnMut(I, Option<X>),
+{
+    if let Some(chunk) = read_data.chunks.get(&tag) {
+        let extra_data: Vec<Option<X>> = bincode::deserialize(chunk)?;
+        if extra_data.len() != indices.len() {
+            return Err(QRMeshError::InvalidData);
+        }
+        indices
+            .iter()
+            .zip(extra_data)
+            .for_each(|(index, extra_data)| set_extra_data(*index, extra_data));
+    }
+    Ok(())
+}
+
+/// Number of elements as `u32`, the number of elements must be
+/// lesser than [`NONE_INDEX`].
+fn num_elements_to_u32(len: usize) -> Result<u32, QRMeshError> {
+    match len.try_into() {
+        Ok(len) if len < NONE_INDEX => Ok(len),
+        _ => Err(QRMeshError::TooManyElements),
+    }
+}
+
+fn write_chunk(data: &mut Vec<u8>, tag: [u8; 4], chunk: &[u8]) {
+    data.extend_from_slice(&tag);
+    data.extend_from_slice(&(chunk.len() as u64).to_le_bytes());
+    data.extend_from_slice(chunk);
+}
+
+fn write_u32(data: &mut Vec<u8>, val: u32) {
+    data.extend_from_slice(&val.to_le_bytes());
+}
+
+fn write_f64(data: &mut Vec<u8>, val: f64) {
+    data.extend_from_slice(&val.to_le_bytes());
+}
+
+fn write_dvec3(data: &mut Vec<u8>, val: &glm::DVec3) {
+    write_f64(data, val[0]);
+    write_f64(data, val[1]);
+    write_f64(data, val[2]);
+}
+
+fn write_optional_dvec3(data: &mut Vec<u8>, val: &Option<glm::DVec3>) {
+    match val {
+        Some(val) => {
+            data.push(1);
+            write_dvec3(data, val);
+        }
+        None => data.push(0),
+    }
+}
+
+/// Read position into a byte slice, all reads past the end of the
+/// slice give [`QRMeshError::InvalidData`].
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], QRMeshError> {
+        let end = self.pos.checked_add(len).ok_or(QRMeshError::InvalidData)?;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or(QRMeshError::InvalidData)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, QRMeshError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, QRMeshError> {
+        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, QRMeshError> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, QRMeshError> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    fn read_f64(&mut self) -> Result<f64, QRMeshError> {
+        Ok(f64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    fn read_dvec3(&mut self) -> Result<glm::DVec3, QRMeshError> {
+        Ok(glm::vec3(
+            self.read_f64()?,
+            self.read_f64()?,
+            self.read_f64()?,
+        ))
+    }
+
+    fn read_optional_dvec3(&mut self) -> Result<Option<glm::DVec3>, QRMeshError> {
+        match self.read_u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.read_dvec3()?)),
+        }
+    }
+
+    /// Read a dense index and map it through `indices`.
+    fn read_optional_index<T: Copy>(&mut self, indices: &[T]) -> Result<Option<T>, QRMeshError> {
+        match self.read_u32()? {
+            NONE_INDEX => Ok(None),
+            i => indices
+                .get(i as usize)
+                .copied()
+                .map(Some)
+                .ok_or(QRMeshError::InvalidData),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::mesh::simple;
+
+    fn assert_same_mesh<END, EVD, EED, EFD>(
+        mesh_1: &Mesh<END, EVD, EED, EFD>,
+        mesh_2: &Mesh<END, EVD, EED, EFD>,
+    ) {
+        assert_eq!(mesh_1.nodes.len(), mesh_2.nodes.len());
+        assert_eq!(mesh_1.verts.len(), mesh_2.verts.len());
+        assert_eq!(mesh_1.edges.len(), mesh_2.edges.len());
+        assert_eq!(mesh_1.faces.len(), mesh_2.faces.len());
+        mesh_1
+            .nodes
+            .iter()
+            .zip(mesh_2.nodes.iter())
+            .for_each(|((_, n1), (_, n2))| {
+                assert_eq!(n1.pos, n2.pos);
+                assert_eq!(n1.normal, n2.normal);
+            });
+        mesh_1
+            .verts
+            .iter()
+            .zip(mesh_2.verts.iter())
+            .for_each(|((_, v1), (_, v2))| {
+                assert_eq!(v1.uv, v2.uv);
+                assert_eq!(v1.edges.len(), v2.edges.len());
+            });
+        mesh_1
+            .edges
+            .iter()
+            .zip(mesh_2.edges.iter())
+            .for_each(|((_, e1), (_, e2))| {
+                assert_eq!(e1.faces.len(), e2.faces.len());
+            });
+        mesh_1
+            .faces
+            .iter()
+            .zip(mesh_2.faces.iter())
+            .for_each(|((_, f1), (_, f2))| {
+                assert_eq!(
+                    mesh_1.get_nodes_of_face(f1).len(),
+                    mesh_2.get_nodes_of_face(f2).len()
+                );
+            });
+    }
+
+    #[test]
+    fn qrmesh_round_trip() {
+        let mesh = simple::Mesh::read_from_file(Path::new("tests/obj_test_01.obj")).unwrap();
+
+        for compression in [
+            QRMeshCompression::None,
+            QRMeshCompression::Gzip,
+            QRMeshCompression::Zstd,
+        ] {
+            let mut data = Vec::new();
+            mesh.write_qrmesh(&mut data, compression).unwrap();
+            if compression == QRMeshCompression::None {
+                assert_eq!(data[0..8], QRMESH_MAGIC);
+            }
+
+            let read_mesh = simple::Mesh::read_qrmesh(data.as_slice()).unwrap();
+            assert_same_mesh(&mesh, &read_mesh);
+        }
+    }
+
+    #[test]
+    fn qrmesh_extra_data() {
+        let mut mesh: Mesh<usize, (), (), String> =
+            Mesh::read_from_file(Path::new("tests/obj_test_01.obj")).unwrap();
+        mesh.nodes
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, (_, node))| node.extra_data = Some(i * 10));
+        mesh.faces
+            .iter_mut()
+            .for_each(|(_, face)| face.extra_data = Some(format!("{}", face.verts.len())));
+
+        let mut data = Vec::new();
+        mesh.write_qrmesh_with_extra_data(&mut data, QRMeshCompression::Zstd)
+            .unwrap();
+
+        let read_mesh: Mesh<usize, (), (), String> =
+            Mesh::read_qrmesh_with_extra_data(data.as_slice()).unwrap();
+        assert_same_mesh(&mesh, &read_mesh);
+        read_mesh
+            .nodes
+            .iter()
+            .enumerate()
+            .for_each(|(i, (_, node))| assert_eq!(node.extra_data, Some(i * 10)));
+        read_mesh
+            .faces
+            .iter()
+            .for_each(|(_, face)| assert_eq!(face.extra_data, Some("3".to_string())));
+
+        // extra data is ignored when not requested
+        let read_mesh: Mesh<usize, (), (), String> = Mesh::read_qrmesh(data.as_slice()).unwrap();
+        read_mesh
+            .nodes
+            .iter()
+            .for_each(|(_, node)| assert!(node.extra_data.is_none()));
+    }
+
+    #[test]
+    fn qrmesh_forward_compatibility() {
+        let mesh = simple::Mesh::read_from_file(Path::new("tests/obj_test_01.obj")).unwrap();
+        let mut data = Vec::new();
+        mesh.write_qrmesh_chunks(
+            &mut data,
+            QRMeshCompression::None,
+            vec![(*b"NEW\0", vec![1, 2, 3, 4])],
+        )
+        .unwrap();
+
+        // newer minor version with unknown chunk
+        data[10..12].copy_from_slice(&(QRMESH_VERSION_MINOR + 1).to_le_bytes());
+        let read_mesh = simple::Mesh::read_qrmesh(data.as_slice()).unwrap();
+        assert_same_mesh(&mesh, &read_mesh);
+
+        // newer major version
+        data[8..10].copy_from_slice(&(QRMESH_VERSION_MAJOR + 1).to_le_bytes());
+        assert!(matches!(
+            simple::Mesh::read_qrmesh(data.as_slice()),
+            Err(QRMeshError::UnsupportedVersion(_))
+        ));
+
+        assert!(matches!(
+            simple::Mesh::read_qrmesh(&b"not a qrmesh file"[..]),
+            Err(QRMeshError::InvalidMagic)
+        ));
+        assert!(matches!(
+            simple::Mesh::read_qrmesh(&QRMESH_MAGIC[..]),
+            Err(QRMeshError::InvalidData)
+        ));
+    }
+
+    #[test]
+    fn qrmesh_length_mismatch() {
+        let mesh = simple::Mesh::read_from_file(Path::new("tests/obj_test_01.obj")).unwrap();
+        let mut data = Vec::new();
+        mesh.write_qrmesh(&mut data, QRMeshCompression::None)
+            .unwrap();
+        let len = u64::from_le_bytes(data[12..20].try_into().unwrap());
+        assert_eq!(len as usize, data.len() - 20);
+
+        let compress = |data: &[u8]| zstd::stream::encode_all(data, 0).unwrap();
+
+        // truncated
+        assert!(matches!(
+            simple::Mesh::read_qrmesh(compress(&data[..data.len() - 1]).as_slice()),
+            Err(QRMeshError::InvalidData)
+        ));
+
+        // trailing data
+        let mut trailing = data.clone();
+        trailing.extend_from_slice(&[0; 16]);
+        assert!(matches!(
+            simple::Mesh::read_qrmesh(compress(&trailing).as_slice()),
+            Err(QRMeshError::InvalidData)
+        ));
+
+        // claims more data than stored, is not read past the end
+        let mut huge = data;
+        huge[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            simple::Mesh::read_qrmesh(compress(&huge).as_slice()),
+            Err(QRMeshError::InvalidData)
+        ));
+    }
+}
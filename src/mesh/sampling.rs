@@ -0,0 +1,310 @@
+//! Point sampling on the surface of [`Mesh`].
+//!
+//! Samples are area weighted, ngons are fan triangulated. All the
+//! sampling functions take a seed so that the samples are
+//! reproducible, [`ChaCha8Rng`] is used since its output doesn't
+//! change across platforms and versions of `rand`.
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::{FaceIndex, Mesh, VertIndex};
+use crate::glm;
+use crate::spatial_hash::SpatialHash;
+use crate::util;
+
+/// Number of candidates generated per expected Poisson disk sample.
+const POISSON_DISK_CANDIDATES_PER_SAMPLE: f64 = 30.0;
+
+/// A point on the surface of the mesh.
+#[derive(Debug, Clone, Copy)]
+pub struct MeshSurfaceSample {
+    /// Face on which the sample lies.
+    pub face_index: FaceIndex,
+    /// Verts of the triangle of the face on which the sample lies,
+    /// the face is fan triangulated if it is an ngon.
+    pub verts: [VertIndex; 3],
+    /// Bary coords of the sample with respect to `verts`.
+    pub bary_coord: glm::DVec3,
+    /// Position of the sample.
+    pub pos: glm::DVec3,
+    /// Normal at the sample, interpolated from the node normals if
+    /// they all exist (and don't cancel out) otherwise the normal of
+    /// the triangle.
+    pub normal: glm::DVec3,
+    /// Interpolated UV at the sample if all the verts have UVs.
+    pub uv: Option<glm::DVec2>,
+}
+
+/// Fan triangulated triangles of the mesh along with the cumulative
+/// areas for area weighted selection.
+struct SamplingTris {
+    tris: Vec<(FaceIndex, [VertIndex; 3])>,
+    cumulative_areas: Vec<f64>,
+}
+
+impl SamplingTris {
+    fn new<END, EVD, EED, EFD>(mesh: &Mesh<END, EVD, EED, EFD>) -> Self {
+        let mut tris = Vec::new();
+        let mut cumulative_areas = Vec::new();
+        let mut total_area = 0.0;
+        for (_, face) in mesh.get_faces() {
+            let verts = face.get_verts();
+            for i in 1..verts.len().saturating_sub(1) {
+                let tri_verts = [verts[0], verts[i], verts[i + 1]];
+                let [p1, p2, p3] = tri_verts.map(|vert_index| mesh.get_vert_node_pos(vert_index));
+                total_area += glm::length(&glm::cross(&(p2 - p1), &(p3 - p1))) * 0.5;
+
+                tris.push((face.get_self_index(), tri_verts));
+                cumulative_areas.push(total_area);
+            }
+        }
+
+        Self {
+            tris,
+            cumulative_areas,
+        }
+    }
+
+    fn total_area(&self) -> f64 {
+        self.cumulative_areas.last().copied().unwrap_or(0.0)
+    }
+
+    /// Pick an area weighted random triangle and a uniformly
+    /// distributed point on it.
+    fn sample<R: Rng, END, EVD, EED, EFD>(
+        &self,
+        mesh: &Mesh<END, EVD, EED, EFD>,
+        rng: &mut R,
+    ) -> MeshSurfaceSample {
+        let area = rng.gen_range(0.0..self.total_area());
+        let tri_index = self
+            .cumulative_areas
+            .partition_point(|cumulative_area| *cumulative_area <= area)
+            .min(self.tris.len() - 1);
+        let (face_index, verts) = self.tris[tri_index];
+
+        let r1: f64 = rng.gen::<f64>().sqrt();
+        let r2: f64 = rng.gen();
+        let bary_coord = glm::vec3(1.0 - r1, r1 * (1.0 - r2), r1 * r2);
+
+        mesh.surface_sample_at(face_index, verts, bary_coord)
+    }
+}
+
+impl<END, EVD, EED, EFD> Mesh<END, EVD, EED, EFD> {
+    fn get_vert_node_pos(&self, vert_index: VertIndex) -> glm::DVec3 {
+        let vert = self.get_vert(vert_index).unwrap();
+        self.get_node(vert.get_node().unwrap()).unwrap().pos
+    }
+
+    fn surface_sample_at(
+        &self,
+        face_index: FaceIndex,
+        verts: [VertIndex; 3],
+        bary_coord: glm::DVec3,
+    ) -> MeshSurfaceSample {
+        let verts_ref = verts.map(|vert_index| self.get_vert(vert_index).unwrap());
+        let nodes = verts_ref.map(|vert| self.get_node(vert.get_node().unwrap()).unwrap());
+
+        let pos =
+            util::vec3_apply_bary_coord(&nodes[0].pos, &nodes[1].pos, &nodes[2].pos, &bary_coord);
+
+        let interpolated_normal = match (nodes[0].normal, nodes[1].normal, nodes[2].normal) {
+            (Some(n1), Some(n2), Some(n3)) => {
+                Some(util::vec3_apply_bary_coord(&n1, &n2, &n3, &bary_coord))
+            }
+            _ => None,
+        };
+        let normal = match interpolated_normal {
+            // opposing node normals can sum to zero
+            Some(normal) if glm::length(&normal) > 0.0 => glm::normalize(&normal),
+            _ => glm::normalize(&glm::cross(
+                &(nodes[1].pos - nodes[0].pos),
+                &(nodes[2].pos - nodes[0].pos),
+            )),
+        };
+
+        let uv = match (verts_ref[0].uv, verts_ref[1].uv, verts_ref[2].uv) {
+            (Some(uv1), Some(uv2), Some(uv3)) => {
+                Some(util::vec2_apply_bary_coord(&uv1, &uv2, &uv3, &bary_coord))
+            }
+            _ => None,
+        };
+
+        MeshSurfaceSample {
+            face_index,
+            verts,
+            bary_coord,
+            pos,
+            normal,
+            uv,
+        }
+    }
+
+    /// Total surface area of the mesh.
+    pub fn compute_surface_area(&self) -> f64 {
+        SamplingTris::new(self).total_area()
+    }
+
+    /// Generate `num_samples` uniformly distributed (area weighted)
+    /// random samples on the surface of the mesh.
+    ///
+    /// Returns no samples if the mesh has no area.
+    pub fn sample_surface_random(&self, num_samples: usize, seed: u64) -> Vec<MeshSurfaceSample> {
+        let tris = SamplingTris::new(self);
+        if tris.total_area() <= 0.0 {
+            return Vec::new();
+        }
+
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        (0..num_samples)
+            .map(|_| tris.sample(self, &mut rng))
+            .collect()
+    }
+
+    /// Generate Poisson disk samples on the surface of the mesh, no
+    /// two samples are closer than `radius` (euclidean distance).
+    ///
+    /// Uses dart throwing, candidates are generated by
+    /// [`Self::sample_surface_random()`] and rejected if they lie
+    /// within `radius` of an accepted sample. The number of
+    /// candidates is proportional to the maximum number of samples
+    /// that can fit on the surface, so the result is close to
+    /// maximal.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `radius` is not positive and finite.
+    pub fn sample_surface_poisson_disk(&self, radius: f64, seed: u64) -> Vec<MeshSurfaceSample> {
+        assert!(
+            radius > 0.0 && radius.is_finite(),
+            "radius must be positive and finite"
+        );
+
+        let tris = SamplingTris::new(self);
+        if tris.total_area() <= 0.0 {
+            return Vec::new();
+        }
+
+        // with hexagonal packing, every sample occupies a hexagon of
+        // area `sqrt(3) / 2 * radius^2`
+        let max_samples = tris.total_area() / (0.75_f64.sqrt() * radius * radius);
+        let num_candidates = (max_samples * POISSON_DISK_CANDIDATES_PER_SAMPLE).ceil() as usize + 1;
+
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let mut spatial_hash = SpatialHash::new(radius);
+        let mut samples = Vec::new();
+        for _ in 0..num_candidates {
+            let candidate = tris.sample(self, &mut rng);
+            if spatial_hash
+                .find_nearest_within_radius(&candidate.pos, radius)
+                .is_none()
+            {
+                spatial_hash.insert(samples.len(), candidate.pos);
+                samples.push(candidate);
+            }
+        }
+
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::mesh::simple;
+
+    #[test]
+    fn mesh_sample_surface_random() {
+        let mesh = simple::Mesh::read_from_file(Path::new("models/monkey_subd_00.obj")).unwrap();
+
+        let samples = mesh.sample_surface_random(1000, 1);
+        assert_eq!(samples.len(), 1000);
+
+        let same_samples = mesh.sample_surface_random(1000, 1);
+        samples.iter().zip(same_samples.iter()).for_each(|(a, b)| {
+            assert_eq!(a.face_index, b.face_index);
+            assert_eq!(a.pos, b.pos);
+        });
+
+        samples.iter().for_each(|sample| {
+            assert!(sample.bary_coord.iter().all(|val| *val >= 0.0));
+            assert!((sample.bary_coord.sum() - 1.0).abs() < 1e-9);
+            assert!((glm::length(&sample.normal) - 1.0).abs() < 1e-9);
+            assert!(sample.uv.is_some());
+            assert!(mesh.get_face(sample.face_index).is_some());
+        });
+    }
+
+    #[test]
+    fn mesh_sample_surface_opposing_normals() {
+        let mut mesh =
+            simple::Mesh::read_from_file(Path::new("models/plane_subd_00_triangulated.obj"))
+                .unwrap();
+        let (face_index, face) = mesh.get_faces().iter().next().unwrap();
+        let face_index = FaceIndex(face_index);
+        let verts = [
+            face.get_verts()[0],
+            face.get_verts()[1],
+            face.get_verts()[2],
+        ];
+
+        // node normals that cancel out midway between the first two
+        // nodes
+        for (vert_index, normal) in verts.iter().zip([
+            glm::vec3(0.0, 1.0, 0.0),
+            glm::vec3(0.0, -1.0, 0.0),
+            glm::vec3(0.0, 1.0, 0.0),
+        ]) {
+            let node_index = mesh.get_vert(*vert_index).unwrap().get_node().unwrap();
+            mesh.get_node_mut(node_index).unwrap().normal = Some(normal);
+        }
+
+        let sample = mesh.surface_sample_at(face_index, verts, glm::vec3(0.5, 0.5, 0.0));
+        let [p1, p2, p3] = verts.map(|vert_index| mesh.get_vert_node_pos(vert_index));
+        let face_normal = glm::normalize(&glm::cross(&(p2 - p1), &(p3 - p1)));
+        assert_eq!(sample.normal, face_normal);
+    }
+
+    #[test]
+    fn mesh_sample_surface_random_area_weighted() {
+        let mesh = simple::Mesh::read_from_file(Path::new("models/plane_subd_00_triangulated.obj"))
+            .unwrap();
+        let (min, max) = mesh.get_nodes().iter().fold(
+            (glm::DVec3::repeat(f64::MAX), glm::DVec3::repeat(f64::MIN)),
+            |acc, (_, node)| (glm::min2(&acc.0, &node.pos), glm::max2(&acc.1, &node.pos)),
+        );
+        let mid = (min + max) * 0.5;
+
+        // plane is a square, a quarter of the samples must be in each
+        // quadrant
+        let samples = mesh.sample_surface_random(4000, 2);
+        let num_in_quadrant = samples
+            .iter()
+            .filter(|sample| sample.pos[0] < mid[0] && sample.pos[2] < mid[2])
+            .count();
+        assert!((num_in_quadrant as f64 - 1000.0).abs() < 100.0);
+    }
+
+    #[test]
+    fn mesh_sample_surface_poisson_disk() {
+        let mesh =
+            simple::Mesh::read_from_file(Path::new("models/ico_sphere_subd_02.obj")).unwrap();
+
+        let radius = 0.2;
+        let samples = mesh.sample_surface_poisson_disk(radius, 3);
+        assert!(samples.len() > 10);
+
+        for (i, a) in samples.iter().enumerate() {
+            for b in samples.iter().skip(i + 1) {
+                assert!(glm::distance(&a.pos, &b.pos) >= radius);
+            }
+        }
+
+        let same_samples = mesh.sample_surface_poisson_disk(radius, 3);
+        assert_eq!(samples.len(), same_samples.len());
+    }
+}
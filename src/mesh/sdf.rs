@@ -0,0 +1,610 @@
+//! Signed distance queries on closed [`Mesh`]es.
+//!
+//! [`MeshSDF`] gives the signed distance (negative inside) from a
+//! point to the surface of the mesh and can sample it onto a 3D grid
+//! ([`SDFGrid`]).
+
+use std::collections::HashMap;
+
+use super::{Mesh, NodeIndex};
+use crate::bvh::{nearest_point_to_tri, BVHFlatNode, BVHTree, NearestData};
+use crate::glm;
+use crate::util;
+
+/// Bary coords below this are considered to be zero, when finding
+/// the feature (vertex, edge or face) of the triangle nearest to the
+/// point.
+const BARY_COORD_EPSILON: f64 = 1e-9;
+
+/// Triangles of a node of the hierarchy farther than this times the
+/// radius of the node from the point are approximated by a dipole
+/// for the winding number.
+const WINDING_NUMBER_FAR_FIELD_RATIO: f64 = 3.0;
+
+/// How [`MeshSDF`] decides if a point is inside the mesh.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeshSDFSign {
+    /// Sign from the angle weighted pseudonormal of the feature
+    /// (vertex, edge or face) nearest to the point, see "Signed
+    /// Distance Computation Using the Angle Weighted Pseudonormal"
+    /// (Bærentzen and Aanæs). Fast, needs a closed, manifold and
+    /// consistently oriented mesh.
+    Pseudonormal,
+    /// Inside if the generalized winding number of the point is
+    /// above 0.5, see "Robust Inside-Outside Segmentation using
+    /// Generalized Winding Numbers" (Jacobson et al.). Robust to
+    /// holes and non manifold geometry. The far away nodes of the
+    /// hierarchy of the BVH are approximated by dipoles, see "Fast
+    /// Winding Numbers for Soups and Clouds" (Barill et al.), so a
+    /// query only visits the triangles near the point.
+    WindingNumber,
+}
+
+/// Triangle of a face (ngons are fan triangulated) with the
+/// pseudonormals of its features.
+#[derive(Debug, Clone)]
+struct SDFTri {
+    positions: [glm::DVec3; 3],
+    face_normal: glm::DVec3,
+    /// Pseudonormal of the edge from vert `i` to vert `(i + 1) % 3`.
+    edge_normals: [glm::DVec3; 3],
+    vert_normals: [glm::DVec3; 3],
+}
+
+/// Node of the hierarchy of the BVH of [`MeshSDF`] with the dipole
+/// approximating its triangles for the winding number.
+#[derive(Debug, Clone)]
+struct WindingNode {
+    node: BVHFlatNode<usize>,
+    /// Sum of the area weighted normals of the triangles.
+    normal: glm::DVec3,
+    /// Area weighted centroid of the triangles.
+    center: glm::DVec3,
+    area: f64,
+    /// Distance from `center` bounding the triangles.
+    radius: f64,
+}
+
+/// Signed distance field of a closed [`Mesh`], the distance is
+/// negative inside the mesh.
+///
+/// It stores a snapshot of the positions of the mesh, it must be
+/// rebuilt if the mesh changes.
+pub struct MeshSDF {
+    tris: Vec<SDFTri>,
+    bvh: BVHTree<f64, usize>,
+    /// Hierarchy of `bvh` with the children before their parent.
+    winding_nodes: Vec<WindingNode>,
+    sign: MeshSDFSign,
+}
+
+/// Signed distance sampled on a regular 3D grid, see
+/// [`MeshSDF::sample_grid()`].
+#[derive(Debug, Clone)]
+pub struct SDFGrid {
+    /// Position of the sample `(0, 0, 0)`.
+    pub min: glm::DVec3,
+    /// Distance between adjacent samples along each axis.
+    pub cell_size: glm::DVec3,
+    /// Number of samples along each axis.
+    pub resolution: [usize; 3],
+    /// Samples with x varying fastest, then y, then z.
+    pub values: Vec<f64>,
+}
+
+impl SDFGrid {
+    fn index(&self, i: usize, j: usize, k: usize) -> usize {
+        (k * self.resolution[1] + j) * self.resolution[0] + i
+    }
+
+    /// Position of the sample `(i, j, k)`.
+    pub fn get_pos(&self, i: usize, j: usize, k: usize) -> glm::DVec3 {
+        self.min
+            + glm::vec3(
+                i as f64 * self.cell_size[0],
+                j as f64 * self.cell_size[1],
+                k as f64 * self.cell_size[2],
+            )
+    }
+
+    /// Signed distance at the sample `(i, j, k)`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the sample is out of range.
+    pub fn get(&self, i: usize, j: usize, k: usize) -> f64 {
+        assert!(i < self.resolution[0] && j < self.resolution[1] && k < self.resolution[2]);
+        self.values[self.index(i, j, k)]
+    }
+}
+
+impl MeshSDF {
+    /// Build the signed distance field for the given mesh, `sign`
+    /// decides how the inside of the mesh is found.
+    pub fn new<END, EVD, EED, EFD>(mesh: &Mesh<END, EVD, EED, EFD>, sign: MeshSDFSign) -> Self {
+        let mut tri_nodes = Vec::new();
+        let mut tris = Vec::new();
+        for (_, face) in mesh.get_faces() {
+            let nodes: Vec<NodeIndex> = mesh
+                .get_nodes_of_face(face)
+                .iter()
+                .map(|node_index| node_index.unwrap())
+                .collect();
+
+            for i in 1..nodes.len().saturating_sub(1) {
+                let nodes = [nodes[0], nodes[i], nodes[i + 1]];
+                let positions = nodes.map(|node_index| mesh.get_node(node_index).unwrap().pos);
+                let normal = glm::cross(
+                    &(positions[1] - positions[0]),
+                    &(positions[2] - positions[0]),
+                );
+                // degenerate triangles don't contribute to the
+                // pseudonormals, their features are shared with
+                // other triangles
+                let face_normal = if glm::length(&normal) > 0.0 {
+                    glm::normalize(&normal)
+                } else {
+                    glm::zero()
+                };
+
+                tri_nodes.push(nodes);
+                tris.push(SDFTri {
+                    positions,
+                    face_normal,
+                    edge_normals: [glm::zero(); 3],
+                    vert_normals: [glm::zero(); 3],
+                });
+            }
+        }
+
+        // accumulate the pseudonormals over the triangles sharing
+        // the feature, edges are weighted by the angle `pi` for both
+        // the triangles so the face normals are just summed
+        let edge_key =
+            |node_1: NodeIndex, node_2: NodeIndex| (node_1.min(node_2), node_1.max(node_2));
+        let mut edge_normals: HashMap<(NodeIndex, NodeIndex), glm::DVec3> = HashMap::new();
+        let mut vert_normals: HashMap<NodeIndex, glm::DVec3> = HashMap::new();
+        tris.iter().zip(tri_nodes.iter()).for_each(|(tri, nodes)| {
+            for i in 0..3 {
+                let (i_next, i_prev) = ((i + 1) % 3, (i + 2) % 3);
+                *edge_normals
+                    .entry(edge_key(nodes[i], nodes[i_next]))
+                    .or_insert_with(glm::zero) += tri.face_normal;
+
+                let angle = glm::angle(
+                    &(tri.positions[i_next] - tri.positions[i]),
+                    &(tri.positions[i_prev] - tri.positions[i]),
+                );
+                if angle.is_finite() {
+                    *vert_normals.entry(nodes[i]).or_insert_with(glm::zero) +=
+                        tri.face_normal * angle;
+                }
+            }
+        });
+        tris.iter_mut()
+            .zip(tri_nodes.iter())
+            .for_each(|(tri, nodes)| {
+                for i in 0..3 {
+                    tri.edge_normals[i] = edge_normals[&edge_key(nodes[i], nodes[(i + 1) % 3])];
+                    tri.vert_normals[i] = vert_normals
+                        .get(&nodes[i])
+                        .copied()
+                        .unwrap_or_else(glm::zero);
+                }
+            });
+
+        let mut bvh = BVHTree::new(tris.len(), 0.0, 4, 6);
+        tris.iter().enumerate().for_each(|(i, tri)| {
+            bvh.insert(i, &tri.positions);
+        });
+        bvh.balance_multithreaded();
+
+        let mut winding_nodes: Vec<WindingNode> = Vec::new();
+        for node in bvh.flatten_hierarchy() {
+            let winding_node = match &node {
+                BVHFlatNode::Leaf(tri_index) => {
+                    let [p1, p2, p3] = tris[*tri_index].positions;
+                    let normal = glm::cross(&(p2 - p1), &(p3 - p1)) * 0.5;
+                    let center = (p1 + p2 + p3) / 3.0;
+                    let radius = [p1, p2, p3]
+                        .iter()
+                        .map(|pos| glm::distance(pos, &center))
+                        .fold(0.0, f64::max);
+                    WindingNode {
+                        node,
+                        normal,
+                        center,
+                        area: glm::length(&normal),
+                        radius,
+                    }
+                }
+                BVHFlatNode::Branch(children) => {
+                    let children: Vec<&WindingNode> =
+                        children.iter().map(|i| &winding_nodes[*i]).collect();
+                    let normal = children
+                        .iter()
+                        .fold(glm::zero(), |acc: glm::DVec3, child| acc + child.normal);
+                    let area: f64 = children.iter().map(|child| child.area).sum();
+                    // degenerate triangles have no area, fall back to
+                    // the plain centroid of the children
+                    let center = if area > 0.0 {
+                        children.iter().fold(glm::zero(), |acc: glm::DVec3, child| {
+                            acc + child.center * child.area
+                        }) / area
+                    } else if children.is_empty() {
+                        glm::zero()
+                    } else {
+                        children
+                            .iter()
+                            .fold(glm::zero(), |acc: glm::DVec3, child| acc + child.center)
+                            / children.len() as f64
+                    };
+                    let radius = children
+                        .iter()
+                        .map(|child| glm::distance(&child.center, &center) + child.radius)
+                        .fold(0.0, f64::max);
+                    WindingNode {
+                        node,
+                        normal,
+                        center,
+                        area,
+                        radius,
+                    }
+                }
+            };
+            winding_nodes.push(winding_node);
+        }
+
+        Self {
+            tris,
+            bvh,
+            winding_nodes,
+            sign,
+        }
+    }
+
+    pub fn get_sign(&self) -> MeshSDFSign {
+        self.sign
+    }
+
+    /// Find the triangle nearest to `pos` along with the nearest
+    /// point on it and the squared distance to it.
+    fn find_nearest(&self, pos: &glm::DVec3) -> Option<(usize, glm::DVec3, f64)> {
+        if self.tris.is_empty() {
+            return None;
+        }
+
+        let nearest = self.bvh.find_nearest(
+            *pos,
+            f64::INFINITY,
+            &Some(
+                |tri_index: usize, co: &glm::DVec3, nearest: &mut NearestData<f64, usize>| {
+                    let tri = &self.tris[tri_index];
+                    let nearest_co = nearest_point_to_tri(
+                        co,
+                        [&tri.positions[0], &tri.positions[1], &tri.positions[2]],
+                    );
+                    let dist_sq = glm::distance2(co, &nearest_co);
+                    if dist_sq < nearest.get_dist_sq() {
+                        nearest.set_info(Some(tri_index), Some(nearest_co), None, dist_sq);
+                    }
+                },
+            ),
+        )?;
+
+        Some((
+            nearest.get_elem_index().unwrap(),
+            nearest.get_co().unwrap(),
+            nearest.get_dist_sq(),
+        ))
+    }
+
+    /// Pseudonormal of the feature of the triangle on which
+    /// `nearest_co` lies.
+    fn pseudonormal(&self, tri_index: usize, nearest_co: &glm::DVec3) -> glm::DVec3 {
+        let tri = &self.tris[tri_index];
+        let bary_coord = util::vec3_compute_bary_coord(
+            nearest_co,
+            &tri.positions[0],
+            &tri.positions[1],
+            &tri.positions[2],
+        );
+        if !bary_coord.iter().all(|val| val.is_finite()) {
+            return tri.face_normal;
+        }
+
+        let zeros: Vec<usize> = (0..3)
+            .filter(|i| bary_coord[*i] < BARY_COORD_EPSILON)
+            .collect();
+        match zeros.as_slice() {
+            // on the vert that isn't zero
+            [i, j] => tri.vert_normals[3 - i - j],
+            // on the edge opposite to the vert that is zero
+            [i] => tri.edge_normals[(i + 1) % 3],
+            _ => tri.face_normal,
+        }
+    }
+
+    /// Generalized winding number of `pos` with respect to the mesh,
+    /// close to 1 inside and 0 outside the mesh.
+    ///
+    /// The nodes of the hierarchy far away from `pos` are
+    /// approximated by their dipoles, the error is small compared to
+    /// the 0.5 threshold used for the inside test.
+    pub fn winding_number(&self, pos: &glm::DVec3) -> f64 {
+        let mut solid_angle_sum = 0.0;
+        let mut stack: Vec<usize> = self
+            .winding_nodes
+            .len()
+            .checked_sub(1)
+            .into_iter()
+            .collect();
+        while let Some(node_index) = stack.pop() {
+            let winding_node = &self.winding_nodes[node_index];
+            match &winding_node.node {
+                BVHFlatNode::Leaf(tri_index) => {
+                    solid_angle_sum += tri_solid_angle(&self.tris[*tri_index], pos);
+                }
+                BVHFlatNode::Branch(children) => {
+                    let offset = winding_node.center - pos;
+                    let dist = glm::length(&offset);
+                    if dist > WINDING_NUMBER_FAR_FIELD_RATIO * winding_node.radius {
+                        solid_angle_sum +=
+                            glm::dot(&offset, &winding_node.normal) / (dist * dist * dist);
+                    } else {
+                        stack.extend(children);
+                    }
+                }
+            }
+        }
+
+        solid_angle_sum / (4.0 * std::f64::consts::PI)
+    }
+
+    /// Signed distance from `pos` to the surface of the mesh,
+    /// negative inside the mesh.
+    ///
+    /// Returns [`None`] if the mesh has no faces.
+    pub fn signed_distance(&self, pos: &glm::DVec3) -> Option<f64> {
+        let (tri_index, nearest_co, dist_sq) = self.find_nearest(pos)?;
+        let dist = dist_sq.sqrt();
+
+        let is_inside = match self.sign {
+            MeshSDFSign::Pseudonormal => {
+                glm::dot(
+                    &(pos - nearest_co),
+                    &self.pseudonormal(tri_index, &nearest_co),
+                ) < 0.0
+            }
+            MeshSDFSign::WindingNumber => self.winding_number(pos) > 0.5,
+        };
+
+        if is_inside {
+            Some(-dist)
+        } else {
+            Some(dist)
+        }
+    }
+
+    /// Check if `pos` is inside the mesh.
+    pub fn is_inside(&self, pos: &glm::DVec3) -> bool {
+        match self.sign {
+            MeshSDFSign::Pseudonormal => {
+                matches!(self.signed_distance(pos), Some(dist) if dist < 0.0)
+            }
+            MeshSDFSign::WindingNumber => self.winding_number(pos) > 0.5,
+        }
+    }
+
+    /// Sample the signed distance on a regular grid of `resolution`
+    /// samples spanning from `min` to `max` (both inclusive). The
+    /// samples are computed in parallel.
+    ///
+    /// If the mesh has no faces, all the samples are
+    /// [`f64::INFINITY`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of `resolution` is 0.
+    pub fn sample_grid(
+        &self,
+        min: &glm::DVec3,
+        max: &glm::DVec3,
+        resolution: [usize; 3],
+    ) -> SDFGrid {
+        assert!(
+            resolution.iter().all(|res| *res > 0),
+            "resolution must be positive"
+        );
+
+        let cell_size = glm::vec3(
+            (max[0] - min[0]) / (resolution[0].max(2) - 1) as f64,
+            (max[1] - min[1]) / (resolution[1].max(2) - 1) as f64,
+            (max[2] - min[2]) / (resolution[2].max(2) - 1) as f64,
+        );
+        let mut grid = SDFGrid {
+            min: *min,
+            cell_size,
+            resolution,
+            values: vec![f64::INFINITY; resolution.iter().product()],
+        };
+
+        // split the grid into contiguous z slices, one chunk per
+        // thread
+        let slice_len = resolution[0] * resolution[1];
+        let thread_num = std::thread::available_parallelism()
+            .map(|num| num.get())
+            .unwrap_or(1)
+            .min(resolution[2]);
+        let slices_per_thread = resolution[2].div_ceil(thread_num);
+
+        let mut values = std::mem::take(&mut grid.values);
+        std::thread::scope(|scope| {
+            values
+                .chunks_mut(slices_per_thread * slice_len)
+                .enumerate()
+                .for_each(|(chunk_index, chunk)| {
+                    let grid = &grid;
+                    scope.spawn(move || {
+                        chunk.iter_mut().enumerate().for_each(|(i, value)| {
+                            let index = chunk_index * slices_per_thread * slice_len + i;
+                            let pos = grid.get_pos(
+                                index % resolution[0],
+                                (index / resolution[0]) % resolution[1],
+                                index / slice_len,
+                            );
+                            if let Some(dist) = self.signed_distance(&pos) {
+                                *value = dist;
+                            }
+                        });
+                    });
+                });
+        });
+        grid.values = values;
+
+        grid
+    }
+}
+
+/// Signed solid angle of the triangle seen from `pos`, see "A
+/// Simple Formula for the Solid Angle of a Triangle" (Van Oosterom
+/// and Strackee).
+fn tri_solid_angle(tri: &SDFTri, pos: &glm::DVec3) -> f64 {
+    let [a, b, c] = tri.positions.map(|p| p - pos);
+    let (la, lb, lc) = (glm::length(&a), glm::length(&b), glm::length(&c));
+    let det = glm::dot(&a, &glm::cross(&b, &c));
+    let denom =
+        la * lb * lc + glm::dot(&a, &b) * lc + glm::dot(&b, &c) * la + glm::dot(&c, &a) * lb;
+    2.0 * det.atan2(denom)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::mesh::simple;
+
+    #[test]
+    fn mesh_sdf_sphere() {
+        let mesh =
+            simple::Mesh::read_from_file(Path::new("models/ico_sphere_subd_02.obj")).unwrap();
+        let radius = mesh
+            .get_nodes()
+            .iter()
+            .map(|(_, node)| glm::length(&node.pos))
+            .fold(0.0, f64::max);
+
+        for sign in [MeshSDFSign::Pseudonormal, MeshSDFSign::WindingNumber] {
+            let sdf = MeshSDF::new(&mesh, sign);
+
+            // nearest feature of the center is a vertex, edge or face
+            // depending on the direction
+            for dir in [
+                glm::vec3(1.0, 0.0, 0.0),
+                glm::vec3(0.0, 1.0, 0.0),
+                glm::vec3(1.0, 1.0, 1.0),
+                glm::vec3(-0.3, 0.7, 0.2),
+            ] {
+                let dir = glm::normalize(&dir);
+                let inside = sdf.signed_distance(&(dir * 0.5 * radius)).unwrap();
+                assert!(inside < 0.0 && inside > -0.6 * radius);
+                assert!(sdf.is_inside(&(dir * 0.5 * radius)));
+
+                let outside = sdf.signed_distance(&(dir * 2.0 * radius)).unwrap();
+                assert!(outside >= radius - 1e-9 && outside < 1.2 * radius);
+                assert!(!sdf.is_inside(&(dir * 2.0 * radius)));
+            }
+
+            // on the surface
+            let node_pos = mesh.get_nodes().iter().next().unwrap().1.pos;
+            assert!(sdf.signed_distance(&node_pos).unwrap().abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn mesh_sdf_winding_number_far_field() {
+        let mesh =
+            simple::Mesh::read_from_file(Path::new("models/ico_sphere_subd_02.obj")).unwrap();
+        let sdf = MeshSDF::new(&mesh, MeshSDFSign::WindingNumber);
+        assert_eq!(
+            sdf.winding_nodes
+                .iter()
+                .filter(|node| matches!(node.node, BVHFlatNode::Leaf(_)))
+                .count(),
+            sdf.tris.len()
+        );
+
+        // the dipole approximation stays close to the exact sum over
+        // all the triangles, inside, outside and near the surface
+        for i in 0..=20 {
+            let t = i as f64 / 20.0;
+            for pos in [
+                glm::vec3(3.0 * t - 1.5, 0.1, 0.2),
+                glm::vec3(0.3, 2.0 * t - 1.0, -0.4),
+                glm::vec3(-10.0 * t, 5.0 * t, 0.0),
+            ] {
+                let exact = sdf
+                    .tris
+                    .iter()
+                    .map(|tri| tri_solid_angle(tri, &pos))
+                    .sum::<f64>()
+                    / (4.0 * std::f64::consts::PI);
+                assert!((sdf.winding_number(&pos) - exact).abs() < 0.01);
+            }
+        }
+
+        let empty = MeshSDF::new(&simple::Mesh::new(), MeshSDFSign::WindingNumber);
+        assert_eq!(empty.winding_number(&glm::zero()), 0.0);
+    }
+
+    #[test]
+    fn mesh_sdf_cube_features() {
+        let mesh = simple::Mesh::read_from_file(Path::new("models/cube_subd_00.obj")).unwrap();
+        let sdf = MeshSDF::new(&mesh, MeshSDFSign::Pseudonormal);
+        let (min, max) = mesh.get_nodes().iter().fold(
+            (glm::DVec3::repeat(f64::MAX), glm::DVec3::repeat(f64::MIN)),
+            |acc, (_, node)| (glm::min2(&acc.0, &node.pos), glm::max2(&acc.1, &node.pos)),
+        );
+        let half_size = (max - min) * 0.5;
+        let center = (min + max) * 0.5;
+
+        // nearest to a corner, an edge and a face of the cube from
+        // just inside and just outside
+        for dir in [
+            glm::vec3(1.0, 1.0, 1.0),
+            glm::vec3(1.0, 1.0, 0.0),
+            glm::vec3(1.0, 0.0, 0.0),
+        ] {
+            let surface_pos = center + half_size.component_mul(&dir);
+            let offset = glm::normalize(&dir) * 0.01;
+            assert!((sdf.signed_distance(&(surface_pos + offset)).unwrap() - 0.01).abs() < 1e-9);
+            assert!(sdf.signed_distance(&(surface_pos - offset)).unwrap() < 0.0);
+        }
+    }
+
+    #[test]
+    fn mesh_sdf_sample_grid() {
+        let mesh =
+            simple::Mesh::read_from_file(Path::new("models/ico_sphere_subd_01.obj")).unwrap();
+        let sdf = MeshSDF::new(&mesh, MeshSDFSign::Pseudonormal);
+
+        let min = glm::vec3(-2.0, -2.0, -2.0);
+        let max = glm::vec3(2.0, 2.0, 2.0);
+        let grid = sdf.sample_grid(&min, &max, [9, 7, 5]);
+        assert_eq!(grid.values.len(), 9 * 7 * 5);
+        assert_eq!(grid.get_pos(0, 0, 0), min);
+        assert_eq!(grid.get_pos(8, 6, 4), max);
+
+        for k in 0..5 {
+            for j in 0..7 {
+                for i in 0..9 {
+                    let expected = sdf.signed_distance(&grid.get_pos(i, j, k)).unwrap();
+                    assert_eq!(grid.get(i, j, k), expected);
+                }
+            }
+        }
+        assert!(grid.get(4, 3, 2) < 0.0);
+        assert!(grid.get(0, 0, 0) > 0.0);
+    }
+}
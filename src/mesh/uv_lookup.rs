@@ -0,0 +1,267 @@
+//! UV space queries on [`Mesh`].
+//!
+//! [`MeshUVLookup`] answers which face (and the bary coords within
+//! it) contains a given UV coordinate, and maps between the UV space
+//! and the 3D space of the mesh. Useful for baking textures and
+//! transferring data between meshes.
+
+use super::{FaceIndex, Mesh, MeshError, VertIndex};
+use crate::bvh::{nearest_point_to_tri, BVHTree, NearestData};
+use crate::glm;
+use crate::util;
+
+/// Tolerance on the bary coords to consider a UV within a triangle,
+/// makes UVs exactly on the edges of the triangle robust.
+const BARY_COORD_EPSILON: f64 = 1e-9;
+
+/// Triangle of a face (ngons are fan triangulated).
+#[derive(Debug, Clone)]
+struct UVLookupTri {
+    face_index: FaceIndex,
+    verts: [VertIndex; 3],
+    uvs: [glm::DVec2; 3],
+    positions: [glm::DVec3; 3],
+}
+
+/// Result of a [`MeshUVLookup`] query.
+#[derive(Debug, Clone, Copy)]
+pub struct MeshUVLookupHit {
+    /// Face that was hit.
+    pub face_index: FaceIndex,
+    /// Verts of the triangle of the face that was hit, the face is
+    /// fan triangulated if it is an ngon.
+    pub verts: [VertIndex; 3],
+    /// Bary coords with respect to `verts`.
+    pub bary_coord: glm::DVec3,
+    /// Triangle of the [`MeshUVLookup`] that was hit.
+    tri_index: usize,
+}
+
+/// Acceleration structure over the faces of a [`Mesh`] for UV space
+/// (and 3D space) point location.
+///
+/// It stores a snapshot of the UVs and positions of the mesh, it must
+/// be rebuilt if the mesh changes.
+pub struct MeshUVLookup {
+    tris: Vec<UVLookupTri>,
+    uv_bvh: BVHTree<f64, usize>,
+    pos_bvh: BVHTree<f64, usize>,
+}
+
+impl MeshUVLookup {
+    /// Build the lookup for the given mesh.
+    ///
+    /// Returns [`MeshError::NoUV`] if any vert of the faces doesn't
+    /// have a UV.
+    pub fn new<END, EVD, EED, EFD>(mesh: &Mesh<END, EVD, EED, EFD>) -> Result<Self, MeshError> {
+        let mut tris = Vec::new();
+        for (_, face) in mesh.get_faces() {
+            let verts = face.get_verts();
+            let uvs = verts
+                .iter()
+                .map(|vert_index| mesh.get_vert(*vert_index).unwrap().uv)
+                .collect::<Option<Vec<_>>>()
+                .ok_or(MeshError::NoUV)?;
+            let positions: Vec<_> = mesh
+                .get_nodes_of_face(face)
+                .iter()
+                .map(|node_index| mesh.get_node(node_index.unwrap()).unwrap().pos)
+                .collect();
+
+            for i in 1..verts.len().saturating_sub(1) {
+                tris.push(UVLookupTri {
+                    face_index: face.get_self_index(),
+                    verts: [verts[0], verts[i], verts[i + 1]],
+                    uvs: [uvs[0], uvs[i], uvs[i + 1]],
+                    positions: [positions[0], positions[i], positions[i + 1]],
+                });
+            }
+        }
+
+        let mut uv_bvh = BVHTree::new(tris.len(), 0.0, 4, 6);
+        let mut pos_bvh = BVHTree::new(tris.len(), 0.0, 4, 6);
+        tris.iter().enumerate().for_each(|(i, tri)| {
+            let uvs: Vec<_> = tri.uvs.iter().map(glm::vec2_to_vec3).collect();
+            uv_bvh.insert(i, &uvs);
+            pos_bvh.insert(i, &tri.positions);
+        });
+        uv_bvh.balance();
+        pos_bvh.balance();
+
+        Ok(Self {
+            tris,
+            uv_bvh,
+            pos_bvh,
+        })
+    }
+
+    fn hit(&self, tri_index: usize, bary_coord: glm::DVec3) -> MeshUVLookupHit {
+        let tri = &self.tris[tri_index];
+        MeshUVLookupHit {
+            face_index: tri.face_index,
+            verts: tri.verts,
+            bary_coord,
+            tri_index,
+        }
+    }
+
+    /// Find the face that contains the given `uv` along with the bary
+    /// coords of `uv` within the face.
+    ///
+    /// If the UV islands overlap, any one of the faces containing
+    /// `uv` is returned.
+    pub fn find_face_at_uv(&self, uv: &glm::DVec2) -> Option<MeshUVLookupHit> {
+        if self.tris.is_empty() {
+            return None;
+        }
+
+        let uv_3d = glm::vec2_to_vec3(uv);
+        let mut found = None;
+        self.uv_bvh.find_in_aabb(&uv_3d, &uv_3d, |tri_index| {
+            if found.is_some() {
+                return;
+            }
+            let tri = &self.tris[tri_index];
+            let bary_coord =
+                util::vec2_compute_bary_coord(uv, &tri.uvs[0], &tri.uvs[1], &tri.uvs[2]);
+            if bary_coord.iter().all(|val| *val >= -BARY_COORD_EPSILON) {
+                found = Some((tri_index, bary_coord));
+            }
+        });
+
+        found.map(|(tri_index, bary_coord)| self.hit(tri_index, bary_coord))
+    }
+
+    /// Find the point on the surface of the mesh nearest to `pos`
+    /// (within `max_dist`), gives the face along with the bary coords
+    /// of the point within the face.
+    pub fn find_nearest_face_at_pos(
+        &self,
+        pos: &glm::DVec3,
+        max_dist: f64,
+    ) -> Option<MeshUVLookupHit> {
+        if self.tris.is_empty() {
+            return None;
+        }
+
+        let nearest = self.pos_bvh.find_nearest(
+            *pos,
+            max_dist * max_dist,
+            &Some(
+                |tri_index: usize, co: &glm::DVec3, nearest: &mut NearestData<f64, usize>| {
+                    let tri = &self.tris[tri_index];
+                    let nearest_co = nearest_point_to_tri(
+                        co,
+                        [&tri.positions[0], &tri.positions[1], &tri.positions[2]],
+                    );
+                    let dist_sq = glm::distance2(co, &nearest_co);
+                    if dist_sq < nearest.get_dist_sq() {
+                        nearest.set_info(Some(tri_index), Some(nearest_co), None, dist_sq);
+                    }
+                },
+            ),
+        )?;
+
+        let tri_index = nearest.get_elem_index().unwrap();
+        let tri = &self.tris[tri_index];
+        let bary_coord = util::vec3_compute_bary_coord(
+            &nearest.get_co().unwrap(),
+            &tri.positions[0],
+            &tri.positions[1],
+            &tri.positions[2],
+        );
+
+        Some(self.hit(tri_index, bary_coord))
+    }
+
+    /// Map the `uv` to the 3D position on the mesh. Returns [`None`]
+    /// if no face contains `uv`.
+    pub fn uv_to_pos(&self, uv: &glm::DVec2) -> Option<glm::DVec3> {
+        self.find_face_at_uv(uv).map(|hit| self.hit_to_pos(&hit))
+    }
+
+    /// Map the 3D position `pos` to the UV of the nearest point on
+    /// the mesh (within `max_dist`).
+    pub fn pos_to_uv(&self, pos: &glm::DVec3, max_dist: f64) -> Option<glm::DVec2> {
+        self.find_nearest_face_at_pos(pos, max_dist)
+            .map(|hit| self.hit_to_uv(&hit))
+    }
+
+    /// 3D position of the given hit.
+    pub fn hit_to_pos(&self, hit: &MeshUVLookupHit) -> glm::DVec3 {
+        let tri = self.get_tri(hit);
+        util::vec3_apply_bary_coord(
+            &tri.positions[0],
+            &tri.positions[1],
+            &tri.positions[2],
+            &hit.bary_coord,
+        )
+    }
+
+    /// UV of the given hit.
+    pub fn hit_to_uv(&self, hit: &MeshUVLookupHit) -> glm::DVec2 {
+        let tri = self.get_tri(hit);
+        util::vec2_apply_bary_coord(&tri.uvs[0], &tri.uvs[1], &tri.uvs[2], &hit.bary_coord)
+    }
+
+    fn get_tri(&self, hit: &MeshUVLookupHit) -> &UVLookupTri {
+        self.tris
+            .get(hit.tri_index)
+            .filter(|tri| tri.face_index == hit.face_index && tri.verts == hit.verts)
+            .expect("hit must be from the same MeshUVLookup")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::mesh::simple;
+
+    #[test]
+    fn uv_lookup_round_trip() {
+        let mesh = simple::Mesh::read_from_file(Path::new("models/monkey_subd_00.obj")).unwrap();
+        let uv_lookup = MeshUVLookup::new(&mesh).unwrap();
+
+        // centroid of the UVs of every face maps back to the face
+        for (_, face) in mesh.get_faces() {
+            let uv = face
+                .get_verts()
+                .iter()
+                .map(|vert_index| mesh.get_vert(*vert_index).unwrap().uv.unwrap())
+                .fold(glm::zero::<glm::DVec2>(), |acc, uv| acc + uv)
+                / face.get_verts().len() as f64;
+
+            let hit = uv_lookup.find_face_at_uv(&uv).unwrap();
+            assert!((uv_lookup.hit_to_uv(&hit) - uv).norm() < 1e-9);
+            assert!((hit.bary_coord.sum() - 1.0).abs() < 1e-9);
+
+            let pos = uv_lookup.uv_to_pos(&uv).unwrap();
+            let back_uv = uv_lookup.pos_to_uv(&pos, 1e-6).unwrap();
+            assert!((back_uv - uv).norm() < 1e-6);
+        }
+
+        assert!(uv_lookup.find_face_at_uv(&glm::vec2(5.0, 5.0)).is_none());
+        assert!(uv_lookup
+            .pos_to_uv(&glm::vec3(100.0, 0.0, 0.0), 1.0)
+            .is_none());
+    }
+
+    #[test]
+    fn uv_lookup_bary_coord() {
+        let mesh = simple::Mesh::read_from_file(Path::new("models/plane_subd_00_triangulated.obj"))
+            .unwrap();
+        let uv_lookup = MeshUVLookup::new(&mesh).unwrap();
+
+        for (_, vert) in mesh.get_verts() {
+            let hit = uv_lookup.find_face_at_uv(&vert.uv.unwrap()).unwrap();
+            let i = hit
+                .verts
+                .iter()
+                .position(|vert_index| *vert_index == vert.get_self_index())
+                .unwrap();
+            assert!((hit.bary_coord[i] - 1.0).abs() < 1e-9);
+        }
+    }
+}
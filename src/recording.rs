@@ -0,0 +1,172 @@
+//! Recording of the frames of an [`crate::app::Environment`] to a
+//! PNG sequence, see [`crate::app::EnvironmentSettings::recording`].
+
+use std::path::PathBuf;
+
+/// Settings for the recording mode of the [`crate::app::Environment`],
+/// see [`crate::app::EnvironmentSettings::recording`].
+#[derive(Debug, Clone)]
+pub struct RecordingSettings {
+    /// Directory to write the frames to, created if it doesn't
+    /// exist.
+    pub output_directory: PathBuf,
+    /// Prefix of the file names, frames are written as
+    /// `{file_prefix}{frame:06}.png`.
+    pub file_prefix: String,
+    /// Width and height of the written frames, also used as the
+    /// window dimensions. If the framebuffer of the window ends up
+    /// with a different size (eg: content scaling), the frames are
+    /// resized to this.
+    pub output_dimensions: (u32, u32),
+    /// Simulated frame rate, [`crate::app::Environment::fps`] always
+    /// reports it no matter how long a frame actually takes.
+    pub frame_rate: f64,
+    /// Write every `frame_step`th frame, the sequence plays back in
+    /// real time at `frame_rate / frame_step` frames per second.
+    pub frame_step: usize,
+}
+
+impl RecordingSettings {
+    /// Default [`Self::file_prefix`].
+    pub const DEFAULT_FILE_PREFIX: &'static str = "frame_";
+    /// Default [`Self::output_dimensions`].
+    pub const DEFAULT_OUTPUT_DIMENSIONS: (u32, u32) = (1920, 1080);
+    /// Default [`Self::frame_rate`].
+    pub const DEFAULT_FRAME_RATE: f64 = 60.0;
+    /// Default [`Self::frame_step`].
+    pub const DEFAULT_FRAME_STEP: usize = 1;
+
+    /// New recording settings writing to `output_directory`, rest is
+    /// default.
+    pub fn new<P: Into<PathBuf>>(output_directory: P) -> Self {
+        Self {
+            output_directory: output_directory.into(),
+            file_prefix: Self::DEFAULT_FILE_PREFIX.to_string(),
+            output_dimensions: Self::DEFAULT_OUTPUT_DIMENSIONS,
+            frame_rate: Self::DEFAULT_FRAME_RATE,
+            frame_step: Self::DEFAULT_FRAME_STEP,
+        }
+    }
+
+    /// Path of the `frame`th written frame.
+    pub fn frame_path(&self, frame: usize) -> PathBuf {
+        self.output_directory
+            .join(format!("{}{:06}.png", self.file_prefix, frame))
+    }
+
+    /// Set [`Self::file_prefix`].
+    pub fn file_prefix<S: Into<String>>(mut self, file_prefix: S) -> Self {
+        self.file_prefix = file_prefix.into();
+        self
+    }
+
+    /// Set [`Self::output_dimensions`].
+    pub fn output_dimensions(mut self, output_dimensions: (u32, u32)) -> Self {
+        self.output_dimensions = output_dimensions;
+        self
+    }
+
+    /// Set [`Self::frame_rate`].
+    pub fn frame_rate(mut self, frame_rate: f64) -> Self {
+        assert!(frame_rate > 0.0);
+        self.frame_rate = frame_rate;
+        self
+    }
+
+    /// Set [`Self::frame_step`].
+    pub fn frame_step(mut self, frame_step: usize) -> Self {
+        assert!(frame_step > 0);
+        self.frame_step = frame_step;
+        self
+    }
+}
+
+/// State of the recording, decides which frames are written and
+/// where.
+pub(crate) struct Recording {
+    settings: RecordingSettings,
+    /// Frame within the current [`RecordingSettings::frame_step`],
+    /// the frame is written when it is 0.
+    frame_in_step: usize,
+    /// Frames written to disk.
+    frames_written: usize,
+}
+
+impl Recording {
+    pub(crate) fn new(settings: RecordingSettings) -> Self {
+        Self {
+            settings,
+            frame_in_step: 0,
+            frames_written: 0,
+        }
+    }
+
+    pub(crate) fn get_settings(&self) -> &RecordingSettings {
+        &self.settings
+    }
+
+    pub(crate) fn get_frames_written(&self) -> usize {
+        self.frames_written
+    }
+
+    /// Advance to the next frame, gives the path to write the
+    /// current frame to if it is due. [`Self::frame_written()`] must
+    /// be called once it is written.
+    pub(crate) fn next_frame_path(&mut self) -> Option<PathBuf> {
+        let frame_in_step = self.frame_in_step;
+        self.frame_in_step = (frame_in_step + 1) % self.settings.frame_step;
+        if frame_in_step != 0 {
+            return None;
+        }
+        Some(self.settings.frame_path(self.frames_written))
+    }
+
+    pub(crate) fn frame_written(&mut self) {
+        self.frames_written += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recording_frame_step() {
+        let settings = RecordingSettings::new("recording")
+            .file_prefix("shot_")
+            .frame_step(3);
+        assert_eq!(
+            settings.frame_path(12),
+            PathBuf::from("recording").join("shot_000012.png")
+        );
+
+        let mut recording = Recording::new(settings);
+        let paths: Vec<_> = (0..8)
+            .map(|_| {
+                let path = recording.next_frame_path();
+                if path.is_some() {
+                    recording.frame_written();
+                }
+                path
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                Some(PathBuf::from("recording/shot_000000.png")),
+                None,
+                None,
+                Some(PathBuf::from("recording/shot_000001.png")),
+                None,
+                None,
+                Some(PathBuf::from("recording/shot_000002.png")),
+                None,
+            ]
+        );
+        assert_eq!(recording.get_frames_written(), 3);
+
+        // a failed write doesn't skip the frame number
+        let mut recording = Recording::new(RecordingSettings::new("recording"));
+        assert_eq!(recording.next_frame_path(), recording.next_frame_path());
+    }
+}
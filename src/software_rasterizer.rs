@@ -0,0 +1,614 @@
+//! CPU software rasterizer, renders triangles into a
+//! [`TextureRGBAFloat`] without any OpenGL context or driver (eg:
+//! thumbnails, rendering tests on machines without GL).
+//!
+//! It follows the OpenGL path as closely as possible: the same
+//! [`Camera`] matrices, clip space clipping against the near plane,
+//! perspective correct interpolation, a depth test with
+//! `gl::LESS` and the lighting model of
+//! `shaders/directional_light.frag`. Pixels are stored linear, bottom
+//! row first, same as a float render target read back with
+//! [`crate::framebuffer::capture_framebuffer()`].
+
+use crate::camera::Camera;
+use crate::gl_mesh::{GLVert, Triangle};
+use crate::glm;
+use crate::mesh::Mesh;
+use crate::texture::TextureRGBAFloat;
+use crate::util::{vec3_append_one, vec3_apply_model_matrix};
+
+/// Material of the directional light model, see
+/// `shaders/directional_light.frag`.
+#[derive(Debug, Clone, Copy)]
+pub struct Material {
+    pub color: glm::DVec3,
+    pub specular: glm::DVec3,
+    pub shininess: f64,
+}
+
+impl Default for Material {
+    /// Same as set by [`crate::shader::builtins::setup_shaders()`].
+    fn default() -> Self {
+        Self {
+            color: glm::vec3(0.3, 0.2, 0.7),
+            specular: glm::vec3(0.3, 0.3, 0.3),
+            shininess: 4.0,
+        }
+    }
+}
+
+/// Light of the directional light model, see
+/// `shaders/directional_light.frag`.
+#[derive(Debug, Clone, Copy)]
+pub struct Light {
+    pub direction: glm::DVec3,
+    pub ambient: glm::DVec3,
+    pub diffuse: glm::DVec3,
+    pub specular: glm::DVec3,
+}
+
+impl Default for Light {
+    /// Same as set by [`crate::shader::builtins::setup_shaders()`].
+    fn default() -> Self {
+        Self {
+            direction: glm::vec3(-0.7, -1.0, -0.7),
+            ambient: glm::vec3(0.3, 0.3, 0.3),
+            diffuse: glm::vec3(1.0, 1.0, 1.0),
+            specular: glm::vec3(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+/// How the fragments of the triangles are colored.
+#[derive(Debug, Clone, Copy)]
+pub enum SoftwareShading {
+    /// Same color for every fragment, like
+    /// [`crate::mesh::MeshUseShader::SmoothColor3D`].
+    Color(glm::DVec4),
+    /// Interpolate [`SoftwareVert::color`].
+    VertexColor,
+    /// Directional light model of `shaders/directional_light.frag`.
+    /// With `smooth`, [`SoftwareVert::normal`] is interpolated like
+    /// [`crate::mesh::MeshUseShader::DirectionalLight`], otherwise
+    /// the normal of the triangle is used (flat shading).
+    DirectionalLight {
+        material: Material,
+        light: Light,
+        smooth: bool,
+    },
+}
+
+/// Vertex of the triangles given to
+/// [`SoftwareRasterizer::draw_triangles()`].
+#[derive(Debug, Clone, Copy)]
+pub struct SoftwareVert {
+    pub pos: glm::DVec3,
+    pub normal: glm::DVec3,
+    pub color: glm::DVec4,
+}
+
+impl SoftwareVert {
+    pub fn new(pos: glm::DVec3, normal: glm::DVec3, color: glm::DVec4) -> Self {
+        Self { pos, normal, color }
+    }
+}
+
+/// Vertex after the vertex stage, in clip space with the world space
+/// attributes needed by the fragment stage.
+#[derive(Debug, Clone, Copy)]
+struct ClipVert {
+    clip_pos: glm::DVec4,
+    world_pos: glm::DVec3,
+    normal: glm::DVec3,
+    color: glm::DVec4,
+}
+
+impl ClipVert {
+    fn lerp(&self, other: &Self, t: f64) -> Self {
+        Self {
+            clip_pos: glm::lerp(&self.clip_pos, &other.clip_pos, t),
+            world_pos: glm::lerp(&self.world_pos, &other.world_pos, t),
+            normal: glm::lerp(&self.normal, &other.normal, t),
+            color: glm::lerp(&self.color, &other.color, t),
+        }
+    }
+}
+
+/// CPU rasterizer with a color and depth buffer, see module
+/// documentation.
+pub struct SoftwareRasterizer {
+    width: usize,
+    height: usize,
+    /// Stored from bottom left row wise.
+    color: Vec<glm::Vec4>,
+    /// Window space depth in [0, 1], stored like `color`.
+    depth: Vec<f64>,
+
+    view: glm::DMat4,
+    projection: glm::DMat4,
+    view_pos: glm::DVec3,
+}
+
+impl SoftwareRasterizer {
+    /// New rasterizer of `width` x `height` pixels, cleared to
+    /// transparent black with identity view and projection.
+    pub fn new(width: usize, height: usize) -> Self {
+        assert!(width > 0 && height > 0);
+        Self {
+            width,
+            height,
+            color: vec![glm::zero(); width * height],
+            depth: vec![1.0; width * height],
+            view: glm::identity(),
+            projection: glm::identity(),
+            view_pos: glm::zero(),
+        }
+    }
+
+    pub fn get_width(&self) -> usize {
+        self.width
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    /// Clear the color buffer to `color` and the depth buffer to 1.0
+    /// (far).
+    pub fn clear(&mut self, color: glm::Vec4) {
+        self.color.iter_mut().for_each(|pixel| *pixel = color);
+        self.depth.iter_mut().for_each(|depth| *depth = 1.0);
+    }
+
+    /// Use the view and perspective projection of `camera`, same as
+    /// [`crate::shader::builtins::setup_shaders()`].
+    pub fn set_camera(&mut self, camera: &Camera) {
+        self.set_matrices(
+            camera.get_view_matrix(),
+            camera.get_perspective_projection_matrix(self.width, self.height),
+            camera.get_position(),
+        );
+    }
+
+    /// Set the view and projection matrices and the view position
+    /// (for the specular of [`SoftwareShading::DirectionalLight`]).
+    pub fn set_matrices(&mut self, view: glm::DMat4, projection: glm::DMat4, view_pos: glm::DVec3) {
+        self.view = view;
+        self.projection = projection;
+        self.view_pos = view_pos;
+    }
+
+    /// Get the color of the pixel (`i`, `j`), (0, 0) is bottom left.
+    pub fn get_pixel(&self, i: usize, j: usize) -> &glm::Vec4 {
+        &self.color[j * self.width + i]
+    }
+
+    /// Get the depth of the pixel (`i`, `j`), (0, 0) is bottom left.
+    pub fn get_depth(&self, i: usize, j: usize) -> f64 {
+        self.depth[j * self.width + i]
+    }
+
+    /// Copy the color buffer to a new [`TextureRGBAFloat`].
+    pub fn to_texture(&self) -> TextureRGBAFloat {
+        TextureRGBAFloat::from_pixels(self.width, self.height, self.color.clone())
+    }
+
+    /// Draw the indexed `triangles` of `verts` transformed by
+    /// `model`.
+    pub fn draw_triangles(
+        &mut self,
+        verts: &[SoftwareVert],
+        triangles: &[[usize; 3]],
+        model: &glm::DMat4,
+        shading: &SoftwareShading,
+    ) {
+        let normal_matrix = glm::mat4_to_mat3(&glm::inverse_transpose(*model));
+        let view_projection = self.projection * self.view;
+        let clip_verts: Vec<ClipVert> = verts
+            .iter()
+            .map(|vert| {
+                let world_pos = vec3_apply_model_matrix(&vert.pos, model);
+                ClipVert {
+                    clip_pos: view_projection * vec3_append_one(&world_pos),
+                    world_pos,
+                    normal: normal_matrix * vert.normal,
+                    color: vert.color,
+                }
+            })
+            .collect();
+
+        triangles.iter().for_each(|triangle| {
+            self.draw_clip_triangle(
+                [
+                    clip_verts[triangle[0]],
+                    clip_verts[triangle[1]],
+                    clip_verts[triangle[2]],
+                ],
+                shading,
+            )
+        });
+    }
+
+    /// Draw [`GLVert`]s and [`Triangle`]s, the data of a
+    /// [`crate::gl_mesh::GLMesh`]. Vertex colors are white.
+    pub fn draw_gl_triangles(
+        &mut self,
+        verts: &[GLVert],
+        triangles: &[Triangle],
+        model: &glm::DMat4,
+        shading: &SoftwareShading,
+    ) {
+        let verts: Vec<SoftwareVert> = verts
+            .iter()
+            .map(|vert| {
+                // copy out of the packed struct
+                let (pos, normal) = (vert.pos, vert.normal);
+                SoftwareVert::new(
+                    glm::convert(pos),
+                    glm::convert(normal),
+                    glm::vec4(1.0, 1.0, 1.0, 1.0),
+                )
+            })
+            .collect();
+        let triangles: Vec<[usize; 3]> = triangles
+            .iter()
+            .map(|triangle| {
+                let (i1, i2, i3) = (triangle.i1, triangle.i2, triangle.i3);
+                [i1 as usize, i2 as usize, i3 as usize]
+            })
+            .collect();
+
+        self.draw_triangles(&verts, &triangles, model, shading);
+    }
+
+    /// Draw `mesh`, faces are triangulated as a fan like
+    /// [`crate::drawable::Drawable::draw()`] of [`Mesh`]. Nodes
+    /// without a normal use the face normal (or no lighting if
+    /// neither exist). Vertex colors are white.
+    pub fn draw_mesh<END, EVD, EED, EFD>(
+        &mut self,
+        mesh: &Mesh<END, EVD, EED, EFD>,
+        model: &glm::DMat4,
+        shading: &SoftwareShading,
+    ) {
+        let mut verts = Vec::new();
+        let mut triangles = Vec::new();
+        mesh.get_faces().iter().for_each(|(_, face)| {
+            let start = verts.len();
+            verts.extend(face.get_verts().iter().map(|vert_index| {
+                let node = mesh
+                    .get_node(mesh.get_vert(*vert_index).unwrap().get_node().unwrap())
+                    .unwrap();
+                SoftwareVert::new(
+                    node.pos,
+                    node.normal.or(face.normal).unwrap_or_else(glm::zero),
+                    glm::vec4(1.0, 1.0, 1.0, 1.0),
+                )
+            }));
+            (start + 1..verts.len().saturating_sub(1))
+                .for_each(|i| triangles.push([start, i, i + 1]));
+        });
+
+        self.draw_triangles(&verts, &triangles, model, shading);
+    }
+
+    /// Clip the triangle against the near plane (z >= -w) and
+    /// rasterize the resulting polygon as a fan.
+    fn draw_clip_triangle(&mut self, triangle: [ClipVert; 3], shading: &SoftwareShading) {
+        let distance = |vert: &ClipVert| vert.clip_pos[2] + vert.clip_pos[3];
+
+        let mut polygon: Vec<ClipVert> = Vec::with_capacity(4);
+        for i in 0..3 {
+            let current = &triangle[i];
+            let next = &triangle[(i + 1) % 3];
+            let (current_distance, next_distance) = (distance(current), distance(next));
+            if current_distance >= 0.0 {
+                polygon.push(*current);
+            }
+            if (current_distance >= 0.0) != (next_distance >= 0.0) {
+                let t = current_distance / (current_distance - next_distance);
+                polygon.push(current.lerp(next, t));
+            }
+        }
+
+        // face normal of the unclipped triangle for flat shading
+        let face_normal = glm::cross(
+            &(triangle[1].world_pos - triangle[0].world_pos),
+            &(triangle[2].world_pos - triangle[0].world_pos),
+        );
+
+        for i in 1..polygon.len().saturating_sub(1) {
+            self.rasterize_triangle(
+                [&polygon[0], &polygon[i], &polygon[i + 1]],
+                &face_normal,
+                shading,
+            );
+        }
+    }
+
+    /// Rasterize a triangle that is in front of the near plane.
+    fn rasterize_triangle(
+        &mut self,
+        triangle: [&ClipVert; 3],
+        face_normal: &glm::DVec3,
+        shading: &SoftwareShading,
+    ) {
+        // window space position, xy in pixels and z in [0, 1]
+        let window = |vert: &ClipVert| {
+            let ndc = glm::vec4_to_vec3(&vert.clip_pos) / vert.clip_pos[3];
+            glm::vec3(
+                (ndc[0] + 1.0) * 0.5 * self.width as f64,
+                (ndc[1] + 1.0) * 0.5 * self.height as f64,
+                (ndc[2] + 1.0) * 0.5,
+            )
+        };
+        let p = [
+            window(triangle[0]),
+            window(triangle[1]),
+            window(triangle[2]),
+        ];
+        let inv_w = [
+            1.0 / triangle[0].clip_pos[3],
+            1.0 / triangle[1].clip_pos[3],
+            1.0 / triangle[2].clip_pos[3],
+        ];
+
+        let edge = |a: &glm::DVec3, b: &glm::DVec3, x: f64, y: f64| {
+            (b[0] - a[0]) * (y - a[1]) - (b[1] - a[1]) * (x - a[0])
+        };
+        let area = edge(&p[0], &p[1], p[2][0], p[2][1]);
+        if area == 0.0 || !area.is_finite() {
+            return;
+        }
+
+        let min_x = p.iter().map(|p| p[0]).fold(f64::INFINITY, f64::min);
+        let max_x = p.iter().map(|p| p[0]).fold(f64::NEG_INFINITY, f64::max);
+        let min_y = p.iter().map(|p| p[1]).fold(f64::INFINITY, f64::min);
+        let max_y = p.iter().map(|p| p[1]).fold(f64::NEG_INFINITY, f64::max);
+        let clamp_x = |x: f64| x.max(0.0).min(self.width as f64) as usize;
+        let clamp_y = |y: f64| y.max(0.0).min(self.height as f64) as usize;
+        let (start_x, end_x) = (clamp_x(min_x.floor()), clamp_x(max_x.ceil()));
+        let (start_y, end_y) = (clamp_y(min_y.floor()), clamp_y(max_y.ceil()));
+
+        for j in start_y..end_y {
+            for i in start_x..end_x {
+                // sample at the pixel center
+                let (x, y) = (i as f64 + 0.5, j as f64 + 0.5);
+                let b0 = edge(&p[1], &p[2], x, y) / area;
+                let b1 = edge(&p[2], &p[0], x, y) / area;
+                let b2 = edge(&p[0], &p[1], x, y) / area;
+                if b0 < 0.0 || b1 < 0.0 || b2 < 0.0 {
+                    continue;
+                }
+
+                // depth is linear in window space, the far plane is
+                // clipped here
+                let depth = b0 * p[0][2] + b1 * p[1][2] + b2 * p[2][2];
+                let index = j * self.width + i;
+                if !(0.0..=1.0).contains(&depth) || depth >= self.depth[index] {
+                    continue;
+                }
+
+                // perspective correct barycentric coordinates for the
+                // attributes
+                let weights = glm::vec3(b0 * inv_w[0], b1 * inv_w[1], b2 * inv_w[2]);
+                let weights = weights / (weights[0] + weights[1] + weights[2]);
+                let interpolate_vec3 = |attribute: fn(&ClipVert) -> glm::DVec3| {
+                    attribute(triangle[0]) * weights[0]
+                        + attribute(triangle[1]) * weights[1]
+                        + attribute(triangle[2]) * weights[2]
+                };
+
+                let color = match shading {
+                    SoftwareShading::Color(color) => *color,
+                    SoftwareShading::VertexColor => {
+                        triangle[0].color * weights[0]
+                            + triangle[1].color * weights[1]
+                            + triangle[2].color * weights[2]
+                    }
+                    SoftwareShading::DirectionalLight {
+                        material,
+                        light,
+                        smooth,
+                    } => {
+                        let normal = if *smooth {
+                            interpolate_vec3(|vert| vert.normal)
+                        } else {
+                            *face_normal
+                        };
+                        let frag_pos = interpolate_vec3(|vert| vert.world_pos);
+                        let color =
+                            directional_light(material, light, &normal, &frag_pos, &self.view_pos);
+                        glm::vec4(color[0], color[1], color[2], 1.0)
+                    }
+                };
+
+                self.depth[index] = depth;
+                self.color[index] = glm::convert(color);
+            }
+        }
+    }
+}
+
+/// Port of `shaders/directional_light.frag`.
+fn directional_light(
+    material: &Material,
+    light: &Light,
+    normal: &glm::DVec3,
+    frag_pos: &glm::DVec3,
+    view_pos: &glm::DVec3,
+) -> glm::DVec3 {
+    let ambient = light.ambient.component_mul(&material.color);
+
+    let norm = glm::normalize(normal);
+    let light_dir = glm::normalize(&-light.direction);
+    let diff = glm::dot(&norm, &light_dir).max(0.0);
+    let diffuse = (light.diffuse * diff).component_mul(&material.color);
+
+    let view_dir = glm::normalize(&(view_pos - frag_pos));
+    // reflect(-light_dir, norm)
+    let reflect_dir = -light_dir - norm * (2.0 * glm::dot(&norm, &-light_dir));
+    let spec = glm::dot(&view_dir, &reflect_dir)
+        .max(0.0)
+        .powf(material.shininess);
+    let specular = (light.specular * spec).component_mul(&material.specular);
+
+    ambient + diffuse + specular
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{compare_with_reference, GoldenSettings};
+    use crate::mesh::builtins;
+
+    /// Quad in the xy plane at `z` from -`half_size` to `half_size`.
+    fn quad(half_size: f64, z: f64, color: glm::DVec4) -> Vec<SoftwareVert> {
+        [
+            (-half_size, -half_size),
+            (half_size, -half_size),
+            (half_size, half_size),
+            (-half_size, half_size),
+        ]
+        .iter()
+        .map(|(x, y)| SoftwareVert::new(glm::vec3(*x, *y, z), glm::vec3(0.0, 0.0, 1.0), color))
+        .collect()
+    }
+
+    #[test]
+    fn software_rasterizer_depth_test() {
+        let mut rasterizer = SoftwareRasterizer::new(8, 8);
+        rasterizer.clear(glm::vec4(0.0, 0.0, 0.0, 1.0));
+
+        // identity matrices, positions are in clip space with w = 1
+        let triangles = [[0, 1, 2], [0, 2, 3]];
+        let near = quad(0.5, -0.5, glm::vec4(0.0, 1.0, 0.0, 1.0));
+        let far = quad(1.0, 0.5, glm::vec4(1.0, 0.0, 0.0, 1.0));
+        rasterizer.draw_triangles(
+            &near,
+            &triangles,
+            &glm::identity(),
+            &SoftwareShading::VertexColor,
+        );
+        rasterizer.draw_triangles(
+            &far,
+            &triangles,
+            &glm::identity(),
+            &SoftwareShading::VertexColor,
+        );
+
+        // near quad covers the center 4x4 pixels and wins no matter
+        // the draw order
+        for j in 0..8 {
+            for i in 0..8 {
+                let inside = (2..6).contains(&i) && (2..6).contains(&j);
+                if inside {
+                    assert_eq!(*rasterizer.get_pixel(i, j), glm::vec4(0.0, 1.0, 0.0, 1.0));
+                    assert!((rasterizer.get_depth(i, j) - 0.25).abs() < 1e-12);
+                } else {
+                    assert_eq!(*rasterizer.get_pixel(i, j), glm::vec4(1.0, 0.0, 0.0, 1.0));
+                    assert!((rasterizer.get_depth(i, j) - 0.75).abs() < 1e-12);
+                }
+            }
+        }
+
+        // beyond the far plane is clipped
+        rasterizer.clear(glm::zero());
+        rasterizer.draw_triangles(
+            &quad(1.0, 1.5, glm::vec4(1.0, 1.0, 1.0, 1.0)),
+            &triangles,
+            &glm::identity(),
+            &SoftwareShading::VertexColor,
+        );
+        assert!(rasterizer
+            .to_texture()
+            .get_pixels()
+            .iter()
+            .all(|pixel| *pixel == glm::Vec4::zeros()));
+    }
+
+    #[test]
+    fn software_rasterizer_near_plane_clipping() {
+        let mut rasterizer = SoftwareRasterizer::new(16, 16);
+        rasterizer.set_camera(&Camera::new(
+            glm::vec3(0.0, 0.0, 0.0),
+            glm::vec3(0.0, 1.0, 0.0),
+            -90.0,
+            0.0,
+            45.0,
+            None,
+        ));
+
+        // floor plane from behind the camera to far in front of it,
+        // the part behind the near plane must be clipped instead of
+        // being projected mirrored
+        let verts = [
+            glm::vec3(-10.0, -1.0, 10.0),
+            glm::vec3(10.0, -1.0, 10.0),
+            glm::vec3(10.0, -1.0, -10.0),
+            glm::vec3(-10.0, -1.0, -10.0),
+        ]
+        .iter()
+        .map(|pos| {
+            SoftwareVert::new(
+                *pos,
+                glm::vec3(0.0, 1.0, 0.0),
+                glm::vec4(1.0, 1.0, 1.0, 1.0),
+            )
+        })
+        .collect::<Vec<_>>();
+        rasterizer.draw_triangles(
+            &verts,
+            &[[0, 1, 2], [0, 2, 3]],
+            &glm::identity(),
+            &SoftwareShading::Color(glm::vec4(1.0, 1.0, 1.0, 1.0)),
+        );
+
+        // below the horizon is the floor, above is empty
+        (0..16).for_each(|i| {
+            assert_eq!(*rasterizer.get_pixel(i, 0), glm::vec4(1.0, 1.0, 1.0, 1.0));
+            assert_eq!(*rasterizer.get_pixel(i, 15), glm::Vec4::zeros());
+        });
+    }
+
+    #[test]
+    fn software_rasterizer_matches_gl() {
+        // same scene as the `directional_light` golden image rendered
+        // with OpenGL, see `crate::golden`
+        let mut rasterizer = SoftwareRasterizer::new(128, 128);
+        rasterizer.clear(glm::vec4(0.1, 0.1, 0.1, 1.0));
+        rasterizer.set_camera(&Camera::new(
+            glm::vec3(0.0, 0.0, 3.0),
+            glm::vec3(0.0, 1.0, 0.0),
+            -90.0,
+            0.0,
+            45.0,
+            None,
+        ));
+        rasterizer.draw_mesh(
+            builtins::get_monkey_subd_01(),
+            &glm::identity(),
+            &SoftwareShading::DirectionalLight {
+                material: Material::default(),
+                light: Light::default(),
+                smooth: true,
+            },
+        );
+
+        // only a few edge pixels are rasterized differently
+        let comparison = compare_with_reference(
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/golden/directional_light.png"
+            ),
+            &rasterizer.to_texture(),
+            &GoldenSettings::default(),
+        )
+        .unwrap();
+        assert!(
+            comparison.passed,
+            "{} of {} pixels differ",
+            comparison.failing_pixels, comparison.total_pixels
+        );
+    }
+}
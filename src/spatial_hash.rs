@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+
+use crate::glm;
+
+/// Integer coordinates of a cell of the [`SpatialHash`].
+type CellCoord = (i64, i64, i64);
+
+/// Uniform grid based spatial hash for fast radius queries on
+/// points.
+///
+/// Points are bucketed into cubic cells of size `cell_size`, a
+/// radius query only needs to test the points of the cells that the
+/// query sphere touches. Queries are fastest when the radius is
+/// close to the `cell_size`.
+#[derive(Debug, Clone)]
+pub struct SpatialHash<E>
+where
+    E: Copy,
+{
+    cell_size: f64,
+    cells: HashMap<CellCoord, Vec<(E, glm::DVec3)>>,
+    /// Min and max coordinates of the occupied cells.
+    bounds: Option<(CellCoord, CellCoord)>,
+    len: usize,
+}
+
+impl<E> SpatialHash<E>
+where
+    E: Copy,
+{
+    /// Create a new [`SpatialHash`] with cubic cells of `cell_size`.
+    ///
+    /// # Panics
+    ///
+    /// * When `cell_size` is not finite or is <= 0.0.
+    pub fn new(cell_size: f64) -> Self {
+        assert!(
+            cell_size.is_finite() && cell_size > 0.0,
+            "cell_size must be finite and greater than 0.0"
+        );
+
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+            bounds: None,
+            len: 0,
+        }
+    }
+
+    /// Create a [`SpatialHash`] with all the given elements inserted.
+    pub fn with_elements<I>(cell_size: f64, elements: I) -> Self
+    where
+        I: IntoIterator<Item = (E, glm::DVec3)>,
+    {
+        let mut spatial_hash = Self::new(cell_size);
+        elements
+            .into_iter()
+            .for_each(|(elem, pos)| spatial_hash.insert(elem, pos));
+        spatial_hash
+    }
+
+    pub fn get_cell_size(&self) -> f64 {
+        self.cell_size
+    }
+
+    /// Number of elements stored.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Remove all the elements, keeps the `cell_size`.
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.bounds = None;
+        self.len = 0;
+    }
+
+    fn cell_coord(&self, pos: &glm::DVec3) -> CellCoord {
+        (
+            (pos[0] / self.cell_size).floor() as i64,
+            (pos[1] / self.cell_size).floor() as i64,
+            (pos[2] / self.cell_size).floor() as i64,
+        )
+    }
+
+    /// Insert the element `elem` at position `pos`.
+    pub fn insert(&mut self, elem: E, pos: glm::DVec3) {
+        let cell = self.cell_coord(&pos);
+        self.cells.entry(cell).or_default().push((elem, pos));
+        self.bounds = Some(match self.bounds {
+            Some((min, max)) => (
+                (min.0.min(cell.0), min.1.min(cell.1), min.2.min(cell.2)),
+                (max.0.max(cell.0), max.1.max(cell.1), max.2.max(cell.2)),
+            ),
+            None => (cell, cell),
+        });
+        self.len += 1;
+    }
+
+    /// Call `callback` for every element (and its position) within
+    /// `radius` (inclusive) of `co`.
+    ///
+    /// The cells touched by the query sphere are clamped to the
+    /// occupied cells, when there are still more of them than
+    /// occupied cells (large or infinite `radius`), the occupied
+    /// cells are tested instead.
+    pub fn find_within_radius_callback<F>(&self, co: &glm::DVec3, radius: f64, mut callback: F)
+    where
+        F: FnMut(E, &glm::DVec3),
+    {
+        let (bounds_min, bounds_max) = match self.bounds {
+            Some(bounds) if radius >= 0.0 => bounds,
+            _ => return,
+        };
+
+        let radius_sq = radius * radius;
+        let mut callback = |cell: &[(E, glm::DVec3)]| {
+            cell.iter()
+                .filter(|(_, pos)| glm::distance2(co, pos) <= radius_sq)
+                .for_each(|(elem, pos)| callback(*elem, pos));
+        };
+
+        // casting to i64 saturates, so an infinite radius is fine
+        let min = self.cell_coord(&(co - glm::vec3(radius, radius, radius)));
+        let max = self.cell_coord(&(co + glm::vec3(radius, radius, radius)));
+        let min = (
+            min.0.max(bounds_min.0),
+            min.1.max(bounds_min.1),
+            min.2.max(bounds_min.2),
+        );
+        let max = (
+            max.0.min(bounds_max.0),
+            max.1.min(bounds_max.1),
+            max.2.min(bounds_max.2),
+        );
+        if min.0 > max.0 || min.1 > max.1 || min.2 > max.2 {
+            return;
+        }
+
+        let num_cells = [(min.0, max.0), (min.1, max.1), (min.2, max.2)]
+            .iter()
+            .try_fold(1_u128, |num_cells, &(min, max)| {
+                num_cells.checked_mul(max.wrapping_sub(min) as u64 as u128 + 1)
+            });
+        if num_cells.map_or(true, |num_cells| num_cells > self.cells.len() as u128) {
+            self.cells
+                .iter()
+                .filter(|((x, y, z), _)| {
+                    (min.0..=max.0).contains(x)
+                        && (min.1..=max.1).contains(y)
+                        && (min.2..=max.2).contains(z)
+                })
+                .for_each(|(_, cell)| callback(cell));
+            return;
+        }
+
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for z in min.2..=max.2 {
+                    if let Some(cell) = self.cells.get(&(x, y, z)) {
+                        callback(cell);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Find all the elements within `radius` (inclusive) of `co`.
+    ///
+    /// Order of the elements is not defined.
+    pub fn find_within_radius(&self, co: &glm::DVec3, radius: f64) -> Vec<E> {
+        let mut res = Vec::new();
+        self.find_within_radius_callback(co, radius, |elem, _| res.push(elem));
+        res
+    }
+
+    /// Find the nearest element within `radius` (inclusive) of
+    /// `co`, returns the element and its distance from `co`.
+    pub fn find_nearest_within_radius(&self, co: &glm::DVec3, radius: f64) -> Option<(E, f64)> {
+        let mut nearest: Option<(E, f64)> = None;
+        self.find_within_radius_callback(co, radius, |elem, pos| {
+            let dist_sq = glm::distance2(co, pos);
+            match nearest {
+                Some((_, nearest_dist_sq)) if nearest_dist_sq <= dist_sq => {}
+                _ => nearest = Some((elem, dist_sq)),
+            }
+        });
+        nearest.map(|(elem, dist_sq)| (elem, dist_sq.sqrt()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spatial_hash_find_within_radius() {
+        let spatial_hash = SpatialHash::with_elements(
+            0.5,
+            vec![
+                (0, glm::vec3(0.0, 0.0, 0.0)),
+                (1, glm::vec3(0.1, 0.0, 0.0)),
+                (2, glm::vec3(-0.4, 0.0, 0.0)),
+                (3, glm::vec3(1.0, 1.0, 1.0)),
+                (4, glm::vec3(0.0, -0.6, 0.0)),
+            ],
+        );
+        assert_eq!(spatial_hash.len(), 5);
+
+        let mut found = spatial_hash.find_within_radius(&glm::vec3(0.0, 0.0, 0.0), 0.5);
+        found.sort_unstable();
+        assert_eq!(found, vec![0, 1, 2]);
+
+        let mut found = spatial_hash.find_within_radius(&glm::vec3(0.0, 0.0, 0.0), 2.0);
+        found.sort_unstable();
+        assert_eq!(found, vec![0, 1, 2, 3, 4]);
+
+        assert!(spatial_hash
+            .find_within_radius(&glm::vec3(5.0, 5.0, 5.0), 1.0)
+            .is_empty());
+
+        let (nearest, dist) = spatial_hash
+            .find_nearest_within_radius(&glm::vec3(0.9, 0.9, 0.9), 1.0)
+            .unwrap();
+        assert_eq!(nearest, 3);
+        assert!((dist - 0.03_f64.sqrt()).abs() < 1e-10);
+
+        // more cells in range than occupied cells
+        for radius in [1e12, f64::INFINITY] {
+            let mut found = spatial_hash.find_within_radius(&glm::vec3(0.0, 0.0, 0.0), radius);
+            found.sort_unstable();
+            assert_eq!(found, vec![0, 1, 2, 3, 4]);
+        }
+        let mut found = spatial_hash.find_within_radius(&glm::vec3(-1e9, 0.0, 0.0), 1e9);
+        found.sort_unstable();
+        assert_eq!(found, vec![0, 2, 4]);
+        assert!(spatial_hash
+            .find_within_radius(&glm::vec3(0.0, 0.0, 0.0), -1.0)
+            .is_empty());
+    }
+}
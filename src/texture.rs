@@ -1,10 +1,47 @@
 use std::convert::TryInto;
+use std::io::Write;
 
 use image::GenericImageView;
 use serde::{Deserialize, Serialize};
 
+use crate::util::linear_to_srgb;
 use crate::{glm, rasterize::Rasterize};
 
+#[derive(Debug)]
+pub enum TextureSaveError {
+    Io(std::io::Error),
+    Image(image::ImageError),
+    /// File extension is not one of the supported formats (png, exr).
+    UnknownFormat,
+    /// Texture has no pixels, the format cannot store it.
+    EmptyTexture,
+}
+
+impl From<std::io::Error> for TextureSaveError {
+    fn from(err: std::io::Error) -> Self {
+        TextureSaveError::Io(err)
+    }
+}
+
+impl From<image::ImageError> for TextureSaveError {
+    fn from(err: image::ImageError) -> Self {
+        TextureSaveError::Image(err)
+    }
+}
+
+impl std::fmt::Display for TextureSaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TextureSaveError::Io(error) => write!(f, "io error {}", error),
+            TextureSaveError::Image(error) => write!(f, "image error {}", error),
+            TextureSaveError::UnknownFormat => write!(f, "unknown format"),
+            TextureSaveError::EmptyTexture => write!(f, "empty texture"),
+        }
+    }
+}
+
+impl std::error::Error for TextureSaveError {}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TextureRGBAFloat {
     /// id that matches Image id from which the texture is made from
@@ -78,6 +115,160 @@ impl TextureRGBAFloat {
                 .collect(),
         ))
     }
+
+    /// Save the texture to `path`, format is picked from the
+    /// extension, see [`Self::write_png()`] and [`Self::write_exr()`].
+    pub fn save_to_disk<P>(&self, path: P) -> Result<(), TextureSaveError>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        let write = match extension.as_deref() {
+            Some("png") => Self::write_png,
+            Some("exr") => Self::write_exr,
+            _ => return Err(TextureSaveError::UnknownFormat),
+        };
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        write(self, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write the texture as an 8 bit RGBA PNG, top row first.
+    ///
+    /// The pixels are taken to be linear (as returned by
+    /// [`crate::framebuffer::capture_framebuffer()`]), the color is
+    /// converted to sRGB, alpha is written as is.
+    pub fn write_png<W: Write>(&self, writer: &mut W) -> Result<(), TextureSaveError> {
+        image::png::PngEncoder::new(writer).encode(
+            &self.to_srgb8(),
+            self.width.try_into().unwrap(),
+            self.height.try_into().unwrap(),
+            image::ColorType::Rgba8,
+        )?;
+        Ok(())
+    }
+
+    /// Get the pixels as 8 bit sRGB RGBA, top row first, as written
+    /// by [`Self::write_png()`].
+    pub(crate) fn to_srgb8(&self) -> Vec<u8> {
+        let to_u8 = |value: f64| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        self.pixels
+            .chunks(self.width.max(1))
+            .rev()
+            .flatten()
+            .flat_map(|pixel| {
+                let srgb = linear_to_srgb(&glm::convert::<_, glm::DVec4>(*pixel));
+                [
+                    to_u8(srgb[0]),
+                    to_u8(srgb[1]),
+                    to_u8(srgb[2]),
+                    to_u8(srgb[3]),
+                ]
+            })
+            .collect()
+    }
+
+    /// Write the texture as an uncompressed 32 bit float RGBA
+    /// OpenEXR, top row first. The pixels are written as is, EXR
+    /// stores linear values.
+    ///
+    /// The `image` crate doesn't support EXR, so this writes the
+    /// minimal single part scanline file, reference:
+    /// <https://openexr.com/en/latest/OpenEXRFileLayout.html>
+    ///
+    /// Returns [`TextureSaveError::EmptyTexture`] if the texture has
+    /// no pixels, EXR cannot store an empty data window.
+    pub fn write_exr<W: Write>(&self, writer: &mut W) -> Result<(), TextureSaveError> {
+        fn attribute(header: &mut Vec<u8>, name: &str, type_name: &str, value: &[u8]) {
+            header.extend_from_slice(name.as_bytes());
+            header.push(0);
+            header.extend_from_slice(type_name.as_bytes());
+            header.push(0);
+            header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+            header.extend_from_slice(value);
+        }
+
+        if self.width == 0 || self.height == 0 {
+            return Err(TextureSaveError::EmptyTexture);
+        }
+        let width: i32 = self.width.try_into().unwrap();
+        let height: i32 = self.height.try_into().unwrap();
+
+        // magic number and version 2, single part scanline
+        let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+
+        // channels must be in alphabetical order
+        let channels = ["A", "B", "G", "R"];
+        let mut chlist = Vec::new();
+        for channel in channels {
+            chlist.extend_from_slice(channel.as_bytes());
+            chlist.push(0);
+            // pixel type FLOAT
+            chlist.extend_from_slice(&2_i32.to_le_bytes());
+            // pLinear and reserved
+            chlist.extend_from_slice(&[0, 0, 0, 0]);
+            // x and y sampling
+            chlist.extend_from_slice(&1_i32.to_le_bytes());
+            chlist.extend_from_slice(&1_i32.to_le_bytes());
+        }
+        chlist.push(0);
+        attribute(&mut header, "channels", "chlist", &chlist);
+        // NO_COMPRESSION
+        attribute(&mut header, "compression", "compression", &[0]);
+        let window: Vec<u8> = [0, 0, width - 1, height - 1]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        attribute(&mut header, "dataWindow", "box2i", &window);
+        attribute(&mut header, "displayWindow", "box2i", &window);
+        // INCREASING_Y
+        attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+        attribute(
+            &mut header,
+            "pixelAspectRatio",
+            "float",
+            &1.0_f32.to_le_bytes(),
+        );
+        attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+        attribute(
+            &mut header,
+            "screenWindowWidth",
+            "float",
+            &1.0_f32.to_le_bytes(),
+        );
+        header.push(0);
+
+        // each scanline is its y, its size and then the channels one
+        // after the other
+        let line_data_size = self.width * channels.len() * std::mem::size_of::<f32>();
+        let line_size = 2 * std::mem::size_of::<i32>() + line_data_size;
+        let lines_start = header.len() + self.height * std::mem::size_of::<u64>();
+
+        writer.write_all(&header)?;
+        for y in 0..self.height {
+            writer.write_all(&((lines_start + y * line_size) as u64).to_le_bytes())?;
+        }
+
+        let mut line = Vec::with_capacity(line_size);
+        for (y, row) in self.pixels.chunks(self.width).rev().enumerate() {
+            line.clear();
+            line.extend_from_slice(&(y as i32).to_le_bytes());
+            line.extend_from_slice(&(line_data_size as i32).to_le_bytes());
+            for channel in [3, 2, 1, 0] {
+                row.iter()
+                    .for_each(|pixel| line.extend_from_slice(&pixel[channel].to_le_bytes()));
+            }
+            writer.write_all(&line)?;
+        }
+
+        Ok(())
+    }
+
     /// # Safety
     ///
     /// There is no way to generate [`TextureRGBAFloat`] without
@@ -226,6 +417,43 @@ impl TextureRGBAFloat {
         &self.pixels[j * self.width + i]
     }
 
+    /// Copy of the texture resized to `width` x `height` with a box
+    /// filter, each pixel is the average of the pixels of the texture
+    /// it covers weighted by the covered area.
+    pub fn to_resized(&self, width: usize, height: usize) -> Self {
+        if self.width == 0 || self.height == 0 {
+            return Self::new_empty(width, height);
+        }
+
+        let weights_x = box_filter_weights(self.width, width);
+        let weights_y = box_filter_weights(self.height, height);
+        let weighted_sum = |weights: &[(usize, f32)], pixel: &dyn Fn(usize) -> glm::Vec4| {
+            weights
+                .iter()
+                .fold(glm::Vec4::zeros(), |acc, (index, weight)| {
+                    acc + pixel(*index) * *weight
+                })
+        };
+
+        // resize the rows first, then the columns
+        let rows: Vec<glm::Vec4> = (0..self.height)
+            .flat_map(|j| {
+                weights_x
+                    .iter()
+                    .map(move |weights| weighted_sum(weights, &|i| *self.get_pixel(i, j)))
+            })
+            .collect();
+        let pixels = weights_y
+            .iter()
+            .flat_map(|weights| {
+                let rows = &rows;
+                (0..width).map(move |i| weighted_sum(weights, &|j| rows[j * width + i]))
+            })
+            .collect();
+
+        Self::from_pixels(width, height, pixels)
+    }
+
     /// Get the pixel from the specified UV coordinates
     ///
     /// Wrapping mode is set to repeat. TODO: need to make wrapping
@@ -263,6 +491,25 @@ impl TextureRGBAFloat {
     }
 }
 
+/// Weights of the box filter resizing `src_len` pixels to `dst_len`
+/// pixels, for every destination pixel the source pixels it covers
+/// along with the covered fraction of the destination pixel.
+fn box_filter_weights(src_len: usize, dst_len: usize) -> Vec<Vec<(usize, f32)>> {
+    let scale = src_len as f64 / dst_len as f64;
+    (0..dst_len)
+        .map(|i| {
+            let start = i as f64 * scale;
+            let end = (i + 1) as f64 * scale;
+            (start.floor() as usize..(end.ceil() as usize).min(src_len))
+                .filter_map(|k| {
+                    let covered = end.min(k as f64 + 1.0) - start.max(k as f64);
+                    (covered > 0.0).then(|| (k, (covered / scale) as f32))
+                })
+                .collect()
+        })
+        .collect()
+}
+
 impl Rasterize for TextureRGBAFloat {
     fn cleanup_opengl(&mut self) {
         unsafe {
@@ -279,3 +526,138 @@ impl Drop for TextureRGBAFloat {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_texture() -> TextureRGBAFloat {
+        // bottom row dark red, top row half transparent linear grey
+        TextureRGBAFloat::from_pixels(
+            2,
+            2,
+            vec![
+                glm::vec4(0.25, 0.0, 0.0, 1.0),
+                glm::vec4(0.25, 0.0, 0.0, 1.0),
+                glm::vec4(0.5, 0.5, 0.5, 0.5),
+                glm::vec4(0.5, 0.5, 0.5, 0.5),
+            ],
+        )
+    }
+
+    #[test]
+    fn texture_write_png() {
+        let texture = test_texture();
+        let mut png = Vec::new();
+        texture.write_png(&mut png).unwrap();
+
+        let image = image::load_from_memory(&png).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), (2, 2));
+        let srgb = |linear: f32| {
+            (linear_to_srgb(&glm::vec3(linear as f64, 0.0, 0.0))[0] * 255.0).round() as u8
+        };
+        // image rows are top down
+        assert_eq!(
+            image.get_pixel(0, 0).0,
+            [srgb(0.5), srgb(0.5), srgb(0.5), 128]
+        );
+        assert_eq!(image.get_pixel(1, 1).0, [srgb(0.25), 0, 0, 255]);
+
+        // loading flips the rows back, `image` widens 8 bit values
+        // to 16 bit by shifting so there is a small error
+        let loaded = TextureRGBAFloat::load_from_reader(std::io::Cursor::new(png)).unwrap();
+        assert!((loaded.get_pixel(0, 0)[0] - srgb(0.25) as f32 / 255.0).abs() < 1e-2);
+        assert!((loaded.get_pixel(1, 1)[3] - 128.0 / 255.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn texture_write_exr() {
+        use exr::prelude::*;
+
+        let texture = test_texture();
+        let mut bytes = Vec::new();
+        texture.write_exr(&mut bytes).unwrap();
+
+        let image = read()
+            .no_deep_data()
+            .largest_resolution_level()
+            .rgba_channels(
+                |resolution, _| {
+                    (
+                        resolution.width(),
+                        vec![[0.0; 4]; resolution.width() * resolution.height()],
+                    )
+                },
+                |(width, pixels), position, (r, g, b, a): (f32, f32, f32, f32)| {
+                    pixels[position.y() * *width + position.x()] = [r, g, b, a];
+                },
+            )
+            .first_valid_layer()
+            .all_attributes()
+            .from_buffered(std::io::Cursor::new(bytes))
+            .unwrap();
+
+        assert_eq!(image.layer_data.size, Vec2(2, 2));
+        let (_, pixels) = &image.layer_data.channel_data.pixels;
+        // top row first
+        assert_eq!(pixels[0], [0.5, 0.5, 0.5, 0.5]);
+        assert_eq!(pixels[1], [0.5, 0.5, 0.5, 0.5]);
+        assert_eq!(pixels[2], [0.25, 0.0, 0.0, 1.0]);
+        assert_eq!(pixels[3], [0.25, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn texture_write_exr_empty() {
+        let texture = TextureRGBAFloat::from_pixels(0, 0, Vec::new());
+        let mut exr = Vec::new();
+        assert!(matches!(
+            texture.write_exr(&mut exr),
+            Err(TextureSaveError::EmptyTexture)
+        ));
+        assert!(exr.is_empty());
+    }
+
+    #[test]
+    fn texture_to_resized() {
+        // 4x2, every pixel different
+        let texture = TextureRGBAFloat::from_pixels(
+            4,
+            2,
+            (0..8).map(|i| glm::vec4(i as f32, 0.0, 1.0, 1.0)).collect(),
+        );
+
+        let same = texture.to_resized(4, 2);
+        assert_eq!(same.get_pixels(), texture.get_pixels());
+
+        // each pixel is the average of a 2x2 block
+        let half = texture.to_resized(2, 1);
+        assert_eq!(half.get_pixels()[0], glm::vec4(2.5, 0.0, 1.0, 1.0));
+        assert_eq!(half.get_pixels()[1], glm::vec4(4.5, 0.0, 1.0, 1.0));
+
+        // non integer scale, pixels covered partially are weighted
+        let third = texture.to_resized(3, 1);
+        // first pixel: 3/4 of (0, 4) and 1/4 of (1, 5), second
+        // pixel: half of (1, 5) and half of (2, 6)
+        assert!((third.get_pixels()[0][0] - 2.25).abs() < 1e-5);
+        assert!((third.get_pixels()[1][0] - 3.5).abs() < 1e-5);
+        assert!(third
+            .get_pixels()
+            .iter()
+            .all(|pixel| (pixel[2] - 1.0).abs() < 1e-6));
+
+        // upscaling repeats the pixels
+        let double = texture.to_resized(8, 4);
+        assert_eq!(double.get_pixel(0, 0), texture.get_pixel(0, 0));
+        assert_eq!(double.get_pixel(7, 3), texture.get_pixel(3, 1));
+        assert_eq!(double.get_pixel(5, 1), texture.get_pixel(2, 0));
+    }
+
+    #[test]
+    fn texture_save_to_disk_unknown_format() {
+        let path = std::env::temp_dir().join("quick_renderer_texture_save_test.bmp");
+        assert!(matches!(
+            test_texture().save_to_disk(&path),
+            Err(TextureSaveError::UnknownFormat)
+        ));
+    }
+}
@@ -95,6 +95,49 @@ pub fn vec3_apply_bary_coord<T: glm::Number>(
     v1 * bary_coord[0] + v2 * bary_coord[1] + v3 * bary_coord[2]
 }
 
+/// Compute the bary coords of `p` with respect to the triangle given
+/// by the [`glm::TVec2`]s (`v1`, `v2`, `v3`).
+///
+/// The bary coords are not finite for degenerate triangles.
+pub fn vec2_compute_bary_coord<T: glm::RealNumber>(
+    p: &glm::TVec2<T>,
+    v1: &glm::TVec2<T>,
+    v2: &glm::TVec2<T>,
+    v3: &glm::TVec2<T>,
+) -> glm::TVec3<T> {
+    vec3_compute_bary_coord(
+        &glm::vec2_to_vec3(p),
+        &glm::vec2_to_vec3(v1),
+        &glm::vec2_to_vec3(v2),
+        &glm::vec2_to_vec3(v3),
+    )
+}
+
+/// Compute the bary coords of `p` with respect to the triangle given
+/// by the [`glm::TVec3`]s (`v1`, `v2`, `v3`). `p` is projected onto
+/// the plane of the triangle.
+///
+/// The bary coords are not finite for degenerate triangles.
+pub fn vec3_compute_bary_coord<T: glm::RealNumber>(
+    p: &glm::TVec3<T>,
+    v1: &glm::TVec3<T>,
+    v2: &glm::TVec3<T>,
+    v3: &glm::TVec3<T>,
+) -> glm::TVec3<T> {
+    let e1 = v2 - v1;
+    let e2 = v3 - v1;
+    let ep = p - v1;
+    let d11 = glm::dot(&e1, &e1);
+    let d12 = glm::dot(&e1, &e2);
+    let d22 = glm::dot(&e2, &e2);
+    let dp1 = glm::dot(&ep, &e1);
+    let dp2 = glm::dot(&ep, &e2);
+    let denom = d11 * d22 - d12 * d12;
+    let v = (d22 * dp1 - d12 * dp2) / denom;
+    let w = (d11 * dp2 - d12 * dp1) / denom;
+    glm::vec3(T::one() - v - w, v, w)
+}
+
 pub fn focal_length_to_fov(focal_length: f64, camera_sensor_size: f64) -> f64 {
     2.0 * (camera_sensor_size / (2.0 * focal_length)).atan()
 }